@@ -1,4 +1,3 @@
-use env_logger;
 use log::*;
 use std::net::*;
 use std::sync::Arc;
@@ -8,12 +7,12 @@ use tokio::signal;
 async fn main() {
     env_logger::init();
     let server = Arc::new(
-        nusbip::UsbIpServer::new_from_host_with_filter(|d| {
-            // Filter all mass storage devices (bInterfaceClass 08h for mass storage)
-            // Caveat: Do NOT export all usb devices
-            // unless you know exactly what you are doing.
-            d.interfaces().any(|i| i.class() == 0x08)
-        })
+        // Filter all mass storage devices (bInterfaceClass 08h for mass storage)
+        // Caveat: Do NOT export all usb devices
+        // unless you know exactly what you are doing.
+        nusbip::UsbIpServer::new_from_host_with_filter(nusbip::filter_by_interface_class(
+            nusbip::ClassCode::MassStorage as u8,
+        ))
         .await,
     );
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
@@ -0,0 +1,181 @@
+//! HTTP admin endpoint
+//!
+//! A minimal handwritten HTTP/1.1 server to manage a running [UsbIpServer]
+//! without a USB/IP client:
+//!
+//! - `GET /devices`: list devices, see [UsbIpServer::devices_json]
+//! - `POST /devices/{bus_id}/detach`: see [UsbIpServer::force_detach]
+//! - `GET /stats`: see [UsbIpServer::stats]
+//!
+//! Requests are not authenticated, bind it to a loopback address only.
+use super::*;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+/// Upper bound of a request head, requests have no body
+const MAX_REQUEST_LEN: u64 = 8192;
+
+/// Time a client has to send the request head
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spawn an HTTP admin server at `addr` for `server`
+///
+/// Anyone reaching `addr` can detach devices: use a loopback address, e.g. `127.0.0.1:8080`.
+pub async fn admin_server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+
+    while let Ok((socket, peer)) = listener.accept().await {
+        debug!("Admin connection from {peer:?}");
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_admin_request(socket, &server).await {
+                warn!("Admin request from {peer:?} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_admin_request<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: T,
+    server: &UsbIpServer,
+) -> Result<()> {
+    let mut reader = BufReader::new(socket).take(MAX_REQUEST_LEN);
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request_line(&mut reader))
+        .await
+        .map_err(|_| std::io::Error::new(ErrorKind::TimedOut, "Request head not received"))??;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (status, body) = match (method, segments.as_slice()) {
+        ("GET", ["devices"]) => ("200 OK", server.devices_json().await),
+        ("GET", ["stats"]) => ("200 OK", server.stats().to_json()),
+        ("POST", ["devices", bus_id, "detach"]) => match server.force_detach(bus_id).await {
+            Ok(()) => (
+                "200 OK",
+                format!(r#"{{"detached":{}}}"#, json_string(bus_id)),
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => ("404 Not Found", error_json(&e)),
+            Err(e) => ("500 Internal Server Error", error_json(&e)),
+        },
+        (_, ["devices"] | ["stats"] | ["devices", _, "detach"]) => (
+            "405 Method Not Allowed",
+            r#"{"error":"method not allowed"}"#.to_string(),
+        ),
+        _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+    };
+    debug!("Admin {method} {path}: {status}");
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let socket = reader.get_mut().get_mut();
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await
+}
+
+/// Read the head of a request up to the limit of `reader`, returning its request line
+async fn read_request_line<T: AsyncBufRead + Unpin>(
+    reader: &mut tokio::io::Take<T>,
+) -> Result<String> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // skip headers
+    let mut line = String::new();
+    while !matches!(line.as_str(), "\r\n" | "\n") {
+        // lines are cut at the limit
+        if reader.limit() == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Request head too long",
+            ));
+        }
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+fn error_json(err: &std::io::Error) -> String {
+    format!(r#"{{"error":{}}}"#, json_string(&err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    async fn http_request(addr: SocketAddr, method: &str, path: &str) -> String {
+        let mut connection = poll_connect(addr).await;
+        let request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        connection.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        connection.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn list_and_detach_devices() {
+        setup_test_logger();
+        let mut device = UsbDevice::new(0);
+        device.vendor_id = 0x1234;
+        device.product_id = 0x5678;
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let mut imported_device = None;
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(b"0-0-0");
+        server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+
+        let addr = get_free_address().await;
+        tokio::spawn(admin_server(addr, server.clone()));
+
+        let response = http_request(addr, "GET", "/devices").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(
+            body,
+            r#"[{"bus_id":"0-0-0","vendor_id":4660,"product_id":22136,"speed":3,"in_use":true}]"#
+        );
+
+        let response = http_request(addr, "POST", "/devices/0-0-0/detach").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(server.devices_json().await.contains(r#""in_use":false"#));
+
+        let response = http_request(addr, "POST", "/devices/0-0-0/detach").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = http_request(addr, "GET", "/stats").await;
+        assert!(response.ends_with(r#"{"connections":0,"urbs_completed":0,"urbs_failed":0}"#));
+    }
+
+    #[tokio::test]
+    async fn unbounded_request_rejected() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let request = tokio::spawn(async move { handle_admin_request(stream, &server).await });
+        // a request line never ending
+        let line = vec![b'a'; 1024];
+        while !request.is_finished() && client.write_all(&line).await.is_ok() {}
+        let err = request.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_request_times_out() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let (mut client, stream) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /devices HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let err = handle_admin_request(stream, &server).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,200 @@
+//! Synchronous server entry point
+//!
+//! Runs the server on OS threads without a tokio runtime, for applications
+//! that don't otherwise start one. The tokio crate is still a dependency: the
+//! commands are handled by the same async code as the tokio server, driven
+//! to completion on each thread over a blocking socket.
+use super::*;
+use crate::util::block_on;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Serve the async command handling over a blocking [Read] + [Write] stream
+///
+/// Every poll completes immediately by blocking the calling thread.
+struct BlockingSocket<S> {
+    inner: S,
+}
+
+impl<S: Read + Unpin> AsyncRead for BlockingSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let res = match self.get_mut().inner.read(buf.initialize_unfilled()) {
+            Ok(n) => {
+                buf.advance(n);
+                Ok(())
+            }
+            // read timeouts are reported as WouldBlock on unix
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("No traffic from client: {e}"),
+            )),
+            Err(e) => Err(e),
+        };
+        Poll::Ready(res)
+    }
+}
+
+impl<S: Write + Unpin> AsyncWrite for BlockingSocket<S> {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().inner.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().inner.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run a USB/IP server at `addr` using [std::net::TcpListener], one thread per connection
+///
+/// Blocks the calling thread until accepting a connection fails.
+pub fn server_blocking(addr: SocketAddr, server: Arc<UsbIpServer>) -> Result<()> {
+    let listener = server.bind_listener(addr)?;
+
+    loop {
+        let (socket, addr) = listener.accept()?;
+        info!("Got connection from {addr:?}");
+        server.configure_socket(socket2::SockRef::from(&socket));
+        if let Err(e) = socket.set_read_timeout(server.inactivity_timeout) {
+            warn!("Failed to set inactivity timeout: {e}");
+        }
+        let new_server = server.clone();
+        std::thread::spawn(move || {
+            let mut socket = BlockingSocket { inner: socket };
+            // releases the device if a handler panics
+            let mut guard = ReleaseGuard {
+                server: new_server.clone(),
+                device: None,
+                session: None,
+            };
+            let res = block_on(handle_connection(
+                &mut socket,
+                new_server.clone(),
+                &mut guard.device,
+                Some(addr),
+                None,
+            ));
+            info!("Handler ended with {res:?}");
+            if let Some(dev) = guard.device.take() {
+                block_on(new_server.release(dev));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::util::tests::*;
+
+    fn poll_connect_blocking(addr: SocketAddr) -> TcpStream {
+        loop {
+            if let Ok(stream) = TcpStream::connect(addr) {
+                return stream;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn read_u32(stream: &mut TcpStream) -> u32 {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        u32::from_be_bytes(buf)
+    }
+
+    #[test]
+    fn list_and_attach_over_std_socket() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        std::thread::spawn(move || server_blocking(addr, server));
+
+        let mut connection = poll_connect_blocking(addr);
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        connection.write_all(&req.to_bytes()).unwrap();
+        assert_eq!(read_u32(&mut connection), 0x01110005);
+        assert_eq!(read_u32(&mut connection), 0);
+        assert_eq!(read_u32(&mut connection), 1);
+        // one device without interfaces
+        connection.read_exact(&mut [0; 0x138]).unwrap();
+
+        let mut busid = [0; 32];
+        busid[..5].copy_from_slice(b"0-0-0");
+        let req = UsbIpCommand::OpReqImport { status: 0, busid };
+        connection.write_all(&req.to_bytes()).unwrap();
+        assert_eq!(read_u32(&mut connection), 0x01110003);
+        assert_eq!(read_u32(&mut connection), 0);
+    }
+
+    #[test]
+    fn panicking_handler_releases_device() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            Arc::new(Mutex::new(Box::new(crate::tests::PanickingHandler))),
+        );
+        let cooldown = Duration::from_millis(50);
+        let server =
+            Arc::new(UsbIpServer::new_simulated(vec![device]).with_release_cooldown(cooldown));
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server_ = server.clone();
+        std::thread::spawn(move || server_blocking(addr, server_));
+
+        let mut busid = [0; 32];
+        busid[..5].copy_from_slice(b"0-0-0");
+        let import = UsbIpCommand::OpReqImport { status: 0, busid };
+        let mut connection = poll_connect_blocking(addr);
+        connection.write_all(&import.to_bytes()).unwrap();
+        connection.read_exact(&mut [0; 0x140]).unwrap();
+        let req = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 1),
+            transfer_flags: 0,
+            transfer_buffer_length: 512,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        connection.write_all(&req.to_bytes()).unwrap();
+        // the thread of the connection panicked
+        assert_eq!(connection.read(&mut [0; 1]).unwrap(), 0);
+
+        // released, the next import waits for the cooldown
+        assert!(!block_on(server.is_used("0-0-0")));
+        assert!(server.cooldown_left("0-0-0").is_some());
+        let mut connection = poll_connect_blocking(addr);
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        connection.write_all(&req.to_bytes()).unwrap();
+        assert_eq!(read_u32(&mut connection), 0x01110005);
+        assert_eq!(read_u32(&mut connection), 0);
+        assert_eq!(read_u32(&mut connection), 1);
+    }
+}
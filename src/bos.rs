@@ -0,0 +1,177 @@
+//! Platform capabilities in the BOS descriptor, and MS OS 1.0 descriptors
+//!
+//! See the WebUSB specification, and the Microsoft OS 1.0 and 2.0 Descriptors Specifications.
+use super::*;
+
+/// bDevCapabilityType of a platform capability
+const PLATFORM: u8 = 0x05;
+
+/// {3408B638-09A9-47A0-8BFD-A0768815B665}, in little endian
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47, 0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+
+/// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}, in little endian
+const MS_OS_20_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+/// wIndex of the WebUSB GET_URL request
+const WEBUSB_GET_URL: u16 = 2;
+/// bDescriptorType of a WebUSB URL descriptor
+const WEBUSB_URL: u8 = 3;
+/// iLandingPage, the only URL of a device
+const WEBUSB_LANDING_PAGE: u8 = 1;
+
+/// wIndex of the request for the MS OS 2.0 descriptor set
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+/// dwWindowsVersion: Windows 8.1, the first to support MS OS 2.0 descriptors
+const MS_OS_20_WINDOWS_VERSION: u32 = 0x0603_0000;
+
+/// Index of the MS OS string descriptor, read by Windows to learn the vendor code
+pub(crate) const MS_OS_10_STRING_INDEX: u8 = 0xEE;
+/// wIndex of the request for the Extended Compat ID OS Feature Descriptor
+const MS_OS_10_EXTENDED_COMPAT_ID: u16 = 4;
+
+/// Platform capabilities of a simulated device
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub(crate) struct PlatformCapabilities {
+    /// bVendorCode and landing page URL
+    pub(crate) webusb: Option<(u8, String)>,
+    /// bMS_VendorCode and descriptor set
+    pub(crate) ms_os_20: Option<(u8, Vec<u8>)>,
+    /// bMS_VendorCode and compatible ids, by first interface number
+    pub(crate) ms_os_10: Option<(u8, BTreeMap<u8, [u8; 8]>)>,
+}
+
+impl PlatformCapabilities {
+    /// The BOS descriptor listing the capabilities
+    pub(crate) fn bos_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x05,                      // bLength
+            DescriptorType::BOS as u8, // bDescriptorType: BOS
+            0x00,
+            0x00, // wTotalLength: to be filled below
+            0x00, // bNumDeviceCaps: to be filled below
+        ];
+        if let Some((vendor_code, _)) = &self.webusb {
+            desc.extend_from_slice(&[
+                0x18,                                   // bLength
+                DescriptorType::DeviceCapability as u8, // bDescriptorType
+                PLATFORM,                               // bDevCapabilityType
+                0x00,                                   // bReserved
+            ]);
+            desc.extend_from_slice(&WEBUSB_UUID); // PlatformCapabilityUUID
+            desc.extend_from_slice(&[
+                0x00,
+                0x01,                // bcdVersion: 1.0
+                *vendor_code,        // bVendorCode
+                WEBUSB_LANDING_PAGE, // iLandingPage
+            ]);
+            desc[4] += 1;
+        }
+        if let Some((vendor_code, descriptor_set)) = &self.ms_os_20 {
+            desc.extend_from_slice(&[
+                0x1C,                                   // bLength
+                DescriptorType::DeviceCapability as u8, // bDescriptorType
+                PLATFORM,                               // bDevCapabilityType
+                0x00,                                   // bReserved
+            ]);
+            desc.extend_from_slice(&MS_OS_20_UUID); // PlatformCapabilityUUID
+            desc.extend_from_slice(&MS_OS_20_WINDOWS_VERSION.to_le_bytes()); // dwWindowsVersion
+            desc.extend_from_slice(&(descriptor_set.len() as u16).to_le_bytes()); // wMSOSDescriptorSetTotalLength
+            desc.extend_from_slice(&[
+                *vendor_code, // bMS_VendorCode
+                0x00,         // bAltEnumCode
+            ]);
+            desc[4] += 1;
+        }
+        let len = desc.len() as u16;
+        desc[2..4].copy_from_slice(&len.to_le_bytes());
+        desc
+    }
+
+    /// Answer a vendor request to the device for a platform descriptor
+    ///
+    /// `None` if the request is not one of the registered capabilities.
+    pub(crate) fn handle_vendor_request(&self, setup: &SetupPacket) -> Option<Vec<u8>> {
+        if let Some((vendor_code, url)) = &self.webusb
+            && setup.request == *vendor_code
+            && setup.index == WEBUSB_GET_URL
+            && setup.value == WEBUSB_LANDING_PAGE as u16
+        {
+            return Some(url_descriptor(url));
+        }
+        if let Some((vendor_code, descriptor_set)) = &self.ms_os_20
+            && setup.request == *vendor_code
+            && setup.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            return Some(descriptor_set.clone());
+        }
+        if let Some((vendor_code, compatible_ids)) = &self.ms_os_10
+            && setup.request == *vendor_code
+            && setup.index == MS_OS_10_EXTENDED_COMPAT_ID
+        {
+            return Some(extended_compat_id_descriptor(compatible_ids));
+        }
+        None
+    }
+
+    /// The MS OS string descriptor, if MS OS 1.0 descriptors are registered
+    pub(crate) fn ms_os_10_string_descriptor(&self) -> Option<Vec<u8>> {
+        let (vendor_code, _) = self.ms_os_10.as_ref()?;
+        let mut desc = vec![
+            0x12,                         // bLength
+            DescriptorType::String as u8, // bDescriptorType
+        ];
+        for c in "MSFT100".encode_utf16() {
+            desc.extend_from_slice(&c.to_le_bytes()); // qwSignature
+        }
+        desc.extend_from_slice(&[
+            *vendor_code, // bMS_VendorCode
+            0x00,         // bPad
+        ]);
+        Some(desc)
+    }
+}
+
+/// The Extended Compat ID OS Feature Descriptor, with a function per interface
+fn extended_compat_id_descriptor(compatible_ids: &BTreeMap<u8, [u8; 8]>) -> Vec<u8> {
+    let len = 16 + 24 * compatible_ids.len() as u32;
+    let mut desc = len.to_le_bytes().to_vec(); // dwLength
+    desc.extend_from_slice(&[
+        0x00, 0x01, // bcdVersion: 1.0
+    ]);
+    desc.extend_from_slice(&MS_OS_10_EXTENDED_COMPAT_ID.to_le_bytes()); // wIndex
+    desc.push(compatible_ids.len() as u8); // bCount
+    desc.extend_from_slice(&[0; 7]); // reserved
+    for (interface, compatible_id) in compatible_ids {
+        desc.extend_from_slice(&[
+            *interface, // bFirstInterfaceNumber
+            0x01,       // reserved
+        ]);
+        desc.extend_from_slice(compatible_id); // compatibleID
+        desc.extend_from_slice(&[0; 8]); // subCompatibleID
+        desc.extend_from_slice(&[0; 6]); // reserved
+    }
+    desc
+}
+
+/// A WebUSB URL descriptor, the scheme is encoded as bScheme
+fn url_descriptor(url: &str) -> Vec<u8> {
+    let (scheme, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (0x01, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (0x00, rest)
+    } else {
+        (0xFF, url)
+    };
+    let mut desc = vec![
+        3 + rest.len() as u8, // bLength
+        WEBUSB_URL,           // bDescriptorType
+        scheme,               // bScheme
+    ];
+    desc.extend_from_slice(rest.as_bytes());
+    desc
+}
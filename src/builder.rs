@@ -0,0 +1,291 @@
+//! Build simulated devices from closures per endpoint
+use super::*;
+
+/// Callback answering a bulk IN URB of up to the given length, see [InterfaceBuilder::on_bulk_in]
+pub type BulkInCallback = dyn FnMut(u32) -> Result<Vec<u8>> + Send;
+
+/// Callback taking the data of a bulk OUT URB, see [InterfaceBuilder::on_bulk_out]
+pub type BulkOutCallback = dyn FnMut(&[u8]) -> Result<()> + Send;
+
+/// Builds a [UsbDevice] whose interfaces are served by closures
+///
+/// ```ignore
+/// let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+///     .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+///         intf.on_bulk_in(0x81, 512, |len| Ok(vec![0; len as usize]))
+///             .on_bulk_out(0x01, 512, |data| Ok(()))
+///     })
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct UsbDeviceBuilder {
+    device: UsbDevice,
+}
+
+impl UsbDeviceBuilder {
+    /// Add interfaces to `device`, e.g. a [UsbDevice::new] with its ids set
+    pub fn new(device: UsbDevice) -> Self {
+        Self { device }
+    }
+
+    /// Add an interface, whose endpoints and closures are set by `build`
+    pub fn with_interface(
+        mut self,
+        interface_class: u8,
+        interface_subclass: u8,
+        interface_protocol: u8,
+        build: impl FnOnce(InterfaceBuilder) -> InterfaceBuilder,
+    ) -> Self {
+        let intf = build(InterfaceBuilder::default());
+        self.device = self.device.with_interface(
+            interface_class,
+            interface_subclass,
+            interface_protocol,
+            intf.name.as_deref(),
+            intf.endpoints,
+            Arc::new(Mutex::new(Box::new(intf.handler))),
+        );
+        self
+    }
+
+    /// The device with the interfaces added
+    pub fn build(self) -> UsbDevice {
+        self.device
+    }
+}
+
+/// The endpoints of an interface of a [UsbDeviceBuilder], and their closures
+#[derive(Debug, Default)]
+pub struct InterfaceBuilder {
+    name: Option<String>,
+    endpoints: Vec<UsbEndpoint>,
+    handler: ClosureHandler,
+}
+
+impl InterfaceBuilder {
+    /// Name of the interface, as string descriptor
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Add bulk IN endpoint `address`, answered by `callback`
+    pub fn on_bulk_in<F>(mut self, address: u8, max_packet_size: u16, callback: F) -> Self
+    where
+        F: FnMut(u32) -> Result<Vec<u8>> + Send + 'static,
+    {
+        let address = address | 0x80;
+        self.endpoints.push(bulk_endpoint(address, max_packet_size));
+        self.handler.bulk_in.insert(address, Box::new(callback));
+        self
+    }
+
+    /// Add bulk OUT endpoint `address`, whose data goes to `callback`
+    pub fn on_bulk_out<F>(mut self, address: u8, max_packet_size: u16, callback: F) -> Self
+    where
+        F: FnMut(&[u8]) -> Result<()> + Send + 'static,
+    {
+        let address = address & 0x7F;
+        self.endpoints.push(bulk_endpoint(address, max_packet_size));
+        self.handler.bulk_out.insert(address, Box::new(callback));
+        self
+    }
+
+    /// Answer control requests to the interface with `callback`
+    pub fn on_control<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(SetupPacket, &[u8]) -> Result<Vec<u8>> + Send + 'static,
+    {
+        self.handler.control = Some(Box::new(callback));
+        self
+    }
+}
+
+fn bulk_endpoint(address: u8, max_packet_size: u16) -> UsbEndpoint {
+    UsbEndpoint {
+        address,
+        attributes: EndpointAttributes::Bulk as u8,
+        max_packet_size,
+        interval: 0,
+    }
+}
+
+/// Dispatches URBs to the closures of an [InterfaceBuilder], others are stalled
+#[derive(Default)]
+struct ClosureHandler {
+    bulk_in: HashMap<u8, Box<BulkInCallback>>,
+    bulk_out: HashMap<u8, Box<BulkOutCallback>>,
+    control: Option<Box<vendor::VendorControlCallback>>,
+}
+
+impl std::fmt::Debug for ClosureHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClosureHandler")
+            .field("bulk_in", &self.bulk_in.keys())
+            .field("bulk_out", &self.bulk_out.keys())
+            .field("control", &self.control.as_ref().map(|_| "Opaque"))
+            .finish()
+    }
+}
+
+impl UsbInterfaceHandler for ClosureHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_flags: u32,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            if let Some(callback) = &self.control {
+                return callback(setup, req);
+            }
+        } else if let Some(callback) = self.bulk_in.get_mut(&ep.address) {
+            return callback(transfer_buffer_length);
+        } else if let Some(callback) = self.bulk_out.get_mut(&ep.address) {
+            callback(req)?;
+            return Ok(vec![]);
+        }
+        Err(stall(format!(
+            "No closure for endpoint {:#04x}",
+            ep.address
+        )))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    /// A vendor request to the interface reading 2 bytes
+    const VENDOR_IN: SetupPacket = SetupPacket {
+        request_type: 0b11000001, // vendor, interface
+        request: 0x01,
+        value: 0,
+        index: 0,
+        length: 2,
+    };
+
+    #[test]
+    fn closures_per_endpoint() {
+        setup_test_logger();
+        let written = Arc::new(Mutex::new(vec![]));
+        let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                let written = written.clone();
+                let mut count = 0;
+                intf.with_name("Loopback")
+                    .on_bulk_in(0x81, 512, move |len| {
+                        count += 1;
+                        Ok(vec![count; len as usize])
+                    })
+                    .on_bulk_out(0x01, 512, move |data| {
+                        written.lock().unwrap().extend_from_slice(data);
+                        Ok(())
+                    })
+                    .on_control(|setup, _req| Ok(vec![setup.request; setup.length as usize]))
+            })
+            .build();
+        assert_eq!(device.interfaces[0].endpoints.len(), 2);
+
+        let server = UsbIpServer::new_simulated(vec![]);
+        let bulk_in = |len| {
+            let header = submit_header(0, 1, 1);
+            submit(
+                &server,
+                &device,
+                header,
+                len,
+                SetupPacket::default(),
+                vec![],
+            )
+        };
+        assert_eq!(bulk_in(4), (0, vec![1; 4]));
+        assert_eq!(bulk_in(2), (0, vec![2; 2]));
+        let header = submit_header(0, 0, 1);
+        let data = vec![7, 8, 9];
+        let bulk_out = submit(&server, &device, header, 3, SetupPacket::default(), data);
+        assert_eq!(bulk_out, (0, vec![]));
+        assert_eq!(*written.lock().unwrap(), vec![7, 8, 9]);
+        let control = submit(
+            &server,
+            &device,
+            submit_header(0, 1, 0),
+            2,
+            VENDOR_IN,
+            vec![],
+        );
+        assert_eq!(control, (0, vec![0x01; 2]));
+    }
+
+    #[test]
+    fn shared_endpoint_number() {
+        setup_test_logger();
+        let written = Arc::new(Mutex::new(vec![]));
+        let written_ = written.clone();
+        let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                intf.on_bulk_out(0x01, 512, move |data| {
+                    written_.lock().unwrap().extend_from_slice(data);
+                    Ok(())
+                })
+            })
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                intf.on_bulk_in(0x81, 512, |len| Ok(vec![2; len as usize]))
+            })
+            .build();
+        assert!(device.validate().is_ok());
+        for (address, intf) in [(0x01, 0), (0x81, 1)] {
+            let (ep, found) = device.find_ep(address).unwrap();
+            assert_eq!(ep.address, address);
+            assert!(std::ptr::eq(found.unwrap(), &device.interfaces[intf]));
+        }
+
+        let server = UsbIpServer::new_simulated(vec![]);
+        let header = submit_header(0, 1, 1);
+        let bulk_in = submit(&server, &device, header, 2, SetupPacket::default(), vec![]);
+        assert_eq!(bulk_in, (0, vec![2; 2]));
+        let header = submit_header(0, 0, 1);
+        let bulk_out = submit(
+            &server,
+            &device,
+            header,
+            2,
+            SetupPacket::default(),
+            vec![1, 1],
+        );
+        assert_eq!(bulk_out, (0, vec![]));
+        assert_eq!(*written.lock().unwrap(), vec![1, 1]);
+    }
+
+    #[test]
+    fn missing_closure_stalls() {
+        setup_test_logger();
+        let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                intf.on_bulk_out(0x01, 512, |_data| Ok(()))
+            })
+            .build();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let control = submit(
+            &server,
+            &device,
+            submit_header(0, 1, 0),
+            2,
+            VENDOR_IN,
+            vec![],
+        );
+        assert_eq!(control.0, UrbStatus::EPIPE);
+    }
+}
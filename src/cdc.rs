@@ -53,6 +53,7 @@ impl UsbInterfaceHandler for UsbCdcAcmHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        _transfer_flags: u32,
         _transfer_buffer_length: u32,
         _setup: SetupPacket,
         req: &[u8],
@@ -0,0 +1,380 @@
+//! Implement a CDC ECM(Ethernet Control Model) device, a virtual network adapter
+use super::*;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+// reference:
+// CDC ECM 1.2: https://www.usb.org/document-library/class-definitions-communication-devices-12
+
+/// Sub class code for CDC ECM
+pub const CDC_ECM_SUBCLASS: u8 = 0x06;
+
+/// Class request codes, see CDC ECM 1.2 Table 6
+const SET_ETHERNET_MULTICAST_FILTERS: u8 = 0x40;
+const SET_ETHERNET_PACKET_FILTER: u8 = 0x43;
+const GET_ETHERNET_STATISTIC: u8 = 0x44;
+
+/// Ethernet statistics feature selectors, see CDC ECM 1.2 Table 9
+const XMIT_OK: u16 = 1;
+const RCV_OK: u16 = 2;
+
+/// bNotificationCode of NETWORK_CONNECTION, see CDC PSTN 1.2 Table 30
+const NETWORK_CONNECTION: u8 = 0x00;
+
+/// Largest ethernet frame, without the FCS
+const MAX_SEGMENT_SIZE: u16 = 1514;
+
+/// The other end of a [UsbCdcEcmHandler], to bridge its frames to a network
+#[derive(Debug)]
+pub struct EcmChannel {
+    /// Frames to deliver to the host, read from the bulk IN endpoint
+    pub to_host: UnboundedSender<Vec<u8>>,
+    /// Frames sent by the host on the bulk OUT endpoint
+    pub from_host: UnboundedReceiver<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct EcmState {
+    to_host: UnboundedReceiver<Vec<u8>>,
+    from_host: UnboundedSender<Vec<u8>>,
+    packet_filter: u16,
+    connection_notified: bool,
+    frames_sent: u32,
+    frames_received: u32,
+}
+
+/// A handler of a CDC ECM network adapter
+///
+/// Its frames are exchanged through the [EcmChannel] returned by [Self::new].
+/// Use [Self::to_device] to build the [UsbDevice] to export, with a
+/// communication interface and a data interface sharing the handler.
+#[derive(Clone, Debug)]
+pub struct UsbCdcEcmHandler {
+    mac_address: [u8; 6],
+    /// iMACAddress, set by [Self::to_device]
+    string_mac_address: u8,
+    // the data interface has no functional descriptors
+    data_interface: bool,
+    state: Arc<Mutex<EcmState>>,
+}
+
+impl UsbCdcEcmHandler {
+    /// An adapter with `mac_address`, and the channel carrying its frames
+    pub fn new(mac_address: [u8; 6]) -> (Self, EcmChannel) {
+        let (to_host, to_host_rx) = mpsc::unbounded_channel();
+        let (from_host_tx, from_host) = mpsc::unbounded_channel();
+        let handler = Self {
+            mac_address,
+            string_mac_address: 0,
+            data_interface: false,
+            state: Arc::new(Mutex::new(EcmState {
+                to_host: to_host_rx,
+                from_host: from_host_tx,
+                packet_filter: 0,
+                connection_notified: false,
+                frames_sent: 0,
+                frames_received: 0,
+            })),
+        };
+        (handler, EcmChannel { to_host, from_host })
+    }
+
+    /// Packet filter set by the host with SET_ETHERNET_PACKET_FILTER
+    ///
+    /// Frames are delivered whatever the filter, it only tells what the host wants.
+    pub fn packet_filter(&self) -> u16 {
+        self.state.lock().unwrap().packet_filter
+    }
+
+    pub fn endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // notification
+            UsbEndpoint {
+                address: 0x81,                                   // IN
+                attributes: EndpointAttributes::Interrupt as u8, // Interrupt
+                max_packet_size: 0x10,                           // 16 bytes
+                interval: 8,
+            },
+        ]
+    }
+
+    pub fn data_endpoints() -> Vec<UsbEndpoint> {
+        vec![
+            // bulk in
+            UsbEndpoint {
+                address: 0x82,                              // IN
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+            // bulk out
+            UsbEndpoint {
+                address: 0x02,                              // OUT
+                attributes: EndpointAttributes::Bulk as u8, // Bulk
+                max_packet_size: 512,                       // 512 bytes
+                interval: 0,
+            },
+        ]
+    }
+
+    /// Build a network adapter device served by this handler
+    pub fn to_device(&self, index: u32) -> UsbDevice {
+        let mut device = UsbDevice::new(index).with_speed(UsbSpeed::High);
+        let mac: String = self
+            .mac_address
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect();
+        let mut control = self.clone();
+        control.string_mac_address = device.new_string(&mac);
+        let mut data = control.clone();
+        data.data_interface = true;
+        device = device
+            .with_interface(
+                ClassCode::CDC as u8,
+                CDC_ECM_SUBCLASS,
+                0x00,
+                Some("CDC ECM"),
+                Self::endpoints(),
+                Arc::new(Mutex::new(Box::new(control))),
+            )
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                None,
+                Self::data_endpoints(),
+                Arc::new(Mutex::new(Box::new(data))),
+            );
+        device.device_class = ClassCode::CDC as u8;
+        device.set_product_name("Virtual Ethernet Adapter");
+        device
+    }
+
+    fn handle_control(&mut self, setup: SetupPacket) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        match setup.request {
+            SET_ETHERNET_PACKET_FILTER => {
+                debug!("Ethernet packet filter {:#06x}", setup.value);
+                state.packet_filter = setup.value;
+                Ok(vec![])
+            }
+            // multicast frames are not filtered
+            SET_ETHERNET_MULTICAST_FILTERS => Ok(vec![]),
+            GET_ETHERNET_STATISTIC => {
+                let count = match setup.value {
+                    XMIT_OK => state.frames_sent,
+                    RCV_OK => state.frames_received,
+                    _ => 0,
+                };
+                let mut resp = count.to_le_bytes().to_vec();
+                resp.truncate(setup.length as usize);
+                Ok(resp)
+            }
+            request => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported CDC ECM request {request:#04x}"),
+            )),
+        }
+    }
+}
+
+impl UsbInterfaceHandler for UsbCdcEcmHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_flags: u32,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            return self.handle_control(setup);
+        }
+        let mut state = self.state.lock().unwrap();
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            // interrupt in: the link is up from the start
+            if state.connection_notified {
+                return Ok(vec![]);
+            }
+            state.connection_notified = true;
+            return Ok(vec![
+                0xA1,               // bmRequestType: class, interface
+                NETWORK_CONNECTION, // bNotificationCode
+                0x01,               // wValue: connected
+                0x00,
+                0x00, // wIndex: interface
+                0x00,
+                0x00, // wLength
+                0x00,
+            ]);
+        }
+        match ep.direction() {
+            Direction::Out => {
+                // bulk out: one frame per transfer
+                state.frames_sent += 1;
+                if state.from_host.send(req.to_vec()).is_err() {
+                    debug!("Dropping frame from the host, nobody is reading them");
+                }
+                Ok(vec![])
+            }
+            Direction::In => {
+                // bulk in: one frame per transfer, the host reads the end of a frame
+                // from a short packet, so frames not fitting in the URB can't be split
+                while let Ok(frame) = state.to_host.try_recv() {
+                    if frame.len() > transfer_buffer_length as usize {
+                        warn!("Dropping {} bytes frame longer than the URB", frame.len());
+                        continue;
+                    }
+                    state.frames_received += 1;
+                    return Ok(frame);
+                }
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        if self.data_interface {
+            return vec![];
+        }
+        vec![
+            // Header
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x00, // Header
+            0x10,
+            0x01, // CDC 1.2
+            // Union
+            0x05, // bFunctionLength
+            0x24, // CS_INTERFACE
+            0x06, // Union
+            0x00, // bControlInterface
+            0x01, // bSubordinateInterface0
+            // Ethernet Networking
+            0x0D,                    // bFunctionLength
+            0x24,                    // CS_INTERFACE
+            0x0F,                    // Ethernet Networking
+            self.string_mac_address, // iMACAddress
+            0x03,                    // XMIT_OK, RCV_OK
+            0x00,
+            0x00,
+            0x00, // bmEthernetStatistics
+            MAX_SEGMENT_SIZE as u8,
+            (MAX_SEGMENT_SIZE >> 8) as u8, // wMaxSegmentSize
+            0x00,
+            0x00, // wNumberMCFilters
+            0x00, // bNumberPowerFilters
+        ]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn desc_verify() {
+        setup_test_logger();
+        let (handler, _channel) = UsbCdcEcmHandler::new([0x02, 0, 0, 0, 0, 1]);
+        let desc = handler.get_class_specific_descriptor();
+        verify_descriptor(&desc);
+        // bmEthernetStatistics: XMIT_OK and RCV_OK
+        assert_eq!(desc[14..18], [0x03, 0, 0, 0]);
+    }
+
+    #[test]
+    fn frames_in_and_out() {
+        setup_test_logger();
+        let (handler, mut channel) = UsbCdcEcmHandler::new([0x02, 0, 0, 0, 0, 1]);
+        let device = handler.to_device(0);
+        assert_eq!(device.interfaces.len(), 2);
+        assert!(
+            device.interfaces[1]
+                .class_specific_descriptor
+                .lock()
+                .unwrap()
+                .is_empty()
+        );
+
+        let server = UsbIpServer::new_simulated(vec![]);
+        let bulk_in = |len| {
+            let header = submit_header(0, 1, 2);
+            submit(
+                &server,
+                &device,
+                header,
+                len,
+                SetupPacket::default(),
+                vec![],
+            )
+        };
+        // one frame per URB, frames longer than the URB are dropped
+        let frame: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        channel.to_host.send(frame.clone()).unwrap();
+        channel.to_host.send(vec![0xAA; 60]).unwrap();
+        channel.to_host.send(frame.clone()).unwrap();
+        channel.to_host.send(vec![0xBB; 60]).unwrap();
+        assert_eq!(bulk_in(2048), (0, frame));
+        assert_eq!(bulk_in(512), (0, vec![0xAA; 60]));
+        assert_eq!(bulk_in(512), (0, vec![0xBB; 60]));
+        // nothing more to deliver
+        assert_eq!(bulk_in(512), (0, vec![]));
+
+        let frame = vec![0xFF; 60];
+        let header = submit_header(0, 0, 2);
+        let bulk_out = submit(
+            &server,
+            &device,
+            header,
+            60,
+            SetupPacket::default(),
+            frame.clone(),
+        );
+        assert_eq!(bulk_out, (0, vec![]));
+        assert_eq!(channel.from_host.try_recv().unwrap(), frame);
+    }
+
+    #[test]
+    fn control_requests() {
+        setup_test_logger();
+        let (handler, channel) = UsbCdcEcmHandler::new([0x02, 0, 0, 0, 0, 1]);
+        let device = handler.to_device(0);
+        let class_request = |request, value, length| SetupPacket {
+            request_type: 0b00100001, // class, interface
+            request,
+            value,
+            index: 0,
+            length,
+        };
+        let set_filter = class_request(SET_ETHERNET_PACKET_FILTER, 0x000E, 0);
+        device
+            .handle_urb(device.ep0_out, None, 0, 0, set_filter, &[])
+            .unwrap();
+        assert_eq!(handler.packet_filter(), 0x000E);
+
+        channel.to_host.send(vec![0; 60]).unwrap();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let header = submit_header(0, 1, 2);
+        submit(
+            &server,
+            &device,
+            header,
+            512,
+            SetupPacket::default(),
+            vec![],
+        );
+        let mut get_statistic = class_request(GET_ETHERNET_STATISTIC, RCV_OK, 4);
+        get_statistic.request_type |= 0x80;
+        let count = device
+            .handle_urb(device.ep0_in, None, 0, 4, get_statistic, &[])
+            .unwrap();
+        assert_eq!(count, 1u32.to_le_bytes());
+    }
+}
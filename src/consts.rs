@@ -74,6 +74,32 @@ impl TransferFlags {
     pub const ALIGNED_TEMP_BUFFER: u32 = 0x00800000;
 }
 
+/// URB completion statuses sent in RET_SUBMIT
+///
+/// USB/IP carries the negative errno of the client's kernel, so these are
+/// the Linux values whatever platform the server runs on.
+pub struct UrbStatus;
+impl UrbStatus {
+    pub const OK: i32 = 0;
+    pub const ENOENT: i32 = -2;
+    pub const ENODEV: i32 = -19;
+    pub const EINVAL: i32 = -22;
+    pub const EPIPE: i32 = -32;
+    pub const EPROTO: i32 = -71;
+    pub const EOVERFLOW: i32 = -75;
+    pub const EMSGSIZE: i32 = -90;
+    pub const ECONNRESET: i32 = -104;
+    pub const ESHUTDOWN: i32 = -108;
+    pub const ETIMEDOUT: i32 = -110;
+}
+
+/// Feature selector of SET_FEATURE/CLEAR_FEATURE to a device, see USB 2.0 Table 9-6
+pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+/// Feature selector of SET_FEATURE to a device, see USB 2.0 Table 9-6
+pub const TEST_MODE: u16 = 2;
+/// Feature selector of SET_FEATURE/CLEAR_FEATURE to an endpoint, see USB 2.0 Table 9-6
+pub const ENDPOINT_HALT: u16 = 0;
+
 /// Emulated max packet size of EP0
 pub const EP0_MAX_PACKET_SIZE: u16 = 64;
 
@@ -93,6 +119,10 @@ pub enum StandardRequest {
     GetInterface = 10,
     SetInterface = 11,
     SynchFrame = 12,
+    /// USB 3.2 Table 9-5, SuperSpeed only
+    SetSel = 48,
+    /// USB 3.2 Table 9-5, SuperSpeed only
+    SetIsochDelay = 49,
 }
 
 /// A list of defined USB descriptor types
@@ -0,0 +1,101 @@
+//! Replies to URBs held back by a connection
+use super::*;
+use tokio::time::Instant;
+
+/// The endpoint of a URB, by devid, direction and endpoint number
+type EndpointKey = (u32, u32, u32);
+
+/// A reply held until `due`
+#[derive(Debug)]
+struct Deferred {
+    seqnum: u32,
+    due: Instant,
+    reply: UsbIpResponse,
+}
+
+/// RET_SUBMITs deferred by a connection, in order per endpoint
+///
+/// Empty replies of interrupt IN endpoints are held until their polling
+/// interval elapsed, so that the client doesn't poll the device faster, while
+/// the connection keeps serving other URBs. Later replies of the endpoint
+/// wait behind them: URBs of an endpoint complete in order.
+#[derive(Debug, Default)]
+pub(crate) struct DeferredReplies {
+    endpoints: HashMap<EndpointKey, VecDeque<Deferred>>,
+}
+
+impl DeferredReplies {
+    /// Hold `reply` to a URB to `ep` polled at `start`, for `interval` after it or after the previous NAK
+    pub(crate) fn defer_nak(
+        &mut self,
+        ep: EndpointKey,
+        seqnum: u32,
+        reply: UsbIpResponse,
+        start: Instant,
+        interval: Duration,
+    ) {
+        let queue = self.endpoints.entry(ep).or_default();
+        let after = queue.back().map_or(start, |last| last.due.max(start));
+        queue.push_back(Deferred {
+            seqnum,
+            due: after + interval,
+            reply,
+        });
+    }
+
+    /// Hold `reply` to a URB to `ep` behind the deferred ones, returned if there are none
+    pub(crate) fn queue(
+        &mut self,
+        ep: EndpointKey,
+        seqnum: u32,
+        reply: UsbIpResponse,
+    ) -> Option<UsbIpResponse> {
+        let Some(queue) = self.endpoints.get_mut(&ep) else {
+            return Some(reply);
+        };
+        // not empty, emptied queues are removed
+        let due = queue.back().unwrap().due;
+        queue.push_back(Deferred { seqnum, due, reply });
+        None
+    }
+
+    /// Wait until a reply is due, forever if none is deferred
+    pub(crate) async fn due(&self) {
+        match self
+            .endpoints
+            .values()
+            .filter_map(|q| q.front())
+            .map(|d| d.due)
+            .min()
+        {
+            Some(due) => tokio::time::sleep_until(due).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Take the replies due by now, in order per endpoint
+    pub(crate) fn take_due(&mut self) -> Vec<UsbIpResponse> {
+        let now = Instant::now();
+        let mut due = vec![];
+        self.endpoints.retain(|_, queue| {
+            while queue.front().is_some_and(|d| d.due <= now) {
+                due.push(queue.pop_front().unwrap().reply);
+            }
+            !queue.is_empty()
+        });
+        due
+    }
+
+    /// Drop the reply to the URB `seqnum`, returning whether it was deferred
+    pub(crate) fn cancel(&mut self, seqnum: u32) -> bool {
+        let mut found = false;
+        self.endpoints.retain(|_, queue| {
+            if let Some(i) = queue.iter().position(|d| d.seqnum == seqnum) {
+                queue.remove(i);
+                found = true;
+            }
+            !queue.is_empty()
+        });
+        found
+    }
+}
@@ -0,0 +1,279 @@
+//! Simulated devices defined in JSON, for reproducible emulation setups
+use super::*;
+use std::io::Read;
+use std::path::Path;
+
+/// A device as defined in JSON, see [UsbDevice::from_json]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceDefinition {
+    #[serde(default)]
+    index: u32,
+    bus_id: Option<String>,
+    vendor_id: u16,
+    product_id: u16,
+    #[serde(default)]
+    device_class: u8,
+    #[serde(default)]
+    device_subclass: u8,
+    #[serde(default)]
+    device_protocol: u8,
+    speed: Option<UsbSpeed>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+    #[serde(default)]
+    interfaces: Vec<InterfaceDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InterfaceDefinition {
+    class: u8,
+    #[serde(default)]
+    subclass: u8,
+    #[serde(default)]
+    protocol: u8,
+    name: Option<String>,
+    #[serde(default)]
+    endpoints: Vec<UsbEndpoint>,
+    /// Replaces the one of the handler if not empty
+    #[serde(default)]
+    class_specific_descriptor: Vec<u8>,
+    /// A built-in handler, see [InterfaceDefinition::handler]
+    handler: Option<String>,
+}
+
+impl InterfaceDefinition {
+    /// The handler named "hid_keyboard" or "cdc_acm", a no-op one by default
+    fn handler(&self) -> Result<Box<dyn UsbInterfaceHandler + Send>> {
+        Ok(match self.handler.as_deref() {
+            None => Box::new(NoopHandler),
+            Some("hid_keyboard") => Box::new(hid::UsbHidKeyboardHandler::new_keyboard()),
+            Some("cdc_acm") => Box::new(cdc::UsbCdcAcmHandler::new()),
+            Some(name) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Unknown handler {name}"),
+                ));
+            }
+        })
+    }
+}
+
+impl DeviceDefinition {
+    fn into_device(self) -> Result<UsbDevice> {
+        let mut device = UsbDevice::new(self.index);
+        if let Some(bus_id) = self.bus_id {
+            device.bus_id = bus_id;
+        }
+        device.vendor_id = self.vendor_id;
+        device.product_id = self.product_id;
+        device.device_class = self.device_class;
+        device.device_subclass = self.device_subclass;
+        device.device_protocol = self.device_protocol;
+        if let Some(speed) = self.speed {
+            device = device.with_speed(speed);
+        }
+        if let Some(name) = &self.manufacturer {
+            device.set_manufacturer_name(name);
+        }
+        if let Some(name) = &self.product {
+            device.set_product_name(name);
+        }
+        if let Some(name) = &self.serial {
+            device.set_serial_number(name);
+        }
+        for intf in self.interfaces {
+            let handler = intf.handler()?;
+            device = device.with_interface(
+                intf.class,
+                intf.subclass,
+                intf.protocol,
+                intf.name.as_deref(),
+                intf.endpoints,
+                Arc::new(Mutex::new(handler)),
+            );
+            if !intf.class_specific_descriptor.is_empty() {
+                let added = device.interfaces.last().unwrap();
+                added.set_class_specific_descriptor(intf.class_specific_descriptor);
+            }
+        }
+        Ok(device)
+    }
+}
+
+/// Answers every URB without data
+#[derive(Debug)]
+struct NoopHandler;
+
+impl UsbInterfaceHandler for NoopHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_flags: u32,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(vec![])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn invalid_json(err: serde_json::Error) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, err)
+}
+
+impl UsbDevice {
+    /// A simulated device defined by a JSON object
+    ///
+    /// ```json
+    /// {
+    ///   "vendor_id": 4660, "product_id": 22136, "product": "Keyboard",
+    ///   "interfaces": [{
+    ///     "class": 3, "subclass": 0, "protocol": 1, "handler": "hid_keyboard",
+    ///     "endpoints": [{"address": 129, "attributes": 3, "max_packet_size": 8, "interval": 10}]
+    ///   }]
+    /// }
+    /// ```
+    ///
+    /// Interfaces get the built-in handler named by `handler`, "hid_keyboard"
+    /// or "cdc_acm", or one answering every URB without data. The bus id is
+    /// "0-0-0" unless `bus_id` is given.
+    pub fn from_json(reader: impl Read) -> Result<Self> {
+        let definition: DeviceDefinition = serde_json::from_reader(reader).map_err(invalid_json)?;
+        definition.into_device()
+    }
+}
+
+impl UsbIpServer {
+    /// Add the devices defined by the JSON array in the file at `path`, see [UsbDevice::from_json]
+    ///
+    /// Devices are added with [Self::add_device], so they need distinct bus ids.
+    /// Nothing is added if a definition is invalid.
+    pub async fn load_devices(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::fs::File::open(path)?;
+        let definitions: Vec<DeviceDefinition> =
+            serde_json::from_reader(std::io::BufReader::new(file)).map_err(invalid_json)?;
+        let devices = definitions
+            .into_iter()
+            .map(DeviceDefinition::into_device)
+            .collect::<Result<Vec<_>>>()?;
+        for device in devices {
+            self.add_device(device).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    const KEYBOARD: &str = r#"{
+        "vendor_id": 4660,
+        "product_id": 22136,
+        "speed": "Full",
+        "manufacturer": "ACME",
+        "product": "Keyboard",
+        "interfaces": [
+            {
+                "class": 3,
+                "subclass": 1,
+                "protocol": 1,
+                "name": "Boot keyboard",
+                "handler": "hid_keyboard",
+                "endpoints": [
+                    {"address": 129, "attributes": 3, "max_packet_size": 8, "interval": 10}
+                ]
+            },
+            {
+                "class": 255,
+                "class_specific_descriptor": [4, 36, 1, 0],
+                "endpoints": [
+                    {"address": 2, "attributes": 2, "max_packet_size": 64, "interval": 0},
+                    {"address": 130, "attributes": 2, "max_packet_size": 64, "interval": 0}
+                ]
+            }
+        ]
+    }"#;
+
+    fn has_string(device: &UsbDevice, s: &str) -> bool {
+        device.string_pool.values().any(|v| v == s)
+    }
+
+    #[test]
+    fn device_from_json() {
+        setup_test_logger();
+        let device = UsbDevice::from_json(KEYBOARD.as_bytes()).unwrap();
+        assert_eq!((device.vendor_id, device.product_id), (0x1234, 0x5678));
+        assert_eq!(device.speed, UsbSpeed::Full as u32);
+        assert!(has_string(&device, "ACME"));
+        assert!(has_string(&device, "Keyboard"));
+        assert!(has_string(&device, "Boot keyboard"));
+
+        assert_eq!(device.interfaces.len(), 2);
+        let keyboard = &device.interfaces[0];
+        assert_eq!(keyboard.interface_class, ClassCode::HID as u8);
+        assert_eq!(keyboard.endpoints[0].address, 0x81);
+        assert_eq!(keyboard.endpoints[0].interval, 10);
+        assert!(
+            keyboard
+                .handler
+                .lock()
+                .unwrap()
+                .as_any()
+                .is::<hid::UsbHidKeyboardHandler>()
+        );
+        let vendor = &device.interfaces[1];
+        let addresses: Vec<u8> = vendor.endpoints.iter().map(|ep| ep.address).collect();
+        assert_eq!(addresses, vec![0x02, 0x82]);
+        assert_eq!(
+            *vendor.class_specific_descriptor.lock().unwrap(),
+            vec![4, 36, 1, 0]
+        );
+    }
+
+    #[test]
+    fn invalid_definitions() {
+        setup_test_logger();
+        let unknown_handler = r#"{"vendor_id": 1, "product_id": 2,
+            "interfaces": [{"class": 3, "handler": "hid_mouse"}]}"#;
+        let err = UsbDevice::from_json(unknown_handler.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let unknown_field = r#"{"vendor_id": 1, "product_id": 2, "vid": 3}"#;
+        let err = UsbDevice::from_json(unknown_field.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn load_devices_from_file() {
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("nusbip-devices-{}.json", std::process::id()));
+        let second = KEYBOARD.replacen('{', r#"{"bus_id": "0-0-1","#, 1);
+        std::fs::write(&path, format!("[{KEYBOARD}, {second}]")).unwrap();
+        let server = UsbIpServer::new_simulated(vec![]);
+        server.load_devices(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let bus_ids: Vec<String> = server
+            .available_devices
+            .read()
+            .await
+            .iter()
+            .map(|d| d.bus_id.clone())
+            .collect();
+        assert_eq!(bus_ids, vec!["0-0-0", "0-0-1"]);
+    }
+}
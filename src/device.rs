@@ -2,7 +2,9 @@
 use std::{os::unix::ffi::OsStrExt, path::PathBuf};
 
 use super::*;
-use nusb::{Device, Interface, MaybeFuture};
+use nusb::{Device, MaybeFuture};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use tokio::sync::watch;
 
 #[derive(Clone, Default, Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -23,6 +25,91 @@ impl From<u16> for Version {
     }
 }
 
+/// How interfaces of a host device are claimed when exporting it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClaimStrategy {
+    /// Detach the kernel driver (on Linux), then claim the interface
+    #[default]
+    DetachAndClaim,
+    /// Claim without detaching, interfaces bound to a kernel driver are skipped
+    ClaimOnly,
+    /// Claim nothing, the device is exported with ep0 only
+    Skip,
+}
+
+/// An interface of a host device left out of its export, see [UsbDevice::claim_errors]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClaimError {
+    pub interface_number: u8,
+    /// Of the error claiming it, e.g. [ErrorKind::ResourceBusy] if it is claimed already
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Driver bound to the interface, "usbfs" if another process claimed it, only known on Linux
+    pub driver: Option<String>,
+}
+
+impl std::fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interface {}: {}", self.interface_number, self.message)?;
+        match self.driver.as_deref() {
+            Some("usbfs") => write!(f, ", claimed by another process"),
+            Some(driver) => write!(f, ", bound to the {driver} driver"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A configuration of a [UsbDevice] besides its default one, see [UsbDevice::with_configuration]
+#[derive(Clone, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct UsbConfiguration {
+    pub configuration_value: u8,
+    pub interfaces: Vec<UsbInterface>,
+    pub attributes: u8,
+    pub max_power: u8,
+    pub string_configuration: u8,
+    pub interface_associations: Vec<UsbInterfaceAssociation>,
+}
+
+/// An Interface Association Descriptor, grouping interfaces into one function
+///
+/// See [UsbDevice::with_interface_association].
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct UsbInterfaceAssociation {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_function: u8,
+}
+
+/// URBs being handled for a device, see [UsbDevice::cancel_transfers]
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    count: watch::Sender<usize>,
+    cancelled: AtomicBool,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self {
+            count: watch::Sender::new(0),
+            cancelled: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Counts a URB as in flight until dropped, even by a panicking handler
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|n| *n -= 1);
+    }
+}
+
 /// Represent a USB device
 #[derive(Clone, Default, Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize))]
@@ -44,14 +131,50 @@ pub struct UsbDevice {
     pub configuration_value: u8,
     pub num_configurations: u8,
     pub interfaces: Vec<UsbInterface>,
+    /// Functions grouping `interfaces`
+    pub interface_associations: Vec<UsbInterfaceAssociation>,
+    /// Configurations besides the one of `configuration_value` and `interfaces`
+    pub configurations: Vec<UsbConfiguration>,
 
     #[cfg_attr(feature = "serde", serde(skip))]
-    pub device_handler: Option<Device>,
+    pub device_handler: Option<Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>>,
 
     pub usb_version: Version,
     pub attributes: u8,
     pub max_power: u8,
 
+    /// Set by the host with SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) remote_wakeup_enabled: Arc<AtomicBool>,
+    /// Advertised in the BOS descriptor
+    pub(crate) platform_capabilities: PlatformCapabilities,
+    /// Set by the host with SET_CONFIGURATION, 0 until then
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) selected_configuration: Arc<AtomicU8>,
+    /// Whether the last control transfer had a data stage, see [crate::UsbIpServer::handle_usbip_cmd_submit]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) after_data_stage: Arc<AtomicBool>,
+    /// Descriptors served by GET_DESCRIPTOR, by type and index, see [Self::with_descriptor]
+    pub(crate) descriptors: HashMap<(u8, u8), Vec<u8>>,
+    /// Counters by endpoint address, shared by the clones of the device
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) endpoint_stats: Arc<Mutex<BTreeMap<u8, EndpointStats>>>,
+    /// URBs being handled, shared by the clones of the device
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) in_flight: Arc<InFlight>,
+    /// Opens the device served once imported, see [Self::with_lazy_open]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) lazy_open: Option<LazyOpen>,
+    /// IN data produced ahead of the URBs, see [Self::with_transfer_queue]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) transfer_queue: Option<TransferQueue>,
+    /// Last given to the handlers, shared by the clones of the device
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) transfer_timeouts: Arc<Mutex<TransferTimeouts>>,
+    /// Interfaces of the host device that could not be claimed
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) claim_errors: Vec<ClaimError>,
+
     pub(crate) ep0_in: UsbEndpoint,
     pub(crate) ep0_out: UsbEndpoint,
     // strings
@@ -96,6 +219,28 @@ impl UsbDevice {
         res
     }
 
+    /// A device serving control transfers only, with `handler`
+    ///
+    /// Its configuration has a single vendor specific interface without endpoints,
+    /// requests to this interface are forwarded to `handler` too.
+    pub fn new_control_only(
+        index: u32,
+        handler: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
+    ) -> Self {
+        Self::new(index)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0,
+                0,
+                None,
+                vec![],
+                Arc::new(Mutex::new(Box::new(DeviceHandlerInterface(
+                    handler.clone(),
+                )))),
+            )
+            .with_device_handler(handler)
+    }
+
     /// Returns the old value, if present.
     pub fn set_configuration_name(&mut self, name: &str) -> Option<String> {
         let old = (self.string_configuration != 0)
@@ -175,27 +320,607 @@ impl UsbDevice {
         interface_protocol: u8,
         name: Option<&str>,
         endpoints: Vec<UsbEndpoint>,
-        handler: Interface,
+        handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
     ) -> Self {
         let string_interface = name.map(|name| self.new_string(name)).unwrap_or(0);
-        let class_specific_descriptor = Vec::new();
-        self.interfaces.push(UsbInterface {
+        let class_specific_descriptor = handler.lock().unwrap().get_class_specific_descriptor();
+        let interfaces = match self.configurations.last_mut() {
+            Some(cfg) => &mut cfg.interfaces,
+            None => &mut self.interfaces,
+        };
+        interfaces.push(UsbInterface {
             interface_class,
             interface_subclass,
             interface_protocol,
             endpoints,
             string_interface,
-            class_specific_descriptor,
+            class_specific_descriptor: Arc::new(Mutex::new(class_specific_descriptor)),
+            alt_setting: Default::default(),
             handler,
         });
         self
     }
 
-    pub fn with_device_handler(mut self, handler: Device) -> Self {
+    /// Group the next `interface_count` interfaces added into one function
+    ///
+    /// Used by composite devices, e.g. the control and data interfaces of CDC ACM.
+    /// The device class becomes Miscellaneous / Common Class / IAD, so that
+    /// the host looks for Interface Association Descriptors.
+    pub fn with_interface_association(
+        mut self,
+        function_class: u8,
+        function_subclass: u8,
+        function_protocol: u8,
+        name: Option<&str>,
+        interface_count: u8,
+    ) -> Self {
+        let string_function = name.map(|name| self.new_string(name)).unwrap_or(0);
+        let (interfaces, associations) = match self.configurations.last_mut() {
+            Some(cfg) => (&cfg.interfaces, &mut cfg.interface_associations),
+            None => (&self.interfaces, &mut self.interface_associations),
+        };
+        associations.push(UsbInterfaceAssociation {
+            first_interface: interfaces.len() as u8,
+            interface_count,
+            function_class,
+            function_subclass,
+            function_protocol,
+            string_function,
+        });
+        self.device_class = ClassCode::Misc as u8;
+        self.device_subclass = 0x02;
+        self.device_protocol = 0x01;
+        self
+    }
+
+    /// Add a configuration, interfaces added afterwards belong to it
+    ///
+    /// The host switches between configurations with SET_CONFIGURATION.
+    pub fn with_configuration(mut self, configuration_value: u8) -> Self {
+        self.configurations.push(UsbConfiguration {
+            configuration_value,
+            attributes: self.attributes,
+            max_power: self.max_power,
+            ..Default::default()
+        });
+        self.num_configurations += 1;
+        self
+    }
+
+    /// bNumConfigurations as advertised to the client
+    ///
+    /// Hosts reject devices without configurations, and the configuration of
+    /// [Self::configuration_value] is always served, so it is at least 1 even
+    /// if [Self::num_configurations] was left at 0. [Self::validate] reports it.
+    pub(crate) fn served_num_configurations(&self) -> u8 {
+        self.num_configurations.max(1)
+    }
+
+    /// bConfigurationValue of the configuration selected by the host
+    pub fn active_configuration(&self) -> u8 {
+        match self.selected_configuration.load(Ordering::Relaxed) {
+            0 => self.configuration_value,
+            value => value,
+        }
+    }
+
+    /// Follow a configuration change made on the device since it was enumerated
+    ///
+    /// The device handler reports the configuration the device is in. Host
+    /// devices only describe the configuration of enumeration, a new value is
+    /// taken as theirs, keeping the interfaces claimed then.
+    pub(crate) fn refresh_configuration(&mut self) {
+        let Some(value) = self
+            .device_handler
+            .as_ref()
+            .and_then(|dh| dh.lock().unwrap().active_configuration())
+        else {
+            return;
+        };
+        if value == self.active_configuration() {
+            return;
+        }
+        info!("Device {} is now in configuration {value}", self.bus_id);
+        if value == self.configuration_value
+            || self
+                .configurations
+                .iter()
+                .any(|c| c.configuration_value == value)
+        {
+            self.selected_configuration.store(value, Ordering::Relaxed);
+        } else {
+            self.configuration_value = value;
+            self.selected_configuration.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Give the handlers of the device `timeouts` for their transfers, see [crate::UsbIpServer::with_transfer_timeout]
+    pub fn set_transfer_timeouts(&self, timeouts: TransferTimeouts) {
+        *self.transfer_timeouts.lock().unwrap() = timeouts;
+        let interfaces = self
+            .configurations
+            .iter()
+            .flat_map(|c| &c.interfaces)
+            .chain(&self.interfaces);
+        for intf in interfaces {
+            intf.handler.lock().unwrap().set_transfer_timeouts(timeouts);
+        }
+        if let Some(dh) = &self.device_handler {
+            dh.lock().unwrap().set_transfer_timeouts(timeouts);
+        }
+    }
+
+    /// Interfaces of the host device that could not be claimed, left out of its export
+    ///
+    /// Each tells the driver holding the interface when the OS exposes it,
+    /// e.g. "usbfs" for another process, so the operator knows what to stop.
+    pub fn claim_errors(&self) -> &[ClaimError] {
+        &self.claim_errors
+    }
+
+    /// The timeouts last given with [Self::set_transfer_timeouts]
+    pub fn transfer_timeouts(&self) -> TransferTimeouts {
+        *self.transfer_timeouts.lock().unwrap()
+    }
+
+    /// Interfaces of the active configuration
+    pub fn active_interfaces(&self) -> &[UsbInterface] {
+        let active = self.active_configuration();
+        match self
+            .configurations
+            .iter()
+            .find(|c| c.configuration_value == active)
+        {
+            Some(cfg) => &cfg.interfaces,
+            None => &self.interfaces,
+        }
+    }
+
+    /// The alternate setting of interface `interface_number` of the active configuration
+    pub fn alt_setting(&self, interface_number: u8) -> Option<u8> {
+        self.active_interfaces()
+            .get(interface_number as usize)
+            .map(UsbInterface::current_alt_setting)
+    }
+
+    pub fn with_device_handler(
+        mut self,
+        handler: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
+    ) -> Self {
         self.device_handler = Some(handler);
         self
     }
 
+    /// Answer IN URBs from a [TransferQueue] holding up to `depth` transfers per endpoint
+    ///
+    /// Producers push data with the queue of [Self::transfer_queue], URBs to
+    /// an endpoint whose queue is empty go to its interface handler as usual.
+    /// Each pushed transfer goes to a single URB, e.g. a whole HID report.
+    pub fn with_transfer_queue(mut self, depth: usize) -> Self {
+        self.transfer_queue = Some(TransferQueue::new(depth));
+        self
+    }
+
+    /// The queue set with [Self::with_transfer_queue], shared by the clones of the device
+    pub fn transfer_queue(&self) -> Option<TransferQueue> {
+        self.transfer_queue.clone()
+    }
+
+    /// Only open the device when a client imports it, with `open`
+    ///
+    /// This device describes it until then, e.g. in OP_REP_DEVLIST. The
+    /// device returned by `open` is served to the client, and dropped when
+    /// released, see [crate::UsbIpServer::release].
+    pub fn with_lazy_open(
+        mut self,
+        open: impl Fn() -> Result<UsbDevice> + Send + Sync + 'static,
+    ) -> Self {
+        self.lazy_open = Some(LazyOpen(Arc::new(open)));
+        self
+    }
+
+    /// Describe a device of the host from `info`, opening it only when imported
+    ///
+    /// Nothing is claimed until then. It is opened and its interfaces claimed
+    /// with `strategy` on each import, see [Self::with_lazy_open].
+    pub fn from_device_info_lazy(info: DeviceInfo, strategy: ClaimStrategy) -> Self {
+        let (path, bus_id, bus_num) = host_location(&info);
+        let speed = info
+            .speed()
+            .map_or(UsbSpeed::Unknown as u32, usbip_speed_code);
+        let interfaces = info
+            .interfaces()
+            .map(|intf| UsbInterface {
+                interface_class: intf.class(),
+                interface_subclass: intf.subclass(),
+                interface_protocol: intf.protocol(),
+                endpoints: vec![],
+                string_interface: 0,
+                class_specific_descriptor: Default::default(),
+                alt_setting: Default::default(),
+                handler: Arc::new(Mutex::new(Box::new(UnopenedInterface))),
+            })
+            .collect();
+        let mut device = UsbDevice {
+            path,
+            bus_id,
+            bus_num,
+            dev_num: info.device_address() as u32,
+            speed,
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            device_class: info.class(),
+            device_subclass: info.subclass(),
+            device_protocol: info.protocol(),
+            device_bcd: info.device_version().into(),
+            configuration_value: 1,
+            num_configurations: 1,
+            interfaces,
+            usb_version: info.usb_version().into(),
+            ..UsbDevice::default()
+        };
+        if let Some(s) = info.product_string() {
+            device.string_product = device.new_string(s)
+        }
+        device.with_lazy_open(move || {
+            let dev = info.open().wait()?;
+            UsbDevice::from_open_device_with_strategy(dev, &info, strategy)
+        })
+    }
+
+    /// Build a [UsbDevice] from a nusb [Device] the caller has already opened
+    ///
+    /// Opening the same device twice fails on platforms with exclusive access,
+    /// so applications that probed the device first can hand it over here.
+    pub fn from_open_device(dev: Device, info: &DeviceInfo) -> Result<Self> {
+        Self::from_open_device_with_strategy(dev, info, ClaimStrategy::default())
+    }
+
+    /// Build a [UsbDevice] from an opened nusb [Device], claiming its interfaces with `strategy`
+    ///
+    /// Interfaces that fail to claim are left out of the export, see
+    /// [Self::claim_errors]. It fails only if none of the interfaces could be
+    /// claimed, with the errors of each one.
+    pub fn from_open_device_with_strategy(
+        dev: Device,
+        info: &DeviceInfo,
+        strategy: ClaimStrategy,
+    ) -> Result<Self> {
+        crate::util::block_on(Self::claim_open_device(dev, info, strategy))
+    }
+
+    /// [Self::from_open_device_with_strategy], for async callers
+    pub(crate) async fn claim_open_device(
+        dev: Device,
+        info: &DeviceInfo,
+        strategy: ClaimStrategy,
+    ) -> Result<Self> {
+        let (path, bus_id, bus_num) = host_location(info);
+        let first_cfg = dev.configurations().next().map(|c| c.configuration_value());
+        let cfg = ensure_configured(
+            || {
+                dev.active_configuration().map_err(|err| {
+                    std::io::Error::other(format!("Impossible to get active configuration: {err}"))
+                })
+            },
+            first_cfg,
+            |value| {
+                let set = dev.set_configuration(value);
+                async move { Ok(set.await?) }
+            },
+        )
+        .await?;
+        let attributes = cfg.attributes();
+        let max_power = cfg.max_power();
+        // ignore alternate settings
+        let intf_nums: Vec<u8> = cfg.interfaces().map(|i| i.interface_number()).collect();
+        // still bound after detaching, or "usbfs" if another process claimed it,
+        // read once the first interface fails
+        #[cfg(target_os = "linux")]
+        let drivers = std::sync::OnceLock::new();
+        #[cfg(target_os = "linux")]
+        let driver = |intf_num| {
+            drivers
+                .get_or_init(|| interface_drivers(info.sysfs_path()))
+                .get(&intf_num)
+                .cloned()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let driver = |_| None;
+        let (claimed, claim_errors) = match strategy {
+            ClaimStrategy::Skip => (vec![], vec![]),
+            _ => {
+                claim_each(
+                    intf_nums.iter().copied(),
+                    |intf_num| {
+                        #[cfg(target_os = "linux")]
+                        if strategy == ClaimStrategy::DetachAndClaim {
+                            let _ = dev.detach_kernel_driver(intf_num);
+                        }
+                        let claim = dev.claim_interface(intf_num);
+                        async move { Ok(claim.await?) }
+                    },
+                    driver,
+                )
+                .await
+            }
+        };
+        if claimed.is_empty() && !claim_errors.is_empty() {
+            return Err(no_interface_claimed(&claim_errors));
+        }
+        let mut interfaces = vec![];
+        for intf in claimed {
+            let intf_num = intf.interface_number();
+            let intf_desc = intf.descriptor().ok_or(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No descriptor for interface {intf_num}"),
+            ))?;
+            let endpoints = intf_desc.endpoints().map(UsbEndpoint::from).collect();
+            let string_interface = match intf_desc.string_index() {
+                Some(i) => i.into(),
+                None => 0,
+            };
+
+            interfaces.push(UsbInterface {
+                interface_class: intf_desc.class(),
+                interface_subclass: intf_desc.subclass(),
+                interface_protocol: intf_desc.protocol(),
+                endpoints,
+                string_interface,
+                class_specific_descriptor: Arc::new(Mutex::new(class_specific_descriptor(
+                    &intf_desc,
+                ))),
+                alt_setting: Default::default(),
+                handler: Arc::new(Mutex::new(Box::new(NusbUsbHostInterfaceHandler::new(intf)))),
+            });
+        }
+
+        let speed = info
+            .speed()
+            .map_or(UsbSpeed::Unknown as u32, usbip_speed_code);
+        let max_packet_size0 = dev.device_descriptor().max_packet_size_0() as u16;
+        let ep0_size = if speed >= UsbSpeed::Super as u32 {
+            1 << max_packet_size0
+        } else {
+            max_packet_size0
+        };
+        let mut device = UsbDevice {
+            path,
+            bus_id,
+            bus_num,
+            dev_num: info.device_address() as u32,
+            speed,
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            device_class: info.class(),
+            device_subclass: info.subclass(),
+            device_protocol: info.protocol(),
+            device_bcd: info.device_version().into(),
+            configuration_value: cfg.configuration_value(),
+            num_configurations: dev.configurations().count() as u8,
+            ep0_in: UsbEndpoint {
+                address: 0x80,
+                attributes: EndpointAttributes::Control as u8,
+                max_packet_size: ep0_size,
+                interval: 0,
+            },
+            ep0_out: UsbEndpoint {
+                address: 0x00,
+                attributes: EndpointAttributes::Control as u8,
+                max_packet_size: ep0_size,
+                interval: 0,
+            },
+            interfaces,
+            device_handler: Some(Arc::new(Mutex::new(Box::new(
+                NusbUsbHostDeviceHandler::new(Arc::new(Mutex::new(dev))),
+            )))),
+            usb_version: info.usb_version().into(),
+            attributes,
+            max_power,
+            claim_errors,
+            ..UsbDevice::default()
+        };
+
+        // set strings
+        if let Some(s) = info.manufacturer_string() {
+            device.string_manufacturer = device.new_string(s)
+        }
+        if let Some(s) = info.product_string() {
+            device.string_product = device.new_string(s)
+        }
+        if let Some(s) = info.serial_number() {
+            device.string_serial = device.new_string(s)
+        }
+        Ok(device)
+    }
+
+    /// Declare the device running at `speed`, with the usual size of ep0 at that speed
+    ///
+    /// SuperSpeed devices get bcdUSB 3.0 at least, as the host expects of them.
+    pub fn with_speed(mut self, speed: UsbSpeed) -> Self {
+        self.speed = speed as u32;
+        if self.speed >= UsbSpeed::Super as u32 && self.usb_version.major < 3 {
+            self.usb_version = Version {
+                major: 3,
+                minor: 0,
+                patch: 0,
+            };
+        }
+        let max_packet_size = ep0_max_packet_size(self.speed);
+        self.with_ep0_max_packet_size(max_packet_size)
+    }
+
+    /// Set the max packet size of ep0
+    ///
+    /// It must suit the speed of the device: 8 at low speed, 8, 16, 32 or 64
+    /// at full speed, 64 at high speed and 512 at SuperSpeed, see [Self::validate].
+    pub fn with_ep0_max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.ep0_in.max_packet_size = max_packet_size;
+        self.ep0_out.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// bMaxPacketSize0, an exponent of 2 for SuperSpeed devices
+    fn max_packet_size0(&self) -> u8 {
+        let size = self.ep0_in.max_packet_size;
+        if self.speed >= UsbSpeed::Super as u32 {
+            size.trailing_zeros() as u8
+        } else {
+            size as u8
+        }
+    }
+
+    /// Advertise remote wakeup in the configuration descriptor
+    ///
+    /// See [UsbIpServer::request_remote_wakeup].
+    pub fn with_remote_wakeup(mut self) -> Self {
+        self.attributes |= 0x20;
+        self
+    }
+
+    /// Advertise a WebUSB landing page `url`, requested with `vendor_code`
+    ///
+    /// Hosts only read the BOS descriptor of devices with bcdUSB 2.01 or above.
+    pub fn with_webusb_landing_page(mut self, vendor_code: u8, url: &str) -> Self {
+        assert!(url.len() <= 252, "URL too long for a descriptor");
+        self.platform_capabilities.webusb = Some((vendor_code, url.to_string()));
+        self
+    }
+
+    /// Advertise an MS OS 2.0 `descriptor_set`, requested with `vendor_code`
+    ///
+    /// Hosts only read the BOS descriptor of devices with bcdUSB 2.01 or above.
+    pub fn with_ms_os_20_descriptors(mut self, vendor_code: u8, descriptor_set: Vec<u8>) -> Self {
+        self.platform_capabilities.ms_os_20 = Some((vendor_code, descriptor_set));
+        self
+    }
+
+    /// Advertise the MS OS 1.0 `compatible_id` of `interface`, e.g. "WINUSB"
+    ///
+    /// Windows reads the MS OS string descriptor of devices it hasn't seen
+    /// before, then requests the compatible ids with `vendor_code`. The vendor
+    /// code must be the same for all interfaces.
+    pub fn with_ms_os_10_compatible_id(
+        mut self,
+        vendor_code: u8,
+        interface: u8,
+        compatible_id: &str,
+    ) -> Self {
+        assert!(compatible_id.len() <= 8, "Compatible id too long");
+        let mut id = [0; 8];
+        id[..compatible_id.len()].copy_from_slice(compatible_id.as_bytes());
+        let (_, compatible_ids) = self
+            .platform_capabilities
+            .ms_os_10
+            .get_or_insert_with(|| (vendor_code, BTreeMap::new()));
+        compatible_ids.insert(interface, id);
+        self
+    }
+
+    /// Serve `descriptor` for GET_DESCRIPTOR of `descriptor_type` and `index`
+    ///
+    /// Only used for types the device doesn't build itself, e.g. Debug or
+    /// Interface Association. Requests for other unknown types are passed to
+    /// the device handler, or stalled.
+    pub fn with_descriptor(mut self, descriptor_type: u8, index: u8, descriptor: Vec<u8>) -> Self {
+        self.descriptors
+            .insert((descriptor_type, index), descriptor);
+        self
+    }
+
+    /// Polling interval of the interrupt IN endpoint `address`, empty replies from it are paced at
+    pub(crate) fn interrupt_in_interval(&self, address: u8) -> Option<Duration> {
+        let (ep, _) = self.find_ep(address)?;
+        (ep.attributes == EndpointAttributes::Interrupt as u8 && ep.direction() == Direction::In)
+            .then(|| ep.polling_interval(self.speed))
+    }
+
+    /// The devid of the device in USBIP_CMD_SUBMIT headers
+    pub fn devid(&self) -> u32 {
+        (self.bus_num << 16) | self.dev_num
+    }
+
+    /// The device with `devid` downstream of this [hub::VirtualHub], URBs to it are routed there
+    pub(crate) fn downstream(&self, devid: u32) -> Option<Arc<UsbDevice>> {
+        if devid == self.devid() || self.device_class != ClassCode::Hub as u8 {
+            return None;
+        }
+        let mut handler = self.device_handler.as_ref()?.lock().unwrap();
+        handler
+            .as_any()
+            .downcast_mut::<hub::VirtualHub>()?
+            .enabled_device(devid)
+    }
+
+    /// Whether the host enabled remote wakeup with SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+    pub fn remote_wakeup_enabled(&self) -> bool {
+        self.remote_wakeup_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Counters of the URBs submitted to each endpoint, by endpoint address
+    pub fn endpoint_stats(&self) -> BTreeMap<u8, EndpointStats> {
+        self.endpoint_stats.lock().unwrap().clone()
+    }
+
+    /// Count a URB to `ep`, completed with the actual length or a failed status and its cause
+    pub(crate) fn record_transfer(&self, ep: u8, result: std::result::Result<u32, (i32, String)>) {
+        let mut stats = self.endpoint_stats.lock().unwrap();
+        let stats = stats.entry(ep).or_default();
+        stats.submits += 1;
+        match result {
+            Ok(len) => stats.bytes += len as u64,
+            Err(err) => {
+                stats.failures += 1;
+                stats.last_error = Some(err);
+            }
+        }
+    }
+
+    /// Handle a URB with `transfer`, unless transfers were cancelled
+    ///
+    /// A URB completing after [Self::cancel_transfers] fails with
+    /// [ErrorKind::Interrupted], its result is discarded.
+    pub(crate) fn track_transfer(
+        &self,
+        transfer: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let cancelled = || {
+            Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                format!("Transfer cancelled, device {} released", self.bus_id),
+            ))
+        };
+        if self.in_flight.cancelled.load(Ordering::Relaxed) {
+            return cancelled();
+        }
+        self.in_flight.count.send_modify(|n| *n += 1);
+        let _guard = InFlightGuard(&self.in_flight);
+        let res = transfer();
+        if self.in_flight.cancelled.load(Ordering::Relaxed) {
+            return cancelled();
+        }
+        res
+    }
+
+    /// Cancel the URBs being handled and wait for their handlers to return
+    ///
+    /// Handlers are not interrupted, host devices give up within the timeout
+    /// of their transfers. URBs fail until [Self::resume_transfers].
+    pub(crate) async fn cancel_transfers(&self) {
+        self.in_flight.cancelled.store(true, Ordering::Relaxed);
+        let mut count = self.in_flight.count.subscribe();
+        if *count.borrow() > 0 {
+            debug!("Waiting for the transfers of {} to drain", self.bus_id);
+        }
+        // the sender lives as long as the device
+        let _ = count.wait_for(|n| *n == 0).await;
+    }
+
+    /// Accept URBs again after [Self::cancel_transfers]
+    pub(crate) fn resume_transfers(&self) {
+        self.in_flight.cancelled.store(false, Ordering::Relaxed);
+    }
+
     pub(crate) fn new_string(&mut self, s: &str) -> u8 {
         for i in 1.. {
             if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
@@ -206,13 +931,147 @@ impl UsbDevice {
         panic!("string poll exhausted")
     }
 
+    /// The configuration descriptor of `index`, with its interface and endpoint descriptors
+    pub(crate) fn configuration_descriptor_bytes(&self, index: u8) -> Vec<u8> {
+        use DescriptorType::*;
+
+        let (value, interfaces, associations, string, attributes, max_power) = match (index
+            as usize)
+            .checked_sub(1)
+            .and_then(|i| self.configurations.get(i))
+        {
+            Some(c) => (
+                c.configuration_value,
+                &c.interfaces,
+                &c.interface_associations,
+                c.string_configuration,
+                c.attributes,
+                c.max_power,
+            ),
+            // index 0, or unknown here, e.g. other configurations of host devices
+            None => (
+                self.configuration_value,
+                &self.interfaces,
+                &self.interface_associations,
+                self.string_configuration,
+                self.attributes,
+                self.max_power,
+            ),
+        };
+        // Standard Configuration Descriptor
+        let mut desc = vec![
+            0x09,                // bLength
+            Configuration as u8, // bDescriptorType: Configuration
+            0x00,
+            0x00,                   // wTotalLength: to be filled below
+            interfaces.len() as u8, // bNumInterfaces
+            value,                  // bConfigurationValue
+            string,                 // iConfiguration
+            attributes,             // bmAttributes: Bus Powered
+            max_power,              // bMaxPower: 100mA
+        ];
+        for (i, intf) in interfaces.iter().enumerate() {
+            // the association precedes the interfaces of its function
+            for iad in associations
+                .iter()
+                .filter(|a| a.first_interface as usize == i)
+            {
+                desc.extend_from_slice(&[
+                    0x08,                       // bLength
+                    InterfaceAssociation as u8, // bDescriptorType: Interface Association
+                    iad.first_interface,        // bFirstInterface
+                    iad.interface_count,        // bInterfaceCount
+                    iad.function_class,         // bFunctionClass
+                    iad.function_subclass,      // bFunctionSubClass
+                    iad.function_protocol,      // bFunctionProtocol
+                    iad.string_function,        // iFunction
+                ]);
+            }
+            let mut intf_desc = vec![
+                0x09,                       // bLength
+                Interface as u8,            // bDescriptorType: Interface
+                i as u8,                    // bInterfaceNum
+                0x00,                       // bAlternateSettings
+                intf.endpoints.len() as u8, // bNumEndpoints
+                intf.interface_class,       // bInterfaceClass
+                intf.interface_subclass,    // bInterfaceSubClass
+                intf.interface_protocol,    // bInterfaceProtocol
+                intf.string_interface,      //iInterface
+            ];
+            // class specific endpoint
+            let mut specific = intf.class_specific_descriptor.lock().unwrap().clone();
+            intf_desc.append(&mut specific);
+            // endpoint descriptors
+            for endpoint in &intf.endpoints {
+                let mut ep_desc = vec![
+                    0x07,                // bLength
+                    Endpoint as u8,      // bDescriptorType: Endpoint
+                    endpoint.address,    // bEndpointAddress
+                    endpoint.attributes, // bmAttributes
+                    endpoint.max_packet_size as u8,
+                    (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
+                    endpoint.interval,                     // bInterval
+                ];
+                intf_desc.append(&mut ep_desc);
+            }
+            desc.append(&mut intf_desc);
+        }
+        // length
+        let len = desc.len() as u16;
+        desc[2] = len as u8;
+        desc[3] = (len >> 8) as u8;
+        desc
+    }
+
+    /// The interface addressed by `wIndex` of a request to an interface
+    fn interface_at(&self, index: u16) -> Result<&UsbInterface> {
+        // only low 8 bits are valid
+        self.active_interfaces()
+            .get(index as usize & 0xFF)
+            .ok_or(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No interface {}", index & 0xFF),
+            ))
+    }
+
+    /// Whether the device is a device of the host, which answers standard requests itself
+    fn is_host_device(&self) -> bool {
+        self.device_handler
+            .as_ref()
+            .is_some_and(|dh| dh.lock().unwrap().as_any().is::<NusbUsbHostDeviceHandler>())
+    }
+
+    /// Number of the interface a URB to `ep` with `setup` is for, `None` for the device
+    pub(crate) fn interface_number(&self, ep: &UsbEndpoint, setup: &SetupPacket) -> Option<u8> {
+        let intf = if ep.is_ep0() {
+            match setup.request_type & 0x1F {
+                // only low 8 bits are valid
+                1 => return Some(setup.index as u8),
+                2 => self.find_ep(setup.index as u8)?.1?,
+                _ => return None,
+            }
+        } else {
+            self.find_ep(ep.address)?.1?
+        };
+        self.active_interfaces()
+            .iter()
+            .position(|i| std::ptr::eq(i, intf))
+            .map(|i| i as u8)
+    }
+
+    /// The endpoint `ep` of the active configuration, and its interface
+    ///
+    /// `ep` includes the direction bit, so interfaces using the same endpoint
+    /// number in opposite directions are told apart. The devid of a URB only
+    /// identifies the device, an address is unique in its configuration, see
+    /// [ValidationError::DuplicateEndpoint].
     pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
         if ep == self.ep0_in.address {
             Some((self.ep0_in, None))
         } else if ep == self.ep0_out.address {
             Some((self.ep0_out, None))
         } else {
-            for intf in &self.interfaces {
+            for intf in self.active_interfaces() {
                 for endpoint in &intf.endpoints {
                     if endpoint.address == ep {
                         return Some((*endpoint, Some(intf)));
@@ -248,18 +1107,19 @@ impl UsbDevice {
         result.push(self.device_class);
         result.push(self.device_subclass);
         result.push(self.device_protocol);
-        result.push(self.configuration_value);
-        result.push(self.num_configurations);
-        result.push(self.interfaces.len() as u8);
+        result.push(self.active_configuration());
+        result.push(self.served_num_configurations());
+        result.push(self.active_interfaces().len() as u8);
 
         result
     }
 
     pub(crate) fn to_bytes_with_interfaces(&self) -> Vec<u8> {
         let mut result = self.to_bytes();
-        result.reserve(4 * self.interfaces.len());
+        let interfaces = self.active_interfaces();
+        result.reserve(4 * interfaces.len());
 
-        for intf in &self.interfaces {
+        for intf in interfaces {
             result.push(intf.interface_class);
             result.push(intf.interface_subclass);
             result.push(intf.interface_protocol);
@@ -273,6 +1133,7 @@ impl UsbDevice {
         &self,
         ep: UsbEndpoint,
         intf: Option<&UsbInterface>,
+        transfer_flags: u32,
         transfer_buffer_length: u32,
         setup_packet: SetupPacket,
         out_data: &[u8],
@@ -298,10 +1159,22 @@ impl UsbDevice {
         //     None => return Err(std::io::Error::new(ErrorKind::NotFound, "No device found")),
         // };
 
-        match (FromPrimitive::from_u8(ep.attributes), ep.direction()) {
-            (Some(Control), In) => {
+        // control transfers only ever target ep0, whatever the recipient
+        match (ep.is_ep0(), ep.direction()) {
+            (true, In) => {
                 // control in
                 debug!("Control IN setup={setup_packet:x?}");
+                if setup_packet.request_type == 0b11000000
+                    && let Some(mut desc) = self
+                        .platform_capabilities
+                        .handle_vendor_request(&setup_packet)
+                {
+                    // requested len too short: wLength < real length
+                    if setup_packet.length < desc.len() as u16 {
+                        desc.resize(setup_packet.length as usize, 0);
+                    }
+                    return Ok(desc);
+                }
                 match (
                     setup_packet.request_type,
                     FromPrimitive::from_u8(setup_packet.request),
@@ -316,12 +1189,12 @@ impl UsbDevice {
                                     0x12,         // bLength
                                     Device as u8, // bDescriptorType: Device
                                     self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB: USB 2.0
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSubClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,   // idVendor
+                                    self.usb_version.major,  // bcdUSB: USB 2.0
+                                    self.device_class,       // bDeviceClass
+                                    self.device_subclass,    // bDeviceSubClass
+                                    self.device_protocol,    // bDeviceProtocol
+                                    self.max_packet_size0(), // bMaxPacketSize0
+                                    self.vendor_id as u8,    // idVendor
                                     (self.vendor_id >> 8) as u8,
                                     self.product_id as u8, // idProduct
                                     (self.product_id >> 8) as u8,
@@ -330,7 +1203,7 @@ impl UsbDevice {
                                     self.string_manufacturer, // iManufacturer
                                     self.string_product,      // iProduct
                                     self.string_serial,       // iSerial
-                                    self.num_configurations,  // bNumConfigurations
+                                    self.served_num_configurations(), // bNumConfigurations
                                 ];
 
                                 // requested len too short: wLength < real length
@@ -341,12 +1214,7 @@ impl UsbDevice {
                             }
                             Some(BOS) => {
                                 debug!("Get BOS descriptor");
-                                let mut desc = vec![
-                                    0x05,      // bLength
-                                    BOS as u8, // bDescriptorType: BOS
-                                    0x05, 0x00, // wTotalLength
-                                    0x00, // bNumCapabilities
-                                ];
+                                let mut desc = self.platform_capabilities.bos_descriptor();
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -356,52 +1224,9 @@ impl UsbDevice {
                             }
                             Some(Configuration) => {
                                 debug!("Get configuration descriptor");
-                                // Standard Configuration Descriptor
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    self.attributes, // bmAttributes: Bus Powered
-                                    self.max_power, // bMaxPower: 100mA
-                                ];
-                                for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
-                                    // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
-                                    // endpoint descriptors
-                                    for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
-                                    }
-                                    desc.append(&mut intf_desc);
-                                }
-                                // length
-                                let len = desc.len() as u16;
-                                desc[2] = len as u8;
-                                desc[3] = (len >> 8) as u8;
+                                // low byte: index
+                                let mut desc =
+                                    self.configuration_descriptor_bytes(setup_packet.value as u8);
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -426,6 +1251,15 @@ impl UsbDevice {
                                         desc.resize(setup_packet.length as usize, 0);
                                     }
                                     Ok(desc)
+                                } else if index == MS_OS_10_STRING_INDEX
+                                    && let Some(mut desc) =
+                                        self.platform_capabilities.ms_os_10_string_descriptor()
+                                {
+                                    // requested len too short: wLength < real length
+                                    if setup_packet.length < desc.len() as u16 {
+                                        desc.resize(setup_packet.length as usize, 0);
+                                    }
+                                    Ok(desc)
                                 } else if let Some(s) = &self.string_pool.get(&index) {
                                     // UNICODE String Descriptor
                                     let bytes: Vec<u16> = s.encode_utf16().collect();
@@ -457,13 +1291,13 @@ impl UsbDevice {
                                     0x0A,                  // bLength
                                     DeviceQualifier as u8, // bDescriptorType: Device Qualifier
                                     self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSUbClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.num_configurations, // bNumConfigurations
-                                    0x00,                   // bReserved
+                                    self.usb_version.major,           // bcdUSB
+                                    self.device_class,                // bDeviceClass
+                                    self.device_subclass,             // bDeviceSUbClass
+                                    self.device_protocol,             // bDeviceProtocol
+                                    self.max_packet_size0(),          // bMaxPacketSize0
+                                    self.served_num_configurations(), // bNumConfigurations
+                                    0x00,                             // bReserved
                                 ];
 
                                 // requested len too short: wLength < real length
@@ -473,47 +1307,112 @@ impl UsbDevice {
                                 Ok(desc)
                             }
                             _ => {
-                                warn!("unknown desc type: {setup_packet:x?}");
-                                Ok(vec![])
+                                // high byte: type, low byte: index
+                                let key =
+                                    ((setup_packet.value >> 8) as u8, setup_packet.value as u8);
+                                if let Some(desc) = self.descriptors.get(&key) {
+                                    let mut desc = desc.clone();
+                                    // requested len too short: wLength < real length
+                                    if setup_packet.length < desc.len() as u16 {
+                                        desc.resize(setup_packet.length as usize, 0);
+                                    }
+                                    Ok(desc)
+                                } else if let Some(dh) = &self.device_handler {
+                                    let mut handler = dh.lock().unwrap();
+                                    handler.handle_urb(
+                                        transfer_buffer_length,
+                                        setup_packet,
+                                        out_data,
+                                    )
+                                } else {
+                                    warn!("unknown desc type: {setup_packet:x?}");
+                                    Err(stall(format!("No descriptor of type {:#04x}", key.0)))
+                                }
                             }
                         }
                     }
-                    _ if setup_packet.request_type & 0xF == 1 => {
-                        // to interface
-                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        // only low 8 bits are valid
-                        // let device = match self.device_handler.clone() {
-                        //     Some(dev) => dev,
-                        //     None => {
-                        //         return Ok(Vec::new());
-                        //     }
-                        // };
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let handler = intf.handler.clone();
-                        handle_urb_for_interface(
-                            handler,
-                            // device,
+                    (0b10000001, Some(GetInterface)) if !self.is_host_device() => {
+                        let intf = self.interface_at(setup_packet.index)?;
+                        let mut desc = vec![intf.current_alt_setting()];
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    _ if setup_packet.request_type & 0x1F == 1 => {
+                        // to interface
+                        // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
+                        // only low 8 bits are valid
+                        let intf = self.interface_at(setup_packet.index)?;
+                        let mut handler = intf.handler.lock().unwrap();
+                        handler.handle_urb(
+                            intf,
                             ep,
+                            transfer_flags,
                             transfer_buffer_length,
                             setup_packet,
                             out_data,
                         )
                     }
-                    _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
-                        // to device
+                    (0b10000010, Some(GetStatus)) if !self.is_host_device() => {
+                        // bit 0: halt, endpoints of simulated devices never stay halted
+                        let mut desc = vec![0x00, 0x00];
+                        desc.truncate(setup_packet.length as usize);
+                        Ok(desc)
+                    }
+                    (0b10000010, Some(SynchFrame)) if !self.is_host_device() => {
+                        match self.find_ep(setup_packet.index as u8) {
+                            Some((ep, _)) if ep.attributes == Isochronous as u8 => {
+                                let mut desc = frame_number().to_le_bytes().to_vec();
+                                desc.truncate(setup_packet.length as usize);
+                                Ok(desc)
+                            }
+                            // only isochronous endpoints synchronize on frames
+                            _ => Err(std::io::Error::new(
+                                ErrorKind::Unsupported,
+                                format!("SYNCH_FRAME to endpoint {:#04x}", setup_packet.index),
+                            )),
+                        }
+                    }
+                    (0b10000000, Some(GetConfiguration)) if !self.is_host_device() => {
+                        let mut desc = vec![self.active_configuration()];
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    (0b10000000, Some(GetStatus)) if !self.is_host_device() => {
+                        // bit 0: self powered, bit 1: remote wakeup
+                        let status = ((self.attributes >> 6) & 1)
+                            | ((self.remote_wakeup_enabled() as u8) << 1);
+                        let mut desc = vec![status, 0x00];
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
+                    _ if matches!(setup_packet.request_type & 0x1F, 0 | 3)
+                        && self.device_handler.is_some() =>
+                    {
+                        // to device, or to other (e.g. hub ports)
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        let handler = self.device_handler.clone().unwrap();
-                        handle_urb_for_device(
-                            handler,
-                            transfer_buffer_length,
-                            setup_packet,
-                            out_data,
-                        )
+                        let dh = self.device_handler.as_ref().unwrap();
+                        let mut handler = dh.lock().unwrap();
+                        handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                    }
+                    _ => {
+                        warn!("Unhandled control IN {setup_packet:x?}");
+                        Err(stall(format!(
+                            "Control IN of type {:#04x}",
+                            setup_packet.request_type
+                        )))
                     }
-                    _ => unimplemented!("control in"),
                 }
             }
-            (Some(Control), Out) => {
+            (true, Out) => {
                 // control out
                 debug!("Control OUT setup={setup_packet:x?}");
                 match (
@@ -521,28 +1420,24 @@ impl UsbDevice {
                     FromPrimitive::from_u8(setup_packet.request),
                 ) {
                     (0b00000000, Some(SetConfiguration)) => {
+                        let value = setup_packet.value as u8;
+                        if value == self.configuration_value
+                            || self
+                                .configurations
+                                .iter()
+                                .any(|c| c.configuration_value == value)
+                        {
+                            self.selected_configuration.store(value, Ordering::Relaxed);
+                        } else {
+                            debug!("Unknown configuration {value}, keeping the interfaces");
+                        }
                         let mut desc = vec![
-                            self.configuration_value, // bConfigurationValue
+                            self.active_configuration(), // bConfigurationValue
                         ];
-                        if let Some(device) = self.device_handler.clone() {
-                            #[cfg(target_os = "linux")]
-                            match intf {
-                                Some(i) => {
-                                    if let Err(e) =
-                                        device.detach_kernel_driver(i.handler.interface_number())
-                                    {
-                                        error!("Failed to detach kernel driver: {e:?}");
-                                    }
-                                }
-                                None => {
-                                    if let Err(e) = device.detach_kernel_driver(0) {
-                                        error!("Failed to detach kernel driver: {e:?}");
-                                    }
-                                }
-                            }
-                            if let Err(e) =
-                                device.set_configuration(setup_packet.value as u8).wait()
-                            {
+                        #[cfg(not(target_os = "windows"))]
+                        if let Some(dh) = &self.device_handler {
+                            let handler = dh.lock().unwrap();
+                            if let Err(e) = handler.set_configuration(&setup_packet.to_bytes()) {
                                 error!("Error setting config: {e:?}");
                             };
                         }
@@ -552,44 +1447,104 @@ impl UsbDevice {
                         }
                         Ok(desc)
                     }
-                    _ if setup_packet.request_type & 0xF == 1 => {
+                    (0b00000000, Some(SetAddress)) => {
+                        // addressing is done by the USB/IP layer, host devices already have one
+                        debug!("Ignoring SET_ADDRESS {}", setup_packet.value);
+                        Ok(vec![])
+                    }
+                    (0b00000000, Some(request @ (SetFeature | ClearFeature)))
+                        if setup_packet.value == DEVICE_REMOTE_WAKEUP =>
+                    {
+                        self.remote_wakeup_enabled
+                            .store(matches!(request, SetFeature), Ordering::Relaxed);
+                        match &self.device_handler {
+                            Some(dh) => {
+                                let mut handler = dh.lock().unwrap();
+                                handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                            }
+                            None => Ok(Vec::new()),
+                        }
+                    }
+                    (0b00000000, Some(SetFeature))
+                        if setup_packet.value == TEST_MODE && !self.is_host_device() =>
+                    {
+                        // a simulated device has no signalling to test
+                        debug!("Entering test mode {}", setup_packet.index >> 8);
+                        Ok(vec![])
+                    }
+                    (0b00000000, Some(SetSel)) => {
+                        // U1/U2 exit latencies of the link, the host already set them for host devices
+                        debug!("Ignoring SET_SEL {out_data:x?}");
+                        Ok(vec![])
+                    }
+                    (0b00000000, Some(SetIsochDelay)) => {
+                        debug!("Ignoring SET_ISOCH_DELAY of {} ns", setup_packet.value);
+                        Ok(vec![])
+                    }
+                    (0b00000010, Some(SetFeature | ClearFeature))
+                        if setup_packet.value == ENDPOINT_HALT && !self.is_host_device() =>
+                    {
+                        debug!("Ignoring halt of endpoint {:#04x}", setup_packet.index);
+                        Ok(vec![])
+                    }
+                    (0b00000001, Some(SetInterface)) => {
+                        let intf = self.interface_at(setup_packet.index)?;
+                        let mut handler = intf.handler.lock().unwrap();
+                        let res = handler.handle_urb(
+                            intf,
+                            ep,
+                            transfer_flags,
+                            transfer_buffer_length,
+                            setup_packet,
+                            out_data,
+                        )?;
+                        intf.alt_setting
+                            .store(setup_packet.value as u8, Ordering::Relaxed);
+                        Ok(res)
+                    }
+                    _ if setup_packet.request_type & 0x1F == 1 => {
                         // to interface
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         // only low 8 bits are valid
 
-                        let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let interface = intf.handler.clone();
-                        // let device = match self.device_handler.clone() {
-                        //     Some(dev) => dev,
-                        //     None => {
-                        //         return Ok(Vec::new());
-                        //     }
-                        // };
-                        handle_urb_for_interface(
-                            interface,
-                            // device,
+                        let intf = self.interface_at(setup_packet.index)?;
+                        let mut handler = intf.handler.lock().unwrap();
+                        handler.handle_urb(
+                            intf,
                             ep,
+                            transfer_flags,
                             transfer_buffer_length,
                             setup_packet,
                             out_data,
                         )
                     }
-                    _ if setup_packet.request_type & 0xF == 0 => {
-                        // to device
+                    _ if matches!(setup_packet.request_type & 0x1F, 0 | 3) => {
+                        // to device, or to other (e.g. hub ports)
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
-                        match self.device_handler.clone() {
-                            Some(dh) => handle_urb_for_device(
-                                dh,
-                                transfer_buffer_length,
-                                setup_packet,
-                                out_data,
-                            ),
+                        match &self.device_handler {
+                            Some(dh) => {
+                                let mut handler = dh.lock().unwrap();
+                                handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                            }
                             None => Ok(Vec::new()),
                         }
                     }
-                    _ => unimplemented!("control out"),
+                    _ => {
+                        warn!("Unhandled control OUT {setup_packet:x?}");
+                        Err(stall(format!(
+                            "Control OUT of type {:#04x}",
+                            setup_packet.request_type
+                        )))
+                    }
                 }
             }
+            (false, _) if ep.attributes == Control as u8 => {
+                warn!("Control transfer to non-zero endpoint {:#04x}", ep.address);
+                Err(stall(format!(
+                    "Control transfer to endpoint {:#04x}",
+                    ep.address
+                )))
+            }
             _ => {
                 // others
                 // if setup_packet.request_type & 0xf == 1 {
@@ -608,18 +1563,19 @@ impl UsbDevice {
                 //     Ok(Vec::new())
                 // }
                 // info!("ep: {ep:?}. interface: {intf:?}");
+                if ep.direction() == In
+                    && let Some(data) = self
+                        .transfer_queue
+                        .as_ref()
+                        .and_then(|q| q.pop(ep.address, transfer_buffer_length as usize))
+                {
+                    return data;
+                }
                 let intf = intf.unwrap();
-                let interface = intf.handler.clone();
-                // let device = match self.device_handler.clone() {
-                //     Some(dev) => dev,
-                //     None => {
-                //         return Ok(Vec::new());
-                //     }
-                // };
-                handle_urb_for_interface(
-                    interface,
-                    // device,
+                intf.handler.lock().unwrap().handle_urb(
+                    intf,
                     ep,
+                    transfer_flags,
                     transfer_buffer_length,
                     setup_packet,
                     out_data,
@@ -629,6 +1585,102 @@ impl UsbDevice {
     }
 }
 
+/// Get the active configuration with `active`
+///
+/// An unconfigured device (bConfigurationValue 0) has none, it is configured
+/// with the `first` configuration using `set_configuration`. Fails if it has
+/// no configuration at all, or it couldn't be configured.
+pub(crate) async fn ensure_configured<C, F: Future<Output = Result<()>>>(
+    mut active: impl FnMut() -> Result<C>,
+    first: Option<u8>,
+    set_configuration: impl FnOnce(u8) -> F,
+) -> Result<C> {
+    let err = match active() {
+        Ok(cfg) => return Ok(cfg),
+        Err(err) => err,
+    };
+    let Some(value) = first else {
+        return Err(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("Unconfigured device without configuration: {err}"),
+        ));
+    };
+    warn!("Device is unconfigured ({err}), setting configuration {value}");
+    set_configuration(value).await?;
+    active()
+}
+
+/// Claim each of the interfaces `intf_nums` with `claim`, skipping those that fail
+///
+/// `driver` tells which driver is bound to an interface that failed to claim.
+pub(crate) async fn claim_each<T, F: Future<Output = Result<T>>>(
+    intf_nums: impl IntoIterator<Item = u8>,
+    mut claim: impl FnMut(u8) -> F,
+    driver: impl Fn(u8) -> Option<String>,
+) -> (Vec<T>, Vec<ClaimError>) {
+    let mut claimed = vec![];
+    let mut errors = vec![];
+    for intf_num in intf_nums {
+        match claim(intf_num).await {
+            Ok(intf) => claimed.push(intf),
+            Err(e) => {
+                let error = ClaimError {
+                    interface_number: intf_num,
+                    kind: e.kind(),
+                    message: e.to_string(),
+                    driver: driver(intf_num),
+                };
+                warn!("Failed to claim {error}, skipping it");
+                errors.push(error);
+            }
+        }
+    }
+    (claimed, errors)
+}
+
+/// The error of a device none of whose interfaces could be claimed
+fn no_interface_claimed(errors: &[ClaimError]) -> std::io::Error {
+    let kind = errors.first().map_or(ErrorKind::Other, |e| e.kind);
+    let reasons: Vec<String> = errors.iter().map(ToString::to_string).collect();
+    std::io::Error::new(
+        kind,
+        format!("No interface could be claimed: {}", reasons.join("; ")),
+    )
+}
+
+/// The max packet size of ep0 at the USB/IP `speed` code, see [UsbDevice::with_speed]
+pub fn ep0_max_packet_size(speed: u32) -> u16 {
+    match speed {
+        s if s == UsbSpeed::Low as u32 => 8,
+        s if s >= UsbSpeed::Super as u32 => 512,
+        _ => EP0_MAX_PACKET_SIZE,
+    }
+}
+
+/// The current USB frame number, counted in milliseconds on 11 bits
+fn frame_number() -> u16 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_millis() % 2048) as u16
+}
+
+/// The USB/IP speed code of a nusb [Speed]
+///
+/// USB/IP has no code above SuperSpeedPlus, so speeds added to nusb later,
+/// which can only be faster, are reported as [UsbSpeed::SuperPlus].
+pub fn usbip_speed_code(speed: Speed) -> u32 {
+    let speed = match speed {
+        Speed::Low => UsbSpeed::Low,
+        Speed::Full => UsbSpeed::Full,
+        Speed::High => UsbSpeed::High,
+        Speed::Super => UsbSpeed::Super,
+        Speed::SuperPlus => UsbSpeed::SuperPlus,
+        _ => UsbSpeed::SuperPlus,
+    };
+    speed as u32
+}
+
 /// A handler for URB targeting the device
 pub trait UsbDeviceHandler: std::fmt::Debug {
     /// Handle a URB(USB Request Block) targeting at this device
@@ -643,18 +1695,44 @@ pub trait UsbDeviceHandler: std::fmt::Debug {
     ) -> Result<Vec<u8>>;
 
     /// Reattach the kernel driver
+    ///
+    /// Only meaningful for host devices, does nothing by default.
     #[cfg(target_os = "linux")]
-    fn release_claim(&mut self);
+    fn release_claim(&mut self) {}
+
+    /// Use `timeouts` for the control transfers of the next URBs, see [UsbDevice::set_transfer_timeouts]
+    ///
+    /// Only meaningful for host devices, does nothing by default.
+    fn set_transfer_timeouts(&mut self, _timeouts: TransferTimeouts) {}
+
+    /// Get the device ready to be imported, see [crate::UsbIpServer::prepare]
+    ///
+    /// Host devices detach kernel drivers reattached since enumeration, does nothing by default.
+    fn prepare(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// bConfigurationValue of the configuration the device is in
+    ///
+    /// The server asks for it on import, in case the host switched configurations
+    /// since enumeration. Host devices read it back from the device, `None` by default.
+    fn active_configuration(&mut self) -> Option<u8> {
+        None
+    }
 
     /// Reset the device, forcing it to re-enumerate.
     /// This Device will no longer be usable, and you should drop it and call list_devices to find and re-open it again.
-    #[cfg(not(target_os = "windows"))]
-    fn reset(&mut self) -> Result<()>;
+    /// Host devices do so themselves, on Windows they reset the pipes of the unclaimed interfaces instead.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
 
     /// Set the device configuration.
     /// The argument is the desired configuration’s `bConfigurationValue` descriptor field from `ConfigurationDescriptor::configuration_value` or `0` to unconfigure the device.
     #[cfg(not(target_os = "windows"))]
-    fn set_configuration(&self, setup: &[u8; 8]) -> Result<()>;
+    fn set_configuration(&self, _setup: &[u8; 8]) -> Result<()> {
+        Ok(())
+    }
 
     /// Helper to downcast to actual struct
     ///
@@ -667,6 +1745,116 @@ pub trait UsbDeviceHandler: std::fmt::Debug {
     fn as_any(&mut self) -> &mut dyn Any;
 }
 
+/// Path, bus id and bus number of a device of the host
+#[cfg(target_os = "linux")]
+fn host_location(info: &DeviceInfo) -> (PathBuf, String, u32) {
+    let path = info.sysfs_path().to_path_buf();
+    let bus_id = match path.file_name() {
+        Some(s) => s.to_os_string().into_string().unwrap_or(format!(
+            "{}-{}-{}",
+            info.busnum(),
+            info.device_address(),
+            0,
+        )),
+        None => format!("{}-{}-{}", info.busnum(), info.device_address(), 0,),
+    };
+    (path, bus_id, info.busnum() as u32)
+}
+
+/// Path, bus id and bus number of a device of the host
+#[cfg(not(target_os = "linux"))]
+fn host_location(info: &DeviceInfo) -> (String, String, u32) {
+    (info.bus_id().to_string(), info.bus_id().to_string(), 0)
+}
+
+/// The descriptors between an interface descriptor and its endpoint descriptors
+///
+/// E.g. the HID descriptor or the CDC functional descriptors, served again
+/// after the interface descriptor.
+pub(crate) fn class_specific_descriptor(
+    intf_desc: &nusb::descriptors::InterfaceDescriptor,
+) -> Vec<u8> {
+    intf_desc
+        .descriptors()
+        .take_while(|desc| {
+            desc.descriptor_type() != DescriptorType::Endpoint as u8
+                && desc.descriptor_type() != DescriptorType::InterfaceAssociation as u8
+        })
+        .flat_map(|desc| desc.to_vec())
+        .collect()
+}
+
+/// Opens a device on import, see [UsbDevice::with_lazy_open]
+#[derive(Clone)]
+pub(crate) struct LazyOpen(pub(crate) Arc<LazyOpenFn>);
+
+type LazyOpenFn = dyn Fn() -> Result<UsbDevice> + Send + Sync;
+
+impl std::fmt::Debug for LazyOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Opaque")
+    }
+}
+
+/// The interfaces of a device described by [UsbDevice::from_device_info_lazy], never served
+#[derive(Debug)]
+struct UnopenedInterface;
+
+impl UsbInterfaceHandler for UnopenedInterface {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_flags: u32,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            ErrorKind::NotConnected,
+            "Device not opened yet",
+        ))
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The interface of a [UsbDevice::new_control_only] device, forwarding requests to the device handler
+#[derive(Debug)]
+struct DeviceHandlerInterface(Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>);
+
+impl UsbInterfaceHandler for DeviceHandlerInterface {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_flags: u32,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        // only ever called for control transfers, the interface has no endpoints
+        self.0
+            .lock()
+            .unwrap()
+            .handle_urb(transfer_buffer_length, setup, req)
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::util::tests::*;
@@ -693,6 +1881,926 @@ mod test {
         assert_eq!(device.string_pool[&4], "test");
     }
 
+    #[test]
+    fn test_switch_configuration() {
+        setup_test_logger();
+        let ep = |address| UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let handler = || -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+            Arc::new(Mutex::new(Box::new(
+                crate::vendor::VendorControlHandler::default(),
+            )))
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(0xFF, 0, 0, None, vec![ep(0x81)], handler())
+            .with_configuration(2)
+            .with_interface(0xFF, 0, 0, None, vec![ep(0x82)], handler())
+            .with_interface(0xFF, 0, 0, None, vec![ep(0x02)], handler());
+        assert_eq!(device.num_configurations, 2);
+        assert_eq!(device.active_configuration(), 1);
+        assert!(device.find_ep(0x81).is_some());
+        assert!(device.find_ep(0x82).is_none());
+
+        // the second configuration descriptor
+        let get_descriptor = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Configuration as u16) << 8 | 1,
+            index: 0,
+            length: 9,
+        };
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 9, get_descriptor, &[])
+            .unwrap();
+        assert_eq!(desc[4], 2); // bNumInterfaces
+        assert_eq!(desc[5], 2); // bConfigurationValue
+
+        let set_configuration = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetConfiguration as u8,
+            value: 2,
+            index: 0,
+            length: 0,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, 0, set_configuration, &[])
+            .unwrap();
+        assert_eq!(device.active_configuration(), 2);
+        assert_eq!(device.active_interfaces().len(), 2);
+        assert!(device.find_ep(0x81).is_none());
+        assert!(device.find_ep(0x82).is_some());
+        assert!(device.find_ep(0x02).is_some());
+        assert_eq!(device.to_bytes()[0x135..], [2, 2, 2]);
+
+        let get_configuration = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetConfiguration as u8,
+            value: 0,
+            index: 0,
+            length: 1,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 0, 1, get_configuration, &[])
+            .unwrap();
+        assert_eq!(resp, vec![2]);
+    }
+
+    #[test]
+    fn test_get_configuration() {
+        setup_test_logger();
+        let mut device = UsbDevice::new(0);
+        device.configuration_value = 3;
+        let device = device.with_configuration(5);
+        let get_configuration = |length| {
+            let setup = SetupPacket {
+                request_type: 0b10000000,
+                request: StandardRequest::GetConfiguration as u8,
+                value: 0,
+                index: 0,
+                length,
+            };
+            device
+                .handle_urb(device.ep0_in, None, 0, length as u32, setup, &[])
+                .unwrap()
+        };
+        let set_configuration = |value| {
+            let setup = SetupPacket {
+                request_type: 0b00000000,
+                request: StandardRequest::SetConfiguration as u8,
+                value,
+                index: 0,
+                length: 0,
+            };
+            device
+                .handle_urb(device.ep0_out, None, 0, 0, setup, &[])
+                .unwrap();
+        };
+        assert_eq!(get_configuration(1), vec![3]);
+        assert_eq!(get_configuration(0), Vec::<u8>::new());
+
+        set_configuration(5);
+        assert_eq!(get_configuration(1), vec![5]);
+        // unknown configuration, left unchanged
+        set_configuration(9);
+        assert_eq!(get_configuration(1), vec![5]);
+        set_configuration(3);
+        assert_eq!(get_configuration(1), vec![3]);
+    }
+
+    #[test]
+    fn test_reserved_request_type() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        // standard requests to reserved recipients
+        for (ep, request_type) in [(device.ep0_in, 0b10011111), (device.ep0_out, 0b00000111)] {
+            let setup = SetupPacket {
+                request_type,
+                request: StandardRequest::GetStatus as u8,
+                value: 0,
+                index: 0,
+                length: 0,
+            };
+            let err = device.handle_urb(ep, None, 0, 0, setup, &[]).unwrap_err();
+            assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+        }
+    }
+
+    #[test]
+    fn test_host_class_specific_descriptor() {
+        setup_test_logger();
+        let hid = [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00];
+        let mut config = vec![0x09, 0x02, 0, 0, 0x03, 0x01, 0x00, 0x80, 0x32];
+        // HID interface, HID descriptor, interrupt IN endpoint
+        config.extend([0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00]);
+        config.extend(hid);
+        config.extend([0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0A]);
+        // no endpoints, followed by the association of the next function
+        config.extend([0x09, 0x04, 0x01, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00]);
+        config.extend([0x08, 0x0B, 0x02, 0x01, 0x02, 0x02, 0x00, 0x00]);
+        config.extend([0x09, 0x04, 0x02, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00]);
+        config[2] = config.len() as u8;
+
+        let config = nusb::descriptors::ConfigurationDescriptor::new(&config).unwrap();
+        let descriptors: Vec<Vec<u8>> = config
+            .interface_alt_settings()
+            .map(|intf| class_specific_descriptor(&intf))
+            .collect();
+        assert_eq!(descriptors, vec![hid.to_vec(), vec![], vec![]]);
+    }
+
+    #[test]
+    fn test_webusb_landing_page() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_webusb_landing_page(0x01, "https://example.com");
+        let get_bos = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::BOS as u16) << 8,
+            index: 0,
+            length: 0xFF,
+        };
+        let bos = device
+            .handle_urb(device.ep0_in, None, 0, 0xFF, get_bos, &[])
+            .unwrap();
+        assert_eq!(bos.len(), 29);
+        assert_eq!(&bos[..5], &[0x05, 0x0F, 29, 0x00, 1]);
+        // bVendorCode, iLandingPage
+        assert_eq!(&bos[27..], &[0x01, 0x01]);
+
+        let get_url = SetupPacket {
+            request_type: 0b11000000,
+            request: 0x01,
+            value: 1,
+            index: 2,
+            length: 0xFF,
+        };
+        let url = device
+            .handle_urb(device.ep0_in, None, 0, 0xFF, get_url, &[])
+            .unwrap();
+        let mut expected = vec![14, 0x03, 0x01];
+        expected.extend_from_slice(b"example.com");
+        assert_eq!(url, expected);
+    }
+
+    #[test]
+    fn test_at_least_one_configuration() {
+        setup_test_logger();
+        let get_device = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x12,
+        };
+        let device = UsbDevice::new(0);
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 0x12, get_device, &[])
+            .unwrap();
+        assert_eq!(desc[17], 1);
+        assert!(device.validate().is_ok());
+
+        // left at 0, still served as 1 but reported
+        let mut device = UsbDevice::new(0);
+        device.num_configurations = 0;
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 0x12, get_device, &[])
+            .unwrap();
+        assert_eq!(desc[17], 1);
+        assert_eq!(device.to_bytes()[310], 1);
+        assert_eq!(
+            device.validate(),
+            Err(vec![ValidationError::ConfigurationCount {
+                declared: 0,
+                actual: 1
+            }])
+        );
+    }
+
+    #[test]
+    fn test_alternate_setting() {
+        setup_test_logger();
+        let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| intf)
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                intf.on_control(|_setup, _req| Ok(vec![]))
+            })
+            .build();
+        assert_eq!(device.alt_setting(1), Some(0));
+        assert_eq!(device.alt_setting(2), None);
+
+        let set_interface = |value, index| SetupPacket {
+            request_type: 0b00000001,
+            request: StandardRequest::SetInterface as u8,
+            value,
+            index,
+            length: 0,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, 0, set_interface(2, 1), &[])
+            .unwrap();
+        assert_eq!(device.alt_setting(1), Some(2));
+        assert_eq!(device.interfaces[1].current_alt_setting(), 2);
+        assert_eq!(device.alt_setting(0), Some(0));
+
+        // refused by the interface, left unchanged
+        assert!(
+            device
+                .handle_urb(device.ep0_out, None, 0, 0, set_interface(1, 0), &[])
+                .is_err()
+        );
+        assert_eq!(device.alt_setting(0), Some(0));
+
+        let get_interface = |index| SetupPacket {
+            request_type: 0b10000001,
+            request: StandardRequest::GetInterface as u8,
+            value: 0,
+            index,
+            length: 1,
+        };
+        let alt = device
+            .handle_urb(device.ep0_in, None, 0, 1, get_interface(1), &[])
+            .unwrap();
+        assert_eq!(alt, vec![2]);
+        let alt = device
+            .handle_urb(device.ep0_in, None, 0, 1, get_interface(0), &[])
+            .unwrap();
+        assert_eq!(alt, vec![0]);
+        assert!(
+            device
+                .handle_urb(device.ep0_in, None, 0, 1, get_interface(2), &[])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ms_os_10_compatible_id() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_ms_os_10_compatible_id(0x20, 0, "WINUSB");
+        let get_os_string = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: ((DescriptorType::String as u16) << 8) | 0xEE,
+            index: 0,
+            length: 0xFF,
+        };
+        let os_string = device
+            .handle_urb(device.ep0_in, None, 0, 0xFF, get_os_string, &[])
+            .unwrap();
+        let mut expected = vec![0x12, 0x03];
+        expected.extend("MSFT100".encode_utf16().flat_map(u16::to_le_bytes));
+        expected.extend_from_slice(&[0x20, 0x00]);
+        assert_eq!(os_string, expected);
+
+        let get_compat_id = SetupPacket {
+            request_type: 0b11000000,
+            request: 0x20,
+            value: 0,
+            index: 4,
+            length: 0x10,
+        };
+        let header = device
+            .handle_urb(device.ep0_in, None, 0, 0x10, get_compat_id, &[])
+            .unwrap();
+        // dwLength, bcdVersion, wIndex, bCount, reserved
+        assert_eq!(
+            header,
+            vec![40, 0, 0, 0, 0x00, 0x01, 0x04, 0x00, 1, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let get_compat_id = SetupPacket {
+            length: 40,
+            ..get_compat_id
+        };
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 40, get_compat_id, &[])
+            .unwrap();
+        assert_eq!(desc.len(), 40);
+        // bFirstInterfaceNumber, reserved, compatibleID
+        assert_eq!(&desc[16..26], b"\x00\x01WINUSB\x00\x00");
+        assert_eq!(&desc[26..], &[0; 14]);
+    }
+
+    #[test]
+    fn test_set_address_not_forwarded() {
+        setup_test_logger();
+        let forwarded = Arc::new(AtomicBool::new(false));
+        let handler = crate::vendor::VendorControlHandler::default().with_callback({
+            let forwarded = forwarded.clone();
+            move |_setup, _req| {
+                forwarded.store(true, Ordering::Relaxed);
+                Ok(vec![])
+            }
+        });
+        let device = UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let set_address = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetAddress as u8,
+            value: 5,
+            index: 0,
+            length: 0,
+        };
+        let resp = device
+            .handle_urb(device.ep0_out, None, 0, 0, set_address, &[])
+            .unwrap();
+        assert!(resp.is_empty());
+        assert!(!forwarded.load(Ordering::Relaxed));
+    }
+
+    /// A device whose handler records whether it got a request
+    fn device_recording_forwards() -> (UsbDevice, Arc<AtomicBool>) {
+        let forwarded = Arc::new(AtomicBool::new(false));
+        let handler = crate::vendor::VendorControlHandler::default().with_callback({
+            let forwarded = forwarded.clone();
+            move |_setup, _req| {
+                forwarded.store(true, Ordering::Relaxed);
+                Ok(vec![])
+            }
+        });
+        let device = UsbDevice::new(0)
+            .with_speed(UsbSpeed::Super)
+            .with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        (device, forwarded)
+    }
+
+    #[test]
+    fn test_set_sel_not_forwarded() {
+        setup_test_logger();
+        let (device, forwarded) = device_recording_forwards();
+        let set_sel = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetSel as u8,
+            value: 0,
+            index: 0,
+            length: 6,
+        };
+        // U1SEL, U1PEL, U2SEL, U2PEL
+        let data = [0x0a, 0x0b, 0x20, 0x00, 0x40, 0x00];
+        let resp = device
+            .handle_urb(device.ep0_out, None, 0, 6, set_sel, &data)
+            .unwrap();
+        assert!(resp.is_empty());
+        assert!(!forwarded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_set_isoch_delay_not_forwarded() {
+        setup_test_logger();
+        let (device, forwarded) = device_recording_forwards();
+        let set_isoch_delay = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetIsochDelay as u8,
+            value: 40,
+            index: 0,
+            length: 0,
+        };
+        let resp = device
+            .handle_urb(device.ep0_out, None, 0, 0, set_isoch_delay, &[])
+            .unwrap();
+        assert!(resp.is_empty());
+        assert!(!forwarded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_interface_association() {
+        setup_test_logger();
+        let cdc = ClassCode::CDC as u8;
+        let endpoints = crate::cdc::UsbCdcAcmHandler::endpoints();
+        let device = UsbDevice::new(0)
+            .with_interface_association(cdc, crate::cdc::CDC_ACM_SUBCLASS, 0x01, None, 2)
+            .with_interface(
+                cdc,
+                crate::cdc::CDC_ACM_SUBCLASS,
+                0x01,
+                None,
+                endpoints[..1].to_vec(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new()))),
+            )
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                None,
+                endpoints[1..].to_vec(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new()))),
+            );
+        assert_eq!(device.device_class, ClassCode::Misc as u8);
+
+        let desc = device.configuration_descriptor_bytes(0);
+        verify_descriptor(&desc);
+        // right after the configuration descriptor, before interface 0
+        assert_eq!(desc[9..17], [0x08, 0x0B, 0x00, 0x02, cdc, 0x02, 0x01, 0x00]);
+        assert_eq!(desc[17..19], [0x09, DescriptorType::Interface as u8]);
+        assert_eq!(desc[19], 0);
+    }
+
+    #[test]
+    fn test_update_class_specific_descriptor() {
+        setup_test_logger();
+        let device = Arc::new(UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![],
+            Arc::new(Mutex::new(Box::new(
+                crate::vendor::VendorControlHandler::default(),
+            ))),
+        ));
+        let get_configuration = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Configuration as u16) << 8,
+            index: 0,
+            length: 0xFF,
+        };
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 0xFF, get_configuration, &[])
+            .unwrap();
+        assert_eq!(desc.len(), 9 + 9);
+
+        // a clone shares the descriptor, like an imported device
+        let class_specific = vec![0x05, 0x24, 0x00, 0x10, 0x01];
+        device.clone().interfaces[0].set_class_specific_descriptor(class_specific.clone());
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0, 0xFF, get_configuration, &[])
+            .unwrap();
+        verify_descriptor(&desc);
+        assert_eq!(desc[2], 9 + 9 + 5, "wTotalLength");
+        assert_eq!(desc[18..], class_specific);
+
+        device.interfaces[0].refresh_class_specific_descriptor();
+        assert_eq!(device.configuration_descriptor_bytes(0).len(), 9 + 9);
+    }
+
+    #[test]
+    fn test_optional_descriptors() {
+        setup_test_logger();
+        // functions of interfaces 0 and 1, CDC ACM
+        let iad = vec![0x08, 0x0B, 0x00, 0x02, 0x02, 0x02, 0x01, 0x00];
+        let device = UsbDevice::new(0).with_descriptor(
+            DescriptorType::InterfaceAssociation as u8,
+            0,
+            iad.clone(),
+        );
+        let mut get_descriptor = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::InterfaceAssociation as u16) << 8,
+            index: 0,
+            length: 255,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 0, 255, get_descriptor, &[])
+            .unwrap();
+        assert_eq!(resp, iad);
+
+        // not registered
+        get_descriptor.value = (DescriptorType::Debug as u16) << 8;
+        let err = device
+            .handle_urb(device.ep0_in, None, 0, 255, get_descriptor, &[])
+            .unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+    }
+
+    #[test]
+    fn test_remote_wakeup_status() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_remote_wakeup();
+        assert_eq!(device.attributes & 0x20, 0x20);
+        let get_status = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetStatus as u8,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let status = device
+            .handle_urb(device.ep0_in, None, 0, 2, get_status, &[])
+            .unwrap();
+        assert_eq!(status, vec![0x00, 0x00]);
+
+        let set_feature = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetFeature as u8,
+            value: DEVICE_REMOTE_WAKEUP,
+            index: 0,
+            length: 0,
+        };
+        device
+            .handle_urb(device.ep0_out, None, 0, 0, set_feature, &[])
+            .unwrap();
+        assert!(device.remote_wakeup_enabled());
+        let status = device
+            .handle_urb(device.ep0_in, None, 0, 2, get_status, &[])
+            .unwrap();
+        assert_eq!(status, vec![0x02, 0x00]);
+    }
+
+    #[test]
+    fn test_status_not_forwarded() {
+        setup_test_logger();
+        let (device, forwarded) = device_recording_forwards();
+        let get_status = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetStatus as u8,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let set_feature = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetFeature as u8,
+            value: DEVICE_REMOTE_WAKEUP,
+            index: 0,
+            length: 0,
+        };
+        let device = device.with_remote_wakeup();
+        device
+            .handle_urb(device.ep0_out, None, 0, 0, set_feature, &[])
+            .unwrap();
+        // the handler gets SET_FEATURE, but the status is answered locally
+        forwarded.store(false, Ordering::Relaxed);
+        let status = device
+            .handle_urb(device.ep0_in, None, 0, 2, get_status, &[])
+            .unwrap();
+        assert_eq!(status, vec![0x02, 0x00]);
+
+        let get_configuration = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetConfiguration as u8,
+            value: 0,
+            index: 0,
+            length: 1,
+        };
+        let configuration = device
+            .handle_urb(device.ep0_in, None, 0, 1, get_configuration, &[])
+            .unwrap();
+        assert_eq!(configuration, vec![device.active_configuration()]);
+        assert!(!forwarded.load(Ordering::Relaxed));
+
+        // reserved recipient, not the device
+        let reserved = SetupPacket {
+            request_type: 0b10010000,
+            ..get_status
+        };
+        let err = device
+            .handle_urb(device.ep0_in, None, 0, 2, reserved, &[])
+            .unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+        assert!(!forwarded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_rare_standard_requests() {
+        setup_test_logger();
+        let endpoint = |address, attributes| UsbEndpoint {
+            address,
+            attributes,
+            max_packet_size: 512,
+            interval: 1,
+        };
+        // the device handler would stall them
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::Audio as u8,
+                0,
+                0,
+                None,
+                vec![
+                    endpoint(0x81, EndpointAttributes::Isochronous as u8),
+                    endpoint(0x02, EndpointAttributes::Bulk as u8),
+                ],
+                Arc::new(Mutex::new(Box::new(
+                    crate::vendor::VendorControlHandler::default(),
+                ))),
+            )
+            .with_device_handler(Arc::new(Mutex::new(Box::new(
+                crate::vendor::VendorControlHandler::default(),
+            ))));
+
+        let synch_frame = |index| SetupPacket {
+            request_type: 0b10000010,
+            request: StandardRequest::SynchFrame as u8,
+            value: 0,
+            index,
+            length: 2,
+        };
+        let frame = device
+            .handle_urb(device.ep0_in, None, 0, 2, synch_frame(0x81), &[])
+            .unwrap();
+        assert_eq!(frame.len(), 2);
+        assert!(u16::from_le_bytes([frame[0], frame[1]]) < 2048);
+        let err = device
+            .handle_urb(device.ep0_in, None, 0, 2, synch_frame(0x02), &[])
+            .unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+
+        let test_mode = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetFeature as u8,
+            value: TEST_MODE,
+            index: 0x0400, // Test_Packet
+            length: 0,
+        };
+        let resp = device
+            .handle_urb(device.ep0_out, None, 0, 0, test_mode, &[])
+            .unwrap();
+        assert!(resp.is_empty());
+
+        let clear_halt = SetupPacket {
+            request_type: 0b00000010,
+            request: StandardRequest::ClearFeature as u8,
+            value: ENDPOINT_HALT,
+            index: 0x02,
+            length: 0,
+        };
+        assert!(
+            device
+                .handle_urb(device.ep0_out, None, 0, 0, clear_halt, &[])
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_interrupt_in_interval() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 20,
+        };
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            Some("Test HID"),
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(
+                hid::UsbHidKeyboardHandler::new_keyboard(),
+            ))),
+        );
+        device.speed = UsbSpeed::Full as u32;
+        assert_eq!(
+            device.interrupt_in_interval(0x81),
+            Some(Duration::from_millis(20))
+        );
+        // only interrupt IN endpoints are paced
+        assert_eq!(device.interrupt_in_interval(0x80), None);
+        assert_eq!(device.interrupt_in_interval(0x01), None);
+    }
+
+    #[test]
+    fn test_control_on_non_zero_endpoint_stalls() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Control as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0xFF,
+            0xFF,
+            None,
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(
+                crate::vendor::VendorControlHandler::default(),
+            ))),
+        );
+        let setup = SetupPacket {
+            request_type: 0b11000001,
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 8,
+        };
+        let (ep, intf) = device.find_ep(0x81).unwrap();
+        let err = device.handle_urb(ep, intf, 0, 8, setup, &[]).unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+    }
+
+    fn never_configured(_: u8) -> std::future::Ready<Result<()>> {
+        unreachable!()
+    }
+
+    #[test]
+    fn test_unconfigured_device_gets_configured() {
+        setup_test_logger();
+        let active = std::cell::Cell::new(0u8);
+        let get_active = || match active.get() {
+            0 => Err(std::io::Error::other("Unconfigured")),
+            value => Ok(value),
+        };
+        let cfg = block_on(ensure_configured(get_active, Some(1), |value| {
+            active.set(value);
+            std::future::ready(Ok(()))
+        }));
+        assert_eq!(cfg.unwrap(), 1);
+
+        // configured devices are left alone
+        let cfg = block_on(ensure_configured(get_active, Some(2), never_configured));
+        assert_eq!(cfg.unwrap(), 1);
+
+        // nothing to select: skipped
+        active.set(0);
+        let err = block_on(ensure_configured(get_active, None, never_configured)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_claim_failure_skips_interface() {
+        setup_test_logger();
+        let (claimed, errors) = block_on(claim_each(
+            [0, 1, 2],
+            |intf_num| {
+                std::future::ready(if intf_num == 1 {
+                    Err(std::io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Permission denied",
+                    ))
+                } else {
+                    Ok(intf_num)
+                })
+            },
+            |_| None,
+        ));
+        assert_eq!(claimed, vec![0, 2]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].interface_number, 1);
+        assert_eq!(errors[0].kind, ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_open_device_claimed_on_runtime() {
+        setup_test_logger();
+        // an opened, unconfigured device whose requests complete later on the runtime,
+        // like the transfers of nusb
+        let active = std::cell::Cell::new(0u8);
+        let get_active = || match active.get() {
+            0 => Err(std::io::Error::other("Unconfigured")),
+            value => Ok(value),
+        };
+        let cfg = ensure_configured(get_active, Some(1), |value| {
+            let active = &active;
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                active.set(value);
+                Ok(())
+            }
+        })
+        .await;
+        assert_eq!(cfg.unwrap(), 1);
+
+        let (claimed, errors) = claim_each(
+            [0, 1, 2],
+            |intf_num| async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                match intf_num {
+                    1 => Err(std::io::Error::new(ErrorKind::ResourceBusy, "Busy")),
+                    _ => Ok(intf_num),
+                }
+            },
+            |_| Some("usbfs".to_string()),
+        )
+        .await;
+        assert_eq!(claimed, vec![0, 2]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].interface_number, 1);
+        assert_eq!(errors[0].driver.as_deref(), Some("usbfs"));
+    }
+
+    #[test]
+    fn test_claim_failure_context() {
+        setup_test_logger();
+        // interface 0 claimed by another process, 1 bound to a driver that wouldn't detach
+        let drivers = HashMap::from([(0, "usbfs".to_string()), (1, "usbhid".to_string())]);
+        let (claimed, errors) = block_on(claim_each(
+            [0, 1],
+            |_| {
+                std::future::ready(Err::<u8, _>(std::io::Error::new(
+                    ErrorKind::ResourceBusy,
+                    "Device or resource busy",
+                )))
+            },
+            |intf_num| drivers.get(&intf_num).cloned(),
+        ));
+        assert!(claimed.is_empty());
+        assert_eq!(
+            errors[0].to_string(),
+            "interface 0: Device or resource busy, claimed by another process"
+        );
+        assert_eq!(errors[1].driver.as_deref(), Some("usbhid"));
+
+        let err = no_interface_claimed(&errors);
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        assert_eq!(
+            err.to_string(),
+            "No interface could be claimed: \
+             interface 0: Device or resource busy, claimed by another process; \
+             interface 1: Device or resource busy, bound to the usbhid driver"
+        );
+    }
+
+    #[test]
+    fn test_ep0_max_packet_size() {
+        setup_test_logger();
+        let get_device_descriptor = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetDescriptor as u8,
+            value: (DescriptorType::Device as u16) << 8,
+            index: 0,
+            length: 0x12,
+        };
+        for (speed, max_packet_size, max_packet_size0) in [
+            (UsbSpeed::Low, 8, 8),
+            (UsbSpeed::High, 64, 64),
+            (UsbSpeed::Super, 512, 9),
+        ] {
+            let device = UsbDevice::new(0).with_speed(speed);
+            assert_eq!(device.validate(), Ok(()));
+            assert_eq!(
+                device.find_ep(0x80).unwrap().0.max_packet_size,
+                max_packet_size
+            );
+            assert_eq!(
+                device.find_ep(0x00).unwrap().0.max_packet_size,
+                max_packet_size
+            );
+            let desc = device
+                .handle_urb(device.ep0_in, None, 0, 0x12, get_device_descriptor, &[])
+                .unwrap();
+            assert_eq!(desc[7], max_packet_size0, "bMaxPacketSize0 at {speed:?}");
+        }
+
+        // high speed devices need 64 bytes
+        let device = UsbDevice::new(0).with_ep0_max_packet_size(8);
+        assert_eq!(
+            device.validate(),
+            Err(vec![ValidationError::InvalidEp0MaxPacketSize {
+                size: 8,
+                speed: UsbSpeed::High as u32
+            }])
+        );
+    }
+
+    #[test]
+    fn test_usbip_speed_code() {
+        setup_test_logger();
+        assert_eq!(usbip_speed_code(Speed::Low), 1);
+        assert_eq!(usbip_speed_code(Speed::Full), 2);
+        assert_eq!(usbip_speed_code(Speed::High), 3);
+        assert_eq!(usbip_speed_code(Speed::Super), 5);
+        assert_eq!(usbip_speed_code(Speed::SuperPlus), 6);
+    }
+
+    #[test]
+    fn test_endpoints_from_descriptor() {
+        setup_test_logger();
+        let desc = [
+            0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x02, 0x08, 0x06, 0x50, 0x00, // interface
+            0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, // bulk in, 512 bytes
+            0x07, 0x05, 0x02, 0x03, 0x08, 0x00, 0x0A, // interrupt out, 8 bytes
+        ];
+        let cfg = nusb::descriptors::ConfigurationDescriptor::new(&desc).unwrap();
+        let intf = cfg.interfaces().next().unwrap().first_alt_setting();
+        let endpoints: Vec<UsbEndpoint> = intf.endpoints().map(UsbEndpoint::from).collect();
+
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0].address, 0x81);
+        assert_eq!(endpoints[0].attributes, EndpointAttributes::Bulk as u8);
+        assert_eq!(endpoints[0].max_packet_size, 512);
+        assert_eq!(endpoints[1].address, 0x02);
+        assert_eq!(endpoints[1].attributes, EndpointAttributes::Interrupt as u8);
+        assert_eq!(endpoints[1].max_packet_size, 8);
+        assert_eq!(endpoints[1].interval, 10);
+    }
+
     // #[tokio::test]
     // async fn test_invalid_string_index() {
     //     setup_test_logger();
@@ -722,19 +2830,3 @@ mod test {
     //     assert!(res.is_err());
     // }
 }
-
-#[cfg(target_os = "linux")]
-pub fn release_claim(device: Device) {
-    let cfg = match device.active_configuration() {
-        Ok(cfg) => cfg,
-        Err(err) => {
-            warn!("Impossible to get active configuration: {err}, ignoring device",);
-            return;
-        }
-    };
-    for intf in cfg.interfaces() {
-        // ignore alternate settings
-        let intf_num = intf.interface_number();
-        let _ = device.attach_kernel_driver(intf_num);
-    }
-}
@@ -1,3 +1,6 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 /// Represent a USB endpoint
 #[derive(Clone, Copy, Debug, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -26,4 +29,78 @@ impl UsbEndpoint {
     pub fn is_ep0(&self) -> bool {
         self.address & 0x7F == 0
     }
+
+    /// Polling period of an interrupt endpoint, from its bInterval
+    ///
+    /// `speed` is the USB/IP speed code of the device. At high speed and above
+    /// bInterval is the exponent of a period in 125µs microframes, below it
+    /// counts 1ms frames. See USB 2.0 Table 9-13.
+    pub fn polling_interval(&self, speed: u32) -> std::time::Duration {
+        if speed >= crate::UsbSpeed::High as u32 && speed != crate::UsbSpeed::Wireless as u32 {
+            let exponent = self.interval.clamp(1, 16) - 1;
+            std::time::Duration::from_micros(125 << exponent)
+        } else {
+            std::time::Duration::from_millis(self.interval.max(1) as u64)
+        }
+    }
+}
+
+impl From<nusb::descriptors::EndpointDescriptor<'_>> for UsbEndpoint {
+    fn from(desc: nusb::descriptors::EndpointDescriptor<'_>) -> Self {
+        Self {
+            address: desc.address(),
+            attributes: desc.transfer_type() as u8,
+            max_packet_size: desc.max_packet_size() as u16,
+            interval: desc.interval(),
+        }
+    }
+}
+
+/// How long transfers to host devices may take before they are cancelled, by transfer type
+///
+/// 1s each by default, see [crate::UsbIpServer::with_transfer_timeout].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferTimeouts {
+    pub control: std::time::Duration,
+    pub isochronous: std::time::Duration,
+    pub bulk: std::time::Duration,
+    pub interrupt: std::time::Duration,
+}
+
+impl Default for TransferTimeouts {
+    fn default() -> Self {
+        let timeout = std::time::Duration::from_secs(1);
+        Self {
+            control: timeout,
+            isochronous: timeout,
+            bulk: timeout,
+            interrupt: timeout,
+        }
+    }
+}
+
+impl TransferTimeouts {
+    /// The timeout of transfers to `ep`, from the transfer type in its bmAttributes
+    pub fn for_endpoint(&self, ep: &UsbEndpoint) -> std::time::Duration {
+        self.for_type(ep.attributes)
+    }
+
+    fn for_type(&self, attributes: u8) -> std::time::Duration {
+        match attributes & 0b11 {
+            0 => self.control,
+            1 => self.isochronous,
+            2 => self.bulk,
+            _ => self.interrupt,
+        }
+    }
+
+    /// Set the timeout of transfers of `transfer_type`
+    pub fn set(&mut self, transfer_type: crate::EndpointAttributes, timeout: std::time::Duration) {
+        *match transfer_type {
+            crate::EndpointAttributes::Control => &mut self.control,
+            crate::EndpointAttributes::Isochronous => &mut self.isochronous,
+            crate::EndpointAttributes::Bulk => &mut self.bulk,
+            crate::EndpointAttributes::Interrupt => &mut self.interrupt,
+        } = timeout;
+    }
 }
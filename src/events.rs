@@ -0,0 +1,125 @@
+//! Lifecycle events of a running server, see [UsbIpServer::events]
+use super::*;
+use futures_core::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+/// Events buffered for each subscriber, older ones are dropped for slow subscribers
+const EVENT_CAPACITY: usize = 256;
+
+/// A lifecycle event of a [UsbIpServer]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client connected, `connection` numbers connections from 1
+    ClientConnected { connection: u64 },
+    /// The client of `connection` disconnected
+    ClientDisconnected { connection: u64 },
+    /// A client imported the device
+    DeviceImported { bus_id: String },
+    /// The device is available again
+    DeviceReleased { bus_id: String },
+    /// A URB to endpoint `ep` of the device completed with the RET_SUBMIT `status`
+    TransferError { bus_id: String, ep: u8, status: i32 },
+}
+
+#[derive(Debug)]
+pub(crate) struct EventSender(Sender<ServerEvent>);
+
+impl Default for EventSender {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+}
+
+impl EventSender {
+    pub(crate) fn send(&self, event: ServerEvent) {
+        // no subscriber is fine
+        let _ = self.0.send(event);
+    }
+
+    pub(crate) fn subscribe(&self) -> ServerEvents {
+        ServerEvents {
+            next: Box::pin(recv(self.0.subscribe())),
+        }
+    }
+}
+
+type Recv = (
+    std::result::Result<ServerEvent, RecvError>,
+    Receiver<ServerEvent>,
+);
+
+async fn recv(mut rx: Receiver<ServerEvent>) -> Recv {
+    let res = rx.recv().await;
+    (res, rx)
+}
+
+/// [Stream] of the [ServerEvent]s of a [UsbIpServer]
+pub(crate) struct ServerEvents {
+    next: Pin<Box<dyn Future<Output = Recv> + Send>>,
+}
+
+impl Stream for ServerEvents {
+    type Item = ServerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerEvent>> {
+        loop {
+            let (res, rx) = match self.next.as_mut().poll(cx) {
+                Poll::Ready(recv) => recv,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.next = Box::pin(recv(rx));
+            match res {
+                Ok(event) => return Poll::Ready(Some(event)),
+                Err(RecvError::Lagged(n)) => warn!("Event subscriber lagged, {n} events dropped"),
+                Err(RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    async fn next_event(events: &mut (impl Stream<Item = ServerEvent> + Unpin)) -> ServerEvent {
+        std::future::poll_fn(|cx| Pin::new(&mut *events).poll_next(cx))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn import_and_release_events() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+        let mut events = server.events();
+
+        let device = server.occupy("0-0-0").await.unwrap();
+        server.release(device).await;
+        // not imported anymore
+        server.release(Arc::new(UsbDevice::new(0))).await;
+        server.occupy("0-0-0").await.unwrap();
+        server.cleanup().await;
+
+        let bus_id = "0-0-0".to_string();
+        for expected in [
+            ServerEvent::DeviceImported {
+                bus_id: bus_id.clone(),
+            },
+            ServerEvent::DeviceReleased {
+                bus_id: bus_id.clone(),
+            },
+            ServerEvent::DeviceImported {
+                bus_id: bus_id.clone(),
+            },
+            ServerEvent::DeviceReleased {
+                bus_id: bus_id.clone(),
+            },
+        ] {
+            assert_eq!(next_event(&mut events).await, expected);
+        }
+    }
+}
@@ -92,6 +92,7 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        _transfer_flags: u32,
         _transfer_buffer_length: u32,
         setup: SetupPacket,
         _req: &[u8],
@@ -106,14 +107,18 @@ impl UsbInterfaceHandler for UsbHidKeyboardHandler {
                         Some(HidDescriptorType::Report) => {
                             return Ok(self.report_descriptor.clone());
                         }
-                        _ => unimplemented!("hid descriptor {:?}", setup),
+                        _ => {
+                            return Err(stall(format!("HID descriptor {setup:x?}")));
+                        }
                     }
                 }
                 (0b00100001, 0x0A) => {
                     // SET_IDLE
                     return Ok(vec![]);
                 }
-                _ => unimplemented!("hid request {:?}", setup),
+                _ => {
+                    return Err(stall(format!("HID request {setup:x?}")));
+                }
             }
         } else {
             // interrupt transfer
@@ -2,35 +2,61 @@
 use log::*;
 use nusb::{
     Device, Interface, MaybeFuture,
-    transfer::{Buffer, Bulk, Direction, In, Interrupt, Out},
+    transfer::{Bulk, Direction, In, Interrupt, Out, TransferError},
 };
-use std::io::Result;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{ErrorKind, Result};
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 use std::{any::Any, time::Duration};
 
 use crate::{
-    EndpointAttributes, SetupPacket, UsbDeviceHandler, UsbEndpoint, UsbInterface,
-    UsbInterfaceHandler,
+    BulkInRing, ClassCode, EndpointAttributes, SetupPacket, TransferFlags, TransferTimeouts,
+    UrbStatus, UsbDeviceHandler, UsbEndpoint, UsbInterface, UsbInterfaceHandler, bulk_in_transfer,
+    class_specific_descriptor,
 };
 
 /// A handler to pass requests to interface of a nusb USB device of the host
 #[derive(Clone)]
 pub struct NusbUsbHostInterfaceHandler {
     handle: nusb::Interface,
+    bulk_in_transfers: usize,
+    timeouts: TransferTimeouts,
+    // shared by the clones, as an endpoint is opened once
+    bulk_in_rings: Arc<Mutex<BulkInRings>>,
 }
 
+/// Rings of the bulk IN endpoints of an interface, by address
+type BulkInRings = HashMap<u8, BulkInRing<nusb::Endpoint<Bulk, In>>>;
+
 impl std::fmt::Debug for NusbUsbHostInterfaceHandler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NusbUsbHostInterfaceHandler")
             .field("handle", &"Opaque")
+            .field("bulk_in_transfers", &self.bulk_in_transfers)
+            .field("timeouts", &self.timeouts)
             .finish()
     }
 }
 
 impl NusbUsbHostInterfaceHandler {
     pub fn new(handle: nusb::Interface) -> Self {
-        Self { handle }
+        Self {
+            handle,
+            bulk_in_transfers: 1,
+            timeouts: Default::default(),
+            bulk_in_rings: Default::default(),
+        }
+    }
+
+    /// Keep `num_transfers` transfers in flight on bulk IN endpoints, see [BulkInRing]
+    ///
+    /// Only worth it for streaming devices, 1 by default. Handlers of exported
+    /// devices are reached with [UsbInterfaceHandler::as_any].
+    pub fn set_bulk_in_transfers(&mut self, num_transfers: usize) {
+        self.bulk_in_transfers = num_transfers;
+        self.bulk_in_rings.lock().unwrap().clear();
     }
 }
 
@@ -39,142 +65,44 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
         &mut self,
         _interface: &UsbInterface,
         ep: UsbEndpoint,
+        transfer_flags: u32,
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
     ) -> Result<Vec<u8>> {
-        let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.clone();
-        // let control = nusb::transfer::ControlIn {
-        //     control_type: match (setup.request_type >> 5) & 0b11 {
-        //         0 => nusb::transfer::ControlType::Standard,
-        //         1 => nusb::transfer::ControlType::Class,
-        //         2 => nusb::transfer::ControlType::Vendor,
-        //         _ => unimplemented!(),
-        //     },
-        //     recipient: match setup.request_type & 0b11111 {
-        //         0 => nusb::transfer::Recipient::Device,
-        //         1 => nusb::transfer::Recipient::Interface,
-        //         2 => nusb::transfer::Recipient::Endpoint,
-        //         3 => nusb::transfer::Recipient::Other,
-        //         _ => unimplemented!(),
-        //     },
-        //     request: setup.request,
-        //     value: setup.value,
-        //     index: setup.index,
-        // };
-        if ep.attributes == EndpointAttributes::Control as u8 {
-            // control
-            if let Direction::In = ep.direction() {
-                // control in
-                let control = nusb::transfer::ControlIn {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    length: setup.length,
-                };
-                if let Ok(buf) = handle.control_in(control, timeout).wait() {
-                    return Ok(buf);
-                }
-            } else {
-                // control out
-                let control = nusb::transfer::ControlOut {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    data: req,
-                };
-                handle.control_out(control, timeout).wait()?;
-            }
-        } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
-            // interrupt
-            // todo!("Missing blocking api for interrupt transfer in nusb")
-            if let Direction::In = ep.direction() {
-                // interrupt in
-                let mut reader = handle
-                    .endpoint::<Interrupt, In>(ep.address)?
-                    .reader(4096)
-                    .with_read_timeout(timeout);
-
-                if let Ok(()) = reader.read_exact(&mut buffer) {
-                    info!("interrupt in {:?}", &buffer);
-                    return Ok(buffer);
-                }
-            } else {
-                // interrupt out
-                let mut writer = handle
-                    .endpoint::<Interrupt, Out>(ep.address)?
-                    .writer(4096)
-                    .with_write_timeout(timeout);
-                writer.write_all(&req)?;
-                writer.flush()?;
-            }
-        } else if ep.attributes == EndpointAttributes::Bulk as u8 {
-            // bulk
-            // todo!("Missing blocking api for bulk transfer in nusb")
-            if let Direction::In = ep.direction() {
-                // bulk in
-                let mut reader = handle
-                    .endpoint::<Bulk, In>(ep.address)?
-                    .reader(4096)
-                    .with_read_timeout(timeout);
-
-                match reader.read_exact(&mut buffer) {
-                    Ok(()) => {
-                        info!("intr in {:02x?}", &buffer);
-                        return Ok(buffer);
-                    }
-                    Err(e) => {
-                        error!("Error when read buffer: {e:?}");
-                        return Err(e);
-                    }
-                }
-                // if let Ok(len) = handle.read_bulk(ep.address, &mut buffer, timeout) {
-                //     return Ok(Vec::from(&buffer[..len]));
-                // }
-            } else {
-                // bulk out
-                let mut writer = handle
-                    .endpoint::<Bulk, Out>(ep.address)?
-                    .writer(4096)
-                    .with_write_timeout(timeout);
-                writer.write_all(&req)?;
-                writer.flush()?;
-                // handle.write_bulk(ep.address, req, timeout).ok();
-            }
+        if self.bulk_in_transfers > 1
+            && ep.attributes == EndpointAttributes::Bulk as u8
+            && ep.direction() == Direction::In
+        {
+            let mut rings = self.bulk_in_rings.lock().unwrap();
+            let ring = match rings.entry(ep.address) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(BulkInRing::new(
+                    self.handle.endpoint::<Bulk, In>(ep.address)?,
+                    self.bulk_in_transfers,
+                )),
+            };
+            return ring.read(transfer_buffer_length as usize, self.timeouts.bulk);
         }
-        Ok(vec![])
+        handle_urb_for_interface(
+            self.handle.clone(),
+            ep,
+            transfer_flags,
+            transfer_buffer_length,
+            setup,
+            req,
+            self.timeouts.for_endpoint(&ep),
+        )
+    }
+
+    fn set_transfer_timeouts(&mut self, timeouts: TransferTimeouts) {
+        self.timeouts = timeouts;
     }
 
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        vec![]
+        self.handle
+            .descriptor()
+            .map_or(vec![], |desc| class_specific_descriptor(&desc))
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -182,34 +110,54 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
     }
 }
 
+/// The control type and recipient of a bmRequestType
+///
+/// The reserved values fail with [ErrorKind::Unsupported], reported as a
+/// stall, rather than being passed to the device.
+fn decode_request_type(
+    request_type: u8,
+) -> Result<(nusb::transfer::ControlType, nusb::transfer::Recipient)> {
+    let control_type = match (request_type >> 5) & 0b11 {
+        0 => Some(nusb::transfer::ControlType::Standard),
+        1 => Some(nusb::transfer::ControlType::Class),
+        2 => Some(nusb::transfer::ControlType::Vendor),
+        _ => None,
+    };
+    let recipient = match request_type & 0b11111 {
+        0 => Some(nusb::transfer::Recipient::Device),
+        1 => Some(nusb::transfer::Recipient::Interface),
+        2 => Some(nusb::transfer::Recipient::Endpoint),
+        3 => Some(nusb::transfer::Recipient::Other),
+        _ => None,
+    };
+    control_type.zip(recipient).ok_or(std::io::Error::new(
+        ErrorKind::Unsupported,
+        format!("Reserved request type {request_type:#010b}"),
+    ))
+}
+
 pub fn handle_urb_for_interface(
     interface: Interface,
     // device: Device,
     ep: UsbEndpoint,
+    transfer_flags: u32,
     transfer_buffer_length: u32,
     setup: SetupPacket,
     req: &[u8],
+    timeout: Duration,
 ) -> Result<Vec<u8>> {
-    let timeout = Duration::new(1, 0);
     // info!(
     //     "Handling interface with endpoint: {ep:?}, interface: {}, transfer length: {transfer_buffer_length}",
     //     interface.interface_number()
     // );
-    if ep.attributes == EndpointAttributes::Control as u8 {
+    if ep.is_ep0() {
         // control
-        let control_type = match (setup.request_type >> 5) & 0b11 {
-            0 => nusb::transfer::ControlType::Standard,
-            1 => nusb::transfer::ControlType::Class,
-            2 => nusb::transfer::ControlType::Vendor,
-            _ => unimplemented!(),
-        };
-        let recipient = match setup.request_type & 0b11111 {
-            0 => nusb::transfer::Recipient::Device,
-            1 => nusb::transfer::Recipient::Interface,
-            2 => nusb::transfer::Recipient::Endpoint,
-            3 => nusb::transfer::Recipient::Other,
-            _ => unimplemented!(),
-        };
+        let (control_type, recipient) = decode_request_type(setup.request_type)?;
+        let mut setup = setup;
+        if let nusb::transfer::Recipient::Interface = recipient {
+            // interfaces are renumbered when some could not be claimed
+            setup.index = (setup.index & 0xFF00) | interface.interface_number() as u16;
+        }
         if let Direction::In = ep.direction() {
             // control in
             let control = nusb::transfer::ControlIn {
@@ -225,9 +173,13 @@ pub fn handle_urb_for_interface(
             //     "Control in command received, setup: {setup:?}, \nreq: {req:02x?},\ncontrol: {control:02x?}"
             // );
 
-            if let Ok(buf) = interface.control_in(control, timeout).wait() {
-                return Ok(buf);
-            }
+            let mut data = interface
+                .control_in(control, timeout)
+                .wait()
+                .map_err(timed_out)?;
+            // never more than the client asked for, whatever the device sent
+            data.truncate(setup.length as usize);
+            return Ok(data);
         } else {
             // control out
             let control = nusb::transfer::ControlOut {
@@ -242,7 +194,10 @@ pub fn handle_urb_for_interface(
             // info!(
             //     "Control out command received, setup: {setup:?}, \nreq: {req:02x?},\ncontrol: {control:02x?}"
             // );
-            interface.control_out(control, timeout).wait()?;
+            interface
+                .control_out(control, timeout)
+                .wait()
+                .map_err(timed_out)?;
         }
     // } else if setup.is_setup() {
     //     if setup.request_type >> 7 == 1 {
@@ -302,12 +257,12 @@ pub fn handle_urb_for_interface(
                 .reader(4096)
                 .with_num_transfers(1)
                 .with_read_timeout(timeout);
-            let mut buffer = vec![0u8; transfer_buffer_length as usize];
+            let mut buffer = crate::buffer_pool().take(transfer_buffer_length as usize);
 
-            if let Ok(()) = reader.read_exact(&mut buffer) {
-                // info!("interrupt in {:?}", &buffer[..len]);
-                return Ok(buffer);
-            }
+            // timing out means nothing to report yet, the server NAKs it
+            reader.read_exact(&mut buffer)?;
+            // info!("interrupt in {:?}", &buffer[..len]);
+            return Ok(buffer);
         } else {
             // interrupt out
             let mut writer = interface
@@ -315,7 +270,7 @@ pub fn handle_urb_for_interface(
                 .writer(4096)
                 .with_num_transfers(1)
                 .with_write_timeout(timeout);
-            writer.write_all(&req)?;
+            writer.write_all(req)?;
             writer.flush()?;
         }
     } else if ep.attributes == EndpointAttributes::Bulk as u8 {
@@ -387,14 +342,7 @@ pub fn handle_urb_for_interface(
             //     .await
             //     .unwrap();
             let mut ep_in = interface.endpoint::<Bulk, In>(ep.address)?;
-            let max_packet_size = ep_in.max_packet_size();
-
-            let requested_len =
-                ((transfer_buffer_length - 1) as usize / max_packet_size + 1) * max_packet_size;
-            let buffer = Buffer::new(requested_len);
-            let c = ep_in.transfer_blocking(buffer, timeout);
-            let buf = c.into_result()?;
-            return Ok(buf.into_vec());
+            return bulk_in_transfer(&mut ep_in, transfer_buffer_length as usize, timeout);
             // let mut reader = ep_in
             //     .reader(4096)
             //     .with_num_transfers(1)
@@ -422,8 +370,12 @@ pub fn handle_urb_for_interface(
                 .with_num_transfers(1)
                 .with_write_timeout(timeout);
             // info!("Writing bulk out buffer {req:02x?}, ep: {ep:02x?}");
-            writer.write_all(&req)?;
-            writer.flush()?;
+            bulk_out_transfer(
+                &mut writer,
+                req,
+                ep.max_packet_size as usize,
+                transfer_flags,
+            )?;
             // handle.write_bulk(ep.address, req, timeout).ok();
         }
     } else {
@@ -432,94 +384,357 @@ pub fn handle_urb_for_interface(
     Ok(vec![])
 }
 
+/// Writes to a bulk OUT endpoint, as used by [bulk_out_transfer]
+pub trait BulkOutSink: Write {
+    /// Flush the data written, terminated by a short or zero-length packet
+    fn flush_end(&mut self) -> Result<()>;
+}
+
+impl BulkOutSink for nusb::io::EndpointWrite<Bulk> {
+    fn flush_end(&mut self) -> Result<()> {
+        nusb::io::EndpointWrite::flush_end(self)
+    }
+}
+
+/// A bulk OUT transfer of `data` to `sink`, terminated by a zero-length packet if [needs_zlp]
+pub fn bulk_out_transfer<W: BulkOutSink>(
+    sink: &mut W,
+    data: &[u8],
+    max_packet_size: usize,
+    transfer_flags: u32,
+) -> Result<()> {
+    sink.write_all(data)?;
+    if needs_zlp(data.len(), max_packet_size, transfer_flags) {
+        sink.flush_end()
+    } else {
+        sink.flush()
+    }
+}
+
+/// Whether a bulk OUT transfer of `len` bytes must be terminated by a zero-length packet
+///
+/// This is the case when the client set `URB_ZERO_PACKET` and the data ends
+/// exactly on a packet boundary, so the device cannot tell the transfer ended.
+pub fn needs_zlp(len: usize, max_packet_size: usize, transfer_flags: u32) -> bool {
+    transfer_flags & TransferFlags::ZERO_PACKET != 0
+        && len != 0
+        && len.is_multiple_of(max_packet_size)
+}
+
+/// The error of a nusb transfer with a timeout, which nusb cancels when it expires
+pub(crate) fn timed_out(err: TransferError) -> std::io::Error {
+    match err {
+        TransferError::Cancelled => std::io::Error::new(ErrorKind::TimedOut, err),
+        err => err.into(),
+    }
+}
+
+/// The error of a request the device does not support, which [urb_status] reports as a stall
+pub(crate) fn stall(msg: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(ErrorKind::Unsupported, msg.into())
+}
+
+/// A device sent more data than the URB holds
+#[derive(Debug)]
+struct Babble {
+    len: usize,
+    requested: usize,
+}
+
+impl std::fmt::Display for Babble {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Received {} bytes for a {} bytes URB",
+            self.len, self.requested
+        )
+    }
+}
+
+impl std::error::Error for Babble {}
+
+/// The error of a transfer receiving `len` bytes for a URB of `requested` bytes
+pub(crate) fn babble(len: usize, requested: usize) -> std::io::Error {
+    std::io::Error::other(Babble { len, requested })
+}
+
+/// The RET_SUBMIT status for an error returned by a URB handler
+///
+/// Errors of nusb transfers are mapped from their [TransferError], others from their [ErrorKind].
+/// Errors nothing else is known of are protocol errors, transfers receiving
+/// more than their URB holds overflow. Unsupported requests stall, like a
+/// device answering a request it does not implement with a STALL handshake.
+pub fn urb_status(err: &std::io::Error) -> i32 {
+    if err.get_ref().is_some_and(|e| e.is::<Babble>()) {
+        return UrbStatus::EOVERFLOW;
+    }
+    if let Some(e) = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<TransferError>())
+    {
+        if err.kind() == ErrorKind::TimedOut {
+            return UrbStatus::ETIMEDOUT;
+        }
+        return match e {
+            // unlinked, or the device was released
+            TransferError::Cancelled => UrbStatus::ECONNRESET,
+            TransferError::Stall => UrbStatus::EPIPE,
+            TransferError::Disconnected => UrbStatus::ESHUTDOWN,
+            TransferError::Fault => UrbStatus::EPROTO,
+            TransferError::InvalidArgument => UrbStatus::EINVAL,
+            TransferError::Unknown(_) => UrbStatus::EPROTO,
+        };
+    }
+    match err.kind() {
+        ErrorKind::TimedOut => UrbStatus::ETIMEDOUT,
+        ErrorKind::Interrupted => UrbStatus::ECONNRESET,
+        ErrorKind::InvalidInput => UrbStatus::EINVAL,
+        ErrorKind::NotFound => UrbStatus::ENOENT,
+        ErrorKind::Unsupported => UrbStatus::EPIPE,
+        _ => UrbStatus::EPROTO,
+    }
+}
+
+/// A filter for [crate::UsbIpServer::new_from_host_with_filter] selecting devices of `class`
+///
+/// Composite devices declare their classes per interface, they match if any interface is of `class`.
+pub fn filter_by_device_class(class: u8) -> impl FnMut(&nusb::DeviceInfo) -> bool {
+    move |d| matches_class(d.class(), d.interfaces().map(|i| i.class()), class, false)
+}
+
+/// A filter for [crate::UsbIpServer::new_from_host_with_filter] selecting devices with an interface of `class`
+pub fn filter_by_interface_class(class: u8) -> impl FnMut(&nusb::DeviceInfo) -> bool {
+    move |d| matches_class(d.class(), d.interfaces().map(|i| i.class()), class, true)
+}
+
+/// idVendor of the Linux Foundation, used by the root hubs of Linux hosts
+const LINUX_FOUNDATION_VENDOR_ID: u16 = 0x1D6B;
+
+/// A filter for [crate::UsbIpServer::new_from_host_with_filter] selecting the devices worth sharing
+///
+/// Hubs, including root hubs, are excluded, as are devices whose `(vendor_id, product_id)`
+/// is in `blocklist`, e.g. the local keyboard and mouse.
+pub fn filter_shareable(blocklist: &[(u16, u16)]) -> impl FnMut(&nusb::DeviceInfo) -> bool + '_ {
+    move |d| {
+        is_shareable(
+            d.vendor_id(),
+            d.product_id(),
+            d.class(),
+            d.interfaces().map(|i| i.class()),
+            blocklist,
+        )
+    }
+}
+
+/// Whether a device is selected by [filter_shareable]
+fn is_shareable(
+    vendor_id: u16,
+    product_id: u16,
+    device_class: u8,
+    interface_classes: impl IntoIterator<Item = u8>,
+    blocklist: &[(u16, u16)],
+) -> bool {
+    vendor_id != LINUX_FOUNDATION_VENDOR_ID
+        && !matches_class(device_class, interface_classes, ClassCode::Hub as u8, true)
+        && !blocklist.contains(&(vendor_id, product_id))
+}
+
+/// Whether a device of `device_class` with interfaces of `interface_classes` is of `class`
+///
+/// Interfaces are only considered for composite devices unless `any_interface` is set.
+fn matches_class(
+    device_class: u8,
+    interface_classes: impl IntoIterator<Item = u8>,
+    class: u8,
+    any_interface: bool,
+) -> bool {
+    let composite =
+        device_class == ClassCode::SeeInterface as u8 || device_class == ClassCode::Misc as u8;
+    // the interface list can be empty, e.g. on Windows for devices bound to a single driver
+    device_class == class
+        || ((any_interface || composite) && interface_classes.into_iter().any(|c| c == class))
+}
+
+/// A device of the host as seen by [crate::UsbIpServer::scan_host]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostDeviceInfo {
+    pub bus_id: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub class: u8,
+    pub interfaces: Vec<HostInterfaceInfo>,
+    /// Whether exporting it is expected to succeed
+    pub claimable: bool,
+    /// Why it is not claimable
+    pub reason: Option<String>,
+}
+
+/// An interface of a [HostDeviceInfo]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostInterfaceInfo {
+    pub interface_number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Kernel driver bound to it, only known on Linux
+    pub driver: Option<String>,
+}
+
+impl HostDeviceInfo {
+    /// Describe `info`, without opening or claiming it except to check permissions on Linux
+    pub(crate) fn new(info: &nusb::DeviceInfo) -> Self {
+        #[cfg(target_os = "linux")]
+        let bus_id = match info.sysfs_path().file_name() {
+            Some(s) => s.to_string_lossy().into_owned(),
+            None => format!("{}-{}-{}", info.busnum(), info.device_address(), 0),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let bus_id = info.bus_id().to_string();
+
+        #[cfg(target_os = "linux")]
+        let drivers = interface_drivers(info.sysfs_path());
+        let interfaces: Vec<HostInterfaceInfo> = info
+            .interfaces()
+            .map(|i| HostInterfaceInfo {
+                interface_number: i.interface_number(),
+                class: i.class(),
+                subclass: i.subclass(),
+                protocol: i.protocol(),
+                #[cfg(target_os = "linux")]
+                driver: drivers.get(&i.interface_number()).cloned(),
+                #[cfg(not(target_os = "linux"))]
+                driver: None,
+            })
+            .collect();
+
+        // usbfs nodes are opened read-write, opening one claims nothing
+        #[cfg(target_os = "linux")]
+        let accessible = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!(
+                "/dev/bus/usb/{:03}/{:03}",
+                info.busnum(),
+                info.device_address()
+            ))
+            .is_ok();
+        #[cfg(not(target_os = "linux"))]
+        let accessible = true;
+
+        let reason = unclaimable_reason(info.class(), &interfaces, accessible);
+        Self {
+            bus_id,
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            manufacturer: info.manufacturer_string().map(String::from),
+            product: info.product_string().map(String::from),
+            serial: info.serial_number().map(String::from),
+            class: info.class(),
+            interfaces,
+            claimable: reason.is_none(),
+            reason,
+        }
+    }
+}
+
+/// Kernel drivers bound to the interfaces of the device at `sysfs_path`, by interface number
+#[cfg(target_os = "linux")]
+pub(crate) fn interface_drivers(
+    sysfs_path: &std::path::Path,
+) -> std::collections::HashMap<u8, String> {
+    let mut drivers = std::collections::HashMap::new();
+    let Ok(entries) = std::fs::read_dir(sysfs_path) else {
+        return drivers;
+    };
+    for entry in entries.flatten() {
+        // interfaces are named <device>:<configuration>.<interface>
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(intf_num) = name
+            .split_once(':')
+            .and_then(|(_, c)| c.split_once('.'))
+            .and_then(|(_, i)| i.parse().ok())
+        else {
+            continue;
+        };
+        if let Ok(driver) = std::fs::read_link(entry.path().join("driver"))
+            && let Some(driver) = driver.file_name()
+        {
+            drivers.insert(intf_num, driver.to_string_lossy().into_owned());
+        }
+    }
+    drivers
+}
+
+/// Why a device can't or shouldn't be exported, `None` if it can
+fn unclaimable_reason(
+    class: u8,
+    interfaces: &[HostInterfaceInfo],
+    accessible: bool,
+) -> Option<String> {
+    if class == ClassCode::Hub as u8 {
+        return Some("Hubs can't be exported".to_string());
+    }
+    // the local user would lose their keyboard
+    if let Some(driver) = interfaces
+        .iter()
+        .filter(|i| i.class == ClassCode::HID as u8 && i.subclass == 1 && i.protocol == 1)
+        .find_map(|i| i.driver.as_ref())
+    {
+        return Some(format!("Keyboard in use by the {driver} driver"));
+    }
+    if !accessible {
+        return Some("No permission to open the device".to_string());
+    }
+    None
+}
+
 /// A handler to pass requests to device of a nusb USB device of the host
 #[derive(Clone)]
 pub struct NusbUsbHostDeviceHandler {
     handle: Arc<Mutex<nusb::Device>>,
+    timeouts: TransferTimeouts,
 }
 
 impl std::fmt::Debug for NusbUsbHostDeviceHandler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("NusbUsbHostDeviceHandler")
             .field("handle", &"Opaque")
+            .field("timeouts", &self.timeouts)
             .finish()
     }
 }
 
 impl NusbUsbHostDeviceHandler {
     pub fn new(handle: Arc<Mutex<nusb::Device>>) -> Self {
-        Self { handle }
+        Self {
+            handle,
+            timeouts: Default::default(),
+        }
     }
 }
 
 impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
     fn handle_urb(
         &mut self,
-        _transfer_buffer_length: u32,
+        transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
     ) -> Result<Vec<u8>> {
-        // info!("To host device: setup={setup:?} req={req:?}");
-        // let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
-        let handle = self.handle.lock().unwrap();
-        // control
-        if cfg!(not(target_os = "windows")) {
-            if setup.request_type & 0x80 == 0 {
-                // control out
-                #[cfg(not(target_os = "windows"))]
-                let control = nusb::transfer::ControlOut {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    data: req,
-                };
-                #[cfg(not(target_os = "windows"))]
-                handle.control_out(control, timeout).wait()?;
-            } else {
-                // control in
-                #[cfg(not(target_os = "windows"))]
-                let control = nusb::transfer::ControlIn {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    length: setup.length,
-                };
-                #[cfg(not(target_os = "windows"))]
-                if let Ok(buf) = handle.control_in(control, timeout).wait() {
-                    return Ok(buf);
-                }
-            }
-        } else {
-            warn!("Not supported in windows")
-        }
-        Ok(vec![])
+        let handle = self.handle.lock().unwrap().clone();
+        handle_urb_for_device(
+            handle,
+            transfer_buffer_length,
+            setup,
+            req,
+            self.timeouts.control,
+        )
+    }
+
+    fn set_transfer_timeouts(&mut self, timeouts: TransferTimeouts) {
+        self.timeouts = timeouts;
     }
 
     #[cfg(target_os = "linux")]
@@ -539,6 +754,24 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         }
     }
 
+    #[cfg(target_os = "linux")]
+    fn prepare(&mut self) -> Result<()> {
+        let dev = self.handle.lock().unwrap();
+        let cfg = dev.active_configuration().map_err(std::io::Error::other)?;
+        for intf in cfg.interfaces() {
+            // fails when no driver is attached, which is what we want
+            let _ = dev.detach_kernel_driver(intf.interface_number());
+        }
+        Ok(())
+    }
+
+    fn active_configuration(&mut self) -> Option<u8> {
+        let dev = self.handle.lock().unwrap();
+        dev.active_configuration()
+            .ok()
+            .map(|cfg| cfg.configuration_value())
+    }
+
     #[cfg(not(target_os = "windows"))]
     fn reset(&mut self) -> Result<()> {
         let mut dev = self.handle.lock().unwrap();
@@ -567,11 +800,22 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         Ok(())
     }
 
+    /// The device is not enumerated again, it keeps its handle
+    #[cfg(target_os = "windows")]
+    fn reset(&mut self) -> Result<()> {
+        reset_pipes(&self.handle.lock().unwrap())
+    }
+
     #[cfg(not(target_os = "windows"))]
     fn set_configuration(&self, setup: &[u8; 8]) -> Result<()> {
         let dev = self.handle.lock().unwrap();
         let sp = SetupPacket::parse(setup);
 
+        #[cfg(target_os = "linux")]
+        if let Err(e) = dev.detach_kernel_driver(0) {
+            error!("Failed to detach kernel driver: {e:?}");
+        }
+
         // let cfg = dev.active_configuration()?;
         // info!("Interface cfg: {cfg:?}");
 
@@ -599,30 +843,65 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
     }
 }
 
+/// WinUSB can't reset the port, reset the pipes of the device instead
+///
+/// Interfaces already claimed, e.g. by their [NusbUsbHostInterfaceHandler],
+/// can't be claimed again and keep their pipes.
+#[cfg(target_os = "windows")]
+fn reset_pipes(dev: &Device) -> Result<()> {
+    use nusb::descriptors::TransferType;
+    let cfg = dev.active_configuration().map_err(std::io::Error::other)?;
+    for intf in cfg.interfaces() {
+        let intf_num = intf.interface_number();
+        let handle = match dev.claim_interface(intf_num).wait() {
+            Ok(handle) => handle,
+            Err(err) => {
+                debug!("Not resetting the pipes of interface {intf_num}: {err}");
+                continue;
+            }
+        };
+        let Some(desc) = handle.descriptor() else {
+            continue;
+        };
+        for ep in desc.endpoints() {
+            let address = ep.address();
+            // WinUsb_ResetPipe
+            let res = match (ep.transfer_type(), ep.direction()) {
+                (TransferType::Bulk, Direction::In) => handle
+                    .endpoint::<Bulk, In>(address)
+                    .and_then(|mut pipe| pipe.clear_halt().wait()),
+                (TransferType::Bulk, Direction::Out) => handle
+                    .endpoint::<Bulk, Out>(address)
+                    .and_then(|mut pipe| pipe.clear_halt().wait()),
+                (TransferType::Interrupt, Direction::In) => handle
+                    .endpoint::<Interrupt, In>(address)
+                    .and_then(|mut pipe| pipe.clear_halt().wait()),
+                (TransferType::Interrupt, Direction::Out) => handle
+                    .endpoint::<Interrupt, Out>(address)
+                    .and_then(|mut pipe| pipe.clear_halt().wait()),
+                _ => Ok(()),
+            };
+            // the other pipes are still reset
+            if let Err(err) = res {
+                warn!("Failed to reset pipe {address:#04x} of interface {intf_num}: {err}");
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn handle_urb_for_device(
     device: Device,
     _transfer_buffer_length: u32,
     setup: SetupPacket,
     req: &[u8],
+    timeout: Duration,
 ) -> Result<Vec<u8>> {
     // info!("To host device: setup={setup:?} req={req:?}");
     // let mut buffer = vec![0u8; transfer_buffer_length as usize];
-    let timeout = std::time::Duration::new(1, 0);
     // control
     if cfg!(not(target_os = "windows")) {
-        let control_type = match (setup.request_type >> 5) & 0b11 {
-            0 => nusb::transfer::ControlType::Standard,
-            1 => nusb::transfer::ControlType::Class,
-            2 => nusb::transfer::ControlType::Vendor,
-            _ => unimplemented!(),
-        };
-        let recipient = match setup.request_type & 0b11111 {
-            0 => nusb::transfer::Recipient::Device,
-            1 => nusb::transfer::Recipient::Interface,
-            2 => nusb::transfer::Recipient::Endpoint,
-            3 => nusb::transfer::Recipient::Other,
-            _ => unimplemented!(),
-        };
+        let (control_type, recipient) = decode_request_type(setup.request_type)?;
         if setup.request_type & 0x80 == 0 {
             // control out
             #[cfg(not(target_os = "windows"))]
@@ -635,7 +914,10 @@ pub fn handle_urb_for_device(
                 data: req,
             };
             #[cfg(not(target_os = "windows"))]
-            device.control_out(control, timeout).wait()?;
+            device
+                .control_out(control, timeout)
+                .wait()
+                .map_err(timed_out)?;
         } else {
             // control in
             #[cfg(not(target_os = "windows"))]
@@ -648,12 +930,211 @@ pub fn handle_urb_for_device(
                 length: setup.length,
             };
             #[cfg(not(target_os = "windows"))]
-            if let Ok(buf) = device.control_in(control, timeout).wait() {
-                return Ok(buf);
-            }
+            let mut data = device
+                .control_in(control, timeout)
+                .wait()
+                .map_err(timed_out)?;
+            // never more than the client asked for, whatever the device sent
+            data.truncate(setup.length as usize);
+            return Ok(data);
         }
     } else {
         warn!("Not supported in windows")
     }
     Ok(vec![])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    /// Records the packets of the transfers written to it
+    #[derive(Default)]
+    struct FakeBulkOut {
+        buffered: Vec<u8>,
+        packets: Vec<Vec<u8>>,
+    }
+
+    impl Write for FakeBulkOut {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.buffered.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            let data = std::mem::take(&mut self.buffered);
+            self.packets.extend(data.chunks(512).map(<[u8]>::to_vec));
+            Ok(())
+        }
+    }
+
+    impl BulkOutSink for FakeBulkOut {
+        fn flush_end(&mut self) -> Result<()> {
+            let len = self.buffered.len();
+            self.flush()?;
+            if len.is_multiple_of(512) {
+                self.packets.push(vec![]);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zlp_submitted_after_full_packet() {
+        setup_test_logger();
+        let mut sink = FakeBulkOut::default();
+        bulk_out_transfer(&mut sink, &[1; 512], 512, TransferFlags::ZERO_PACKET).unwrap();
+        assert_eq!(sink.packets, vec![vec![1; 512], vec![]]);
+
+        // not requested
+        let mut sink = FakeBulkOut::default();
+        bulk_out_transfer(&mut sink, &[1; 512], 512, 0).unwrap();
+        assert_eq!(sink.packets, vec![vec![1; 512]]);
+
+        // already terminated by a short packet
+        let mut sink = FakeBulkOut::default();
+        bulk_out_transfer(&mut sink, &[1; 600], 512, TransferFlags::ZERO_PACKET).unwrap();
+        assert_eq!(sink.packets, vec![vec![1; 512], vec![1; 88]]);
+    }
+
+    #[test]
+    fn zlp_after_full_packet() {
+        setup_test_logger();
+        let flags = TransferFlags::ZERO_PACKET;
+        assert!(needs_zlp(512, 512, flags));
+        assert!(needs_zlp(1024, 512, flags));
+        assert!(!needs_zlp(512, 512, 0));
+        assert!(!needs_zlp(511, 512, flags));
+        assert!(!needs_zlp(0, 512, flags));
+    }
+
+    #[test]
+    fn shareable_devices() {
+        setup_test_logger();
+        let hid = ClassCode::HID as u8;
+        let hub = ClassCode::Hub as u8;
+        // (idVendor, idProduct, bDeviceClass, bInterfaceClass of each interface)
+        let keyboard = (0x046D, 0xC31C, 0x00, vec![hid]);
+        let mouse = (0x046D, 0xC077, 0x00, vec![hid]);
+        let gamepad = (0x045E, 0x028E, 0xFF, vec![0xFF]);
+        let storage = (0x0781, 0x5581, 0x00, vec![ClassCode::MassStorage as u8]);
+        let external_hub = (0x05E3, 0x0610, hub, vec![hub]);
+        let root_hub = (LINUX_FOUNDATION_VENDOR_ID, 0x0002, hub, vec![]);
+        let devices = [keyboard, mouse, gamepad, storage, external_hub, root_hub];
+
+        let shareable = |blocklist: &[(u16, u16)]| {
+            devices
+                .iter()
+                .map(|(v, p, d, i)| is_shareable(*v, *p, *d, i.iter().copied(), blocklist))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shareable(&[]), [true, true, true, true, false, false]);
+        assert_eq!(
+            shareable(&[(0x046D, 0xC31C), (0x046D, 0xC077)]),
+            [false, false, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn claimable_heuristic() {
+        setup_test_logger();
+        let interface = |class, subclass, protocol, driver: Option<&str>| HostInterfaceInfo {
+            interface_number: 0,
+            class,
+            subclass,
+            protocol,
+            driver: driver.map(String::from),
+        };
+        let storage = [interface(
+            ClassCode::MassStorage as u8,
+            6,
+            80,
+            Some("usb-storage"),
+        )];
+        assert_eq!(unclaimable_reason(0, &storage, true), None);
+        assert!(unclaimable_reason(0, &storage, false).is_some());
+        assert!(unclaimable_reason(ClassCode::Hub as u8, &[], true).is_some());
+
+        let keyboard = interface(ClassCode::HID as u8, 1, 1, Some("usbhid"));
+        assert_eq!(
+            unclaimable_reason(0, std::slice::from_ref(&keyboard), true).unwrap(),
+            "Keyboard in use by the usbhid driver"
+        );
+        // not bound to a driver
+        let keyboard = HostInterfaceInfo {
+            driver: None,
+            ..keyboard
+        };
+        assert_eq!(unclaimable_reason(0, &[keyboard], true), None);
+    }
+
+    #[test]
+    fn transfer_error_status() {
+        setup_test_logger();
+        let status = |e: TransferError| urb_status(&e.into());
+        assert_eq!(status(TransferError::Stall), UrbStatus::EPIPE);
+        assert_eq!(status(TransferError::Cancelled), UrbStatus::ECONNRESET);
+        assert_eq!(
+            urb_status(&timed_out(TransferError::Cancelled)),
+            UrbStatus::ETIMEDOUT
+        );
+        assert_eq!(
+            urb_status(&timed_out(TransferError::Stall)),
+            UrbStatus::EPIPE
+        );
+        assert_eq!(status(TransferError::Disconnected), UrbStatus::ESHUTDOWN);
+        assert_eq!(status(TransferError::Fault), UrbStatus::EPROTO);
+        assert_eq!(status(TransferError::InvalidArgument), UrbStatus::EINVAL);
+        assert_eq!(status(TransferError::Unknown(5)), UrbStatus::EPROTO);
+    }
+
+    #[test]
+    fn io_error_status() {
+        setup_test_logger();
+        let status = |kind: ErrorKind| urb_status(&std::io::Error::new(kind, "test"));
+        assert_eq!(status(ErrorKind::TimedOut), UrbStatus::ETIMEDOUT);
+        assert_eq!(status(ErrorKind::Interrupted), UrbStatus::ECONNRESET);
+        assert_eq!(status(ErrorKind::InvalidInput), UrbStatus::EINVAL);
+        assert_eq!(status(ErrorKind::NotFound), UrbStatus::ENOENT);
+        assert_eq!(status(ErrorKind::Unsupported), UrbStatus::EPIPE);
+        assert_eq!(status(ErrorKind::Other), UrbStatus::EPROTO);
+    }
+
+    #[test]
+    fn reserved_request_types_stall() {
+        setup_test_logger();
+        let (control_type, recipient) = decode_request_type(0b10100011).unwrap();
+        assert_eq!(control_type, nusb::transfer::ControlType::Class);
+        assert_eq!(recipient, nusb::transfer::Recipient::Other);
+        // reserved type, then reserved recipients
+        for request_type in [0b11100000, 0b10000100, 0b00011111] {
+            let err = decode_request_type(request_type).unwrap_err();
+            assert_eq!(urb_status(&err), UrbStatus::EPIPE);
+        }
+    }
+
+    #[test]
+    fn filter_by_class() {
+        setup_test_logger();
+        let hid = ClassCode::HID as u8;
+        // (bDeviceClass, bInterfaceClass of each interface)
+        let mouse = (0x00, vec![hid]);
+        let headset = (ClassCode::Misc as u8, vec![0x01, 0x01, hid]);
+        let hub = (ClassCode::Hub as u8, vec![ClassCode::Hub as u8]);
+        let vendor = (0xFF, vec![hid]);
+        let windows_hub = (ClassCode::Hub as u8, vec![]);
+
+        let select = |class, any_interface| {
+            [&mouse, &headset, &hub, &vendor, &windows_hub]
+                .iter()
+                .map(|(d, i)| matches_class(*d, i.iter().copied(), class, any_interface))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(select(hid, false), [true, true, false, false, false]);
+        assert_eq!(select(hid, true), [true, true, false, true, false]);
+        let hub = ClassCode::Hub as u8;
+        assert_eq!(select(hub, false), [false, false, true, false, true]);
+        assert_eq!(select(hub, true), [false, false, true, false, true]);
+    }
+}
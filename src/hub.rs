@@ -0,0 +1,542 @@
+//! Implement a software USB hub
+use super::*;
+
+// reference:
+// USB 2.0 Chapter 11: https://www.usb.org/document-library/usb-20-specification
+
+/// Hub class request codes, see USB 2.0 Table 11-16
+const GET_STATUS: u8 = 0x00;
+const CLEAR_FEATURE: u8 = 0x01;
+const SET_FEATURE: u8 = 0x03;
+const GET_DESCRIPTOR: u8 = 0x06;
+
+/// bDescriptorType of the hub descriptor
+const HUB_DESCRIPTOR_TYPE: u8 = 0x29;
+
+/// Port feature selectors, see USB 2.0 Table 11-17
+const PORT_ENABLE: u16 = 1;
+const PORT_SUSPEND: u16 = 2;
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_RESET: u16 = 20;
+
+/// wPortStatus bits, see USB 2.0 Table 11-21
+const PORT_STAT_CONNECTION: u16 = 0x0001;
+const PORT_STAT_ENABLE: u16 = 0x0002;
+const PORT_STAT_SUSPEND: u16 = 0x0004;
+const PORT_STAT_POWER: u16 = 0x0100;
+const PORT_STAT_LOW_SPEED: u16 = 0x0200;
+
+/// wPortChange bits, see USB 2.0 Table 11-22
+const PORT_STAT_C_CONNECTION: u16 = 0x0001;
+const PORT_STAT_C_SUSPEND: u16 = 0x0004;
+const PORT_STAT_C_RESET: u16 = 0x0010;
+
+/// A downstream port of a [VirtualHub]
+#[derive(Clone, Debug, Default)]
+struct HubPort {
+    /// The connected device
+    device: Option<Arc<UsbDevice>>,
+    /// Speed bits reported in wPortStatus for the connected device
+    speed: u16,
+    status: u16,
+    change: u16,
+}
+
+impl HubPort {
+    fn is_connected(&self) -> bool {
+        self.device.is_some() && self.status & PORT_STAT_POWER != 0
+    }
+}
+
+/// A software USB hub
+///
+/// Each downstream port holds another [UsbDevice], which is not exported on its own:
+/// URBs whose devid is that of the device (`busnum << 16 | devnum`) are routed to it
+/// through the connection importing the hub, once its port is enabled.
+/// The hub answers hub class requests (e.g. `GET_PORT_STATUS`, `SET_PORT_FEATURE`) on ep0
+/// and reports port changes on its status change endpoint, so that the client enumerates the
+/// connected devices. Use [VirtualHub::to_device] to build the [UsbDevice] to export.
+///
+/// The hub runs at full speed, so are the devices connected to it.
+#[derive(Clone, Debug)]
+pub struct VirtualHub {
+    ports: Arc<Mutex<Vec<HubPort>>>,
+}
+
+impl VirtualHub {
+    pub fn new(num_ports: u8) -> Self {
+        Self {
+            ports: Arc::new(Mutex::new(vec![HubPort::default(); num_ports as usize])),
+        }
+    }
+
+    pub fn num_ports(&self) -> u8 {
+        self.ports.lock().unwrap().len() as u8
+    }
+
+    /// Connect `device` to the downstream `port`, counting from 1
+    pub fn connect(&self, port: u8, device: UsbDevice) -> Result<()> {
+        let mut ports = self.ports.lock().unwrap();
+        let p = port_mut(&mut ports, port)?;
+        // faster devices fall back to full speed, like the hub
+        p.speed = if device.speed == UsbSpeed::Low as u32 {
+            PORT_STAT_LOW_SPEED
+        } else {
+            0
+        };
+        p.device = Some(Arc::new(device));
+        if p.status & PORT_STAT_POWER != 0 {
+            p.status |= PORT_STAT_CONNECTION | p.speed;
+            p.change |= PORT_STAT_C_CONNECTION;
+        }
+        Ok(())
+    }
+
+    /// Disconnect the device on the downstream `port`, returning its bus id
+    pub fn disconnect(&self, port: u8) -> Result<Option<String>> {
+        let mut ports = self.ports.lock().unwrap();
+        let p = port_mut(&mut ports, port)?;
+        if p.status & PORT_STAT_CONNECTION != 0 {
+            p.change |= PORT_STAT_C_CONNECTION;
+        }
+        p.status &= PORT_STAT_POWER;
+        Ok(p.device.take().map(|d| d.bus_id.clone()))
+    }
+
+    /// Bus id of the device connected to the downstream `port`, if any
+    pub fn downstream(&self, port: u8) -> Option<String> {
+        let ports = self.ports.lock().unwrap();
+        let port = ports.get((port as usize).checked_sub(1)?)?;
+        port.device.as_ref().map(|d| d.bus_id.clone())
+    }
+
+    /// The device with `devid` on an enabled downstream port, which URBs are routed to
+    pub(crate) fn enabled_device(&self, devid: u32) -> Option<Arc<UsbDevice>> {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .iter()
+            .filter(|p| p.status & PORT_STAT_ENABLE != 0)
+            .filter_map(|p| p.device.as_ref())
+            .find(|d| d.devid() == devid)
+            .cloned()
+    }
+
+    /// The device with `bus_id` connected to a downstream port
+    pub(crate) fn connected_device(&self, bus_id: &str) -> Option<Arc<UsbDevice>> {
+        let ports = self.ports.lock().unwrap();
+        ports
+            .iter()
+            .filter_map(|p| p.device.as_ref())
+            .find(|d| d.bus_id == bus_id)
+            .cloned()
+    }
+
+    /// Resume the suspended port the device with `bus_id` is connected to
+    ///
+    /// Returns whether such a port was found.
+    pub fn remote_wakeup(&self, bus_id: &str) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        match ports.iter_mut().find(|p| {
+            p.device.as_ref().is_some_and(|d| d.bus_id == bus_id)
+                && p.status & PORT_STAT_SUSPEND != 0
+        }) {
+            Some(p) => {
+                p.status &= !PORT_STAT_SUSPEND;
+                p.change |= PORT_STAT_C_SUSPEND;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Build a hub device served by this handler
+    pub fn to_device(&self, index: u32) -> UsbDevice {
+        let status_change_ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: self.bitmap_len() as u16,
+            interval: 255,
+        };
+        let mut device = UsbDevice::new(index)
+            .with_interface(
+                ClassCode::Hub as u8,
+                0x00,
+                0x00,
+                Some("Hub"),
+                vec![status_change_ep],
+                Arc::new(Mutex::new(Box::new(self.clone()))),
+            )
+            .with_device_handler(Arc::new(Mutex::new(Box::new(self.clone()))));
+        device.speed = UsbSpeed::Full as u32;
+        device.device_class = ClassCode::Hub as u8;
+        device.set_product_name("Virtual Hub");
+        device
+    }
+
+    /// Length of the port bitmaps: one bit for the hub and one per port
+    fn bitmap_len(&self) -> usize {
+        self.num_ports() as usize / 8 + 1
+    }
+
+    fn hub_descriptor(&self) -> Vec<u8> {
+        let bitmap_len = self.bitmap_len();
+        let mut desc = vec![
+            (7 + 2 * bitmap_len) as u8, // bDescLength
+            HUB_DESCRIPTOR_TYPE,        // bDescriptorType
+            self.num_ports(),           // bNbrPorts
+            0x09,                       // wHubCharacteristics: per-port power and over-current
+            0x00,
+            50,  // bPwrOn2PwrGood, in 2ms
+            100, // bHubContrCurrent, in mA
+        ];
+        // DeviceRemovable: all removable
+        desc.extend(vec![0x00; bitmap_len]);
+        // PortPwrCtrlMask: all ones for compatibility
+        desc.extend(vec![0xFF; bitmap_len]);
+        desc
+    }
+
+    /// Bitmap of ports with pending changes, bit 0 being the hub itself
+    fn status_change_bitmap(&self) -> Vec<u8> {
+        let mut bitmap = vec![0u8; self.bitmap_len()];
+        for (i, p) in self.ports.lock().unwrap().iter().enumerate() {
+            if p.change != 0 {
+                bitmap[(i + 1) / 8] |= 1 << ((i + 1) % 8);
+            }
+        }
+        bitmap
+    }
+
+    fn set_port_feature(&self, port: u8, feature: u16) -> Result<()> {
+        let mut ports = self.ports.lock().unwrap();
+        let p = port_mut(&mut ports, port)?;
+        match feature {
+            PORT_POWER => {
+                p.status |= PORT_STAT_POWER;
+                if p.is_connected() && p.status & PORT_STAT_CONNECTION == 0 {
+                    p.status |= PORT_STAT_CONNECTION | p.speed;
+                    p.change |= PORT_STAT_C_CONNECTION;
+                }
+            }
+            PORT_RESET => {
+                // reset completes immediately and enables the port
+                if p.is_connected() {
+                    p.status |= PORT_STAT_ENABLE;
+                    p.status &= !PORT_STAT_SUSPEND;
+                    p.change |= PORT_STAT_C_RESET;
+                }
+            }
+            PORT_SUSPEND => p.status |= PORT_STAT_SUSPEND,
+            _ => warn!("Unsupported port feature {feature} for port {port}"),
+        }
+        Ok(())
+    }
+
+    fn clear_port_feature(&self, port: u8, feature: u16) -> Result<()> {
+        let mut ports = self.ports.lock().unwrap();
+        let p = port_mut(&mut ports, port)?;
+        match feature {
+            PORT_ENABLE => p.status &= !PORT_STAT_ENABLE,
+            PORT_SUSPEND => p.status &= !PORT_STAT_SUSPEND,
+            PORT_POWER => p.status = 0,
+            C_PORT_CONNECTION..=C_PORT_RESET => p.change &= !(1 << (feature - C_PORT_CONNECTION)),
+            _ => warn!("Unsupported port feature {feature} for port {port}"),
+        }
+        Ok(())
+    }
+
+    fn port_status(&self, port: u8) -> Result<Vec<u8>> {
+        let mut ports = self.ports.lock().unwrap();
+        let p = port_mut(&mut ports, port)?;
+        let mut status = p.status.to_le_bytes().to_vec();
+        status.extend(p.change.to_le_bytes());
+        Ok(status)
+    }
+}
+
+fn port_mut(ports: &mut [HubPort], port: u8) -> Result<&mut HubPort> {
+    (port as usize)
+        .checked_sub(1)
+        .and_then(|i| ports.get_mut(i))
+        .ok_or(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("No such hub port: {port}"),
+        ))
+}
+
+impl UsbDeviceHandler for VirtualHub {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        debug!("Hub request setup={setup:x?} req={req:x?}");
+        let port = setup.index as u8;
+        let mut resp = match (setup.request_type, setup.request) {
+            // standard GET_STATUS: self powered
+            (0b10000000, GET_STATUS) => vec![0x01, 0x00],
+            // hub requests
+            (0b10100000, GET_DESCRIPTOR) if setup.value >> 8 == HUB_DESCRIPTOR_TYPE as u16 => {
+                self.hub_descriptor()
+            }
+            (0b10100000, GET_STATUS) => vec![0x00; 4],
+            (0b00100000, CLEAR_FEATURE | SET_FEATURE) => vec![],
+            // port requests
+            (0b10100011, GET_STATUS) => self.port_status(port)?,
+            (0b00100011, SET_FEATURE) => {
+                self.set_port_feature(port, setup.value)?;
+                vec![]
+            }
+            (0b00100011, CLEAR_FEATURE) => {
+                self.clear_port_feature(port, setup.value)?;
+                vec![]
+            }
+            _ => {
+                warn!("Unsupported hub request: {setup:x?}");
+                vec![]
+            }
+        };
+        // requested len too short: wLength < real length
+        if setup.length < resp.len() as u16 {
+            resp.truncate(setup.length as usize);
+        }
+        Ok(resp)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbInterfaceHandler for VirtualHub {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_flags: u32,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.attributes == EndpointAttributes::Interrupt as u8 {
+            // status change endpoint: nothing to report if no port changed
+            let bitmap = self.status_change_bitmap();
+            if bitmap.iter().any(|b| *b != 0) {
+                return Ok(bitmap);
+            }
+        }
+        Ok(vec![])
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbip_protocol::UsbIpResponse;
+    use crate::util::tests::*;
+
+    fn port_request(request_type: u8, request: u8, value: u16, port: u16) -> SetupPacket {
+        SetupPacket {
+            request_type,
+            request,
+            value,
+            index: port,
+            length: if request_type & 0x80 != 0 { 4 } else { 0 },
+        }
+    }
+
+    #[tokio::test]
+    async fn downstream_device_enumerates() {
+        setup_test_logger();
+        let hub = VirtualHub::new(4);
+        let mut hub_device = hub.to_device(0);
+        hub_device.bus_id = "1-1".to_string();
+        // a high speed device
+        let mut child = UsbDevice::new(1);
+        child.bus_id = "1-1.2".to_string();
+        let child_devid = child.devid();
+        hub.connect(2, child).unwrap();
+        assert_eq!(hub.downstream(2).as_deref(), Some("1-1.2"));
+
+        let server = UsbIpServer::new_simulated(vec![hub_device]);
+        let mut busid = [0u8; 32];
+        busid[..3].copy_from_slice(b"1-1");
+        let mut imported_device = None;
+        server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+        let device = imported_device.unwrap();
+        assert_eq!(device.device_class, ClassCode::Hub as u8);
+        let hub_devid = device.devid();
+        let control = |devid, direction, setup: SetupPacket| {
+            let header = submit_header(devid, direction, 0);
+            let length = setup.length.into();
+            let (status, data) = submit(&server, &device, header, length, setup, vec![]);
+            assert_eq!(status, 0);
+            data
+        };
+        // the status change bitmap on the interrupt endpoint
+        let status_change = || {
+            let header = submit_header(hub_devid, 1, 1);
+            submit(&server, &device, header, 1, SetupPacket::default(), vec![])
+        };
+
+        // hub descriptor
+        let desc = control(
+            hub_devid,
+            1,
+            SetupPacket {
+                request_type: 0b10100000,
+                request: GET_DESCRIPTOR,
+                value: (HUB_DESCRIPTOR_TYPE as u16) << 8,
+                index: 0,
+                length: 64,
+            },
+        );
+        assert_eq!(desc[1], HUB_DESCRIPTOR_TYPE);
+        assert_eq!(desc[2], 4);
+
+        // nothing changes before ports are powered
+        assert_eq!(status_change(), (0, vec![]));
+        for port in 1..=4 {
+            control(
+                hub_devid,
+                0,
+                port_request(0b00100011, SET_FEATURE, PORT_POWER, port),
+            );
+        }
+
+        // connection change reported on port 2
+        assert_eq!(status_change(), (0, vec![1 << 2]));
+        let status = control(hub_devid, 1, port_request(0b10100011, GET_STATUS, 0, 2));
+        assert_eq!(
+            status,
+            [
+                // at full speed behind the hub
+                (PORT_STAT_POWER | PORT_STAT_CONNECTION).to_le_bytes(),
+                PORT_STAT_C_CONNECTION.to_le_bytes()
+            ]
+            .concat()
+        );
+
+        // acknowledge and reset the port
+        control(
+            hub_devid,
+            0,
+            port_request(0b00100011, CLEAR_FEATURE, C_PORT_CONNECTION, 2),
+        );
+        control(
+            hub_devid,
+            0,
+            port_request(0b00100011, SET_FEATURE, PORT_RESET, 2),
+        );
+        let status = control(hub_devid, 1, port_request(0b10100011, GET_STATUS, 0, 2));
+        let port_status = u16::from_le_bytes([status[0], status[1]]);
+        let port_change = u16::from_le_bytes([status[2], status[3]]);
+        assert_ne!(port_status & PORT_STAT_ENABLE, 0);
+        assert_eq!(port_change, PORT_STAT_C_RESET);
+
+        // other ports stay empty
+        let status = control(hub_devid, 1, port_request(0b10100011, GET_STATUS, 0, 1));
+        assert_eq!(status, [PORT_STAT_POWER.to_le_bytes(), [0, 0]].concat());
+
+        // the enabled downstream device is reached through the hub
+        let desc = control(
+            child_devid,
+            1,
+            SetupPacket {
+                request_type: 0b10000000,
+                request: GET_DESCRIPTOR,
+                value: 0x0100,
+                index: 0,
+                length: 18,
+            },
+        );
+        assert_eq!(desc[1], 0x01);
+        assert_ne!(desc[4], ClassCode::Hub as u8);
+
+        // and neither listed nor importable on its own, while the hub is imported
+        match server.handle_op_req_devlist().await.unwrap() {
+            UsbIpResponse::OpRepDevlist { device_count, .. } => assert_eq!(device_count, 0),
+            resp => panic!("Expected OP_REP_DEVLIST, got {resp:?}"),
+        }
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(b"1-1.2");
+        let mut imported_child = None;
+        let _ = server
+            .handle_op_req_import(busid, &mut imported_child)
+            .await;
+        assert!(imported_child.is_none());
+    }
+
+    #[tokio::test]
+    async fn remote_wakeup_resumes_port() {
+        setup_test_logger();
+        let hub = VirtualHub::new(1);
+        let mut hub_device = hub.to_device(0);
+        hub_device.bus_id = "1-1".to_string();
+        let mut child = UsbDevice::new(1).with_remote_wakeup();
+        child.bus_id = "1-1.1".to_string();
+        let child_devid = child.devid();
+        hub.connect(1, child).unwrap();
+        let server = UsbIpServer::new_simulated(vec![hub_device]);
+
+        let mut busid = [0u8; 32];
+        busid[..3].copy_from_slice(b"1-1");
+        let mut imported_device = None;
+        server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+        let hub_device = &imported_device.unwrap();
+        let hub_devid = hub_device.devid();
+        let control = |devid, direction, setup: SetupPacket| {
+            let header = submit_header(devid, direction, 0);
+            let length = setup.length.into();
+            let (status, data) = submit(&server, hub_device, header, length, setup, vec![]);
+            assert_eq!(status, 0);
+            data
+        };
+
+        for feature in [PORT_POWER, PORT_RESET, PORT_SUSPEND] {
+            control(
+                hub_devid,
+                0,
+                port_request(0b00100011, SET_FEATURE, feature, 1),
+            );
+        }
+        let err = server.request_remote_wakeup("1-1.1").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+
+        // the host enables remote wakeup before suspending
+        control(
+            child_devid,
+            0,
+            SetupPacket {
+                request_type: 0b00000000,
+                request: SET_FEATURE,
+                value: DEVICE_REMOTE_WAKEUP,
+                index: 0,
+                length: 0,
+            },
+        );
+        server.request_remote_wakeup("1-1.1").await.unwrap();
+        let status = control(hub_devid, 1, port_request(0b10100011, GET_STATUS, 0, 1));
+        let port_status = u16::from_le_bytes([status[0], status[1]]);
+        let port_change = u16::from_le_bytes([status[2], status[3]]);
+        assert_eq!(port_status & PORT_STAT_SUSPEND, 0);
+        assert_ne!(port_change & PORT_STAT_C_SUSPEND, 0);
+    }
+}
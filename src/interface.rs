@@ -1,6 +1,5 @@
-use nusb::Interface;
-
 use super::*;
+use std::sync::atomic::AtomicU8;
 
 /// Represent a USB interface
 #[derive(Clone, Debug)]
@@ -11,10 +10,37 @@ pub struct UsbInterface {
     pub interface_protocol: u8,
     pub endpoints: Vec<UsbEndpoint>,
     pub string_interface: u8,
-    pub class_specific_descriptor: Vec<u8>,
+    /// Inserted after the interface descriptor, shared by the clones of the interface
+    ///
+    /// Updated at runtime with [Self::set_class_specific_descriptor].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub class_specific_descriptor: Arc<Mutex<Vec<u8>>>,
+    /// bAlternateSetting selected by SET_INTERFACE, shared by the clones of the interface
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub alt_setting: Arc<AtomicU8>,
 
     #[cfg_attr(feature = "serde", serde(skip))]
-    pub handler: Interface,
+    pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+}
+
+impl UsbInterface {
+    /// Replace the class specific descriptor, served from the next GET_DESCRIPTOR(Configuration)
+    pub fn set_class_specific_descriptor(&self, descriptor: Vec<u8>) {
+        *self.class_specific_descriptor.lock().unwrap() = descriptor;
+    }
+
+    /// Get the class specific descriptor from the handler again
+    ///
+    /// For handlers generating it lazily, e.g. once the state it describes changed.
+    pub fn refresh_class_specific_descriptor(&self) {
+        let descriptor = self.handler.lock().unwrap().get_class_specific_descriptor();
+        self.set_class_specific_descriptor(descriptor);
+    }
+
+    /// The alternate setting selected by the last SET_INTERFACE, 0 by default
+    pub fn current_alt_setting(&self) -> u8 {
+        self.alt_setting.load(Ordering::Relaxed)
+    }
 }
 
 /// A handler of a custom usb interface
@@ -26,10 +52,13 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
     ///
     /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint.
     /// The resulting data should not exceed `transfer_buffer_length`.
+    /// `transfer_flags` are the `URB_*` flags sent by the client, see [TransferFlags].
+    /// `setup` is all zeros for transfers to endpoints other than ep0.
     fn handle_urb(
         &mut self,
         interface: &UsbInterface,
         ep: UsbEndpoint,
+        transfer_flags: u32,
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
@@ -44,4 +73,9 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
     /// }
     /// ```
     fn as_any(&mut self) -> &mut dyn Any;
+
+    /// Use `timeouts` for the transfers of the next URBs, see [UsbDevice::set_transfer_timeouts]
+    ///
+    /// Only meaningful for host devices, does nothing by default.
+    fn set_transfer_timeouts(&mut self, _timeouts: TransferTimeouts) {}
 }
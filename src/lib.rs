@@ -1,46 +1,426 @@
 //! A library for running a USB/IP server
 
+use futures_core::Stream;
 use log::*;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use nusb::transfer::Direction;
 use nusb::{DeviceInfo, Speed};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::any::Any;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 use usbip_protocol::UsbIpCommand;
 
+#[cfg(feature = "admin")]
+mod admin;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod bos;
+mod builder;
 pub mod cdc;
+pub mod cdc_ecm;
 mod consts;
+mod deferred;
+#[cfg(feature = "serde")]
+mod definition;
 mod device;
 mod endpoint;
+mod events;
 pub mod hid;
 mod host;
+pub mod hub;
 mod interface;
+mod pool;
+mod queue;
+mod ring;
 mod setup;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "client"))]
+pub mod usbip_client;
 pub mod usbip_protocol;
 mod util;
+mod validate;
+pub mod vendor;
+#[cfg(feature = "admin")]
+pub use admin::*;
+#[cfg(feature = "blocking")]
+pub use blocking::*;
+pub use builder::*;
 pub use consts::*;
 pub use device::*;
 pub use endpoint::*;
+pub use events::*;
 pub use host::*;
 pub use interface::*;
+pub use pool::*;
+pub use queue::*;
+pub use ring::*;
 pub use setup::*;
 pub use util::*;
+pub use validate::*;
 
-use crate::usbip_protocol::{USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpHeaderBasic, UsbIpResponse};
+use crate::bos::{MS_OS_10_STRING_INDEX, PlatformCapabilities};
+use crate::deferred::DeferredReplies;
+
+use crate::usbip_protocol::{
+    COMPRESSION_LZ4, OP_REQ_COMPRESSION, OP_REQ_DEVLIST, OP_REQ_IMPORT, USBIP_RET_SUBMIT,
+    USBIP_RET_UNLINK, UnsupportedVersion, UsbIpHeaderBasic, UsbIpResponse,
+};
+
+/// URBs handled at a time on the pool set up by [UsbIpServer::with_transfer_retries]
+pub const DEFAULT_TRANSFER_THREADS: usize = 64;
 
 /// Main struct of a USB/IP server
 #[derive(Default, Debug)]
 pub struct UsbIpServer {
+    // when taking both, lock `used_devices` first to avoid deadlocks
     available_devices: RwLock<Vec<UsbDevice>>,
-    used_devices: RwLock<Vec<UsbDevice>>,
+    used_devices: RwLock<Vec<Arc<UsbDevice>>>,
+    // port of each imported device, updated under the `used_devices` lock
+    ports: Mutex<BTreeMap<u32, String>>,
+    next_port: AtomicU32,
+    tcp_keepalive: Option<Duration>,
+    // Nagle's algorithm left enabled on accepted connections
+    tcp_delay: bool,
+    listen_backlog: Option<i32>,
+    reuse_port: bool,
+    inactivity_timeout: Option<Duration>,
+    devlist_include_used: bool,
+    devlist_filter: Option<DevlistFilter>,
+    // interfaces clients may transfer to, by bus id
+    allowed_interfaces: HashMap<String, BTreeSet<u8>>,
+    // bus ids of the devices clients may not write to
+    read_only: BTreeSet<String>,
+    // MODE SENSE last sent to each read-only device, by bus id, its data gets the WP bit
+    mode_sense: Mutex<HashMap<String, u8>>,
+    interrupt_in_timeout_fails: bool,
+    prepare_on_import: bool,
+    // SET_CONFIGURATION on import, of this value or else the one the device describes
+    configure_on_import: Option<Option<u8>>,
+    release_cooldown: Option<Duration>,
+    // devices opened on import replace these until released, by bus id
+    unopened: Mutex<HashMap<String, UsbDevice>>,
+    // bus ids of the devices `occupy` took out of both lists to open them
+    opening: Mutex<BTreeSet<String>>,
+    // when each device was last released, for `release_cooldown`
+    released_at: Mutex<HashMap<String, std::time::Instant>>,
+    strict_validation: bool,
+    auto_release: bool,
+    compression: bool,
+    unknown_endpoint: UnknownEndpoint,
+    unimported_submit: UnimportedSubmit,
+    max_unimported_submits: Option<u32>,
+    // largest transfer_buffer_length by transfer type, see `with_max_transfer_size`
+    max_transfer_sizes: HashMap<u8, u32>,
+    transfer_timeouts: TransferTimeouts,
+    // timeout of all the transfers of a device, by bus id
+    timeout_overrides: Mutex<HashMap<String, Duration>>,
+    transfer_retries: u32,
+    // URBs handled on the blocking thread pool at a time, see `with_transfer_pool`
+    transfer_pool: Option<Arc<tokio::sync::Semaphore>>,
+    retry_backoff: Duration,
+    // URBs held for each paused device, by bus id
+    paused: Mutex<HashMap<String, Vec<PausedUrb>>>,
+    paused_out: PausedOut,
+    // bumped by `simulate_disconnect`, to wake the connections of detached devices
+    unplugged: tokio::sync::watch::Sender<()>,
+    // the last connections closed, oldest first
+    sessions: Mutex<VecDeque<SessionRecord>>,
+    // device imported by each open connection
+    active_imports: Mutex<BTreeMap<u64, ActiveImport>>,
+    // new imports are refused, see `enter_drain_mode`
+    draining: AtomicBool,
+    counters: Counters,
+    events: EventSender,
+}
+
+/// A device of the host, see [UsbIpServer::add_host_device_by_path]
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HostLocation {
+    /// Bus and ports, the name of the device in sysfs
+    Port(String),
+    /// Bus and device numbers
+    BusDev(u8, u8),
+}
+
+#[cfg(target_os = "linux")]
+impl HostLocation {
+    fn from_path(path: &std::path::Path) -> Self {
+        let numbers: Vec<u8> = path
+            .strip_prefix("/dev/bus/usb")
+            .into_iter()
+            .flat_map(|p| p.iter())
+            .filter_map(|n| n.to_str()?.parse().ok())
+            .collect();
+        match numbers[..] {
+            [bus, dev] => Self::BusDev(bus, dev),
+            _ => {
+                let port = path.file_name().unwrap_or(path.as_os_str());
+                Self::Port(port.to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// The device of `devices` here, `describe` gives its port, bus and device numbers
+    fn find<T>(
+        &self,
+        devices: impl IntoIterator<Item = T>,
+        describe: impl Fn(&T) -> (String, u8, u8),
+    ) -> Result<T> {
+        devices
+            .into_iter()
+            .find(|dev| {
+                let (port, bus, addr) = describe(dev);
+                match self {
+                    Self::Port(p) => *p == port,
+                    Self::BusDev(b, d) => (*b, *d) == (bus, addr),
+                }
+            })
+            .ok_or(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No host device at {self:?}"),
+            ))
+    }
+}
+
+/// bInterfaceProtocol of mass storage interfaces using the bulk-only transport
+const BULK_ONLY_TRANSPORT: u8 = 0x50;
+
+/// Whether `intf` is a mass storage interface using the bulk-only transport
+fn is_bulk_only_storage(intf: &UsbInterface) -> bool {
+    intf.interface_class == ClassCode::MassStorage as u8
+        && intf.interface_protocol == BULK_ONLY_TRANSPORT
+}
+
+/// Operation codes of the SCSI commands a read-only device accepts, see SPC-4 and SBC-3
+///
+/// None of them writes to the medium or carries data to the device.
+const SCSI_READ_ONLY_COMMANDS: [u8; 16] = [
+    0x00, // TEST UNIT READY
+    0x03, // REQUEST SENSE
+    0x08, // READ(6)
+    0x12, // INQUIRY
+    0x1A, // MODE SENSE(6)
+    0x1B, // START STOP UNIT
+    0x1E, // PREVENT ALLOW MEDIUM REMOVAL
+    0x23, // READ FORMAT CAPACITIES
+    0x25, // READ CAPACITY(10)
+    0x28, // READ(10)
+    0x35, // SYNCHRONIZE CACHE(10)
+    0x5A, // MODE SENSE(10)
+    0x88, // READ(16)
+    0x9E, // SERVICE ACTION IN(16), e.g. READ CAPACITY(16)
+    0xA0, // REPORT LUNS
+    0xA8, // READ(12)
+];
+const MODE_SENSE_6: u8 = 0x1A;
+const MODE_SENSE_10: u8 = 0x5A;
+
+/// The SCSI operation code of `data`, if it is a command block wrapper
+fn cbw_opcode(data: &[u8]) -> Option<u8> {
+    const CBW_SIGNATURE: &[u8] = b"USBC";
+    (data.len() == 31 && data.starts_with(CBW_SIGNATURE)).then(|| data[15])
+}
+
+/// Decides which devices a client sees, see [UsbIpServer::with_devlist_filter]
+#[derive(Clone)]
+struct DevlistFilter(Arc<DevlistFilterFn>);
+
+type DevlistFilterFn = dyn Fn(&SocketAddr, &UsbDevice) -> bool + Send + Sync;
+
+impl std::fmt::Debug for DevlistFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Opaque")
+    }
+}
+
+/// How URBs to endpoints the device doesn't have complete, see [UsbIpServer::with_unknown_endpoint]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownEndpoint {
+    /// `-EPIPE`, like a stall from the device
+    #[default]
+    Stall,
+    /// `-ENOENT`, like the client's USB core rejecting the URB
+    Fail,
+}
+
+/// How URBs submitted before importing a device complete, see [UsbIpServer::with_unimported_submit]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnimportedSubmit {
+    /// `-ENODEV`, the connection stays open
+    #[default]
+    Fail,
+    /// Not at all, the connection is closed
+    Close,
+}
+
+/// How OUT URBs to a paused device complete, see [UsbIpServer::pause]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PausedOut {
+    /// `-ETIMEDOUT`, the client may submit them again
+    #[default]
+    Reject,
+    /// Successfully, their data is sent to the device by [UsbIpServer::resume]
+    ///
+    /// The data is acknowledged before it reaches the device: it is lost if the
+    /// device is released while paused. Control OUT URBs are rejected still.
+    Buffer,
+}
+
+/// An OUT URB held while its device is paused
+#[derive(Debug)]
+struct PausedUrb {
+    ep: u8,
+    transfer_flags: u32,
+    setup: [u8; 8],
+    data: Vec<u8>,
+}
+
+#[derive(Default, Debug)]
+struct Counters {
+    connections: AtomicU64,
+    urbs_completed: AtomicU64,
+    urbs_failed: AtomicU64,
+}
+
+/// Counters of a running [UsbIpServer], see [UsbIpServer::stats]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Client connections accepted
+    pub connections: u64,
+    /// URBs completed successfully
+    pub urbs_completed: u64,
+    /// URBs completed with an error status
+    pub urbs_failed: u64,
+}
+
+/// Counters of the URBs to one endpoint, see [UsbDevice::endpoint_stats]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    /// URBs submitted
+    pub submits: u64,
+    /// URBs completed with an error status
+    pub failures: u64,
+    /// Bytes transferred by URBs completed successfully
+    pub bytes: u64,
+    /// RET_SUBMIT status and description of the last failure
+    pub last_error: Option<(i32, String)>,
+}
+
+/// What a client connection imported, see [UsbIpServer::recent_sessions]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionRecord {
+    /// Number of the connection, as in [ServerEvent::ClientConnected]
+    pub connection: u64,
+    /// Address of the client, if served with [serve_connection_from] or [server]
+    pub peer: Option<SocketAddr>,
+    pub connected_at: SystemTime,
+    pub disconnected_at: SystemTime,
+    /// Devices imported, in order
+    pub imports: Vec<ImportRecord>,
+}
+
+/// A device imported during a session, see [SessionRecord]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportRecord {
+    pub bus_id: String,
+    pub imported_at: SystemTime,
+    /// When the client released it, or the session ended
+    pub released_at: SystemTime,
+}
+
+/// A device imported by an open connection, see [UsbIpServer::active_imports]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActiveImport {
+    /// Number of the connection, as in [ServerEvent::ClientConnected]
+    pub connection: u64,
+    /// Address of the client, if served with [serve_connection_from] or [server]
+    pub peer: Option<SocketAddr>,
+    pub bus_id: String,
+    pub imported_at: SystemTime,
+}
+
+/// Number of closed connections remembered, see [UsbIpServer::recent_sessions]
+const SESSION_HISTORY: usize = 256;
+
+/// Builds the [SessionRecord] of a connection as its commands are served
+struct SessionTracker {
+    record: SessionRecord,
+    // bus id of the device imported, and since when
+    current: Option<(String, SystemTime)>,
+}
+
+impl SessionTracker {
+    fn new(connection: u64, peer: Option<SocketAddr>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            record: SessionRecord {
+                connection,
+                peer,
+                connected_at: now,
+                disconnected_at: now,
+                imports: vec![],
+            },
+            current: None,
+        }
+    }
+
+    /// Note the device imported now, whether it changed since the last call
+    fn update(&mut self, imported_device: Option<&Arc<UsbDevice>>) -> bool {
+        let bus_id = imported_device.map(|dev| dev.bus_id.as_str());
+        if self.current.as_ref().map(|(b, _)| b.as_str()) == bus_id {
+            return false;
+        }
+        let now = SystemTime::now();
+        if let Some((bus_id, imported_at)) = self.current.take() {
+            self.record.imports.push(ImportRecord {
+                bus_id,
+                imported_at,
+                released_at: now,
+            });
+        }
+        self.current = bus_id.map(|bus_id| (bus_id.to_string(), now));
+        true
+    }
+
+    /// The device imported since the last [Self::update]
+    fn active_import(&self) -> Option<ActiveImport> {
+        let (bus_id, imported_at) = self.current.clone()?;
+        Some(ActiveImport {
+            connection: self.record.connection,
+            peer: self.record.peer,
+            bus_id,
+            imported_at,
+        })
+    }
+
+    /// The record of the session, the device still imported is released with it
+    fn finish(mut self) -> SessionRecord {
+        self.update(None);
+        self.record.disconnected_at = SystemTime::now();
+        self.record
+    }
+}
+
+impl ServerStats {
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"connections":{},"urbs_completed":{},"urbs_failed":{}}}"#,
+            self.connections, self.urbs_completed, self.urbs_failed
+        )
+    }
 }
 
 impl UsbIpServer {
@@ -48,163 +428,357 @@ impl UsbIpServer {
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
         Self {
             available_devices: RwLock::new(devices),
-            used_devices: RwLock::new(Vec::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Enable TCP keepalive on accepted connections, probing after `idle` without traffic.
+    ///
+    /// This lets the OS detect clients whose network died without a clean FIN.
+    pub fn with_tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    /// Set TCP_NODELAY on accepted connections, enabled by default
+    ///
+    /// Replies are sent as soon as they are written instead of waiting for
+    /// the ack of the previous segment, which saves up to the delayed ack
+    /// timeout (40ms on Linux) per URB of interrupt-heavy devices like HID.
+    /// Disable it to coalesce the small segments of bulk-heavy devices.
+    pub fn with_tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_delay = !enabled;
+        self
+    }
+
+    /// Queue up to `backlog` connections not accepted yet, 1024 by default
+    pub fn with_listen_backlog(mut self, backlog: i32) -> Self {
+        self.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// Set SO_REUSEPORT on the listening socket, so that several processes can serve the same port
+    #[cfg(unix)]
+    pub fn with_reuse_port(mut self) -> Self {
+        self.reuse_port = true;
+        self
+    }
+
+    /// Also list devices imported by other clients in OP_REP_DEVLIST
+    ///
+    /// Used devices come after available ones. Importing a used device still fails.
+    pub fn with_used_devices_in_devlist(mut self) -> Self {
+        self.devlist_include_used = true;
+        self
+    }
+
+    /// Only list the devices for which `filter` returns true to the client at an address
+    ///
+    /// Devices hidden from a client can't be imported by it either. Clients
+    /// served over [serve_connection] have no address and see all devices,
+    /// use [serve_connection_from] to give one.
+    pub fn with_devlist_filter(
+        mut self,
+        filter: impl Fn(&SocketAddr, &UsbDevice) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.devlist_filter = Some(DevlistFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Only let clients transfer to the `interfaces` of device `bus_id`
+    ///
+    /// URBs to other interfaces stall, as do control requests to them. Requests
+    /// to the device itself, e.g. for its descriptors, are always allowed.
+    pub fn with_allowed_interfaces(mut self, bus_id: &str, interfaces: &[u8]) -> Self {
+        self.allowed_interfaces
+            .insert(bus_id.to_string(), interfaces.iter().copied().collect());
+        self
+    }
+
+    /// Don't let clients write to device `bus_id`
+    ///
+    /// On mass storage interfaces using the bulk-only transport, SCSI commands
+    /// other than reads and queries stall, and MODE SENSE reports the medium
+    /// as write-protected, so clients mount it read-only. Other bulk OUT URBs
+    /// stall: UAS interfaces (protocol 0x62) send their commands that way, so
+    /// even their reads fail.
+    pub fn with_read_only(mut self, bus_id: &str) -> Self {
+        self.read_only.insert(bus_id.to_string());
+        self
+    }
+
+    /// Close the connection and release the imported device if no command
+    /// is received from the client within `timeout`.
+    pub fn with_inactivity_timeout(mut self, timeout: Duration) -> Self {
+        self.inactivity_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail interrupt IN URBs that time out with `-ETIMEDOUT`
+    ///
+    /// By default they complete successfully without data, like a NAK from
+    /// real hardware, and the client polls again.
+    pub fn with_interrupt_in_timeout_failures(mut self) -> Self {
+        self.interrupt_in_timeout_fails = true;
+        self
+    }
+
+    /// [Self::prepare] devices when a client imports them
+    ///
+    /// Imports fail if the device can't be prepared.
+    pub fn with_prepare_on_import(mut self) -> Self {
+        self.prepare_on_import = true;
+        self
+    }
+
+    /// Send SET_CONFIGURATION to devices when a client imports them
+    ///
+    /// For host devices enumerated unconfigured, as the kernel of the client
+    /// keeps the configuration it selected from them. `configuration_value`
+    /// is set, or the configuration the device describes if `None`. Failures
+    /// are logged, the client may still configure the device itself.
+    pub fn with_configure_on_import(mut self, configuration_value: Option<u8>) -> Self {
+        self.configure_on_import = Some(configuration_value);
+        self
+    }
+
+    /// Throttle clients importing a device again and again
+    ///
+    /// An import within `cooldown` of the release of the device waits for the
+    /// rest of it, and the device is not prepared again, see
+    /// [Self::with_prepare_on_import]: it keeps the claim of the previous import.
+    pub fn with_release_cooldown(mut self, cooldown: Duration) -> Self {
+        self.release_cooldown = Some(cooldown);
+        self
+    }
+
+    /// Release the imported device when the client relinquishes all its interfaces
+    ///
+    /// The client does so by unconfiguring the device with SET_CONFIGURATION(0).
+    /// The device becomes available again while the connection stays open, and
+    /// the client can import a device again on it.
+    pub fn with_auto_release(mut self) -> Self {
+        self.auto_release = true;
+        self
+    }
+
+    /// Compress RET_SUBMIT data for clients negotiating it with [usbip_protocol::OP_REQ_COMPRESSION]
+    ///
+    /// Other clients get the usual uncompressed data.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Complete URBs to endpoints the device doesn't have as `behavior`
+    ///
+    /// Some clients probe endpoints speculatively and cope better with a stall, the default.
+    pub fn with_unknown_endpoint(mut self, behavior: UnknownEndpoint) -> Self {
+        self.unknown_endpoint = behavior;
+        self
+    }
+
+    /// Complete URBs submitted before importing a device as `behavior` says, they fail by default
+    pub fn with_unimported_submit(mut self, behavior: UnimportedSubmit) -> Self {
+        self.unimported_submit = behavior;
+        self
+    }
+
+    /// Close connections after `max` URBs submitted without importing a device
+    ///
+    /// Each of them fails as set by [Self::with_unimported_submit], the count
+    /// starts over once a device is imported.
+    pub fn with_max_unimported_submits(mut self, max: u32) -> Self {
+        self.max_unimported_submits = Some(max);
+        self
+    }
+
+    /// Fail URBs of `transfer_type` longer than `max_size` bytes, without handling them
+    ///
+    /// Interrupt and isochronous URBs fail with `-EMSGSIZE`, like a transfer
+    /// not fitting the bandwidth reserved for the endpoint, bulk and control
+    /// ones with `-EINVAL`.
+    pub fn with_max_transfer_size(
+        mut self,
+        transfer_type: EndpointAttributes,
+        max_size: u32,
+    ) -> Self {
+        self.max_transfer_sizes
+            .insert(transfer_type as u8, max_size);
+        self
+    }
+
+    /// Cancel transfers of `transfer_type` to host devices after `timeout`, 1s by default
+    ///
+    /// Control transfers are mostly descriptor reads during enumeration and
+    /// may fail fast, while bulk transfers to slow devices need longer. See
+    /// [Self::override_transfer_timeout] for a single slow operation.
+    pub fn with_transfer_timeout(
+        mut self,
+        transfer_type: EndpointAttributes,
+        timeout: Duration,
+    ) -> Self {
+        self.transfer_timeouts.set(transfer_type, timeout);
+        self
+    }
+
+    /// Retry URBs failing with a transient error up to `retries` times, waiting `backoff` before each retry
+    ///
+    /// Stalls, disconnections and invalid requests are not retried, neither are
+    /// timeouts of interrupt IN URBs, which complete as a NAK. Retries of OUT
+    /// URBs may send data the device already received before the error.
+    ///
+    /// The backoff blocks the thread handling the URB, so URBs are retried on
+    /// the thread pool of [Self::with_transfer_pool], never on a worker of the
+    /// runtime. Unless one is configured, a pool of [DEFAULT_TRANSFER_THREADS]
+    /// threads is.
+    pub fn with_transfer_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.transfer_retries = retries;
+        self.retry_backoff = backoff;
+        if retries > 0 && self.transfer_pool.is_none() {
+            self = self.with_transfer_pool(DEFAULT_TRANSFER_THREADS);
         }
+        self
+    }
+
+    /// Handle URBs on the blocking thread pool of tokio, up to `threads` at a time
+    ///
+    /// It replaces the pool set up by [Self::with_transfer_retries].
+    ///
+    /// Handlers block until the transfer completes, on a worker thread of the
+    /// runtime by default. Many slow transfers then leave no worker to accept
+    /// connections or read commands. With this, URBs waiting for a thread of
+    /// the pool don't hold a worker; keep `threads` below the
+    /// `max_blocking_threads` of the runtime, 512 by default.
+    pub fn with_transfer_pool(mut self, threads: usize) -> Self {
+        self.transfer_pool = Some(Arc::new(tokio::sync::Semaphore::new(threads.max(1))));
+        self
+    }
+
+    /// Complete OUT URBs to paused devices as `policy`, see [Self::pause]
+    pub fn with_paused_out(mut self, policy: PausedOut) -> Self {
+        self.paused_out = policy;
+        self
+    }
+
+    /// Reject devices failing [UsbDevice::validate] in [Self::add_device]
+    pub fn with_strict_validation(mut self) -> Self {
+        self.strict_validation = true;
+        self
     }
 
     /// Create a [UsbIpServer] with Vec<[nusb::DeviceInfo]> for sharing host devices
     pub async fn with_nusb_devices(nusb_device_infos: Vec<nusb::DeviceInfo>) -> Vec<UsbDevice> {
+        Self::with_nusb_devices_and_claim_strategy(nusb_device_infos, ClaimStrategy::default())
+            .await
+    }
+
+    /// Like [Self::with_nusb_devices], claiming interfaces with `strategy`
+    pub async fn with_nusb_devices_and_claim_strategy(
+        nusb_device_infos: Vec<nusb::DeviceInfo>,
+        strategy: ClaimStrategy,
+    ) -> Vec<UsbDevice> {
+        Self::with_nusb_devices_and_claim_strategy_with_errors(nusb_device_infos, strategy)
+            .await
+            .0
+    }
+
+    /// Like [Self::with_nusb_devices_and_claim_strategy], also returning the devices that could not be exported
+    ///
+    /// Each comes with the error opening it, or claiming its interfaces.
+    pub async fn with_nusb_devices_and_claim_strategy_with_errors(
+        nusb_device_infos: Vec<nusb::DeviceInfo>,
+        strategy: ClaimStrategy,
+    ) -> (Vec<UsbDevice>, Vec<(nusb::DeviceInfo, std::io::Error)>) {
         let mut devices = vec![];
+        let mut errors = vec![];
         for device_info in nusb_device_infos {
             let dev = match device_info.open().await {
                 Ok(dev) => dev,
                 Err(err) => {
                     warn!("Impossible to open device {device_info:?}: {err}, ignoring device",);
+                    errors.push((device_info, err.into()));
                     continue;
                 }
             };
-
-            #[cfg(target_os = "linux")]
-            let path = device_info.sysfs_path().to_path_buf();
-            #[cfg(not(target_os = "linux"))]
-            let path = device_info.bus_id().to_string();
-            #[cfg(target_os = "linux")]
-            let bus_id = match path.file_name() {
-                Some(s) => s.to_os_string().into_string().unwrap_or(format!(
-                    "{}-{}-{}",
-                    device_info.busnum(),
-                    device_info.device_address(),
-                    0,
-                )),
-                None => format!(
-                    "{}-{}-{}",
-                    device_info.busnum(),
-                    device_info.device_address(),
-                    0,
-                ),
-            };
-            #[cfg(not(target_os = "linux"))]
-            let bus_id = device_info.bus_id().to_string();
-
-            #[cfg(target_os = "linux")]
-            let bus_num = device_info.busnum() as u32;
-            #[cfg(not(target_os = "linux"))]
-            let bus_num = 0u32;
-            let cfg = match dev.active_configuration() {
-                Ok(cfg) => cfg,
+            match UsbDevice::claim_open_device(dev, &device_info, strategy).await {
+                Ok(device) => devices.push(device),
                 Err(err) => {
-                    warn!(
-                        "Impossible to get active configuration {device_info:?}: {err}, ignoring device",
-                    );
-                    continue;
-                }
-            };
-            let attributes = cfg.attributes();
-            let max_power = cfg.max_power();
-            let mut interfaces = vec![];
-            for intf in cfg.interfaces() {
-                // ignore alternate settings
-                let intf_num = intf.interface_number();
-
-                #[cfg(target_os = "linux")]
-                let _ = dev.detach_kernel_driver(intf_num);
-
-                let intf = dev.claim_interface(intf_num).await.unwrap();
-                let intf_desc = intf.descriptor().unwrap();
-
-                let mut endpoints = vec![];
-
-                for ep_desc in intf_desc.endpoints() {
-                    endpoints.push(UsbEndpoint {
-                        address: ep_desc.address(),
-                        attributes: ep_desc.transfer_type() as u8,
-                        max_packet_size: ep_desc.max_packet_size() as u16,
-                        interval: ep_desc.interval(),
-                    });
+                    warn!("Impossible to export device {device_info:?}: {err}, ignoring device",);
+                    errors.push((device_info, err));
                 }
-
-                let handler = intf.clone();
-
-                interfaces.push(UsbInterface {
-                    interface_class: intf_desc.class(),
-                    interface_subclass: intf_desc.subclass(),
-                    interface_protocol: intf_desc.protocol(),
-                    endpoints,
-                    string_interface: match intf_desc.string_index() {
-                        Some(i) => i.into(),
-                        None => 0,
-                    },
-                    class_specific_descriptor: Vec::new(),
-                    handler,
-                });
             }
+        }
+        (devices, errors)
+    }
 
-            let speed = match device_info.speed() {
-                Some(s) => match s {
-                    Speed::Low => 1u32,
-                    Speed::Full => 2,
-                    Speed::High => 3,
-                    Speed::Super => 5,
-                    Speed::SuperPlus => 6,
-                    _ => s as u32 + 1,
-                },
-                None => 0u32,
-            };
-            let mut device = UsbDevice {
-                path,
-                bus_id,
-                bus_num,
-                dev_num: device_info.device_address() as u32,
-                speed,
-                vendor_id: device_info.vendor_id(),
-                product_id: device_info.product_id(),
-                device_class: device_info.class(),
-                device_subclass: device_info.subclass(),
-                device_protocol: device_info.protocol(),
-                device_bcd: device_info.device_version().into(),
-                configuration_value: cfg.configuration_value(),
-                num_configurations: dev.configurations().count() as u8,
-                ep0_in: UsbEndpoint {
-                    address: 0x80,
-                    attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: EP0_MAX_PACKET_SIZE,
-                    interval: 0,
-                },
-                ep0_out: UsbEndpoint {
-                    address: 0x00,
-                    attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: EP0_MAX_PACKET_SIZE,
-                    interval: 0,
-                },
-                interfaces,
-                device_handler: Some(dev),
-                usb_version: device_info.usb_version().into(),
-                attributes,
-                max_power,
-                ..UsbDevice::default()
-            };
+    /// Like [Self::with_nusb_devices_and_claim_strategy], opening each device only when imported
+    ///
+    /// Devices nobody imports are never opened nor claimed, see [UsbDevice::from_device_info_lazy].
+    pub fn with_nusb_devices_lazy(
+        nusb_device_infos: Vec<nusb::DeviceInfo>,
+        strategy: ClaimStrategy,
+    ) -> Vec<UsbDevice> {
+        nusb_device_infos
+            .into_iter()
+            .map(|info| UsbDevice::from_device_info_lazy(info, strategy))
+            .collect()
+    }
 
-            // set strings
-            if let Some(s) = device_info.manufacturer_string() {
-                device.string_manufacturer = device.new_string(s)
-            }
-            if let Some(s) = device_info.product_string() {
-                device.string_product = device.new_string(s)
-            }
-            if let Some(s) = device_info.serial_number() {
-                device.string_serial = device.new_string(s)
+    /// List the devices of the host, with whether each can be exported
+    ///
+    /// Nothing is claimed, so it is suitable for a device selection UI.
+    pub async fn scan_host() -> Vec<HostDeviceInfo> {
+        match nusb::list_devices().await {
+            Ok(list) => list.map(|info| HostDeviceInfo::new(&info)).collect(),
+            Err(err) => {
+                warn!("Failed to list host devices: {err}");
+                vec![]
             }
-            devices.push(device);
         }
-        devices
     }
 
-    /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using libusb
+    /// Export the device of the host at `path`, e.g. "1-2" as in `/sys/bus/usb/devices/1-2`
+    ///
+    /// Sysfs paths of the device, and its `/dev/bus/usb/BBB/DDD` node, are
+    /// accepted too. Fails with [ErrorKind::NotFound] if no device is there.
+    #[cfg(target_os = "linux")]
+    pub async fn add_host_device_by_path(&self, path: impl AsRef<std::path::Path>) -> Result<()> {
+        self.add_host_device_at(&HostLocation::from_path(path.as_ref()))
+            .await
+    }
+
+    /// Export the device `dev` of bus `bus` of the host, as listed by `lsusb`
+    ///
+    /// Fails with [ErrorKind::NotFound] if there is no such device.
+    #[cfg(target_os = "linux")]
+    pub async fn add_host_device_by_bus_dev(&self, bus: u8, dev: u8) -> Result<()> {
+        self.add_host_device_at(&HostLocation::BusDev(bus, dev))
+            .await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn add_host_device_at(&self, location: &HostLocation) -> Result<()> {
+        let info = location.find(nusb::list_devices().await?, |info| {
+            let port = info.sysfs_path().file_name().unwrap_or_default();
+            (
+                port.to_string_lossy().into_owned(),
+                info.busnum(),
+                info.device_address(),
+            )
+        })?;
+        let dev = info.open().await?;
+        let device = UsbDevice::claim_open_device(dev, &info, ClaimStrategy::default()).await?;
+        self.add_device(device).await
+    }
+
+    /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using nusb
     pub async fn new_from_host() -> Self {
         Self::new_from_host_with_filter(|_| true).await
     }
 
-    /// Create a [UsbIpServer] exposing filtered devices in the host, and redirect all USB transfers to them using libusb
+    /// Create a [UsbIpServer] exposing filtered devices in the host, and redirect all USB transfers to them using nusb
     pub async fn new_from_host_with_filter<F>(filter: F) -> Self
     where
         F: FnMut(&DeviceInfo) -> bool,
@@ -222,26 +796,64 @@ impl UsbIpServer {
         }
     }
 
-    pub async fn add_device(&self, device: UsbDevice) {
-        self.available_devices.write().await.push(device);
+    /// Create a [UsbIpServer] exposing the devices in the host but hubs and `blocklist`
+    ///
+    /// `blocklist` lists the `(vendor_id, product_id)` of devices to keep local, see [filter_shareable].
+    pub async fn new_from_host_except(blocklist: &[(u16, u16)]) -> Self {
+        Self::new_from_host_with_filter(filter_shareable(blocklist)).await
+    }
+
+    /// Make `device` available to clients
+    ///
+    /// Its bus id must be unique, non-empty and fit in 31 bytes. With
+    /// [Self::with_strict_validation], malformed devices are rejected too.
+    pub async fn add_device(&self, device: UsbDevice) -> Result<()> {
+        if device.bus_id.is_empty() || device.bus_id.len() > 31 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid bus id {:?}", device.bus_id),
+            ));
+        }
+        if self.strict_validation
+            && let Err(errors) = device.validate()
+        {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid device {}: {}", device.bus_id, errors.join(", ")),
+            ));
+        }
+        let used_devices = self.used_devices.read().await;
+        let mut available_devices = self.available_devices.write().await;
+        if used_devices
+            .iter()
+            .map(|d| &**d)
+            .chain(available_devices.iter())
+            .any(|d| d.bus_id == device.bus_id)
+            || self.opening.lock().unwrap().contains(&device.bus_id)
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Device {} already exists", device.bus_id),
+            ));
+        }
+        available_devices.push(device);
+        Ok(())
     }
 
     pub async fn remove_device(&self, bus_id: &str) -> Result<()> {
+        let used_devices = self.used_devices.read().await;
         let mut available_devices = self.available_devices.write().await;
 
         if let Some(i) = available_devices.iter().position(|d| d.bus_id == bus_id) {
             #[cfg(target_os = "linux")]
-            if let Some(dev) = available_devices[i].device_handler.clone() {
-                release_claim(dev);
+            if let Some(dh) = &available_devices[i].device_handler {
+                dh.lock().unwrap().release_claim();
             }
             available_devices.remove(i);
             Ok(())
-        } else if self
-            .used_devices
-            .read()
-            .await
-            .iter()
-            .any(|d| d.bus_id == bus_id)
+        } else if used_devices.iter().any(|d| d.bus_id == bus_id)
+            || self.opening.lock().unwrap().contains(bus_id)
         {
             Err(std::io::Error::other(format!(
                 "Device {} is in use",
@@ -255,358 +867,4302 @@ impl UsbIpServer {
         }
     }
 
-    pub async fn occupy(&self, bus_id: &str) -> Result<UsbDevice> {
-        let mut ad = self.available_devices.write().await;
-        let device = match ad.iter().position(|d| d.bus_id == bus_id) {
-            Some(i) => ad.remove(i),
-            None => return Err(std::io::Error::other(format!("No available device"))),
-        };
-        let mut ud = self.used_devices.write().await;
-        if !ud.iter().any(|d| d.bus_id == device.bus_id) {
-            ud.push(device.clone());
+    /// Replace the device with `bus_id` by `device`, e.g. after its descriptors changed
+    ///
+    /// `device` takes over `bus_id`. A client that imported the old device is
+    /// disconnected at its next URB, so it has to import the device again and
+    /// enumerates the new descriptors.
+    pub async fn replace_device(&self, bus_id: &str, mut device: UsbDevice) -> Result<()> {
+        device.bus_id = bus_id.to_string();
+        if let Some(old) = self.used_device(bus_id).await {
+            old.cancel_transfers().await;
         }
-        Ok(device)
-    }
-
-    pub async fn release(&self, device: UsbDevice) {
         let mut ud = self.used_devices.write().await;
         let mut ad = self.available_devices.write().await;
-        let new_vec = ud.clone();
-        let new_ud: Vec<UsbDevice> = new_vec
-            .into_iter()
-            .filter(|d| d.bus_id != device.bus_id)
-            .collect();
-        if !ad.iter().any(|d| d.bus_id == device.bus_id) {
+        if let Some(i) = ad.iter().position(|d| d.bus_id == bus_id) {
+            ad[i] = device;
+        } else if let Some(i) = ud.iter().position(|d| d.bus_id == bus_id) {
+            ud.remove(i);
+            self.free_port(bus_id);
             ad.push(device);
+        } else {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("Device {bus_id} not found"),
+            ));
         }
-        *ud = new_ud;
+        Ok(())
     }
 
-    /// Reclaim the detached os driver.
-    pub async fn cleanup(&self) {
-        let mut ud = self.used_devices.write().await;
-        let mut ad = self.available_devices.write().await;
-        for d in ud.clone() {
-            if !ad.iter().any(|dev| d.bus_id == dev.bus_id) {
-                ad.push(d);
+    /// Signal remote wakeup from the imported device with `bus_id`
+    ///
+    /// The host must have enabled it with SET_FEATURE(DEVICE_REMOTE_WAKEUP).
+    /// USB/IP has no message for resume signaling, so it is delivered by the
+    /// [hub::VirtualHub] the device is connected to, as a port status change.
+    pub async fn request_remote_wakeup(&self, bus_id: &str) -> Result<()> {
+        let ud = self.used_devices.read().await;
+        let ad = self.available_devices.read().await;
+        // devices downstream of an imported hub are imported with it
+        let downstream = ud.iter().find_map(|d| {
+            let mut handler = d.device_handler.as_ref()?.lock().unwrap();
+            handler
+                .as_any()
+                .downcast_mut::<hub::VirtualHub>()?
+                .connected_device(bus_id)
+        });
+        match ud
+            .iter()
+            .find(|d| d.bus_id == bus_id)
+            .or(downstream.as_ref())
+        {
+            Some(d) if d.remote_wakeup_enabled() => {}
+            Some(_) => {
+                return Err(std::io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("Remote wakeup of device {bus_id} is not enabled by the host"),
+                ));
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("Device {bus_id} is not imported"),
+                ));
             }
         }
-        *ud = Vec::new();
-        #[cfg(target_os = "linux")]
+        for dh in ud
+            .iter()
+            .map(|d| &**d)
+            .chain(ad.iter())
+            .filter_map(|d| d.device_handler.as_ref())
         {
-            for d in ad.iter() {
-                if let Some(dh) = d.device_handler.clone() {
-                    release_claim(dh);
-                }
+            let mut handler = dh.lock().unwrap();
+            if let Some(hub) = handler.as_any().downcast_mut::<hub::VirtualHub>()
+                && hub.remote_wakeup(bus_id)
+            {
+                return Ok(());
             }
-            *ad = Vec::new();
         }
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            format!("Device {bus_id} is not on a suspended port of a VirtualHub"),
+        ))
     }
 
-    pub async fn handle_op_req_devlist(&self) -> Result<UsbIpResponse> {
-        trace!("Got OP_REQ_DEVLIST");
-        let devices = self.available_devices.read().await;
-
-        // OP_REP_DEVLIST
-        let usbip_resp = UsbIpResponse::op_rep_devlist(&devices);
-        trace!("Sent OP_REP_DEVLIST");
-        Ok(usbip_resp)
+    /// Stop forwarding URBs to the device with `bus_id`, keeping it imported
+    ///
+    /// E.g. while another tool flashes its firmware. Until [Self::resume], IN
+    /// URBs complete without data like a NAK, and OUT URBs as set by
+    /// [Self::with_paused_out].
+    pub async fn pause(&self, bus_id: &str) -> Result<()> {
+        let ud = self.used_devices.read().await;
+        let ad = self.available_devices.read().await;
+        if !ud.iter().any(|d| d.bus_id == bus_id) && !ad.iter().any(|d| d.bus_id == bus_id) {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No device {bus_id}"),
+            ));
+        }
+        self.paused
+            .lock()
+            .unwrap()
+            .entry(bus_id.to_string())
+            .or_default();
+        Ok(())
     }
 
-    pub async fn handle_op_req_import(
-        &self,
-        busid: [u8; 32],
-        imported_device: &mut Option<UsbDevice>,
-    ) -> Result<UsbIpResponse> {
-        trace!("Got OP_REQ_IMPORT");
-
-        let trimmed_busid = &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
-        let bus_id = match str::from_utf8(trimmed_busid) {
-            Ok(s) => s,
-            Err(_e) => return Err(std::io::Error::other(format!("Invalid bus id: {busid:?}"))),
-        };
-
-        match imported_device.take() {
-            Some(dev) => self.release(dev).await,
-            None => (),
+    /// Forward URBs to the device with `bus_id` again, see [Self::pause]
+    ///
+    /// Buffered OUT URBs are sent to the device first, in order. Their
+    /// failures can't be reported to the client anymore, they are only
+    /// logged and counted in [Self::endpoint_stats].
+    pub async fn resume(&self, bus_id: &str) -> Result<()> {
+        if !self.paused.lock().unwrap().contains_key(bus_id) {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("Device {bus_id} is not paused"),
+            ));
         }
-
-        let usbip_resp = match self.occupy(bus_id).await {
-            Ok(dev) => {
-                let res = UsbIpResponse::op_rep_import_success(&dev);
-                *imported_device = Some(dev);
-                res
+        let device = self.used_device(bus_id).await;
+        // still paused meanwhile, so that URBs the client submits now come after
+        loop {
+            let held = {
+                let mut paused = self.paused.lock().unwrap();
+                match paused.get_mut(bus_id) {
+                    Some(held) if !held.is_empty() => std::mem::take(held),
+                    Some(_) => {
+                        paused.remove(bus_id);
+                        return Ok(());
+                    }
+                    // resumed concurrently
+                    None => return Ok(()),
+                }
+            };
+            // dropped with the device if it was released
+            let Some(device) = device.clone() else {
+                continue;
+            };
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let replay = runtime.spawn_blocking(move || Self::replay(&device, held));
+                    if let Err(err) = replay.await
+                        && err.is_panic()
+                    {
+                        std::panic::resume_unwind(err.into_panic());
+                    }
+                }
+                Err(_) => Self::replay(&device, held),
             }
-            Err(_) => UsbIpResponse::op_rep_import_fail(),
-        };
-
-        trace!("Sent OP_REP_IMPORT");
-        Ok(usbip_resp)
+        }
     }
 
-    pub fn handle_usbip_cmd_submit(
+    /// Send OUT URBs `held` while `device` was paused to it, in order
+    fn replay(device: &UsbDevice, held: Vec<PausedUrb>) {
+        for urb in held {
+            let Some((ep, intf)) = device.find_ep(urb.ep) else {
+                continue;
+            };
+            let res = device.handle_urb(
+                ep,
+                intf,
+                urb.transfer_flags,
+                urb.data.len() as u32,
+                SetupPacket::parse(&urb.setup),
+                &urb.data,
+            );
+            match res {
+                Ok(_) => device.record_transfer(ep.address, Ok(urb.data.len() as u32)),
+                Err(err) => {
+                    warn!("Error sending buffered URB to {:#04x}: {err}", ep.address);
+                    device.record_transfer(ep.address, Err((urb_status(&err), err.to_string())));
+                }
+            }
+            buffer_pool().give(urb.data);
+        }
+    }
+
+    /// Detach the device with `bus_id` from the client that imported it
+    ///
+    /// The device becomes available again, and the client is disconnected at its next URB.
+    /// URBs being handled are cancelled first, see [Self::release].
+    pub async fn force_detach(&self, bus_id: &str) -> Result<()> {
+        let device = self.used_device(bus_id).await;
+        if let Some(device) = &device {
+            device.cancel_transfers().await;
+        }
+        let mut ud = self.used_devices.write().await;
+        let mut ad = self.available_devices.write().await;
+        // unless it was released and imported again meanwhile
+        let imported = |d: &Arc<UsbDevice>| device.as_ref().is_some_and(|dev| Arc::ptr_eq(d, dev));
+        match ud.iter().position(imported) {
+            Some(i) => {
+                let device = ud.remove(i);
+                self.free_port(bus_id);
+                self.released_at
+                    .lock()
+                    .unwrap()
+                    .insert(bus_id.to_string(), std::time::Instant::now());
+                device.resume_transfers();
+                // the client still holds it until its next URB
+                self.make_available(&mut ad, device);
+                self.events.send(ServerEvent::DeviceReleased {
+                    bus_id: bus_id.to_string(),
+                });
+                Ok(())
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("Device {bus_id} is not imported"),
+            )),
+        }
+    }
+
+    /// Unplug the device with `bus_id` from the client that imported it
+    ///
+    /// Like [Self::force_detach], but the connection of the client is closed
+    /// right away, as a real server does when the device is unplugged, so
+    /// that the client reports the device as detached.
+    pub async fn simulate_disconnect(&self, bus_id: &str) -> Result<()> {
+        self.force_detach(bus_id).await?;
+        self.unplugged.send_replace(());
+        Ok(())
+    }
+
+    /// Wait until `device` is detached with [Self::simulate_disconnect]
+    ///
+    /// Never returns if no device is imported.
+    async fn wait_unplugged(
+        &self,
+        unplugged: &mut tokio::sync::watch::Receiver<()>,
+        device: Option<&Arc<UsbDevice>>,
+    ) {
+        let Some(device) = device else {
+            return std::future::pending().await;
+        };
+        while unplugged.changed().await.is_ok() {
+            if !self.holds(device).await {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+
+    /// Subscribe to the [ServerEvent]s from now on
+    pub fn events(&self) -> impl Stream<Item = ServerEvent> + Send + Unpin + use<> {
+        self.events.subscribe()
+    }
+
+    /// List all devices as a JSON array, with whether each is imported by a client
+    pub async fn devices_json(&self) -> String {
+        let ud = self.used_devices.read().await;
+        let ad = self.available_devices.read().await;
+        let devices: Vec<String> = ad
+            .iter()
+            .map(|d| (d, false))
+            .chain(ud.iter().map(|d| (&**d, true)))
+            .map(|(d, in_use)| {
+                format!(
+                    r#"{{"bus_id":{},"vendor_id":{},"product_id":{},"speed":{},"in_use":{}}}"#,
+                    json_string(&d.bus_id),
+                    d.vendor_id,
+                    d.product_id,
+                    d.speed,
+                    in_use
+                )
+            })
+            .collect();
+        format!("[{}]", devices.join(","))
+    }
+
+    /// Snapshot of the server counters
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            connections: self.counters.connections.load(Ordering::Relaxed),
+            urbs_completed: self.counters.urbs_completed.load(Ordering::Relaxed),
+            urbs_failed: self.counters.urbs_failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The last `limit` connections closed, most recent first
+    ///
+    /// Up to 256 are kept, with the devices each one imported and released.
+    pub fn recent_sessions(&self, limit: usize) -> Vec<SessionRecord> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// The devices imported by the open connections, oldest connection first
+    ///
+    /// Along with [Self::enter_drain_mode], this tells a new instance of the
+    /// server which clients to expect back.
+    pub fn active_imports(&self) -> Vec<ActiveImport> {
+        let imports = self.active_imports.lock().unwrap();
+        imports.values().cloned().collect()
+    }
+
+    fn update_active_import(&self, connection: u64, import: Option<ActiveImport>) {
+        let mut imports = self.active_imports.lock().unwrap();
+        match import {
+            Some(import) => imports.insert(connection, import),
+            None => imports.remove(&connection),
+        };
+    }
+
+    /// Refuse new imports, while the devices imported already keep serving URBs
+    ///
+    /// For restarts without downtime: drain this instance while a new one
+    /// takes the new connections, and stop it once [Self::active_imports] is
+    /// empty. Clients may still import the device they hold again.
+    pub fn enter_drain_mode(&self) {
+        info!("Draining, refusing new imports");
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [Self::enter_drain_mode] was called
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Give all the transfers of device `bus_id` `timeout` until [Self::clear_transfer_timeout_override]
+    ///
+    /// For a known slow operation, like the erase command of a firmware
+    /// update, while other devices keep the timeouts of
+    /// [Self::with_transfer_timeout]. It applies from the next URB.
+    pub fn override_transfer_timeout(&self, bus_id: &str, timeout: Duration) {
+        let mut overrides = self.timeout_overrides.lock().unwrap();
+        overrides.insert(bus_id.to_string(), timeout);
+    }
+
+    /// Back to the timeouts of [Self::with_transfer_timeout] for device `bus_id`
+    pub fn clear_transfer_timeout_override(&self, bus_id: &str) {
+        let mut overrides = self.timeout_overrides.lock().unwrap();
+        overrides.remove(bus_id);
+    }
+
+    /// The timeouts of the transfers of device `bus_id`, with its override if any
+    fn transfer_timeouts_of(&self, bus_id: &str) -> TransferTimeouts {
+        match self.timeout_overrides.lock().unwrap().get(bus_id) {
+            Some(&timeout) => TransferTimeouts {
+                control: timeout,
+                isochronous: timeout,
+                bulk: timeout,
+                interrupt: timeout,
+            },
+            None => self.transfer_timeouts,
+        }
+    }
+
+    fn record_session(&self, record: SessionRecord) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() == SESSION_HISTORY {
+            sessions.pop_front();
+        }
+        sessions.push_back(record);
+    }
+
+    /// Port of the imported device with `bus_id`
+    ///
+    /// Each import gets the next port number, which stays the same until the
+    /// device is released, like the ports listed by `usbip port`.
+    pub async fn port_of(&self, bus_id: &str) -> Option<u32> {
+        let _ud = self.used_devices.read().await;
+        self.ports
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, b)| *b == bus_id)
+            .map(|(port, _)| *port)
+    }
+
+    /// Bus id of the device imported on `port`, see [Self::port_of]
+    pub async fn device_by_port(&self, port: u32) -> Option<String> {
+        let _ud = self.used_devices.read().await;
+        self.ports.lock().unwrap().get(&port).cloned()
+    }
+
+    /// Imported devices with their ports, in port order
+    pub async fn ports(&self) -> Vec<(u32, String)> {
+        let _ud = self.used_devices.read().await;
+        self.ports
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(port, bus_id)| (*port, bus_id.clone()))
+            .collect()
+    }
+
+    fn assign_port(&self, bus_id: &str) {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        self.ports.lock().unwrap().insert(port, bus_id.to_string());
+    }
+
+    fn free_port(&self, bus_id: &str) {
+        self.ports.lock().unwrap().retain(|_, b| b != bus_id);
+    }
+
+    /// [EndpointStats] of the device with `bus_id`, imported or not
+    pub async fn endpoint_stats(&self, bus_id: &str) -> Option<BTreeMap<u8, EndpointStats>> {
+        let ud = self.used_devices.read().await;
+        let ad = self.available_devices.read().await;
+        ud.iter()
+            .map(|d| &**d)
+            .chain(ad.iter())
+            .find(|d| d.bus_id == bus_id)
+            .map(|d| d.endpoint_stats())
+    }
+
+    #[cfg(test)]
+    async fn is_used(&self, bus_id: &str) -> bool {
+        self.used_devices
+            .read()
+            .await
+            .iter()
+            .any(|d| d.bus_id == bus_id)
+    }
+
+    /// Whether `device` is still imported, rather than detached or imported again by another client
+    async fn holds(&self, device: &Arc<UsbDevice>) -> bool {
+        self.used_devices
+            .read()
+            .await
+            .iter()
+            .any(|d| Arc::ptr_eq(d, device))
+    }
+
+    /// Get an available device ready to be imported by a client
+    ///
+    /// Host devices released to the OS, e.g. by [Self::cleanup], detach
+    /// kernel drivers again. Management tooling can call it ahead of an
+    /// import, see also [Self::with_prepare_on_import].
+    pub async fn prepare(&self, bus_id: &str) -> Result<()> {
+        let ad = self.available_devices.read().await;
+        match ad.iter().find(|d| d.bus_id == bus_id) {
+            Some(device) => Self::prepare_device(device),
+            None => Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("No available device {bus_id}"),
+            )),
+        }
+    }
+
+    fn prepare_device(device: &UsbDevice) -> Result<()> {
+        match &device.device_handler {
+            Some(dh) => dh.lock().unwrap().prepare(),
+            None => Ok(()),
+        }
+    }
+
+    /// SET_CONFIGURATION of `device`, as a client would, see [Self::with_configure_on_import]
+    fn configure_device(&self, device: &UsbDevice, value: u8) {
+        debug!("Setting configuration {value} of {}", device.bus_id);
+        let setup = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetConfiguration as u8,
+            value: value.into(),
+            index: 0,
+            length: 0,
+        };
+        if let Err(e) = device.handle_urb(device.ep0_out, None, 0, 0, setup, &[]) {
+            error!(
+                "Failed to set configuration {value} of {}: {e}",
+                device.bus_id
+            );
+        }
+    }
+
+    /// Move an available device to the used ones
+    ///
+    /// The returned device is shared with the used list, see [Self::release].
+    pub async fn occupy(&self, bus_id: &str) -> Result<Arc<UsbDevice>> {
+        let cooling_down = self.cooldown_left(bus_id);
+        if let Some(left) = cooling_down {
+            debug!("Device {bus_id} released recently, importing it in {left:?}");
+            crate::util::sleep(left).await;
+        }
+        let ud = self.used_devices.write().await;
+        let mut ad = self.available_devices.write().await;
+        let Some(i) = ad.iter().position(|d| d.bus_id == bus_id) else {
+            return Err(std::io::Error::other("No available device"));
+        };
+        if self.prepare_on_import && cooling_down.is_none() {
+            Self::prepare_device(&ad[i])?;
+        }
+        // out of the lists while opening and configuring it, without holding the locks
+        let available = ad.remove(i);
+        self.opening
+            .lock()
+            .unwrap()
+            .insert(available.bus_id.clone());
+        drop(ad);
+        drop(ud);
+        let mut device = match available.lazy_open.clone() {
+            Some(LazyOpen(open)) => {
+                debug!("Opening device {bus_id}");
+                let opened = match tokio::runtime::Handle::try_current() {
+                    Ok(runtime) => runtime
+                        .spawn_blocking(move || open())
+                        .await
+                        .unwrap_or_else(|e| Err(std::io::Error::other(e))),
+                    Err(_) => open(),
+                };
+                let mut opened = match opened {
+                    Ok(opened) => opened,
+                    Err(err) => {
+                        let mut ad = self.available_devices.write().await;
+                        self.opening.lock().unwrap().remove(&available.bus_id);
+                        ad.push(available);
+                        return Err(err);
+                    }
+                };
+                opened.bus_id.clone_from(&available.bus_id);
+                self.unopened
+                    .lock()
+                    .unwrap()
+                    .insert(available.bus_id.clone(), available);
+                opened
+            }
+            None => available,
+        };
+        if let Some(value) = self.configure_on_import {
+            self.configure_device(&device, value.unwrap_or(device.configuration_value));
+        }
+        // the host may have switched configurations since enumeration
+        device.refresh_configuration();
+        device.set_transfer_timeouts(self.transfer_timeouts_of(bus_id));
+        let device = Arc::new(device);
+        let mut ud = self.used_devices.write().await;
+        self.opening.lock().unwrap().remove(&device.bus_id);
+        // not while its bus id was opening, but an untracked device is never handed out
+        if ud.iter().any(|d| d.bus_id == device.bus_id) {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Device {} is already imported", device.bus_id),
+            ));
+        }
+        ud.push(Arc::clone(&device));
+        self.assign_port(&device.bus_id);
+        self.events.send(ServerEvent::DeviceImported {
+            bus_id: device.bus_id.clone(),
+        });
+        Ok(device)
+    }
+
+    /// How long imports of `bus_id` wait for the cooldown after its release, see [Self::with_release_cooldown]
+    fn cooldown_left(&self, bus_id: &str) -> Option<Duration> {
+        let cooldown = self.release_cooldown?;
+        let released_at = *self.released_at.lock().unwrap().get(bus_id)?;
+        cooldown.checked_sub(released_at.elapsed())
+    }
+
+    /// The imported device with `bus_id`
+    async fn used_device(&self, bus_id: &str) -> Option<Arc<UsbDevice>> {
+        let ud = self.used_devices.read().await;
+        ud.iter().find(|d| d.bus_id == bus_id).cloned()
+    }
+
+    /// Move a device from [Self::occupy] back to the available ones
+    ///
+    /// URBs still being handled for the device are cancelled, and it only
+    /// becomes available once their handlers returned.
+    pub async fn release(&self, device: Arc<UsbDevice>) {
+        // detached, and maybe imported by another client since: not ours to cancel
+        if !self.holds(&device).await {
+            return;
+        }
+        device.cancel_transfers().await;
+        let mut ud = self.used_devices.write().await;
+        let mut ad = self.available_devices.write().await;
+        device.resume_transfers();
+        let used = ud.len();
+        ud.retain(|d| !Arc::ptr_eq(d, &device));
+        if ud.len() < used {
+            self.free_port(&device.bus_id);
+            self.mode_sense.lock().unwrap().remove(&device.bus_id);
+            self.released_at
+                .lock()
+                .unwrap()
+                .insert(device.bus_id.clone(), std::time::Instant::now());
+            self.events.send(ServerEvent::DeviceReleased {
+                bus_id: device.bus_id.clone(),
+            });
+            self.make_available(&mut ad, device);
+        }
+    }
+
+    /// Put a `device` released by its client back with the available ones, unopened again if it was opened on import
+    fn make_available(&self, ad: &mut Vec<UsbDevice>, device: Arc<UsbDevice>) {
+        if ad.iter().any(|d| d.bus_id == device.bus_id) {
+            return;
+        }
+        // the device stays paused, without the data of the previous client
+        if let Some(held) = self.paused.lock().unwrap().get_mut(&device.bus_id)
+            && !held.is_empty()
+        {
+            warn!(
+                "Dropping {} URBs buffered for paused device {}",
+                held.len(),
+                device.bus_id
+            );
+            held.drain(..).for_each(|urb| buffer_pool().give(urb.data));
+        }
+        match self.unopened.lock().unwrap().remove(&device.bus_id) {
+            Some(unopened) => {
+                #[cfg(target_os = "linux")]
+                if let Some(dh) = &device.device_handler {
+                    dh.lock().unwrap().release_claim();
+                }
+                // the opened device is closed once the client dropped it too
+                ad.push(unopened)
+            }
+            // not cloned unless the device was shared elsewhere
+            None => ad.push(Arc::unwrap_or_clone(device)),
+        }
+    }
+
+    /// Reclaim the detached os driver.
+    pub async fn cleanup(&self) {
+        let mut ud = self.used_devices.write().await;
+        let mut ad = self.available_devices.write().await;
+        self.ports.lock().unwrap().clear();
+        for d in ud.drain(..) {
+            self.events.send(ServerEvent::DeviceReleased {
+                bus_id: d.bus_id.clone(),
+            });
+            self.make_available(&mut ad, d);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            for d in ad.iter() {
+                if let Some(dh) = &d.device_handler {
+                    dh.lock().unwrap().release_claim();
+                }
+            }
+            *ad = Vec::new();
+        }
+    }
+
+    /// A socket listening at `addr`, as configured by the builder
+    ///
+    /// SO_REUSEADDR is set, so a restarted server binds while connections of
+    /// the previous one linger in TIME_WAIT.
+    pub(crate) fn bind_listener(&self, addr: SocketAddr) -> Result<std::net::TcpListener> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        // on Windows, it allows stealing a port in use instead
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.listen_backlog.unwrap_or(1024))?;
+        Ok(socket.into())
+    }
+
+    pub async fn handle_op_req_devlist(&self) -> Result<UsbIpResponse> {
+        trace!("Got OP_REQ_DEVLIST");
+        let devices = self.available_devices.read().await;
+
+        // OP_REP_DEVLIST
+        let usbip_resp = UsbIpResponse::op_rep_devlist(&devices);
+        trace!("Sent OP_REP_DEVLIST");
+        Ok(usbip_resp)
+    }
+
+    /// [Self::handle_usbip_cmd_submit] on the blocking thread pool, see [Self::with_transfer_pool]
+    ///
+    /// Handled in place without a pool, or outside of a tokio runtime.
+    async fn submit_on_pool(
+        self: &Arc<Self>,
+        header: UsbIpHeaderBasic,
+        transfer_flags: u32,
+        transfer_buffer_length: u32,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        device: &Arc<UsbDevice>,
+    ) -> Result<UsbIpResponse> {
+        let (Some(pool), Ok(runtime)) =
+            (&self.transfer_pool, tokio::runtime::Handle::try_current())
+        else {
+            return self.handle_usbip_cmd_submit(
+                header,
+                transfer_flags,
+                transfer_buffer_length,
+                setup,
+                data,
+                device,
+            );
+        };
+        // the semaphore is never closed
+        let permit = pool.clone().acquire_owned().await.unwrap();
+        let server = self.clone();
+        let device = device.clone();
+        let res = runtime
+            .spawn_blocking(move || {
+                let _permit = permit;
+                server.handle_usbip_cmd_submit(
+                    header,
+                    transfer_flags,
+                    transfer_buffer_length,
+                    setup,
+                    data,
+                    &device,
+                )
+            })
+            .await;
+        match res {
+            Ok(res) => res,
+            // as if the handler panicked on this task
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(std::io::Error::other(err)),
+        }
+    }
+
+    /// Write OP_REP_DEVLIST for `peer` to `socket`, as configured on the server
+    ///
+    /// Devices are encoded from the device lists rather than cloned. The lists
+    /// are unlocked before writing, a client not reading the reply holds nothing.
+    async fn write_op_rep_devlist_to<T: AsyncWriteExt + Unpin>(
+        &self,
+        socket: &mut T,
+        peer: Option<&SocketAddr>,
+    ) -> Result<()> {
+        trace!("Got OP_REQ_DEVLIST");
+        let records: Vec<Vec<u8>> = {
+            let used = match self.devlist_include_used {
+                true => Some(self.used_devices.read().await),
+                false => None,
+            };
+            let available = self.available_devices.read().await;
+            available
+                .iter()
+                .chain(used.iter().flat_map(|used| used.iter().map(|d| &**d)))
+                .filter(|dev| match (&self.devlist_filter, peer) {
+                    (Some(filter), Some(peer)) => (filter.0)(peer, dev),
+                    _ => true,
+                })
+                .map(UsbDevice::to_bytes_with_interfaces)
+                .collect()
+        };
+
+        // OP_REP_DEVLIST
+        usbip_protocol::write_devlist(socket, 0, records.len() as u32, records.iter()).await?;
+        trace!("Sent OP_REP_DEVLIST");
+        Ok(())
+    }
+
+    /// Whether the devlist filter hides the device `busid` from `peer`
+    async fn hidden_from(&self, peer: Option<&SocketAddr>, busid: &[u8; 32]) -> bool {
+        let (Some(filter), Some(peer)) = (&self.devlist_filter, peer) else {
+            return false;
+        };
+        let bus_id = &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
+        let used = self.used_devices.read().await;
+        let available = self.available_devices.read().await;
+        let mut devices = used.iter().map(|d| &**d).chain(available.iter());
+        devices.any(|dev| dev.bus_id.as_bytes() == bus_id && !(filter.0)(peer, dev))
+    }
+
+    pub async fn handle_op_req_import(
+        &self,
+        busid: [u8; 32],
+        imported_device: &mut Option<Arc<UsbDevice>>,
+    ) -> Result<UsbIpResponse> {
+        trace!("Got OP_REQ_IMPORT");
+
+        let trimmed_busid = &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
+        let bus_id = match str::from_utf8(trimmed_busid) {
+            Ok(s) => s,
+            Err(_e) => return Err(std::io::Error::other(format!("Invalid bus id: {busid:?}"))),
+        };
+
+        if let Some(dev) = imported_device.as_ref()
+            && dev.bus_id == bus_id
+            && self.holds(dev).await
+        {
+            // re-import by the same client, e.g. on reconnect: keep holding it
+            trace!("Sent OP_REP_IMPORT");
+            return Ok(UsbIpResponse::op_rep_import_success(dev));
+        }
+
+        if self.is_draining() {
+            warn!("Refusing import of {bus_id} while draining");
+            trace!("Sent OP_REP_IMPORT");
+            return Ok(UsbIpResponse::op_rep_import_fail());
+        }
+
+        if let Some(dev) = imported_device.take() {
+            self.release(dev).await;
+        }
+
+        let usbip_resp = match self.occupy(bus_id).await {
+            Ok(dev) => {
+                let res = UsbIpResponse::op_rep_import_success(&dev);
+                *imported_device = Some(dev);
+                res
+            }
+            Err(_) => UsbIpResponse::op_rep_import_fail(),
+        };
+
+        trace!("Sent OP_REP_IMPORT");
+        Ok(usbip_resp)
+    }
+
+    /// Whether a URB failing with `err` may succeed if submitted again
+    fn is_transient(err: &std::io::Error, ep: &UsbEndpoint) -> bool {
+        let nak = ep.attributes == EndpointAttributes::Interrupt as u8
+            && ep.direction() == Direction::In
+            && err.kind() == ErrorKind::TimedOut;
+        !nak && matches!(urb_status(err), UrbStatus::EPROTO | UrbStatus::ETIMEDOUT)
+    }
+
+    /// The interface a URB to `ep` of `device` is for, if clients may not transfer to it
+    fn disallowed_interface(
+        &self,
+        device: &UsbDevice,
+        ep: &UsbEndpoint,
+        setup: &[u8; 8],
+    ) -> Option<u8> {
+        let allowed = self.allowed_interfaces.get(&device.bus_id)?;
+        device
+            .interface_number(ep, &SetupPacket::parse(setup))
+            .filter(|i| !allowed.contains(i))
+    }
+
+    /// Whether an OUT URB with `data` to `ep` of `intf` would write to `device`, if it is read-only
+    fn writes_to_read_only(
+        &self,
+        device: &UsbDevice,
+        ep: &UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        data: &[u8],
+    ) -> bool {
+        if !self.read_only.contains(&device.bus_id)
+            || ep.attributes != EndpointAttributes::Bulk as u8
+            || ep.direction() != Direction::Out
+        {
+            return false;
+        }
+        match intf {
+            Some(intf) if is_bulk_only_storage(intf) => {
+                cbw_opcode(data).is_none_or(|opcode| !SCSI_READ_ONLY_COMMANDS.contains(&opcode))
+            }
+            // including UAS, whose reads are commands on a bulk OUT pipe too
+            _ => true,
+        }
+    }
+
+    /// Set the WP bit of MODE SENSE data from the read-only `device`, in `resp` of a URB to `ep`
+    ///
+    /// The command block wrapper of the MODE SENSE is sent in `data` of a
+    /// previous URB, its data comes in the next bulk IN one.
+    fn write_protect(
+        &self,
+        device: &UsbDevice,
+        ep: &UsbEndpoint,
+        intf: Option<&UsbInterface>,
+        data: &[u8],
+        resp: &mut [u8],
+    ) {
+        if !self.read_only.contains(&device.bus_id)
+            || ep.attributes != EndpointAttributes::Bulk as u8
+            || !intf.is_some_and(is_bulk_only_storage)
+        {
+            return;
+        }
+        let mut mode_sense = self.mode_sense.lock().unwrap();
+        if ep.direction() == Direction::Out {
+            match cbw_opcode(data) {
+                Some(opcode @ (MODE_SENSE_6 | MODE_SENSE_10)) => {
+                    mode_sense.insert(device.bus_id.clone(), opcode)
+                }
+                _ => mode_sense.remove(&device.bus_id),
+            };
+            return;
+        }
+        // the device-specific parameter of the mode parameter header, not a CSW without data
+        let is_csw = resp.len() == 13 && resp.starts_with(b"USBS");
+        let offset = match mode_sense.remove(&device.bus_id) {
+            Some(MODE_SENSE_6) => 2,
+            Some(_) => 3,
+            None => return,
+        };
+        if !is_csw && let Some(param) = resp.get_mut(offset) {
+            *param |= 0x80;
+        }
+    }
+
+    /// Handle USBIP_CMD_SUBMIT of a URB to `device`
+    ///
+    /// A zero-length control submit right after one with a data stage is taken
+    /// as its status stage, and completes without reaching the device.
+    ///
+    /// Fails with [ErrorKind::InvalidData] if `data` is not `transfer_buffer_length`
+    /// bytes for an OUT URB, or not empty for an IN URB. Commands read with
+    /// [UsbIpCommand::read_from_socket] always match, this guards callers
+    /// building them otherwise.
+    pub fn handle_usbip_cmd_submit(
         &self,
         mut header: UsbIpHeaderBasic,
+        transfer_flags: u32,
         transfer_buffer_length: u32,
         setup: [u8; 8],
         data: Vec<u8>,
         device: &UsbDevice,
     ) -> Result<UsbIpResponse> {
+        let downstream = device.downstream(header.devid);
+        let device = downstream.as_deref().unwrap_or(device);
         let out = header.direction == 0;
         let real_ep = if out { header.ep } else { header.ep | 0x80 };
 
+        let expected_len = if out {
+            transfer_buffer_length as usize
+        } else {
+            0
+        };
+        if data.len() != expected_len {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "URB {} carries {} bytes of data, expected {expected_len}",
+                    header.seqnum,
+                    data.len()
+                ),
+            ));
+        }
+
         header.command = USBIP_RET_SUBMIT.into();
 
-        // Reply header from server should have devid/direction/ep all 0.
-        header.devid = 0;
-        header.direction = 0;
-        header.ep = 0;
+        // Reply header from server should have devid/direction/ep all 0.
+        header.devid = 0;
+        header.direction = 0;
+        header.ep = 0;
+
+        if real_ep & 0x7F == 0 {
+            let data_stage = transfer_buffer_length > 0;
+            let after_data_stage = device.after_data_stage.swap(data_stage, Ordering::Relaxed);
+            // some clients submit the status stage of a control transfer on its own:
+            // without data, and with no setup or the one of the data stage
+            if after_data_stage
+                && !data_stage
+                && (setup == [0; 8] || SetupPacket::parse(&setup).length > 0)
+            {
+                trace!("Status stage of the last control transfer");
+                self.counters.urbs_completed.fetch_add(1, Ordering::Relaxed);
+                buffer_pool().give(data);
+                return Ok(UsbIpResponse::usbip_ret_submit_success(
+                    &header,
+                    0,
+                    0,
+                    vec![],
+                    vec![],
+                ));
+            }
+        }
+
+        // nothing reaches a paused device
+        if let Some(held) = self.paused.lock().unwrap().get_mut(&device.bus_id)
+            && let Some((ep, intf)) = device.find_ep(real_ep as u8)
+            && self.disallowed_interface(device, &ep, &setup).is_none()
+            && !self.writes_to_read_only(device, &ep, intf, &data)
+        {
+            trace!("Holding URB to paused device {}", device.bus_id);
+            return Ok(match (ep.direction(), self.paused_out) {
+                (Direction::In, _) => {
+                    self.counters.urbs_completed.fetch_add(1, Ordering::Relaxed);
+                    UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![], vec![])
+                }
+                // requests like SET_CONFIGURATION must not be acknowledged unsent
+                (Direction::Out, PausedOut::Buffer) if ep.address & 0x7F != 0 => {
+                    self.counters.urbs_completed.fetch_add(1, Ordering::Relaxed);
+                    let actual_length = data.len() as u32;
+                    held.push(PausedUrb {
+                        ep: ep.address,
+                        transfer_flags,
+                        setup,
+                        data,
+                    });
+                    UsbIpResponse::usbip_ret_submit_success(
+                        &header,
+                        0,
+                        actual_length,
+                        vec![],
+                        vec![],
+                    )
+                }
+                (Direction::Out, _) => {
+                    self.counters.urbs_failed.fetch_add(1, Ordering::Relaxed);
+                    buffer_pool().give(data);
+                    UsbIpResponse::usbip_ret_submit_fail(&header, UrbStatus::ETIMEDOUT, 0)
+                }
+            });
+        }
+
+        let usbip_resp = match device.find_ep(real_ep as u8) {
+            None => {
+                warn!("Endpoint {real_ep:02x?} not found");
+                self.counters.urbs_failed.fetch_add(1, Ordering::Relaxed);
+                let status = match self.unknown_endpoint {
+                    UnknownEndpoint::Stall => UrbStatus::EPIPE,
+                    UnknownEndpoint::Fail => UrbStatus::ENOENT,
+                };
+                device
+                    .record_transfer(real_ep as u8, Err((status, "No such endpoint".to_string())));
+                // nothing was transferred, in either direction
+                UsbIpResponse::usbip_ret_submit_fail(&header, status, 0)
+            }
+            Some((ep, _))
+                if self
+                    .max_transfer_sizes
+                    .get(&(ep.attributes & 0x03))
+                    .is_some_and(|max| transfer_buffer_length > *max) =>
+            {
+                warn!(
+                    "URB of {transfer_buffer_length} bytes to {:#04x} is too large",
+                    ep.address
+                );
+                self.counters.urbs_failed.fetch_add(1, Ordering::Relaxed);
+                let status = match FromPrimitive::from_u8(ep.attributes & 0x03) {
+                    Some(EndpointAttributes::Interrupt | EndpointAttributes::Isochronous) => {
+                        UrbStatus::EMSGSIZE
+                    }
+                    _ => UrbStatus::EINVAL,
+                };
+                device.record_transfer(ep.address, Err((status, "URB too large".to_string())));
+                UsbIpResponse::usbip_ret_submit_fail(&header, status, 0)
+            }
+            Some((ep, intf)) => {
+                // only meaningful for control transfers, clients may leave garbage otherwise
+                let setup = if ep.attributes == EndpointAttributes::Control as u8 {
+                    setup
+                } else {
+                    [0; 8]
+                };
+                let mut retries = 0;
+                let res = if let Some(i) = self.disallowed_interface(device, &ep, &setup) {
+                    Err(stall(format!("Interface {i} is not allowed")))
+                } else if self.writes_to_read_only(device, &ep, intf, &data) {
+                    Err(stall(format!("Device {} is read-only", device.bus_id)))
+                } else {
+                    let timeouts = self.transfer_timeouts_of(&device.bus_id);
+                    if device.transfer_timeouts() != timeouts {
+                        device.set_transfer_timeouts(timeouts);
+                    }
+                    // don't block a worker of the runtime during the backoff
+                    let may_retry = self.transfer_pool.is_some()
+                        || tokio::runtime::Handle::try_current().is_err();
+                    device.track_transfer(|| {
+                        loop {
+                            let res = device.handle_urb(
+                                ep,
+                                intf,
+                                transfer_flags,
+                                transfer_buffer_length,
+                                SetupPacket::parse(&setup),
+                                &data,
+                            );
+                            match &res {
+                                Err(err)
+                                    if may_retry
+                                        && retries < self.transfer_retries
+                                        && Self::is_transient(err, &ep) =>
+                                {
+                                    retries += 1;
+                                    debug!(
+                                        "Retrying URB to {:#04x} after {err} ({retries}/{})",
+                                        ep.address, self.transfer_retries
+                                    );
+                                    std::thread::sleep(self.retry_backoff);
+                                }
+                                _ => break res,
+                            }
+                        }
+                    })
+                };
+                match res {
+                    Ok(mut resp) => {
+                        self.counters.urbs_completed.fetch_add(1, Ordering::Relaxed);
+                        self.write_protect(device, &ep, intf, &data, &mut resp);
+                        if out {
+                            trace!("<-Wrote {}", data.len());
+                        } else {
+                            trace!("<-Resp {resp:02x?}");
+                        }
+                        let (actual_length, resp) = match ep.direction() {
+                            Direction::In => {
+                                // the client's buffer holds no more, and control reads stop at wLength
+                                let mut cap = transfer_buffer_length as usize;
+                                if ep.is_ep0() {
+                                    cap = cap.min(SetupPacket::parse(&setup).length as usize);
+                                }
+                                if resp.len() > cap {
+                                    warn!(
+                                        "Truncating {} bytes from {:#04x} to {cap}",
+                                        resp.len(),
+                                        ep.address
+                                    );
+                                    resp.truncate(cap);
+                                }
+                                (resp.len() as u32, resp)
+                            }
+                            // the whole data stage was written, replies to OUT carry no data
+                            Direction::Out => (data.len() as u32, vec![]),
+                        };
+                        device.record_transfer(ep.address, Ok(actual_length));
+                        UsbIpResponse::usbip_ret_submit_success(
+                            &header,
+                            0,
+                            actual_length,
+                            resp,
+                            vec![],
+                        )
+                    }
+                    Err(err)
+                        if err.kind() == ErrorKind::TimedOut
+                            && ep.attributes == EndpointAttributes::Interrupt as u8
+                            && ep.direction() == Direction::In
+                            && !self.interrupt_in_timeout_fails =>
+                    {
+                        // no data ready: NAK
+                        self.counters.urbs_completed.fetch_add(1, Ordering::Relaxed);
+                        device.record_transfer(ep.address, Ok(0));
+                        UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![], vec![])
+                    }
+                    Err(err) => {
+                        warn!("Error handling URB: {err}");
+                        self.counters.urbs_failed.fetch_add(1, Ordering::Relaxed);
+                        self.events.send(ServerEvent::TransferError {
+                            bus_id: device.bus_id.clone(),
+                            ep: ep.address,
+                            status: urb_status(&err),
+                        });
+                        device
+                            .record_transfer(ep.address, Err((urb_status(&err), err.to_string())));
+                        let actual_length = match ep.direction() {
+                            Direction::In => 0,
+                            Direction::Out => transfer_buffer_length,
+                        };
+                        UsbIpResponse::usbip_ret_submit_fail(
+                            &header,
+                            urb_status(&err),
+                            actual_length,
+                        )
+                    }
+                }
+            }
+        };
+        buffer_pool().give(data);
+        trace!("Sent USBIP_RET_SUBMIT");
+        Ok(usbip_resp)
+    }
+
+    /// Handle USBIP_CMD_UNLINK, `completed` tells whether the URB `unlink_seqnum` already completed
+    ///
+    /// An URB that completed, or that was never submitted, can't be unlinked
+    /// and the status is 0, otherwise it is `-ECONNRESET` and the URB won't complete.
+    pub fn handle_usbip_cmd_unlink(
+        &self,
+        mut header: UsbIpHeaderBasic,
+        unlink_seqnum: u32,
+        completed: bool,
+    ) -> Result<UsbIpResponse> {
+        trace!("Got USBIP_CMD_UNLINK for {unlink_seqnum:10x?}");
+
+        header.command = USBIP_RET_UNLINK.into();
+        // Reply header from server should have devid/direction/ep all 0.
+        header.devid = 0;
+        header.direction = 0;
+        header.ep = 0;
+
+        let res = if completed {
+            UsbIpResponse::usbip_ret_unlink_success(&header)
+        } else {
+            UsbIpResponse::UsbIpRetUnlink {
+                header,
+                status: UrbStatus::ECONNRESET as u32,
+            }
+        };
+        trace!("Sent USBIP_RET_UNLINK");
+        Ok(res)
+    }
+}
+
+pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    imported_device: &mut Option<Arc<UsbDevice>>,
+) -> Result<()> {
+    let inactivity_timeout = server.inactivity_timeout;
+    handle_connection(socket, server, imported_device, None, inactivity_timeout).await
+}
+
+/// Serve the commands of one client until it disconnects
+///
+/// Reads are bounded by `inactivity_timeout` using the tokio timer, callers
+/// without a runtime pass `None` and time out on the socket instead.
+pub(crate) async fn handle_connection<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    imported_device: &mut Option<Arc<UsbDevice>>,
+    peer: Option<SocketAddr>,
+    inactivity_timeout: Option<Duration>,
+) -> Result<()> {
+    let connection = server.counters.connections.fetch_add(1, Ordering::Relaxed) + 1;
+    server
+        .events
+        .send(ServerEvent::ClientConnected { connection });
+    // records the session however it ends
+    let mut guard = ReleaseGuard {
+        server: server.clone(),
+        device: None,
+        session: Some(SessionTracker::new(connection, peer)),
+    };
+    let session = guard.session.as_mut().unwrap();
+    serve_commands(
+        socket,
+        server,
+        imported_device,
+        peer,
+        inactivity_timeout,
+        session,
+    )
+    .await
+}
+
+async fn serve_commands<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    imported_device: &mut Option<Arc<UsbDevice>>,
+    peer: Option<SocketAddr>,
+    inactivity_timeout: Option<Duration>,
+    session: &mut SessionTracker,
+) -> Result<()> {
+    // replies are written while the next command is read
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let socket = &mut writer;
+    // negotiated with OP_REQ_COMPRESSION
+    let mut compressed = false;
+    // NAKs paced by the timer, it needs a runtime
+    let pace_naks = tokio::runtime::Handle::try_current().is_ok();
+    let mut deferred = DeferredReplies::default();
+    let mut unplugged = server.unplugged.subscribe();
+    // URBs submitted since the last import, see `with_max_unimported_submits`
+    let mut unimported_submits = 0;
+    loop {
+        if session.update(imported_device.as_ref()) {
+            server.update_active_import(session.record.connection, session.active_import());
+        }
+        if imported_device.is_some() {
+            unimported_submits = 0;
+        }
+        let read = async {
+            match inactivity_timeout {
+                Some(timeout) => {
+                    match tokio::time::timeout(timeout, UsbIpCommand::read_from_socket(&mut reader))
+                        .await
+                    {
+                        Ok(res) => res,
+                        Err(_) => Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            format!("No traffic from client in {timeout:?}"),
+                        )),
+                    }
+                }
+                None => UsbIpCommand::read_from_socket(&mut reader).await,
+            }
+        };
+        let mut read = std::pin::pin!(read);
+        let command = loop {
+            tokio::select! {
+                res = &mut read => break res,
+                _ = server.wait_unplugged(&mut unplugged, imported_device.as_ref()) => {
+                    // already available again
+                    let device = imported_device.take().unwrap();
+                    info!("Device {} was unplugged, closing connection", device.bus_id);
+                    return Ok(());
+                }
+                _ = deferred.due() => {
+                    for reply in deferred.take_due() {
+                        write_urb_reply(socket, reply, compressed).await?;
+                    }
+                }
+            }
+        };
+        let command = match command {
+            Ok(c) => c,
+            Err(err) => {
+                if let Some(dev) = imported_device.take() {
+                    server.release(dev).await;
+                }
+                if err.kind() == ErrorKind::UnexpectedEof {
+                    info!("Remote closed the connection");
+                    return Ok(());
+                } else if let Some(e) = err
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<UnsupportedVersion>())
+                {
+                    warn!(
+                        "Rejecting request of unsupported version {:#06x}",
+                        e.version
+                    );
+                    let reply = match e.command {
+                        OP_REQ_DEVLIST => Some(UsbIpResponse::op_rep_devlist_fail()),
+                        OP_REQ_IMPORT => Some(UsbIpResponse::op_rep_import_fail()),
+                        OP_REQ_COMPRESSION => Some(UsbIpResponse::op_rep_compression(None)),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        reply.write_to_socket(socket).await?;
+                    }
+                    return Ok(());
+                } else if err.kind() == ErrorKind::InvalidData {
+                    // the stream can't be resynchronized after a malformed command
+                    warn!("Closing connection after malformed command: {err}");
+                    return Ok(());
+                } else {
+                    return Err(err);
+                }
+            }
+        };
+
+        match command {
+            UsbIpCommand::OpReqDevlist { .. } => {
+                server
+                    .write_op_rep_devlist_to(socket, peer.as_ref())
+                    .await?;
+            }
+            UsbIpCommand::OpReqImport { busid, .. } => {
+                if server.hidden_from(peer.as_ref(), &busid).await {
+                    debug!("Device hidden from {peer:?}, refusing import");
+                    UsbIpResponse::op_rep_import_fail()
+                        .write_to_socket(socket)
+                        .await?;
+                    continue;
+                }
+                match server.handle_op_req_import(busid, imported_device).await {
+                    Ok(r) => {
+                        r.write_to_socket(socket).await?;
+                    }
+                    Err(e) => {
+                        error!("UsbipCommand OpReqImport handling error: {e:?}");
+                        if let Some(dev) = imported_device.take() {
+                            server.release(dev).await;
+                        }
+                    }
+                }
+                info!("Imported device: {imported_device:?}");
+            }
+            UsbIpCommand::OpReqCompression { algorithms } => {
+                let selected = (server.compression && algorithms & COMPRESSION_LZ4 != 0)
+                    .then_some(COMPRESSION_LZ4);
+                debug!("Compression negotiated: {selected:?}");
+                compressed = selected.is_some();
+                UsbIpResponse::op_rep_compression(selected)
+                    .write_to_socket(socket)
+                    .await?;
+            }
+            UsbIpCommand::UsbIpCmdSubmit {
+                header,
+                transfer_flags,
+                transfer_buffer_length,
+                setup,
+                data,
+                ..
+            } => {
+                let device = match imported_device.as_ref() {
+                    Some(d) => d,
+                    None if server.unimported_submit == UnimportedSubmit::Close => {
+                        warn!("URB submitted before importing a device, closing connection");
+                        return Ok(());
+                    }
+                    None => {
+                        warn!("URB {} submitted before importing a device", header.seqnum);
+                        server.counters.urbs_failed.fetch_add(1, Ordering::Relaxed);
+                        let header = UsbIpHeaderBasic {
+                            command: USBIP_RET_SUBMIT.into(),
+                            seqnum: header.seqnum,
+                            devid: 0,
+                            direction: 0,
+                            ep: 0,
+                        };
+                        UsbIpResponse::usbip_ret_submit_fail(&header, UrbStatus::ENODEV, 0)
+                            .write_to_socket(socket)
+                            .await?;
+                        unimported_submits += 1;
+                        if server
+                            .max_unimported_submits
+                            .is_some_and(|max| unimported_submits >= max)
+                        {
+                            warn!(
+                                "{unimported_submits} URBs submitted without import, closing connection"
+                            );
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
+                if !server.holds(device).await {
+                    // detached or replaced meanwhile, the client has to import it again
+                    info!(
+                        "Device {} was detached or replaced, closing connection",
+                        device.bus_id
+                    );
+                    imported_device.take();
+                    return Ok(());
+                }
+                let seqnum = header.seqnum;
+                let ep = (header.devid, header.direction, header.ep);
+                // nothing to report: don't NAK faster than the endpoint is polled
+                let nak_interval = match header.direction {
+                    1 if pace_naks => device
+                        .downstream(header.devid)
+                        .as_deref()
+                        .unwrap_or(device)
+                        .interrupt_in_interval(header.ep as u8 | 0x80),
+                    _ => None,
+                };
+                let start = tokio::time::Instant::now();
+                let unconfigure = server.auto_release
+                    && header.ep == 0
+                    && header.direction == 0
+                    && SetupPacket::parse(&setup).is_unconfigure()
+                    && device.downstream(header.devid).is_none();
+                match server
+                    .submit_on_pool(
+                        header,
+                        transfer_flags,
+                        transfer_buffer_length,
+                        setup,
+                        data,
+                        device,
+                    )
+                    .await
+                {
+                    Ok(r) => {
+                        if let UsbIpResponse::UsbIpRetSubmit {
+                            status: 0,
+                            actual_length: 0,
+                            ..
+                        } = r
+                            && let Some(interval) = nak_interval
+                        {
+                            deferred.defer_nak(ep, seqnum, r, start, interval);
+                            continue;
+                        }
+                        // released before the reply, so the client can import it again right away
+                        if unconfigure
+                            && matches!(r, UsbIpResponse::UsbIpRetSubmit { status: 0, .. })
+                            && let Some(dev) = imported_device.take()
+                        {
+                            info!("All interfaces of {} released by the client", dev.bus_id);
+                            server.release(dev).await;
+                        }
+                        if let Some(r) = deferred.queue(ep, seqnum, r) {
+                            write_urb_reply(socket, r, compressed).await?;
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        // the stream can't be resynchronized after a malformed command
+                        warn!("Closing connection after malformed command: {e}");
+                        if let Some(dev) = imported_device.take() {
+                            server.release(dev).await;
+                        }
+                        return Ok(());
+                    }
+                    Err(e) => error!("UsbipCmdSubmit handling error: {e:?}"),
+                }
+            }
+            UsbIpCommand::UsbIpCmdUnlink {
+                header,
+                unlink_seqnum,
+            } => match server.handle_usbip_cmd_unlink(
+                header,
+                unlink_seqnum,
+                // URBs are handled in order, only those with a deferred reply are in flight
+                !deferred.cancel(unlink_seqnum),
+            ) {
+                Ok(r) => {
+                    r.write_to_socket(socket).await?;
+                }
+                Err(e) => error!("UsbipCmdUnlink handling error: {e:?}"),
+            },
+        }
+    }
+}
+
+/// Write the `reply` to a URB, with its data compressed if negotiated
+async fn write_urb_reply<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    reply: UsbIpResponse,
+    compressed: bool,
+) -> Result<()> {
+    match compressed {
+        #[cfg(feature = "compression")]
+        true => reply.write_to_socket_compressed(socket).await?,
+        _ => reply.write_to_socket(socket).await?,
+    }
+    if let UsbIpResponse::UsbIpRetSubmit {
+        transfer_buffer, ..
+    } = reply
+    {
+        buffer_pool().give(transfer_buffer);
+    }
+    Ok(())
+}
+
+/// Spawn a USB/IP server at `addr` using [TcpListener]
+pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
+    let listener = server.bind_listener(addr).expect("bind to addr");
+    if let Err(err) = server_from_listener(listener, server).await {
+        error!("Stopped accepting connections: {err}");
+    }
+}
+
+/// Run a USB/IP server on a `listener` bound by the caller, e.g. passed by systemd socket activation
+///
+/// A listener from a raw file descriptor is built with `FromRawFd`. The
+/// listener options of the server, like [UsbIpServer::with_listen_backlog],
+/// are up to the caller. Returns when accepting a connection fails.
+pub async fn server_from_listener(
+    listener: std::net::TcpListener,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+
+    loop {
+        let (socket, addr) = listener.accept().await?;
+        info!("Got connection from {addr:?}");
+        server.configure_socket(socket2::SockRef::from(&socket));
+        let new_server = server.clone();
+        tokio::spawn(async move {
+            let res = serve_connection_from(socket, addr, new_server).await;
+            info!("Handler ended with {res:?}");
+        });
+    }
+}
+
+/// Serve one client over an established `stream`, e.g. a WebSocket or an SSH channel
+///
+/// The device imported by the client is released when the session ends.
+pub async fn serve_connection<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: T,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    serve_session(stream, None, server).await
+}
+
+/// Like [serve_connection], for a client at `peer`, see [UsbIpServer::with_devlist_filter]
+pub async fn serve_connection_from<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: T,
+    peer: SocketAddr,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    serve_session(stream, Some(peer), server).await
+}
+
+async fn serve_session<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    mut stream: T,
+    peer: Option<SocketAddr>,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    let mut guard = ReleaseGuard {
+        server: server.clone(),
+        device: None,
+        session: None,
+    };
+    let inactivity_timeout = server.inactivity_timeout;
+    let res = handle_connection(
+        &mut stream,
+        server.clone(),
+        &mut guard.device,
+        peer,
+        inactivity_timeout,
+    )
+    .await;
+    if let Some(dev) = guard.device.take() {
+        server.release(dev).await;
+    }
+    res
+}
+
+/// Releases the imported device of a session that didn't end normally, and records the session
+///
+/// A panicking handler or an aborted task drops the session without running
+/// its cleanup, this hands the device back to the server in the background,
+/// or right away without a runtime.
+struct ReleaseGuard {
+    server: Arc<UsbIpServer>,
+    device: Option<Arc<UsbDevice>>,
+    session: Option<SessionTracker>,
+}
+
+impl Drop for ReleaseGuard {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            let connection = session.record.connection;
+            self.server.update_active_import(connection, None);
+            self.server.record_session(session.finish());
+            self.server
+                .events
+                .send(ServerEvent::ClientDisconnected { connection });
+        }
+        let Some(dev) = self.device.take() else {
+            return;
+        };
+        warn!("Session ended abnormally, releasing {}", dev.bus_id);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let server = self.server.clone();
+                runtime.spawn(async move { server.release(dev).await });
+            }
+            // on a thread of the blocking server
+            Err(_) => crate::util::block_on(self.server.release(dev)),
+        }
+    }
+}
+
+impl UsbIpServer {
+    /// Apply the TCP options of the server to an accepted connection
+    pub(crate) fn configure_socket(&self, socket: socket2::SockRef<'_>) {
+        if let Err(e) = socket.set_tcp_nodelay(!self.tcp_delay) {
+            warn!("Failed to set TCP_NODELAY: {e}");
+        }
+        if let Some(idle) = self.tcp_keepalive {
+            set_tcp_keepalive(socket, idle);
+        }
+    }
+}
+
+fn set_tcp_keepalive(socket: socket2::SockRef<'_>, idle: Duration) {
+    let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+    if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+        warn!("Failed to enable TCP keepalive: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpStream, task::JoinSet};
+
+    use super::*;
+    use crate::usbip_protocol::USBIP_CMD_UNLINK;
+    use crate::util::tests::*;
+
+    const SINGLE_DEVICE_BUSID: &str = "0-0-0";
+
+    fn op_req_import(busid: &str) -> Vec<u8> {
+        let mut busid = busid.to_string().as_bytes().to_vec();
+        busid.resize(32, 0);
+        UsbIpCommand::OpReqImport {
+            status: 0,
+            busid: busid.try_into().unwrap(),
+        }
+        .to_bytes()
+    }
+
+    async fn attach_device(
+        connection: &mut TcpStream,
+        busid: &str,
+    ) -> usbip_client::ImportedDevice {
+        usbip_client::import(connection, busid).await.unwrap()
+    }
+
+    /// An interrupt endpoint that never has data ready
+    #[derive(Debug)]
+    struct IdleInterruptHandler;
+
+    impl UsbInterfaceHandler for IdleInterruptHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Err(std::io::Error::new(ErrorKind::TimedOut, "No data ready"))
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn empty_interrupt_in_naks() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            None,
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(IdleInterruptHandler))),
+        );
+        let header = submit_header(0, 1, 1);
+        let mut reply = header.clone();
+        reply.command = USBIP_RET_SUBMIT.into();
+        reply.ep = 0;
+        reply.direction = 0;
+
+        let server = UsbIpServer::new_simulated(vec![]);
+        let resp = server
+            .handle_usbip_cmd_submit(header.clone(), 0, 8, [0; 8], vec![], &device)
+            .unwrap();
+        let nak = UsbIpResponse::usbip_ret_submit_success(&reply, 0, 0, vec![], vec![]);
+        assert_eq!(resp.to_bytes(), nak.to_bytes());
+
+        let server = UsbIpServer::new_simulated(vec![]).with_interrupt_in_timeout_failures();
+        let resp = server
+            .handle_usbip_cmd_submit(header, 0, 8, [0; 8], vec![], &device)
+            .unwrap();
+        let fail = UsbIpResponse::usbip_ret_submit_fail(&reply, UrbStatus::ETIMEDOUT, 0);
+        assert_eq!(resp.to_bytes(), fail.to_bytes());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interrupt_in_naks_paced_by_interval() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 20,
+        };
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::HID as u8,
+            0x00,
+            0x00,
+            None,
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(IdleInterruptHandler))),
+        );
+        device.speed = UsbSpeed::Full as u32;
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(stream, server));
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        let submit = |seqnum, ep, transfer_buffer_length, setup| {
+            UsbIpCommand::UsbIpCmdSubmit {
+                header: UsbIpHeaderBasic {
+                    seqnum,
+                    ..submit_header(0, 1, ep)
+                },
+                transfer_flags: 0,
+                transfer_buffer_length,
+                start_frame: 0,
+                number_of_packets: 0,
+                interval: 0,
+                setup,
+                data: vec![],
+                iso_packet_descriptor: vec![],
+            }
+            .to_bytes()
+        };
+        // seqnum and actual_length of the next RET_SUBMIT
+        async fn read_reply(client: &mut tokio::io::DuplexStream) -> (u32, u32) {
+            let mut reply = [0; 48];
+            client.read_exact(&mut reply).await.unwrap();
+            let actual_length = u32::from_be_bytes(reply[24..28].try_into().unwrap());
+            let mut data = vec![0; actual_length as usize];
+            client.read_exact(&mut data).await.unwrap();
+            (
+                u32::from_be_bytes(reply[4..8].try_into().unwrap()),
+                actual_length,
+            )
+        }
+
+        let start = tokio::time::Instant::now();
+        for seqnum in 1..=3 {
+            client
+                .write_all(&submit(seqnum, 1, 8, [0; 8]))
+                .await
+                .unwrap();
+        }
+        let get_status = [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        client
+            .write_all(&submit(4, 0, 2, get_status))
+            .await
+            .unwrap();
+        // other URBs are served meanwhile
+        assert_eq!(read_reply(&mut client).await, (4, 2));
+        assert!(start.elapsed() < Duration::from_millis(20));
+        // NAKs come at most once per interval, in order
+        for seqnum in 1..=3 {
+            assert_eq!(read_reply(&mut client).await, (seqnum, 0));
+            assert!(start.elapsed() >= Duration::from_millis(20) * seqnum);
+        }
+
+        // unlinked before its NAK was due
+        client.write_all(&submit(5, 1, 8, [0; 8])).await.unwrap();
+        let unlink = UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum: 6,
+                ..submit_header(0, 0, 0)
+            },
+            unlink_seqnum: 5,
+        };
+        client.write_all(&unlink.to_bytes()).await.unwrap();
+        let mut reply = [0; 48];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], u32::from(USBIP_RET_UNLINK).to_be_bytes());
+        assert_eq!(reply[20..24], UrbStatus::ECONNRESET.to_be_bytes());
+        client
+            .write_all(&submit(7, 0, 2, get_status))
+            .await
+            .unwrap();
+        assert_eq!(read_reply(&mut client).await, (7, 2));
+    }
+
+    fn control_out(setup: SetupPacket, data: Vec<u8>, device: &UsbDevice) -> UsbIpResponse {
+        let header = submit_header(0, 0, 0);
+        UsbIpServer::new_simulated(vec![])
+            .handle_usbip_cmd_submit(header, 0, data.len() as u32, setup.to_bytes(), data, device)
+            .unwrap()
+    }
+
+    fn ret_submit_out(actual_length: u32) -> Vec<u8> {
+        let reply = UsbIpHeaderBasic {
+            command: USBIP_RET_SUBMIT.into(),
+            seqnum: 1,
+            devid: 0,
+            direction: 0,
+            ep: 0,
+        };
+        UsbIpResponse::usbip_ret_submit_success(&reply, 0, actual_length, vec![], vec![]).to_bytes()
+    }
+
+    /// Bulk IN endpoint 1 returns 4 bytes, endpoint 2 stalls
+    #[derive(Debug)]
+    struct HalfStalledHandler;
+
+    impl UsbInterfaceHandler for HalfStalledHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            match ep.address {
+                0x81 => Ok(vec![1, 2, 3, 4]),
+                _ => Err(std::io::Error::new(ErrorKind::Unsupported, "Stalled")),
+            }
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoint_stats() {
+        setup_test_logger();
+        let endpoints = [0x81, 0x82]
+            .into_iter()
+            .map(|address| UsbEndpoint {
+                address,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            })
+            .collect();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            endpoints,
+            Arc::new(Mutex::new(Box::new(HalfStalledHandler))),
+        );
+        let server = UsbIpServer::new_simulated(vec![device]);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        for ep in [1, 2, 1] {
+            let header = submit_header(0, 1, ep);
+            server
+                .handle_usbip_cmd_submit(header, 0, 512, [0; 8], vec![], &device)
+                .unwrap();
+        }
+
+        let stats = server.endpoint_stats(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(
+            stats[&0x81],
+            EndpointStats {
+                submits: 2,
+                failures: 0,
+                bytes: 8,
+                last_error: None,
+            }
+        );
+        assert_eq!(
+            stats[&0x82],
+            EndpointStats {
+                submits: 1,
+                failures: 1,
+                bytes: 0,
+                last_error: Some((UrbStatus::EPIPE, "Stalled".to_string())),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn allowed_interfaces() {
+        setup_test_logger();
+        let device = UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::MassStorage as u8, 0x06, 0x50, |intf| {
+                intf.on_bulk_in(0x81, 512, |_len| Ok(vec![1; 4]))
+                    .on_control(|_setup, _req| Ok(vec![1]))
+            })
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                intf.on_bulk_in(0x82, 512, |_len| Ok(vec![2; 4]))
+                    .on_control(|_setup, _req| Ok(vec![2]))
+            })
+            .build();
+        let server = UsbIpServer::new_simulated(vec![device])
+            .with_allowed_interfaces(SINGLE_DEVICE_BUSID, &[0]);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        let submit =
+            |ep, setup| submit(&server, &device, submit_header(0, 1, ep), 4, setup, vec![]).0;
+        let to_interface = |index| SetupPacket {
+            request_type: 0b11000001, // vendor, interface
+            request: 0x01,
+            value: 0,
+            index,
+            length: 1,
+        };
+
+        assert_eq!(submit(1, to_interface(0)), 0);
+        assert_eq!(submit(2, to_interface(0)), UrbStatus::EPIPE);
+        assert_eq!(submit(0, to_interface(0)), 0);
+        assert_eq!(submit(0, to_interface(1)), UrbStatus::EPIPE);
+        // to the device
+        let get_status = SetupPacket {
+            request_type: 0b10000000,
+            request: StandardRequest::GetStatus as u8,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        assert_eq!(submit(0, get_status), 0);
+    }
+
+    #[test]
+    fn read_only_device() {
+        setup_test_logger();
+        let written = Arc::new(Mutex::new(vec![]));
+        let mut other = UsbDevice::new(1);
+        other.bus_id = "0-0-1".to_string();
+        let devices = [UsbDevice::new(0), other].map(|device| {
+            let written = written.clone();
+            UsbDeviceBuilder::new(device)
+                .with_interface(ClassCode::MassStorage as u8, 0x06, 0x50, |intf| {
+                    intf.on_bulk_in(0x81, 512, |len| Ok(vec![0; len as usize]))
+                        .on_bulk_out(0x02, 512, move |data| {
+                            written.lock().unwrap().push(data[15]);
+                            Ok(())
+                        })
+                })
+                .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                    intf.on_bulk_out(0x03, 512, |_data| Ok(()))
+                })
+                .build()
+        });
+        let server =
+            UsbIpServer::new_simulated(devices.to_vec()).with_read_only(SINGLE_DEVICE_BUSID);
+        // the mode parameter header following a MODE SENSE(6)
+        let mode_parameter_header = |device| {
+            let header = submit_header(0, 1, 1);
+            submit(&server, device, header, 4, SetupPacket::default(), vec![])
+        };
+        let submit = |device: &UsbDevice, direction, ep, data: Vec<u8>| {
+            let header = submit_header(0, direction, ep);
+            let len = if direction == 0 {
+                data.len() as u32
+            } else {
+                512
+            };
+            submit(&server, device, header, len, SetupPacket::default(), data).0
+        };
+        // command block wrapper of `opcode`
+        let cbw = |opcode| {
+            let mut cbw = vec![0; 31];
+            cbw[..4].copy_from_slice(b"USBC");
+            cbw[15] = opcode;
+            cbw
+        };
+        const READ_10: u8 = 0x28;
+        const WRITE_10: u8 = 0x2A;
+        const MODE_SELECT_6: u8 = 0x15;
+
+        let [read_only, writable] = &devices;
+        assert_eq!(submit(read_only, 0, 2, cbw(READ_10)), 0);
+        assert_eq!(submit(read_only, 1, 1, vec![]), 0);
+        assert_eq!(submit(read_only, 0, 2, cbw(WRITE_10)), UrbStatus::EPIPE);
+        // only known read-only commands pass, and no data to the device
+        assert_eq!(
+            submit(read_only, 0, 2, cbw(MODE_SELECT_6)),
+            UrbStatus::EPIPE
+        );
+        assert_eq!(submit(read_only, 0, 2, vec![0; 512]), UrbStatus::EPIPE);
+        assert_eq!(submit(read_only, 0, 3, vec![1, 2, 3]), UrbStatus::EPIPE);
+        // write-protected
+        assert_eq!(submit(read_only, 0, 2, cbw(MODE_SENSE_6)), 0);
+        assert_eq!(mode_parameter_header(read_only), (0, vec![0, 0, 0x80, 0]));
+        assert_eq!(mode_parameter_header(read_only), (0, vec![0; 4]));
+        assert_eq!(*written.lock().unwrap(), vec![READ_10, MODE_SENSE_6]);
+
+        assert_eq!(submit(writable, 0, 2, cbw(WRITE_10)), 0);
+        assert_eq!(submit(writable, 0, 3, vec![1, 2, 3]), 0);
+        assert_eq!(submit(writable, 0, 2, cbw(MODE_SENSE_6)), 0);
+        assert_eq!(mode_parameter_header(writable), (0, vec![0; 4]));
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![READ_10, MODE_SENSE_6, WRITE_10, MODE_SENSE_6]
+        );
+    }
+
+    /// Bulk IN endpoints failing with a transient error `failures` times, then returning 4 bytes
+    #[derive(Debug)]
+    struct FlakyHandler {
+        failures: u32,
+    }
+
+    impl UsbInterfaceHandler for FlakyHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::other(nusb::transfer::TransferError::Fault));
+            }
+            Ok(vec![1, 2, 3, 4])
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_retries() {
+        setup_test_logger();
+        for (retries, pool, status) in [
+            (2, true, 0),
+            (1, true, UrbStatus::EPROTO),
+            (0, true, UrbStatus::EPROTO),
+            // on the default pool
+            (2, false, 0),
+            (0, false, UrbStatus::EPROTO),
+        ] {
+            let device = UsbDevice::new(0).with_interface(
+                ClassCode::VendorSpecific as u8,
+                0,
+                0,
+                None,
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                }],
+                Arc::new(Mutex::new(Box::new(FlakyHandler { failures: 2 }))),
+            );
+            let mut server = UsbIpServer::new_simulated(vec![device])
+                .with_transfer_retries(retries, Duration::from_millis(1));
+            if pool {
+                server = server.with_transfer_pool(1);
+            }
+            assert_eq!(server.transfer_pool.is_some(), pool || retries > 0);
+            let server = Arc::new(server);
+            let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+            let header = submit_header(0, 1, 1);
+            let resp = server
+                .submit_on_pool(header, 0, 512, [0; 8], vec![], &device)
+                .await
+                .unwrap();
+            let UsbIpResponse::UsbIpRetSubmit {
+                status: actual_status,
+                actual_length,
+                ..
+            } = resp
+            else {
+                panic!("Unexpected response {resp:?}");
+            };
+            assert_eq!(
+                actual_status as i32, status,
+                "with {retries} retries, pool: {pool}"
+            );
+            assert_eq!(actual_length, if status == 0 { 4 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn submit_to_unknown_endpoint() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let reply = UsbIpHeaderBasic {
+            command: USBIP_RET_SUBMIT.into(),
+            seqnum: 1,
+            devid: 0,
+            direction: 0,
+            ep: 0,
+        };
+        for (behavior, status) in [
+            (UnknownEndpoint::Stall, UrbStatus::EPIPE),
+            (UnknownEndpoint::Fail, UrbStatus::ENOENT),
+        ] {
+            let server = UsbIpServer::new_simulated(vec![]).with_unknown_endpoint(behavior);
+            // bulk OUT to ep 3 with data
+            let header = submit_header(0, 0, 3);
+            let resp = server
+                .handle_usbip_cmd_submit(header, 0, 4, [0; 8], vec![1, 2, 3, 4], &device)
+                .unwrap();
+            let expected = UsbIpResponse::usbip_ret_submit_fail(&reply, status, 0);
+            assert_eq!(resp.to_bytes(), expected.to_bytes());
+            assert_eq!(server.stats().urbs_failed, 1);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resolve_host_location() {
+        setup_test_logger();
+        use std::path::Path;
+        // as listed by the host: port, bus, device
+        let host = || {
+            vec![
+                ("usb1".to_string(), 1, 1),
+                ("1-2".to_string(), 1, 4),
+                ("1-2.3".to_string(), 1, 7),
+                ("2-1".to_string(), 2, 4),
+            ]
+        };
+        let describe = |dev: &(String, u8, u8)| dev.clone();
+        let find = |location: HostLocation| location.find(host(), describe).map(|dev| dev.0);
+
+        for path in [
+            "1-2.3",
+            "/sys/bus/usb/devices/1-2.3",
+            "/dev/bus/usb/001/007",
+        ] {
+            assert_eq!(
+                find(HostLocation::from_path(Path::new(path))).unwrap(),
+                "1-2.3"
+            );
+        }
+        assert_eq!(find(HostLocation::BusDev(2, 4)).unwrap(), "2-1");
+        assert_eq!(find(HostLocation::BusDev(1, 4)).unwrap(), "1-2");
+
+        let err = find(HostLocation::from_path(Path::new("3-1"))).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = find(HostLocation::BusDev(2, 7)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn max_transfer_size_per_type() {
+        setup_test_logger();
+        let ep = |address, attributes: EndpointAttributes, max_packet_size| UsbEndpoint {
+            address,
+            attributes: attributes as u8,
+            max_packet_size,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![
+                ep(0x81, EndpointAttributes::Interrupt, 8),
+                ep(0x02, EndpointAttributes::Bulk, 512),
+            ],
+            Arc::new(Mutex::new(
+                Box::new(vendor::VendorControlHandler::default()),
+            )),
+        );
+        let server = UsbIpServer::new_simulated(vec![])
+            .with_max_transfer_size(EndpointAttributes::Interrupt, 64)
+            .with_max_transfer_size(EndpointAttributes::Bulk, 4096);
+        let submit = |direction, ep, len| {
+            let header = submit_header(0, direction, ep);
+            let data = if direction == 0 {
+                vec![0; len as usize]
+            } else {
+                vec![]
+            };
+            submit(&server, &device, header, len, SetupPacket::default(), data).0
+        };
+
+        assert_eq!(submit(1, 1, 1024), UrbStatus::EMSGSIZE);
+        assert_eq!(submit(0, 2, 4096), 0);
+        assert_eq!(submit(0, 2, 4097), UrbStatus::EINVAL);
+        let stats = device.endpoint_stats();
+        assert_eq!(stats[&0x81].failures, 1);
+        assert_eq!(stats[&0x02].failures, 1);
+        assert_eq!(server.stats().urbs_failed, 2);
+    }
+
+    /// Records the timeout a host device would give each transfer
+    #[derive(Debug, Default)]
+    struct TimeoutRecorder {
+        timeouts: TransferTimeouts,
+        used: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    impl UsbInterfaceHandler for TimeoutRecorder {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.used
+                .lock()
+                .unwrap()
+                .push(self.timeouts.for_endpoint(&ep));
+            Ok(vec![])
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn set_transfer_timeouts(&mut self, timeouts: TransferTimeouts) {
+            self.timeouts = timeouts;
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_timeout_per_type() {
+        setup_test_logger();
+        let handler = TimeoutRecorder::default();
+        let used = handler.used.clone();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![UsbEndpoint {
+                address: 0x02,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            Arc::new(Mutex::new(Box::new(handler))),
+        );
+        let server = UsbIpServer::new_simulated(vec![device])
+            .with_transfer_timeout(EndpointAttributes::Control, Duration::from_millis(100))
+            .with_transfer_timeout(EndpointAttributes::Bulk, Duration::from_secs(10));
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        let submit = |direction, ep, setup, data: Vec<u8>| {
+            let header = submit_header(0, direction, ep);
+            let len = if direction == 0 { data.len() as u32 } else { 2 };
+            assert_eq!(submit(&server, &device, header, len, setup, data).0, 0);
+        };
+
+        // vendor request to the interface, then bulk OUT
+        let setup = SetupPacket {
+            request_type: 0b11000001,
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        submit(1, 0, setup, vec![]);
+        submit(0, 2, SetupPacket::default(), vec![0; 4]);
+        assert_eq!(
+            *used.lock().unwrap(),
+            vec![Duration::from_millis(100), Duration::from_secs(10)]
+        );
+    }
+
+    #[test]
+    fn transfer_timeout_override() {
+        setup_test_logger();
+        let recorder = |bus_id: &str| {
+            let handler = TimeoutRecorder::default();
+            let used = handler.used.clone();
+            let mut device = UsbDevice::new(0).with_interface(
+                ClassCode::VendorSpecific as u8,
+                0,
+                0,
+                None,
+                vec![UsbEndpoint {
+                    address: 0x02,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                }],
+                Arc::new(Mutex::new(Box::new(handler))),
+            );
+            device.bus_id = bus_id.to_string();
+            (device, used)
+        };
+        let (updated, updated_used) = recorder("1-1");
+        let (other, other_used) = recorder("1-2");
+        let server = UsbIpServer::new_simulated(vec![])
+            .with_transfer_timeout(EndpointAttributes::Bulk, Duration::from_secs(2));
+        let submit = |device: &UsbDevice| {
+            let header = submit_header(0, 0, 2);
+            server
+                .handle_usbip_cmd_submit(header, 0, 4, [0; 8], vec![0; 4], device)
+                .unwrap();
+        };
+
+        // around the erase command of a firmware update
+        submit(&updated);
+        server.override_transfer_timeout("1-1", Duration::from_secs(60));
+        submit(&updated);
+        submit(&other);
+        server.clear_transfer_timeout_override("1-1");
+        submit(&updated);
+
+        let secs = |used: Arc<Mutex<Vec<Duration>>>| {
+            let used = used.lock().unwrap();
+            used.iter().map(Duration::as_secs).collect::<Vec<_>>()
+        };
+        assert_eq!(secs(updated_used), vec![2, 60, 2]);
+        assert_eq!(secs(other_used), vec![2]);
+    }
+
+    #[test]
+    fn control_out_with_data() {
+        setup_test_logger();
+        let written = Arc::new(Mutex::new(vec![]));
+        let handler = crate::vendor::VendorControlHandler::default().with_callback({
+            let written = written.clone();
+            move |_setup, req| {
+                *written.lock().unwrap() = req.to_vec();
+                // echoed, but not sent back for OUT
+                Ok(req.to_vec())
+            }
+        });
+        let device = UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+
+        // larger than ep0's max packet size, sent in one CMD_SUBMIT
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let setup = SetupPacket {
+            request_type: 0b01000000, // vendor, device
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: data.len() as u16,
+        };
+        let resp = control_out(setup, data.clone(), &device);
+        assert_eq!(resp.to_bytes(), ret_submit_out(300));
+        assert_eq!(*written.lock().unwrap(), data);
+    }
+
+    #[test]
+    fn separate_control_status_stage() {
+        setup_test_logger();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler = crate::vendor::VendorControlHandler::default().with_callback({
+            let calls = calls.clone();
+            move |_setup, _req| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Ok(vec![1, 2, 3])
+            }
+        });
+        let device = UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let server = UsbIpServer::new_simulated(vec![]);
+        let setup = SetupPacket {
+            request_type: 0b11000000, // vendor, device
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 3,
+        };
+        let submit = |direction, len| {
+            let header = submit_header(0, direction, 0);
+            server
+                .handle_usbip_cmd_submit(header, 0, len, setup.to_bytes(), vec![], &device)
+                .unwrap()
+        };
+
+        // data stage
+        match submit(1, 3) {
+            UsbIpResponse::UsbIpRetSubmit {
+                status: 0,
+                transfer_buffer,
+                ..
+            } => assert_eq!(transfer_buffer, vec![1, 2, 3]),
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+        // status stage, an OUT ZLP
+        match submit(0, 0) {
+            UsbIpResponse::UsbIpRetSubmit {
+                status: 0,
+                actual_length: 0,
+                ..
+            } => {}
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // without a data stage before, it is a request of its own
+        submit(0, 0);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn control_out_without_data() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let setup = SetupPacket {
+            request_type: 0b00000000,
+            request: StandardRequest::SetConfiguration as u8,
+            value: 1,
+            index: 0,
+            length: 0,
+        };
+        // only the status stage
+        let resp = control_out(setup, vec![], &device);
+        assert_eq!(resp.to_bytes(), ret_submit_out(0));
+    }
+
+    #[tokio::test]
+    async fn control_only_device() {
+        setup_test_logger();
+        let handler = crate::vendor::VendorControlHandler::new(HashMap::from([(
+            (0x01, 0, 0),
+            vec![1, 2, 3],
+        )]));
+        let device = UsbDevice::new_control_only(0, Arc::new(Mutex::new(Box::new(handler))));
+        assert_eq!(device.validate(), Ok(()));
+        let desc = device.configuration_descriptor_bytes(0);
+        assert_eq!(desc[4], 1, "bNumInterfaces");
+        assert_eq!(desc.len(), 9 + 9);
+        assert_eq!(desc[9 + 4], 0, "bNumEndpoints");
+
+        let server = UsbIpServer::new_simulated(vec![device]);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert!(device.find_ep(0x80).is_some_and(|(ep, _)| ep.is_ep0()));
+        assert!(device.find_ep(0x00).is_some_and(|(ep, _)| ep.is_ep0()));
+        // vendor requests, to the device then to its interface
+        for request_type in [0b11000000, 0b11000001] {
+            let header = submit_header(0, 1, 0);
+            let setup = SetupPacket {
+                request_type,
+                request: 0x01,
+                value: 0,
+                index: 0,
+                length: 64,
+            };
+            let resp = submit(&server, &device, header, 64, setup, vec![]);
+            assert_eq!(resp, (0, vec![1, 2, 3]));
+        }
+    }
+
+    /// Panics on any transfer to a data endpoint
+    #[derive(Debug)]
+    pub(crate) struct PanickingHandler;
+
+    impl UsbInterfaceHandler for PanickingHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            panic!("Handler bug");
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_handler_releases_device() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            Arc::new(Mutex::new(Box::new(PanickingHandler))),
+        );
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let session = tokio::spawn(serve_connection(stream, server_.clone()));
+
+        client
+            .write_all(&op_req_import(SINGLE_DEVICE_BUSID))
+            .await
+            .unwrap();
+        let mut reply = vec![0; 0x140];
+        client.read_exact(&mut reply).await.unwrap();
+        assert!(server_.is_used(SINGLE_DEVICE_BUSID).await);
+        assert!(server_.port_of(SINGLE_DEVICE_BUSID).await.is_some());
+
+        let req = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 1),
+            transfer_flags: 0,
+            transfer_buffer_length: 512,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        client.write_all(&req.to_bytes()).await.unwrap();
+        assert!(session.await.unwrap_err().is_panic());
+
+        // released in the background
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while server_.is_used(SINGLE_DEVICE_BUSID).await {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(server_.available_devices.read().await.len(), 1);
+        assert_eq!(server_.port_of(SINGLE_DEVICE_BUSID).await, None);
+        // the session is recorded all the same
+        assert!(server_.active_imports().is_empty());
+        let sessions = server_.recent_sessions(1);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].imports.len(), 1);
+    }
+
+    /// Echoes the setup packet it got on its bulk IN endpoint
+    #[derive(Debug)]
+    struct SetupEchoHandler;
+
+    impl UsbInterfaceHandler for SetupEchoHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(setup.to_bytes().to_vec())
+        }
+
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn bulk_setup_is_ignored() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Bulk as u8,
+                max_packet_size: 512,
+                interval: 0,
+            }],
+            Arc::new(Mutex::new(Box::new(SetupEchoHandler))),
+        );
+        let server = UsbIpServer::new_simulated(vec![]);
+        let setup = SetupPacket::parse(&[0xA5; 8]);
+        let resp = submit(&server, &device, submit_header(0, 1, 1), 512, setup, vec![]);
+        assert_eq!(resp, (0, vec![0; 8]));
+    }
+
+    #[tokio::test]
+    async fn paused_device() {
+        setup_test_logger();
+        let written = Arc::new(Mutex::new(vec![]));
+        let device = crate::UsbDeviceBuilder::new(UsbDevice::new(0))
+            .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                let written = written.clone();
+                intf.on_bulk_in(0x81, 512, |_| Ok(vec![1, 2, 3, 4]))
+                    .on_bulk_out(0x01, 512, move |data| {
+                        written.lock().unwrap().push(data.to_vec());
+                        Ok(())
+                    })
+            })
+            .build();
+        let server = UsbIpServer::new_simulated(vec![device]).with_paused_out(PausedOut::Buffer);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        let bulk_in = |server: &UsbIpServer, device: &UsbDevice| {
+            let header = submit_header(0, 1, 1);
+            let (status, data) =
+                submit(server, device, header, 512, SetupPacket::default(), vec![]);
+            assert_eq!(status, 0);
+            data
+        };
+        let bulk_out = |server: &UsbIpServer, device: &UsbDevice, data: Vec<u8>| {
+            let header = submit_header(0, 0, 1);
+            let len = data.len() as u32;
+            submit(server, device, header, len, SetupPacket::default(), data).0
+        };
+
+        server.pause(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert!(bulk_in(&server, &device).is_empty());
+        assert_eq!(bulk_out(&server, &device, vec![1, 2, 3]), 0);
+        assert_eq!(bulk_out(&server, &device, vec![4]), 0);
+        // control requests are never acknowledged before reaching the device
+        let set_configuration = SetupPacket {
+            request_type: 0x00,
+            request: 0x09,
+            value: 1,
+            index: 0,
+            length: 0,
+        };
+        let header = submit_header(0, 0, 0);
+        let (status, _) = submit(&server, &device, header, 0, set_configuration, vec![]);
+        assert_eq!(status, UrbStatus::ETIMEDOUT);
+        // held until resumed
+        assert!(written.lock().unwrap().is_empty());
+
+        server.resume(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(*written.lock().unwrap(), vec![vec![1, 2, 3], vec![4]]);
+        assert_eq!(bulk_in(&server, &device), vec![1, 2, 3, 4]);
+        assert!(server.resume(SINGLE_DEVICE_BUSID).await.is_err());
+
+        // released while paused, the data is not sent to the next client's device
+        server.pause(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(bulk_out(&server, &device, vec![5]), 0);
+        server.release(device).await;
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        server.resume(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(written.lock().unwrap().len(), 2);
+        assert_eq!(bulk_out(&server, &device, vec![6]), 0);
+        assert_eq!(written.lock().unwrap()[2], vec![6]);
+    }
+
+    /// Answers control requests with more data than asked for
+    #[derive(Debug)]
+    struct OverlongHandler;
+
+    impl UsbDeviceHandler for OverlongHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok((0..16).collect())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn control_in_capped_at_w_length() {
+        setup_test_logger();
+        let device =
+            UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(OverlongHandler))));
+        let header = submit_header(0, 1, 0);
+        let setup = SetupPacket {
+            request_type: 0b11000000, // vendor, device
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 4,
+        };
+        let resp = UsbIpServer::new_simulated(vec![])
+            .handle_usbip_cmd_submit(header, 0, 64, setup.to_bytes(), vec![], &device)
+            .unwrap();
+        let UsbIpResponse::UsbIpRetSubmit {
+            status,
+            actual_length,
+            transfer_buffer,
+            ..
+        } = resp
+        else {
+            panic!("Unexpected response {resp:?}");
+        };
+        assert_eq!(status, 0);
+        assert_eq!(actual_length, 4);
+        assert_eq!(transfer_buffer, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn config_descriptor_in_two_reads() {
+        setup_test_logger();
+        let endpoints = crate::cdc::UsbCdcAcmHandler::endpoints();
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::CDC as u8,
+                crate::cdc::CDC_ACM_SUBCLASS,
+                0x01,
+                None,
+                endpoints[..1].to_vec(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new()))),
+            )
+            .with_interface(
+                ClassCode::CDCData as u8,
+                0x00,
+                0x00,
+                None,
+                endpoints[1..].to_vec(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new()))),
+            );
+        let server = UsbIpServer::new_simulated(vec![]);
+        // wValue holds the type and index, clients read the header then the whole of it
+        let read = |length: u16| {
+            let header = submit_header(0, 1, 0);
+            let setup = SetupPacket {
+                request_type: 0b10000000,
+                request: StandardRequest::GetDescriptor as u8,
+                value: (DescriptorType::Configuration as u16) << 8,
+                index: 0,
+                length,
+            };
+            match server
+                .handle_usbip_cmd_submit(
+                    header,
+                    0,
+                    length.into(),
+                    setup.to_bytes(),
+                    vec![],
+                    &device,
+                )
+                .unwrap()
+            {
+                UsbIpResponse::UsbIpRetSubmit {
+                    status: 0,
+                    actual_length,
+                    transfer_buffer,
+                    ..
+                } => {
+                    assert_eq!(actual_length as usize, transfer_buffer.len());
+                    transfer_buffer
+                }
+                resp => panic!("Unexpected response {resp:?}"),
+            }
+        };
+
+        let header = read(9);
+        assert_eq!(header.len(), 9);
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        assert!(total_length > 64, "longer than a packet of ep0");
+        let desc = read(total_length);
+        assert_eq!(desc.len(), total_length as usize);
+        assert_eq!(desc[..9], header);
+        assert_eq!(desc, device.configuration_descriptor_bytes(0));
+        // a larger window gets the descriptor only
+        assert_eq!(read(0xFFFF), desc);
+    }
+
+    #[test]
+    fn rebind_after_restart() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]).with_listen_backlog(16);
+        let listener = server
+            .bind_listener("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // closed by the server first, its side lingers in TIME_WAIT
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        drop(socket);
+        drop(listener);
+        drop(client);
+
+        let listener = server.bind_listener(addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn req_empty_devlist() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]);
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        let mut imported_device: Box<Option<Arc<UsbDevice>>> = Box::new(None);
+        let mut mock_socket = MockSocket::new(req.to_bytes());
+        handler(&mut mock_socket, Arc::new(server), &mut imported_device)
+            .await
+            .ok();
+
+        assert_eq!(
+            mock_socket.output,
+            UsbIpResponse::op_rep_devlist(&[]).to_bytes(),
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_read_errors() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let req = UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes();
+        let reply = UsbIpResponse::op_rep_devlist(&[UsbDevice::new(0)]).to_bytes();
+
+        // a request split across reads, then end of file
+        let mut socket = MockSocket::scripted(vec![
+            MockRead::Data(req[..3].to_vec()),
+            MockRead::Data(req[3..].to_vec()),
+        ]);
+        handler(&mut socket, server.clone(), &mut None)
+            .await
+            .unwrap();
+        assert_eq!(socket.output, reply);
+
+        // the connection reset in the middle of an import
+        let import = op_req_import(SINGLE_DEVICE_BUSID);
+        let mut socket = MockSocket::scripted(vec![
+            MockRead::Data(req.clone()),
+            MockRead::Data(import[..10].to_vec()),
+            MockRead::Error(ErrorKind::ConnectionReset),
+        ]);
+        let err = handler(&mut socket, server.clone(), &mut None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(socket.output, reply);
+        assert!(!server.is_used(SINGLE_DEVICE_BUSID).await);
+    }
+
+    #[tokio::test]
+    async fn req_devlist_unsupported_version() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+        let mut req = UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes();
+        req[..2].copy_from_slice(&0x0100u16.to_be_bytes());
+        let mut imported_device: Box<Option<Arc<UsbDevice>>> = Box::new(None);
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server), &mut imported_device)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock_socket.output,
+            UsbIpResponse::op_rep_devlist_fail().to_bytes(),
+        );
+    }
+
+    #[tokio::test]
+    async fn req_devlist_with_used_devices() {
+        setup_test_logger();
+        let mut used = UsbDevice::new(1);
+        used.bus_id = "0-0-1".to_string();
+        let server = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0), used.clone()])
+                .with_used_devices_in_devlist(),
+        );
+        server.occupy("0-0-1").await.unwrap();
+
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        let mut imported_device: Box<Option<Arc<UsbDevice>>> = Box::new(None);
+        let mut mock_socket = MockSocket::new(req.to_bytes());
+        handler(&mut mock_socket, server, &mut imported_device)
+            .await
+            .ok();
+
+        let expected = UsbIpResponse::op_rep_devlist(&[UsbDevice::new(0), used]).to_bytes();
+        assert_eq!(mock_socket.output, expected);
+        // device_count
+        assert_eq!(mock_socket.output[8..12], [0, 0, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn unread_devlist_does_not_lock_devices() {
+        setup_test_logger();
+        let devices = (0..100)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("0-0-{i}");
+                device
+            })
+            .collect();
+        let server = Arc::new(UsbIpServer::new_simulated(devices));
+        // far smaller than the reply, which is never read
+        let (mut client, stream) = tokio::io::duplex(64);
+        let _connection = tokio::spawn(serve_connection(stream, server.clone()));
+        client
+            .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+            .await
+            .unwrap();
+        tokio::task::yield_now().await;
+
+        let mut device = UsbDevice::new(100);
+        device.bus_id = "0-0-100".to_string();
+        tokio::time::timeout(Duration::from_secs(1), server.add_device(device))
+            .await
+            .expect("device lists still locked")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unknown_command_closes_connection() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        // bogus OP code followed by garbage
+        req.extend_from_slice(&[0x01, 0x11, 0x80, 0x42, 0xde, 0xad, 0xbe, 0xef]);
+        let mut imported_device: Box<Option<Arc<UsbDevice>>> = Box::new(None);
+        let mut mock_socket = MockSocket::new(req);
+        let res = handler(&mut mock_socket, server.clone(), &mut imported_device).await;
+
+        assert!(res.is_ok());
+        assert!(imported_device.is_none());
+        assert_eq!(server.available_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_10_devices() {
+        setup_test_logger();
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut join_set = JoinSet::new();
+        let devices = (0..10)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("0-0-{i}");
+                device
+            })
+            .collect::<Vec<_>>();
+
+        for device in devices.iter() {
+            let new_server = server_.clone();
+            let new_device = device.clone();
+            join_set.spawn(async move {
+                new_server.add_device(new_device).await.unwrap();
+            });
+        }
+
+        for device in devices.iter() {
+            let new_server = server_.clone();
+            let new_device = device.clone();
+            join_set.spawn(async move {
+                new_server.remove_device(&new_device.bus_id).await.unwrap();
+            });
+        }
+
+        while join_set.join_next().await.is_some() {}
+
+        let device_len = server_.clone().available_devices.read().await.len();
+
+        assert_eq!(device_len, 0);
+    }
+
+    #[tokio::test]
+    async fn inactivity_timeout_releases_device() {
+        setup_test_logger();
+        let server_ = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)])
+                .with_inactivity_timeout(Duration::from_millis(100)),
+        );
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert!(server_.available_devices.read().await.is_empty());
+
+        // stall: the client stays connected but sends nothing
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), connection.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        assert_eq!(server_.available_devices.read().await.len(), 1);
+        assert!(server_.used_devices.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reimport_keeps_device() {
+        setup_test_logger();
+        let mut other = UsbDevice::new(1);
+        other.bus_id = "0-0-1".to_string();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0), other]);
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(SINGLE_DEVICE_BUSID.as_bytes());
+
+        let mut imported_device = None;
+        let mut other_imported_device = None;
+        server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+        let mut other_busid = [0u8; 32];
+        other_busid[..5].copy_from_slice(b"0-0-1");
+        server
+            .handle_op_req_import(other_busid, &mut other_imported_device)
+            .await
+            .unwrap();
+
+        let res = server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+        assert!(matches!(res, UsbIpResponse::OpRepImport { status: 0, .. }));
+        assert_eq!(imported_device.unwrap().bus_id, SINGLE_DEVICE_BUSID);
+        assert!(server.available_devices.read().await.is_empty());
+        // a release and occupy would have moved the device to the end
+        let used: Vec<String> = server
+            .used_devices
+            .read()
+            .await
+            .iter()
+            .map(|d| d.bus_id.clone())
+            .collect();
+        assert_eq!(used, vec![SINGLE_DEVICE_BUSID, "0-0-1"]);
+    }
+
+    #[tokio::test]
+    async fn reimport_after_force_detach_fails() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0)]);
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(SINGLE_DEVICE_BUSID.as_bytes());
+        let mut stale_device = None;
+        server
+            .handle_op_req_import(busid, &mut stale_device)
+            .await
+            .unwrap();
+        server.force_detach(SINGLE_DEVICE_BUSID).await.unwrap();
+        let mut imported_device = None;
+        server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+
+        // the same bus id, held by another client now
+        let res = server
+            .handle_op_req_import(busid, &mut stale_device)
+            .await
+            .unwrap();
+        assert!(matches!(res, UsbIpResponse::OpRepImport { status: 1, .. }));
+        assert!(stale_device.is_none());
+        let imported_device = imported_device.unwrap();
+        assert!(server.holds(&imported_device).await);
+    }
+
+    #[derive(Debug, Default)]
+    struct PreparedDeviceHandler {
+        prepared: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl UsbDeviceHandler for PreparedDeviceHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn prepare(&mut self) -> Result<()> {
+            self.prepared.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    /// Counts the devices it is part of that are still open
+    #[derive(Debug)]
+    struct OpenedHandler(Arc<AtomicUsize>);
+
+    impl Drop for OpenedHandler {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    impl UsbDeviceHandler for OpenedHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn lazy_open_on_import() {
+        setup_test_logger();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let device = UsbDevice::new(0).with_lazy_open({
+            let opened = opened.clone();
+            move || {
+                opened.fetch_add(1, Ordering::Relaxed);
+                let handler = OpenedHandler(opened.clone());
+                Ok(UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler)))))
+            }
+        });
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        server.handle_op_req_devlist().await.unwrap();
+        assert_eq!(opened.load(Ordering::Relaxed), 0);
+
+        for _ in 0..2 {
+            let (mut client, stream) = tokio::io::duplex(4096);
+            let connection = tokio::spawn(serve_connection(stream, server.clone()));
+            usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+                .await
+                .unwrap();
+            assert_eq!(opened.load(Ordering::Relaxed), 1);
+            let device = server.used_device(SINGLE_DEVICE_BUSID).await.unwrap();
+            assert!(device.device_handler.is_some());
+            drop(device);
+
+            // detached, closed
+            drop(client);
+            connection.await.unwrap().unwrap();
+            assert_eq!(opened.load(Ordering::Relaxed), 0);
+            let ad = server.available_devices.read().await;
+            assert!(ad[0].device_handler.is_none());
+            assert!(ad[0].lazy_open.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn lazy_open_without_locks() {
+        setup_test_logger();
+        let slow = UsbDevice::new(0).with_lazy_open(|| {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(UsbDevice::new(0))
+        });
+        let mut failing = UsbDevice::new(1).with_lazy_open(|| {
+            Err(std::io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Access denied",
+            ))
+        });
+        failing.bus_id = "0-0-1".to_string();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![slow, failing]));
+
+        let import = tokio::spawn({
+            let server = server.clone();
+            async move { server.occupy(SINGLE_DEVICE_BUSID).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the other devices are served meanwhile
+        let start = std::time::Instant::now();
+        server.handle_op_req_devlist().await.unwrap();
+        let err = server.occupy("0-0-1").await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        // the device being opened is in neither list, its bus id is taken still
+        let err = server.add_device(UsbDevice::new(0)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let err = server.remove_device(SINGLE_DEVICE_BUSID).await.unwrap_err();
+        assert_ne!(err.kind(), ErrorKind::NotFound);
+
+        import.await.unwrap().unwrap();
+        assert!(server.is_used(SINGLE_DEVICE_BUSID).await);
+        // back for the next import
+        let ad = server.available_devices.read().await;
+        assert_eq!(ad.len(), 1);
+        assert_eq!(ad[0].bus_id, "0-0-1");
+    }
+
+    #[tokio::test]
+    async fn release_cooldown_coalesces_imports() {
+        setup_test_logger();
+        let handler = PreparedDeviceHandler::default();
+        let prepared = handler.prepared.clone();
+        let device = UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let cooldown = Duration::from_millis(50);
+        let server = UsbIpServer::new_simulated(vec![device])
+            .with_prepare_on_import()
+            .with_release_cooldown(cooldown);
+
+        let start = std::time::Instant::now();
+        for _ in 0..5 {
+            let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+            server.release(device).await;
+        }
+        // imports 2 to 5 waited for the cooldown, and kept the claim of the first
+        assert!(start.elapsed() >= cooldown * 4);
+        assert_eq!(prepared.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(cooldown).await;
+        let start = std::time::Instant::now();
+        server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert!(start.elapsed() < cooldown);
+        assert_eq!(prepared.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn release_cooldown_without_runtime() {
+        setup_test_logger();
+        let cooldown = Duration::from_millis(50);
+        let server =
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_release_cooldown(cooldown);
+
+        let device = crate::util::block_on(server.occupy(SINGLE_DEVICE_BUSID)).unwrap();
+        crate::util::block_on(server.release(device));
+        let start = std::time::Instant::now();
+        crate::util::block_on(server.occupy(SINGLE_DEVICE_BUSID)).unwrap();
+        assert!(start.elapsed() >= cooldown / 2);
+    }
+
+    #[tokio::test]
+    async fn prepare_before_import() {
+        setup_test_logger();
+        let handler = PreparedDeviceHandler::default();
+        let prepared = handler.prepared.clone();
+        let device = UsbDevice::new(0).with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let server = UsbIpServer::new_simulated(vec![device]).with_prepare_on_import();
+
+        server.prepare(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(prepared.load(Ordering::Relaxed), 1);
+
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(SINGLE_DEVICE_BUSID.as_bytes());
+        let mut imported_device = None;
+        let res = server
+            .handle_op_req_import(busid, &mut imported_device)
+            .await
+            .unwrap();
+        assert!(matches!(res, UsbIpResponse::OpRepImport { status: 0, .. }));
+        assert_eq!(prepared.load(Ordering::Relaxed), 2);
+
+        // only available devices can be prepared
+        let err = server.prepare(SINGLE_DEVICE_BUSID).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    /// Reports the configuration the device is in, as changed by the host
+    #[derive(Debug, Default)]
+    struct ConfiguredDeviceHandler {
+        configuration: Arc<std::sync::atomic::AtomicU8>,
+    }
+
+    impl UsbDeviceHandler for ConfiguredDeviceHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn active_configuration(&mut self) -> Option<u8> {
+            Some(self.configuration.load(Ordering::Relaxed))
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        fn set_configuration(&self, setup: &[u8; 8]) -> Result<()> {
+            let value = SetupPacket::parse(setup).value as u8;
+            self.configuration.store(value, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn import_refreshes_configuration() {
+        setup_test_logger();
+        let handler = ConfiguredDeviceHandler::default();
+        let configuration = handler.configuration.clone();
+        configuration.store(1, Ordering::Relaxed);
+        let device = UsbDevice::new(0)
+            .with_configuration(2)
+            .with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let server = UsbIpServer::new_simulated(vec![device]);
+
+        // switched after enumeration
+        configuration.store(2, Ordering::Relaxed);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(device.active_configuration(), 2);
+        let reply = UsbIpResponse::op_rep_import_success(&device).to_bytes();
+        // bConfigurationValue
+        assert_eq!(reply[8 + 309], 2);
+        server.release(device).await;
+
+        // a value unknown to the device description, like host devices in another configuration
+        configuration.store(3, Ordering::Relaxed);
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(device.active_configuration(), 3);
+        assert_eq!(device.configuration_value, 3);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn configure_on_import() {
+        setup_test_logger();
+        let handler = ConfiguredDeviceHandler::default();
+        let configuration = handler.configuration.clone();
+        let device = UsbDevice::new(0)
+            .with_configuration(2)
+            .with_device_handler(Arc::new(Mutex::new(Box::new(handler))));
+        let server = UsbIpServer::new_simulated(vec![device.clone()]);
+
+        // enumerated unconfigured, and left so by default
+        let device_ = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(configuration.load(Ordering::Relaxed), 0);
+        server.release(device_).await;
+
+        let server =
+            UsbIpServer::new_simulated(vec![device.clone()]).with_configure_on_import(None);
+        let device_ = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(configuration.load(Ordering::Relaxed), 1);
+        assert_eq!(device_.active_configuration(), 1);
+
+        configuration.store(0, Ordering::Relaxed);
+        let server = UsbIpServer::new_simulated(vec![device]).with_configure_on_import(Some(2));
+        let device_ = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(configuration.load(Ordering::Relaxed), 2);
+        assert_eq!(device_.active_configuration(), 2);
+    }
+
+    #[tokio::test]
+    async fn occupy_and_release_move_device() {
+        setup_test_logger();
+        let handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> = Arc::new(Mutex::new(
+            Box::new(crate::vendor::VendorControlHandler::default()),
+        ));
+        let device = UsbDevice::new(0).with_interface(0xFF, 0, 0, None, vec![], handler.clone());
+        let server = UsbIpServer::new_simulated(vec![device]);
+        assert_eq!(Arc::strong_count(&handler), 2);
+
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(Arc::strong_count(&handler), 2);
+        // shared with the used list
+        assert_eq!(Arc::strong_count(&device), 2);
+
+        server.release(device).await;
+        assert_eq!(Arc::strong_count(&handler), 2);
+        assert_eq!(server.available_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn release_cancels_transfers() {
+        setup_test_logger();
+        let slow = crate::testing::DelayHandler::new(
+            HalfStalledHandler,
+            crate::testing::Delay::Fixed(Duration::from_millis(300)),
+        );
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(slow))),
+        );
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let header = submit_header(0, 1, 1);
+        let bulk_in = |device: Arc<UsbDevice>| {
+            let server = server.clone();
+            let header = header.clone();
+            tokio::task::spawn_blocking(move || {
+                server
+                    .handle_usbip_cmd_submit(header, 0, 512, [0; 8], vec![], &device)
+                    .unwrap()
+            })
+        };
+
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        let start = std::time::Instant::now();
+        let transfer = bulk_in(device);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.force_detach(SINGLE_DEVICE_BUSID).await.unwrap();
+        // drained before the device is available
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        match transfer.await.unwrap() {
+            UsbIpResponse::UsbIpRetSubmit {
+                status,
+                actual_length,
+                ..
+            } => {
+                assert_eq!(status as i32, UrbStatus::ECONNRESET);
+                assert_eq!(actual_length, 0);
+            }
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+        assert_eq!(server.available_devices.read().await.len(), 1);
+
+        // the next client gets a working device
+        let device = server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        match bulk_in(device.clone()).await.unwrap() {
+            UsbIpResponse::UsbIpRetSubmit {
+                status: 0,
+                transfer_buffer,
+                ..
+            } => assert_eq!(transfer_buffer, vec![1, 2, 3, 4]),
+            resp => panic!("Unexpected response {resp:?}"),
+        }
+        server.release(device).await;
+        assert_eq!(server.available_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn force_detached_client_loses_reimported_device() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let get_status = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 0),
+            transfer_flags: 0,
+            transfer_buffer_length: 2,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes();
+        let (mut stale, stream) = tokio::io::duplex(4096);
+        let stale_connection = tokio::spawn(serve_connection(stream, server.clone()));
+        usbip_client::import(&mut stale, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        server.force_detach(SINGLE_DEVICE_BUSID).await.unwrap();
+        let (mut owner, stream) = tokio::io::duplex(4096);
+        let _owner_connection = tokio::spawn(serve_connection(stream, server.clone()));
+        usbip_client::import(&mut owner, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+
+        // the same bus id, imported by another client
+        stale.write_all(&get_status).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), stale.read(&mut buf))
+            .await
+            .expect("connection still open");
+        assert_eq!(read.unwrap(), 0);
+        stale_connection.await.unwrap().unwrap();
+
+        assert!(server.is_used(SINGLE_DEVICE_BUSID).await);
+        owner.write_all(&get_status).await.unwrap();
+        let mut reply = [0; 50];
+        owner.read_exact(&mut reply).await.unwrap();
+        // status
+        assert_eq!(reply[20..24], [0; 4]);
+    }
+
+    #[tokio::test]
+    async fn submit_data_length_mismatch() {
+        setup_test_logger();
+        let device = UsbDevice::new(0);
+        let header = |direction| submit_header(0, direction, 0);
+        // commands built by callers of the API
+        let server = UsbIpServer::new_simulated(vec![]);
+        for (direction, data) in [(0, vec![0; 4]), (1, vec![0; 8])] {
+            let err = server
+                .handle_usbip_cmd_submit(header(direction), 0, 8, [0; 8], data, &device)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        // the wire format has no data section for IN, and too large OUT URBs are
+        // rejected before allocating their data
+        let mut cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: header(1),
+            transfer_flags: 0,
+            transfer_buffer_length: u32::MAX,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes();
+        // as an OUT URB, without the data it advertises
+        cmd[12..16].copy_from_slice(&0u32.to_be_bytes());
+        let mut socket = MockSocket::new(cmd.clone());
+        let err = UsbIpCommand::read_from_socket(&mut socket)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        cmd[24..28].copy_from_slice(&usbip_protocol::MAX_TRANSFER_BUFFER_LENGTH.to_be_bytes());
+        let mut socket = MockSocket::new(cmd.clone());
+        let err = UsbIpCommand::read_from_socket(&mut socket)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        // advertising 8 bytes of data, and sending 4 before closing
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let connection = tokio::spawn(serve_connection(stream, server.clone()));
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: header(0),
+            transfer_flags: 0,
+            transfer_buffer_length: 8,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![0; 8],
+            iso_packet_descriptor: vec![],
+        };
+        let bytes = cmd.to_bytes();
+        client.write_all(&bytes[..bytes.len() - 4]).await.unwrap();
+        client.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), connection)
+            .await
+            .expect("connection still open")
+            .unwrap()
+            .unwrap();
+        assert!(!server.is_used(SINGLE_DEVICE_BUSID).await);
+    }
+
+    #[tokio::test]
+    async fn simulate_disconnect_closes_connection() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let connection = tokio::spawn(serve_connection(stream, server.clone()));
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        assert!(server.is_used(SINGLE_DEVICE_BUSID).await);
+
+        server
+            .simulate_disconnect(SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut buf))
+            .await
+            .expect("connection still open");
+        assert_eq!(read.unwrap(), 0);
+        connection.await.unwrap().unwrap();
+        assert!(!server.is_used(SINGLE_DEVICE_BUSID).await);
+        assert_eq!(server.available_devices.read().await.len(), 1);
+
+        let err = server
+            .simulate_disconnect(SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_occupy_and_release() {
+        setup_test_logger();
+        let devices = (0..8)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("0-0-{i}");
+                device
+            })
+            .collect();
+        let server = Arc::new(UsbIpServer::new_simulated(devices).with_transfer_pool(4));
+
+        let mut set = JoinSet::new();
+        for task in 0..64 {
+            let server = server.clone();
+            set.spawn(async move {
+                let bus_id = format!("0-0-{}", task % 8);
+                for _ in 0..100 {
+                    if let Ok(device) = server.occupy(&bus_id).await {
+                        server.devices_json().await;
+                        server.release(device).await;
+                    } else {
+                        let _ = server.force_detach(&bus_id).await;
+                    }
+                }
+            });
+        }
+        tokio::time::timeout(Duration::from_secs(30), set.join_all())
+            .await
+            .expect("deadlock");
+
+        assert_eq!(server.available_devices.read().await.len(), 8);
+        assert!(server.used_devices.read().await.is_empty());
+        assert!(server.ports().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strict_validation_rejects_devices() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]).with_strict_validation();
+        let mut device = UsbDevice::new(0);
+        device.num_configurations = 2;
+        let err = server.add_device(device).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        server.add_device(UsbDevice::new(0)).await.unwrap();
+        assert_eq!(server.available_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn add_device_rejects_duplicate_bus_id() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![]);
+        server.add_device(UsbDevice::new(0)).await.unwrap();
+        let err = server.add_device(UsbDevice::new(1)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        // also while the first one is in use
+        server.occupy(SINGLE_DEVICE_BUSID).await.unwrap();
+        let err = server.add_device(UsbDevice::new(1)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        let mut device = UsbDevice::new(1);
+        device.bus_id = "1".repeat(32);
+        let err = server.add_device(device).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(server.available_devices.read().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn imported_devices_get_ports() {
+        setup_test_logger();
+        let devices = (0..2)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("0-0-{i}");
+                device
+            })
+            .collect();
+        let server = UsbIpServer::new_simulated(devices);
+
+        let first = server.occupy("0-0-0").await.unwrap();
+        server.occupy("0-0-1").await.unwrap();
+        let port0 = server.port_of("0-0-0").await.unwrap();
+        let port1 = server.port_of("0-0-1").await.unwrap();
+        assert_ne!(port0, port1);
+        assert_eq!(server.device_by_port(port0).await.unwrap(), "0-0-0");
+        assert_eq!(server.device_by_port(port1).await.unwrap(), "0-0-1");
+
+        // releasing one leaves the port of the other
+        server.release(first).await;
+        assert_eq!(server.port_of("0-0-0").await, None);
+        assert_eq!(server.device_by_port(port0).await, None);
+        assert_eq!(server.port_of("0-0-1").await, Some(port1));
+        assert_eq!(server.ports().await, vec![(port1, "0-0-1".to_string())]);
+
+        // a new import gets a new port
+        server.occupy("0-0-0").await.unwrap();
+        let port = server.port_of("0-0-0").await.unwrap();
+        assert!(port > port1);
+    }
+
+    fn get_product_string() -> Vec<u8> {
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 0),
+            transfer_flags: 0,
+            transfer_buffer_length: 255,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            // GET_DESCRIPTOR of string 3, the product name
+            setup: [0x80, 0x06, 0x03, 0x03, 0x09, 0x04, 0xFF, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes()
+    }
+
+    async fn read_product_string(connection: &mut TcpStream) -> String {
+        connection.write_all(&get_product_string()).await.unwrap();
+        let mut header = [0; 48];
+        connection.read_exact(&mut header).await.unwrap();
+        let actual_length = u32::from_be_bytes(header[24..28].try_into().unwrap());
+        let mut desc = vec![0; actual_length as usize];
+        connection.read_exact(&mut desc).await.unwrap();
+        let utf16: Vec<u16> = desc[2..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16(&utf16).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_pre_bound_listener() {
+        setup_test_logger();
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server_from_listener(listener, server_.clone()));
+
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        let imported = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(imported.bus_id, SINGLE_DEVICE_BUSID);
+        assert!(server_.is_used(SINGLE_DEVICE_BUSID).await);
+    }
+
+    #[test]
+    fn tcp_nodelay_on_accepted_socket() {
+        setup_test_logger();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (server, nodelay) in [
+            (UsbIpServer::new_simulated(vec![]), true),
+            (
+                UsbIpServer::new_simulated(vec![]).with_tcp_nodelay(false),
+                false,
+            ),
+        ] {
+            let _client = std::net::TcpStream::connect(addr).unwrap();
+            let (socket, _) = listener.accept().unwrap();
+            server.configure_socket(socket2::SockRef::from(&socket));
+            assert_eq!(socket.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    #[tokio::test]
+    async fn unlink_after_completion() {
+        setup_test_logger();
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        // completes seqnum 1
+        read_product_string(&mut connection).await;
 
-        let usbip_resp = match device.find_ep(real_ep as u8) {
-            None => {
-                warn!("Endpoint {real_ep:02x?} not found");
-                UsbIpResponse::usbip_ret_submit_fail(&header, 0)
-            }
-            Some((ep, intf)) => {
-                match device.handle_urb(
-                    ep,
-                    intf,
-                    transfer_buffer_length,
-                    SetupPacket::parse(&setup),
-                    &data,
-                ) {
-                    Ok(resp) => {
-                        if out {
-                            trace!("<-Wrote {}", data.len());
-                        } else {
-                            trace!("<-Resp {resp:02x?}");
-                        }
-                        let actual_length = match ep.direction() {
-                            Direction::In => resp.len() as u32,
-                            Direction::Out => transfer_buffer_length,
-                        };
-                        UsbIpResponse::usbip_ret_submit_success(
-                            &header,
-                            0,
-                            actual_length,
-                            resp,
-                            vec![],
-                        )
-                    }
-                    Err(err) => {
-                        warn!("Error handling URB: {err}");
-                        let actual_length = match ep.direction() {
-                            Direction::In => 0,
-                            Direction::Out => transfer_buffer_length,
-                        };
-                        UsbIpResponse::usbip_ret_submit_fail(&header, actual_length)
-                    }
-                }
+        let unlink = |seqnum, unlink_seqnum| {
+            UsbIpCommand::UsbIpCmdUnlink {
+                header: UsbIpHeaderBasic {
+                    command: USBIP_CMD_UNLINK.into(),
+                    seqnum,
+                    devid: 0,
+                    direction: 0,
+                    ep: 0,
+                },
+                unlink_seqnum,
             }
+            .to_bytes()
         };
-        trace!("Sent USBIP_RET_SUBMIT");
-        Ok(usbip_resp)
+        let status = |reply: &[u8; 48]| i32::from_be_bytes(reply[20..24].try_into().unwrap());
+
+        // too late, the client keeps the result of the URB
+        connection.write_all(&unlink(2, 1)).await.unwrap();
+        let mut reply = [0; 48];
+        connection.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            u32::from_be_bytes(reply[0..4].try_into().unwrap()),
+            USBIP_RET_UNLINK as u32
+        );
+        assert_eq!(status(&reply), UrbStatus::OK);
+
+        // never submitted, there is nothing to unlink
+        connection.write_all(&unlink(3, 7)).await.unwrap();
+        connection.read_exact(&mut reply).await.unwrap();
+        assert_eq!(status(&reply), UrbStatus::OK);
+        // URBs in flight are, see `interrupt_in_naks_paced_by_interval`
     }
 
-    pub fn handle_usbip_cmd_unlink(
-        &self,
-        mut header: UsbIpHeaderBasic,
-        unlink_seqnum: u32,
-    ) -> Result<UsbIpResponse> {
-        trace!("Got USBIP_CMD_UNLINK for {unlink_seqnum:10x?}");
+    #[tokio::test]
+    async fn unconfigured_device_is_auto_released() {
+        setup_test_logger();
+        let server_ =
+            Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_auto_release());
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
 
-        header.command = USBIP_RET_UNLINK.into();
-        // Reply header from server should have devid/direction/ep all 0.
-        header.devid = 0;
-        header.direction = 0;
-        header.ep = 0;
+        let mut connection = poll_connect(addr).await;
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert!(server_.is_used(SINGLE_DEVICE_BUSID).await);
 
-        let res = UsbIpResponse::usbip_ret_unlink_success(&header);
-        trace!("Sent USBIP_RET_UNLINK");
-        Ok(res)
+        // SET_CONFIGURATION(0)
+        let unconfigure = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 0, 0),
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        connection.write_all(&unconfigure.to_bytes()).await.unwrap();
+        let mut header = [0; 48];
+        connection.read_exact(&mut header).await.unwrap();
+        // status
+        assert_eq!(header[20..24], [0; 4]);
+
+        assert!(!server_.is_used(SINGLE_DEVICE_BUSID).await);
+        assert_eq!(server_.available_devices.read().await.len(), 1);
+
+        // the connection is still open
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert!(server_.is_used(SINGLE_DEVICE_BUSID).await);
     }
-}
 
-pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-    socket: &mut T,
-    server: Arc<UsbIpServer>,
-    imported_device: &mut Option<UsbDevice>,
-) -> Result<()> {
-    loop {
-        let command = match UsbIpCommand::read_from_socket(socket).await {
-            Ok(c) => c,
-            Err(err) => {
-                if let Some(dev) = imported_device.take() {
-                    server.release(dev).await;
-                }
-                if err.kind() == ErrorKind::UnexpectedEof {
-                    info!("Remote closed the connection");
-                    return Ok(());
-                } else {
-                    return Err(err);
-                }
-            }
+    #[tokio::test]
+    async fn session_history() {
+        setup_test_logger();
+        let server_ =
+            Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_auto_release());
+        let peer: SocketAddr = "192.0.2.1:3240".parse().unwrap();
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let session = tokio::spawn(serve_connection_from(stream, peer, server_.clone()));
+
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        // SET_CONFIGURATION(0), releasing it
+        let unconfigure = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 0, 0),
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
         };
+        client.write_all(&unconfigure.to_bytes()).await.unwrap();
+        let mut header = [0; 48];
+        client.read_exact(&mut header).await.unwrap();
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
 
-        match command {
-            UsbIpCommand::OpReqDevlist { .. } => match server.handle_op_req_devlist().await {
-                Ok(r) => {
-                    r.write_to_socket(socket).await?;
-                }
-                Err(e) => error!("UsbipCommand OpReqDevlist handling error: {e:?}"),
-            },
-            UsbIpCommand::OpReqImport { busid, .. } => {
-                match server.handle_op_req_import(busid, imported_device).await {
-                    Ok(r) => {
-                        r.write_to_socket(socket).await?;
-                    }
-                    Err(e) => {
-                        error!("UsbipCommand OpReqImport handling error: {e:?}");
-                        if let Some(dev) = imported_device.take() {
-                            server.release(dev).await;
-                        }
-                    }
-                }
-                info!("Imported device: {imported_device:?}");
-            }
-            UsbIpCommand::UsbIpCmdSubmit {
-                header,
-                transfer_buffer_length,
-                setup,
-                data,
-                ..
-            } => {
-                let device = match imported_device.as_ref() {
-                    Some(d) => d,
-                    None => {
-                        error!("No device currently imported");
-                        continue;
-                    }
-                };
-                match server.handle_usbip_cmd_submit(
-                    header,
-                    transfer_buffer_length,
-                    setup,
-                    data,
-                    device,
-                ) {
-                    Ok(r) => {
-                        r.write_to_socket(socket).await?;
-                    }
-                    Err(e) => error!("UsbipCmdSubmit handling error: {e:?}"),
-                }
+        // a second connection, without imports
+        let (client, stream) = tokio::io::duplex(4096);
+        drop(client);
+        serve_connection(stream, server_.clone()).await.unwrap();
+
+        let sessions = server_.recent_sessions(10);
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(server_.recent_sessions(1), sessions[..1]);
+        assert_eq!(sessions[0].peer, None);
+        assert!(sessions[0].imports.is_empty());
+
+        let record = &sessions[1];
+        assert_eq!(record.peer, Some(peer));
+        let bus_ids: Vec<&str> = record.imports.iter().map(|i| i.bus_id.as_str()).collect();
+        assert_eq!(bus_ids, vec![SINGLE_DEVICE_BUSID; 2]);
+        let (first, second) = (&record.imports[0], &record.imports[1]);
+        assert!(record.connected_at <= first.imported_at);
+        assert!(first.imported_at <= first.released_at);
+        assert!(first.released_at <= second.imported_at);
+        assert!(second.released_at <= record.disconnected_at);
+        assert!(sessions[0].connected_at >= record.disconnected_at);
+    }
+
+    #[tokio::test]
+    async fn drain_mode() {
+        setup_test_logger();
+        let mut second = UsbDevice::new(1);
+        second.bus_id = "0-0-1".to_string();
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0), second]));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server_from_listener(listener, server_.clone()));
+
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        server_.enter_drain_mode();
+        assert!(server_.is_draining());
+
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        assert!(usbip_client::import(&mut other, "0-0-1").await.is_err());
+        assert!(!server_.is_used("0-0-1").await);
+
+        // the device imported before keeps serving URBs
+        assert!(!read_product_string(&mut connection).await.is_empty());
+        let imports = server_.active_imports();
+        assert_eq!(imports.len(), 1);
+        assert_eq!(imports[0].bus_id, SINGLE_DEVICE_BUSID);
+        assert_eq!(imports[0].peer, Some(connection.local_addr().unwrap()));
+
+        drop(connection);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !server_.active_imports().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-            UsbIpCommand::UsbIpCmdUnlink {
-                header,
-                unlink_seqnum,
-            } => match server.handle_usbip_cmd_unlink(header, unlink_seqnum) {
-                Ok(r) => {
-                    r.write_to_socket(socket).await?;
-                }
-                Err(e) => error!("UsbipCmdUnlink handling error: {e:?}"),
+        })
+        .await
+        .unwrap();
+        assert!(!server_.is_used(SINGLE_DEVICE_BUSID).await);
+    }
+
+    #[tokio::test]
+    async fn submit_before_import() {
+        setup_test_logger();
+        let submit = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                seqnum: 7,
+                ..submit_header(0, 1, 0)
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 18,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(stream, server_.clone()));
+        client.write_all(&submit.to_bytes()).await.unwrap();
+        let mut reply = [0; 48];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], u32::from(USBIP_RET_SUBMIT).to_be_bytes());
+        assert_eq!(reply[4..8], 7u32.to_be_bytes());
+        assert_eq!(reply[20..24], UrbStatus::ENODEV.to_be_bytes());
+        assert_eq!(server_.stats().urbs_failed, 1);
+        // the connection is still usable
+        usbip_client::import(&mut client, SINGLE_DEVICE_BUSID)
+            .await
+            .unwrap();
+
+        let server_ = Arc::new(
+            UsbIpServer::new_simulated(vec![]).with_unimported_submit(UnimportedSubmit::Close),
+        );
+        let (mut client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(stream, server_));
+        client.write_all(&submit.to_bytes()).await.unwrap();
+        let mut buf = vec![];
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn close_after_unimported_submits() {
+        setup_test_logger();
+        let server_ = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0)]).with_max_unimported_submits(3),
+        );
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let connection = tokio::spawn(serve_connection(stream, server_.clone()));
+        let submit = |seqnum| UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                seqnum,
+                ..submit_header(0, 0, 0)
             },
+            transfer_flags: 0,
+            transfer_buffer_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        for seqnum in 1..=3 {
+            client.write_all(&submit(seqnum).to_bytes()).await.unwrap();
+            let mut reply = [0; 48];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[20..24], UrbStatus::ENODEV.to_be_bytes());
         }
+        let mut buf = vec![];
+        let read = tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut buf))
+            .await
+            .expect("connection still open");
+        assert_eq!(read.unwrap(), 0);
+        connection.await.unwrap().unwrap();
+        assert_eq!(server_.stats().urbs_failed, 3);
     }
-}
 
-/// Spawn a USB/IP server at `addr` using [TcpListener]
-pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
-    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+    #[tokio::test]
+    async fn transfer_pool_keeps_accepting() {
+        setup_test_logger();
+        let devices = (0..4)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("0-0-{i}");
+                UsbDeviceBuilder::new(device)
+                    .with_interface(ClassCode::VendorSpecific as u8, 0, 0, |intf| {
+                        intf.on_bulk_in(0x81, 512, |len| {
+                            std::thread::sleep(Duration::from_millis(300));
+                            Ok(vec![0; len as usize])
+                        })
+                    })
+                    .build()
+            })
+            .collect();
+        let server_ = Arc::new(UsbIpServer::new_simulated(devices).with_transfer_pool(4));
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
 
-    while let Ok((mut socket, _addr)) = listener.accept().await {
-        info!("Got connection from {:?}", socket.peer_addr());
-        let new_server = server.clone();
-        tokio::spawn(async move {
-            let mut imported_device: Box<Option<UsbDevice>> = Box::new(None);
-            let res = handler(&mut socket, new_server.clone(), &mut imported_device).await;
-            info!("Handler ended with {res:?}");
-            if let Some(dev) = imported_device.take() {
-                new_server.release(dev).await;
-            }
-        });
+        let mut connections = vec![];
+        for i in 0..4 {
+            let mut connection = poll_connect(addr).await;
+            attach_device(&mut connection, &format!("0-0-{i}")).await;
+            connections.push(connection);
+        }
+        let submit = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 1),
+            transfer_flags: 0,
+            transfer_buffer_length: 512,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        for connection in &mut connections {
+            connection.write_all(&submit.to_bytes()).await.unwrap();
+        }
+
+        // the thread of the runtime isn't blocked by the transfers
+        let start = std::time::Instant::now();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut connection = poll_connect(addr).await;
+        connection
+            .write_all(&UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes())
+            .await
+            .unwrap();
+        let mut header = [0; 12];
+        connection.read_exact(&mut header).await.unwrap();
+        info!("Devlist answered in {:?}", start.elapsed());
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        for mut connection in connections {
+            let mut reply = vec![0; 48 + 512];
+            connection.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply[20..24], [0; 4]);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use tokio::{net::TcpStream, task::JoinSet};
+    /// A bulk IN endpoint returning repetitive data, like storage reads of empty blocks
+    #[cfg(feature = "compression")]
+    #[derive(Debug)]
+    struct RepeatingBulkHandler;
 
-    use super::*;
-    use crate::{
-        usbip_protocol::{USBIP_CMD_SUBMIT, UsbIpHeaderBasic},
-        util::tests::*,
-    };
+    #[cfg(feature = "compression")]
+    impl UsbInterfaceHandler for RepeatingBulkHandler {
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok((0..transfer_buffer_length).map(|i| (i % 4) as u8).collect())
+        }
 
-    const SINGLE_DEVICE_BUSID: &str = "0-0-0";
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
 
-    fn op_req_import(busid: &str) -> Vec<u8> {
-        let mut busid = busid.to_string().as_bytes().to_vec();
-        busid.resize(32, 0);
-        UsbIpCommand::OpReqImport {
-            status: 0,
-            busid: busid.try_into().unwrap(),
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
         }
-        .to_bytes()
     }
 
-    async fn attach_device(connection: &mut TcpStream, busid: &str) -> u32 {
-        let req = op_req_import(busid);
-        connection.write_all(req.as_slice()).await.unwrap();
-        connection.read_u32().await.unwrap();
-        let result = connection.read_u32().await.unwrap();
-        if result == 0 {
-            connection.read_exact(&mut vec![0; 0x138]).await.unwrap();
+    #[cfg(feature = "compression")]
+    async fn bulk_in(connection: &mut TcpStream, compressed: bool) -> Vec<u8> {
+        let submit = UsbIpCommand::UsbIpCmdSubmit {
+            header: submit_header(0, 1, 1),
+            transfer_flags: 0,
+            transfer_buffer_length: 4096,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        connection.write_all(&submit.to_bytes()).await.unwrap();
+        let mut header = [0; 48];
+        connection.read_exact(&mut header).await.unwrap();
+        let actual_length = u32::from_be_bytes(header[24..28].try_into().unwrap());
+        if compressed {
+            let len = connection.read_u32().await.unwrap();
+            assert!(len < actual_length);
+            let mut block = vec![0; len as usize];
+            connection.read_exact(&mut block).await.unwrap();
+            lz4_flex::block::decompress(&block, actual_length as usize).unwrap()
+        } else {
+            let mut data = vec![0; actual_length as usize];
+            connection.read_exact(&mut data).await.unwrap();
+            data
         }
-        result
     }
 
+    #[cfg(feature = "compression")]
     #[tokio::test]
-    async fn req_empty_devlist() {
+    async fn compressed_bulk_in() {
         setup_test_logger();
-        let server = UsbIpServer::new_simulated(vec![]);
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        };
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            None,
+            vec![ep],
+            Arc::new(Mutex::new(Box::new(RepeatingBulkHandler))),
+        );
+        let expected: Vec<u8> = (0..4096).map(|i| (i % 4) as u8).collect();
+
+        for (server_compresses, client_offers, compressed) in [
+            (true, true, true),
+            // fallbacks
+            (true, false, false),
+            (false, true, false),
+        ] {
+            let mut server_ = UsbIpServer::new_simulated(vec![device.clone()]);
+            if server_compresses {
+                server_ = server_.with_compression();
+            }
+            let addr = get_free_address().await;
+            tokio::spawn(server(addr, Arc::new(server_)));
+
+            let mut connection = poll_connect(addr).await;
+            if client_offers {
+                let req = UsbIpCommand::OpReqCompression {
+                    algorithms: COMPRESSION_LZ4,
+                };
+                connection.write_all(&req.to_bytes()).await.unwrap();
+                let mut reply = [0; 12];
+                connection.read_exact(&mut reply).await.unwrap();
+                let expected_reply =
+                    UsbIpResponse::op_rep_compression(compressed.then_some(COMPRESSION_LZ4));
+                assert_eq!(reply.to_vec(), expected_reply.to_bytes());
+            }
+            attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+            assert_eq!(bulk_in(&mut connection, compressed).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn session_over_duplex_stream() {
+        setup_test_logger();
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let session = tokio::spawn(serve_connection(stream, server_.clone()));
+
         let req = UsbIpCommand::OpReqDevlist { status: 0 };
-        let mut imported_device: Box<Option<UsbDevice>> = Box::new(None);
-        let mut mock_socket = MockSocket::new(req.to_bytes());
-        handler(&mut mock_socket, Arc::new(server), &mut imported_device)
+        client.write_all(&req.to_bytes()).await.unwrap();
+        let expected = UsbIpResponse::op_rep_devlist(&[UsbDevice::new(0)]).to_bytes();
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        client
+            .write_all(&op_req_import(SINGLE_DEVICE_BUSID))
             .await
-            .ok();
+            .unwrap();
+        let expected = UsbIpResponse::op_rep_import_success(&UsbDevice::new(0)).to_bytes();
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+        assert!(server_.is_used(SINGLE_DEVICE_BUSID).await);
 
-        assert_eq!(
-            mock_socket.output,
-            UsbIpResponse::op_rep_devlist(&[]).to_bytes(),
+        // the client goes away
+        drop(client);
+        session.await.unwrap().unwrap();
+        assert!(!server_.is_used(SINGLE_DEVICE_BUSID).await);
+        assert_eq!(server_.available_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn devlist_filter_per_peer() {
+        setup_test_logger();
+        let mut hidden = UsbDevice::new(1);
+        hidden.bus_id = "0-0-1".to_string();
+        let trusted: SocketAddr = "10.0.0.1:3240".parse().unwrap();
+        let server_ = Arc::new(
+            UsbIpServer::new_simulated(vec![UsbDevice::new(0), hidden.clone()])
+                .with_devlist_filter(move |peer, dev| dev.bus_id != "0-0-1" || *peer == trusted),
         );
+
+        let devlist = |peer: SocketAddr| {
+            let server_ = server_.clone();
+            async move {
+                let (mut client, stream) = tokio::io::duplex(4096);
+                tokio::spawn(serve_connection_from(stream, peer, server_));
+                let req = UsbIpCommand::OpReqDevlist { status: 0 };
+                client.write_all(&req.to_bytes()).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut reply = vec![];
+                client.read_to_end(&mut reply).await.unwrap();
+                reply
+            }
+        };
+
+        let reply = devlist(trusted).await;
+        let expected = UsbIpResponse::op_rep_devlist(&[UsbDevice::new(0), hidden]).to_bytes();
+        assert_eq!(reply, expected);
+
+        let other: SocketAddr = "10.0.0.2:3240".parse().unwrap();
+        let reply = devlist(other).await;
+        let expected = UsbIpResponse::op_rep_devlist(&[UsbDevice::new(0)]).to_bytes();
+        assert_eq!(reply, expected);
+
+        // nor can it import the device by bus id
+        let (mut client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection_from(stream, other, server_.clone()));
+        assert!(usbip_client::import(&mut client, "0-0-1").await.is_err());
+        assert!(!server_.is_used("0-0-1").await);
     }
 
     #[tokio::test]
-    async fn add_and_remove_10_devices() {
+    async fn replace_device_forces_reattach() {
         setup_test_logger();
-        let server_ = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let mut device = UsbDevice::new(0);
+        device.set_product_name("Old product");
+        let server_ = Arc::new(UsbIpServer::new_simulated(vec![device]));
         let addr = get_free_address().await;
         tokio::spawn(server(addr, server_.clone()));
 
-        let mut join_set = JoinSet::new();
-        let devices = (0..10).map(UsbDevice::new).collect::<Vec<_>>();
-
-        for device in devices.iter() {
-            let new_server = server_.clone();
-            let new_device = device.clone();
-            join_set.spawn(async move {
-                new_server.add_device(new_device).await;
-            });
-        }
-
-        for device in devices.iter() {
-            let new_server = server_.clone();
-            let new_device = device.clone();
-            join_set.spawn(async move {
-                new_server.remove_device(&new_device.bus_id).await.unwrap();
-            });
-        }
+        let mut connection = poll_connect(addr).await;
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(read_product_string(&mut connection).await, "Old product");
 
-        while join_set.join_next().await.is_some() {}
+        let mut device = UsbDevice::new(0);
+        device.set_product_name("New product");
+        server_
+            .replace_device(SINGLE_DEVICE_BUSID, device)
+            .await
+            .unwrap();
 
-        let device_len = server_.clone().available_devices.read().await.len();
+        // the stale import is dropped at the next URB
+        connection.write_all(&get_product_string()).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(matches!(connection.read(&mut buf).await, Ok(0) | Err(_)));
 
-        assert_eq!(device_len, 0);
+        let mut connection = poll_connect(addr).await;
+        attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(read_product_string(&mut connection).await, "New product");
     }
 }
@@ -1,4 +1,3 @@
-use env_logger;
 use log::*;
 use std::net::*;
 use std::sync::Arc;
@@ -0,0 +1,107 @@
+//! Reuse of transfer buffers across URBs
+use super::*;
+
+/// Buffers larger than this are not kept, to bound the memory held by the pool
+const MAX_POOLED_CAPACITY: usize = 256 * 1024;
+/// Default number of buffers kept, see [BufferPool::set_capacity]
+const DEFAULT_POOL_CAPACITY: usize = 32;
+
+static BUFFER_POOL: BufferPool = BufferPool::new(DEFAULT_POOL_CAPACITY);
+
+/// The pool of transfer buffers shared by the servers of this process
+///
+/// Host device handlers take URB data buffers from it, and the server
+/// gives them back once the RET_SUBMIT is sent.
+pub fn buffer_pool() -> &'static BufferPool {
+    &BUFFER_POOL
+}
+
+/// A pool of byte buffers, to avoid an allocation per URB
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    capacity: AtomicUsize,
+    allocations: AtomicU64,
+}
+
+impl BufferPool {
+    /// Create a pool keeping up to `capacity` buffers
+    pub const fn new(capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            capacity: AtomicUsize::new(capacity),
+            allocations: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep up to `capacity` buffers, 0 disables pooling
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.buffers.lock().unwrap().truncate(capacity);
+    }
+
+    /// Number of buffers allocated because none in the pool was large enough
+    pub fn allocations(&self) -> u64 {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    /// A zeroed buffer of `len` bytes
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        let pooled = {
+            let mut buffers = self.buffers.lock().unwrap();
+            buffers
+                .iter()
+                .rposition(|b| b.capacity() >= len)
+                .map(|i| buffers.swap_remove(i))
+        };
+        match pooled {
+            Some(mut buf) => {
+                buf.resize(len, 0);
+                buf
+            }
+            None => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                // rounded up, so it fits transfers of similar lengths later
+                let mut buf = Vec::with_capacity(len.next_power_of_two());
+                buf.resize(len, 0);
+                buf
+            }
+        }
+    }
+
+    /// Return a buffer to the pool
+    pub fn give(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity.load(Ordering::Relaxed) {
+            buf.clear();
+            buffers.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[test]
+    fn buffers_are_reused() {
+        setup_test_logger();
+        let pool = BufferPool::new(4);
+        for len in (0..1000).map(|i| 512 + i % 512) {
+            let buf = pool.take(len);
+            assert_eq!(buf, vec![0; len]);
+            pool.give(buf);
+        }
+        // 512 bytes, then 1024 bytes for the longer transfers
+        assert_eq!(pool.allocations(), 2);
+
+        pool.set_capacity(0);
+        pool.give(pool.take(512));
+        pool.take(512);
+        assert_eq!(pool.allocations(), 4);
+    }
+}
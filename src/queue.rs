@@ -0,0 +1,132 @@
+//! Bounded queues of IN data, between bursty producers and the URBs of the client
+use super::*;
+
+/// IN data produced ahead of the URBs taking it, see [UsbDevice::with_transfer_queue]
+///
+/// A producer, e.g. a thread polling a sensor, pushes data whenever it has
+/// some, and the next URBs to the endpoint take it in order. Each endpoint
+/// holds up to `depth` transfers, so a burst is not lost while the client
+/// is not polling yet. Clones share the queues.
+#[derive(Clone, Debug)]
+pub struct TransferQueue {
+    depth: usize,
+    queues: Arc<Mutex<HashMap<u8, VecDeque<Vec<u8>>>>>,
+}
+
+impl TransferQueue {
+    /// Queues holding up to `depth` transfers per endpoint, at least one
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            queues: Default::default(),
+        }
+    }
+
+    /// Number of transfers each endpoint holds
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Queue `data` for the next URB to IN endpoint `ep`
+    ///
+    /// Fails with [ErrorKind::WouldBlock] if `depth` transfers are queued
+    /// already, `data` is not queued then.
+    pub fn push(&self, ep: u8, data: Vec<u8>) -> Result<()> {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(ep | 0x80).or_default();
+        if queue.len() >= self.depth {
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                format!("Transfer queue of {:#04x} is full", ep | 0x80),
+            ));
+        }
+        queue.push_back(data);
+        Ok(())
+    }
+
+    /// Number of transfers queued for IN endpoint `ep`
+    pub fn len(&self, ep: u8) -> usize {
+        self.queues
+            .lock()
+            .unwrap()
+            .get(&(ep | 0x80))
+            .map_or(0, VecDeque::len)
+    }
+
+    /// The data for a URB of `len` bytes to `ep`
+    ///
+    /// Transfers are not split, a URB shorter than the next transfer fails
+    /// with EOVERFLOW and the transfer stays queued for a longer URB.
+    pub(crate) fn pop(&self, ep: u8, len: usize) -> Option<Result<Vec<u8>>> {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.get_mut(&ep)?;
+        let data = queue.front()?;
+        if data.len() > len {
+            return Some(Err(babble(data.len(), len)));
+        }
+        queue.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[test]
+    fn bursty_interrupt_source() {
+        setup_test_logger();
+        let ep = UsbEndpoint {
+            address: 0x81,
+            attributes: EndpointAttributes::Interrupt as u8,
+            max_packet_size: 8,
+            interval: 1,
+        };
+        let device = UsbDevice::new(0)
+            .with_interface(
+                ClassCode::VendorSpecific as u8,
+                0,
+                0,
+                None,
+                vec![ep],
+                Arc::new(Mutex::new(
+                    Box::new(vendor::VendorControlHandler::default()),
+                )),
+            )
+            .with_transfer_queue(4);
+        let queue = device.transfer_queue().unwrap();
+
+        // a burst while the client isn't polling
+        let producer = std::thread::spawn(move || {
+            (1..=6)
+                .map(|i| queue.push(0x01, vec![i; 8]).is_ok())
+                .collect::<Vec<_>>()
+        });
+        let pushed = producer.join().unwrap();
+        assert_eq!(pushed, vec![true, true, true, true, false, false]);
+        assert_eq!(device.transfer_queue().unwrap().len(0x81), 4);
+
+        let intf = &device.interfaces[0];
+        let read = |len| {
+            device
+                .handle_urb(ep, Some(intf), 0, len, SetupPacket::default(), &[])
+                .unwrap()
+        };
+        for i in 1..=4 {
+            assert_eq!(read(8), vec![i; 8]);
+        }
+        // drained, answered by the handler again
+        assert!(read(8).is_empty());
+
+        // longer data overflows the URB, and waits for one holding it
+        let queue = device.transfer_queue().unwrap();
+        queue.push(0x81, vec![7; 12]).unwrap();
+        let err = device
+            .handle_urb(ep, Some(intf), 0, 8, SetupPacket::default(), &[])
+            .unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EOVERFLOW);
+        assert_eq!(queue.len(0x81), 1);
+        assert_eq!(read(16), vec![7; 12]);
+        assert_eq!(queue.len(0x81), 0);
+    }
+}
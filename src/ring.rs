@@ -0,0 +1,270 @@
+//! Overlapping bulk IN transfers, to keep the pipe of fast devices busy
+use super::*;
+use nusb::transfer::{Buffer, Bulk, In, TransferError};
+
+/// Transfers of a bulk IN endpoint, as used by [BulkInRing]
+pub trait BulkInSource {
+    /// Start a transfer of up to `len` bytes
+    fn submit(&mut self, len: usize);
+
+    /// Wait for the oldest transfer, `None` if it is still pending after `timeout`
+    fn wait_next(
+        &mut self,
+        timeout: Duration,
+    ) -> Option<std::result::Result<Vec<u8>, TransferError>>;
+
+    /// Number of transfers submitted and not returned by [Self::wait_next] yet
+    fn pending(&self) -> usize;
+
+    /// Cancel the pending transfers, they are still returned by [Self::wait_next]
+    fn cancel_all(&mut self);
+}
+
+impl BulkInSource for nusb::Endpoint<Bulk, In> {
+    fn submit(&mut self, len: usize) {
+        // whole packets, the device may send up to that much
+        let max_packet_size = self.max_packet_size();
+        let requested_len = len.div_ceil(max_packet_size).max(1) * max_packet_size;
+        let mut buffer = buffer_pool().take(requested_len);
+        buffer.clear();
+        let mut buffer = Buffer::from(buffer);
+        buffer.set_requested_len(requested_len);
+        nusb::Endpoint::submit(self, buffer);
+    }
+
+    fn wait_next(
+        &mut self,
+        timeout: Duration,
+    ) -> Option<std::result::Result<Vec<u8>, TransferError>> {
+        self.wait_next_complete(timeout)
+            .map(|c| c.into_result().map(Buffer::into_vec))
+    }
+
+    fn pending(&self) -> usize {
+        nusb::Endpoint::pending(self)
+    }
+
+    fn cancel_all(&mut self) {
+        nusb::Endpoint::cancel_all(self)
+    }
+}
+
+/// A single bulk IN transfer of `source`, for a URB of `len` bytes
+///
+/// The transfer ends at the first short packet from the device, whose data
+/// is returned right away rather than waiting for the rest of the buffer.
+/// It is cancelled after `timeout`, like [nusb::Endpoint::transfer_blocking].
+///
+/// Transfers are submitted in whole packets, a device sending more than `len`
+/// bytes fails the URB with EOVERFLOW, see [urb_status].
+pub fn bulk_in_transfer<S: BulkInSource>(
+    source: &mut S,
+    len: usize,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    source.submit(len);
+    let data = match source.wait_next(timeout) {
+        Some(res) => res?,
+        None => {
+            source.cancel_all();
+            loop {
+                if let Some(res) = source.wait_next(Duration::from_secs(1)) {
+                    break res.map_err(timed_out)?;
+                }
+                warn!("Cancelled bulk IN transfer has not returned yet");
+            }
+        }
+    };
+    if data.len() > len {
+        return Err(babble(data.len(), len));
+    }
+    Ok(data)
+}
+
+/// Keeps several bulk IN transfers in flight, returning their data in order
+///
+/// A single transfer leaves the pipe idle between the completion of a URB
+/// and the submission of the next one. With a ring, the device fills the
+/// next transfers meanwhile. Data read ahead is lost if the client stops
+/// reading, so only use it for streaming endpoints.
+#[derive(Debug)]
+pub struct BulkInRing<S> {
+    source: S,
+    num_transfers: usize,
+    // a transfer longer than the URB that took it, for the next URB
+    overflowed: Option<Vec<u8>>,
+}
+
+impl<S: BulkInSource> BulkInRing<S> {
+    /// Keep `num_transfers` transfers of `source` in flight, at least one
+    pub fn new(source: S, num_transfers: usize) -> Self {
+        Self {
+            source,
+            num_transfers: num_transfers.max(1),
+            overflowed: None,
+        }
+    }
+
+    /// The data of the next transfer, for a URB of `len` bytes
+    ///
+    /// Transfers are submitted with the length of the URB requesting them.
+    /// Transfers are not split, a URB shorter than the data of the next one
+    /// fails with EOVERFLOW and the data is kept for the following URB.
+    pub fn read(&mut self, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        let data = match self.overflowed.take() {
+            Some(data) => data,
+            None => {
+                while self.source.pending() < self.num_transfers {
+                    self.source.submit(len);
+                }
+                // still pending when timing out, its data goes to the next URB
+                match self.source.wait_next(timeout) {
+                    Some(res) => res?,
+                    None => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            "Bulk IN transfer timed out",
+                        ));
+                    }
+                }
+            }
+        };
+        if data.len() > len {
+            let err = babble(data.len(), len);
+            self.overflowed = Some(data);
+            return Err(err);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use std::time::Instant;
+
+    /// A device answering each transfer `latency` after it is submitted, with its sequence number
+    struct SimulatedSource {
+        latency: Duration,
+        // length of the answers instead of the transfers when set
+        packet_len: Option<usize>,
+        submitted: VecDeque<(Instant, usize)>,
+        count: u8,
+        cancelled: bool,
+    }
+
+    impl SimulatedSource {
+        fn new(latency: Duration) -> Self {
+            Self {
+                latency,
+                packet_len: None,
+                submitted: VecDeque::new(),
+                count: 0,
+                cancelled: false,
+            }
+        }
+    }
+
+    impl BulkInSource for SimulatedSource {
+        fn submit(&mut self, len: usize) {
+            self.submitted
+                .push_back((Instant::now() + self.latency, len));
+        }
+
+        fn wait_next(
+            &mut self,
+            timeout: Duration,
+        ) -> Option<std::result::Result<Vec<u8>, TransferError>> {
+            let (done, len) = *self.submitted.front()?;
+            if self.cancelled {
+                self.submitted.pop_front();
+                return Some(Err(TransferError::Cancelled));
+            }
+            let len = self.packet_len.unwrap_or(len);
+            let wait = done.saturating_duration_since(Instant::now());
+            if wait > timeout {
+                std::thread::sleep(timeout);
+                return None;
+            }
+            std::thread::sleep(wait);
+            self.submitted.pop_front();
+            self.count += 1;
+            Some(Ok(vec![self.count; len]))
+        }
+
+        fn pending(&self) -> usize {
+            self.submitted.len()
+        }
+
+        fn cancel_all(&mut self) {
+            self.cancelled = true;
+        }
+    }
+
+    fn read_all(num_transfers: usize) -> Duration {
+        let source = SimulatedSource::new(Duration::from_millis(5));
+        let mut ring = BulkInRing::new(source, num_transfers);
+        let start = Instant::now();
+        for i in 1..=20 {
+            let data = ring.read(512, Duration::from_secs(1)).unwrap();
+            assert_eq!(data, vec![i; 512]);
+        }
+        start.elapsed()
+    }
+
+    #[test]
+    fn overlapping_transfers() {
+        setup_test_logger();
+        let single = read_all(1);
+        let multi = read_all(4);
+        info!("20 transfers: {single:?} one at a time, {multi:?} with 4 in flight");
+        assert!(multi * 2 < single);
+    }
+
+    #[test]
+    fn shorter_urb_overflows() {
+        setup_test_logger();
+        let source = SimulatedSource::new(Duration::ZERO);
+        let mut ring = BulkInRing::new(source, 2);
+        assert_eq!(ring.read(8, Duration::from_secs(1)).unwrap(), vec![1; 8]);
+        // the second transfer was submitted for 8 bytes
+        let err = ring.read(4, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EOVERFLOW);
+        // not split, a URB holding it gets it whole
+        assert_eq!(ring.read(8, Duration::from_secs(1)).unwrap(), vec![2; 8]);
+        // the third one was submitted for the 4 byte URB
+        assert_eq!(ring.read(8, Duration::from_secs(1)).unwrap(), vec![3; 4]);
+    }
+
+    #[test]
+    fn short_packet_ends_transfer() {
+        setup_test_logger();
+        let mut source = SimulatedSource::new(Duration::from_millis(5));
+        source.packet_len = Some(13);
+        let start = Instant::now();
+        let data = bulk_in_transfer(&mut source, 512, Duration::from_secs(1)).unwrap();
+        assert_eq!(data, vec![1; 13]);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(source.pending(), 0);
+    }
+
+    #[test]
+    fn overflow_fails_transfer() {
+        setup_test_logger();
+        // the transfer is rounded up to a whole packet, more than the URB holds
+        let mut source = SimulatedSource::new(Duration::ZERO);
+        source.packet_len = Some(512);
+        let err = bulk_in_transfer(&mut source, 500, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::EOVERFLOW);
+    }
+
+    #[test]
+    fn transfer_cancelled_after_timeout() {
+        setup_test_logger();
+        let mut source = SimulatedSource::new(Duration::from_secs(10));
+        let err = bulk_in_transfer(&mut source, 512, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(urb_status(&err), UrbStatus::ETIMEDOUT);
+        assert_eq!(source.pending(), 0);
+    }
+}
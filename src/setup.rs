@@ -29,6 +29,30 @@ impl SetupPacket {
         }
     }
 
+    /// Serialize back into a raw setup packet
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let [value_lo, value_hi] = self.value.to_le_bytes();
+        let [index_lo, index_hi] = self.index.to_le_bytes();
+        let [length_lo, length_hi] = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value_lo,
+            value_hi,
+            index_lo,
+            index_hi,
+            length_lo,
+            length_hi,
+        ]
+    }
+
+    /// Whether this is SET_CONFIGURATION(0), putting the device back in the address state
+    pub fn is_unconfigure(&self) -> bool {
+        self.request_type == 0
+            && self.request == crate::StandardRequest::SetConfiguration as u8
+            && self.value == 0
+    }
+
     pub fn is_setup(&self) -> bool {
         self.request_type != 0
             || self.request != 0
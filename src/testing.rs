@@ -0,0 +1,330 @@
+//! Helpers to test code built on this crate, enabled by the `testing` feature
+use crate::{SetupPacket, TransferTimeouts, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// One scripted read of a [MockSocket]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MockRead {
+    /// Bytes returned by one or more reads, never merged with the next chunk
+    Data(Vec<u8>),
+    /// A read failing with this kind, e.g. [ErrorKind::ConnectionReset]
+    Error(ErrorKind),
+}
+
+/// An in-memory socket, reads follow a script and writes are collected in `output`
+///
+/// Once the script is exhausted, reads return end of file.
+#[derive(Debug, Default)]
+pub struct MockSocket {
+    reads: VecDeque<MockRead>,
+    pub output: Vec<u8>,
+}
+
+impl MockSocket {
+    /// A socket reading `input`, then end of file
+    pub fn new(input: Vec<u8>) -> Self {
+        Self::scripted(vec![MockRead::Data(input)])
+    }
+
+    /// A socket following `reads`, to test partial reads and errors mid-stream
+    pub fn scripted(reads: Vec<MockRead>) -> Self {
+        Self {
+            reads: reads.into(),
+            output: vec![],
+        }
+    }
+
+    /// Number of scripted bytes not read yet
+    pub fn unread(&self) -> usize {
+        self.reads
+            .iter()
+            .map(|r| match r {
+                MockRead::Data(data) => data.len(),
+                MockRead::Error(_) => 0,
+            })
+            .sum()
+    }
+}
+
+impl AsyncRead for MockSocket {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, buf: &mut ReadBuf) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match this.reads.pop_front() {
+            Some(MockRead::Data(mut data)) => {
+                let len = data.len().min(buf.remaining());
+                buf.put_slice(&data[..len]);
+                if len < data.len() {
+                    // the rest of the chunk is returned by the next reads
+                    this.reads.push_front(MockRead::Data(data.split_off(len)));
+                }
+                Poll::Ready(Ok(()))
+            }
+            Some(MockRead::Error(kind)) => Poll::Ready(Err(kind.into())),
+            // end of file
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncWrite for MockSocket {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.get_mut().output.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// How long a [DelayHandler] holds each URB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delay {
+    Fixed(Duration),
+    /// Uniformly distributed between both bounds
+    Random {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+/// Wraps an interface handler to answer slowly, to test how clients cope with slow devices
+///
+/// The delay blocks the thread handling the URB, like a slow device does. It
+/// is measured on the tokio clock, so tests pausing it advance the delay
+/// with `tokio::time::advance`. [UsbInterfaceHandler::as_any] reaches the
+/// wrapped handler, e.g. to configure a host interface.
+#[derive(Debug)]
+pub struct DelayHandler<H> {
+    inner: H,
+    delay: Delay,
+    // xorshift state for random delays
+    seed: u64,
+}
+
+impl<H: UsbInterfaceHandler> DelayHandler<H> {
+    pub fn new(inner: H, delay: Delay) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        Self {
+            inner,
+            delay,
+            seed: u64::from(nanos) | 1,
+        }
+    }
+
+    /// The wrapped handler
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    /// The delay of the next URB
+    fn next_delay(&mut self) -> Duration {
+        match self.delay {
+            Delay::Fixed(delay) => delay,
+            Delay::Random { min, max } => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                let span = max.saturating_sub(min).as_nanos() as u64;
+                min + Duration::from_nanos(self.seed % (span + 1))
+            }
+        }
+    }
+}
+
+impl<H: UsbInterfaceHandler + 'static> UsbInterfaceHandler for DelayHandler<H> {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.get_class_specific_descriptor()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_flags: u32,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let res = self.inner.handle_urb(
+            interface,
+            ep,
+            transfer_flags,
+            transfer_buffer_length,
+            setup,
+            req,
+        );
+        block_for(self.next_delay());
+        res
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self.inner.as_any()
+    }
+
+    fn set_transfer_timeouts(&mut self, timeouts: TransferTimeouts) {
+        self.inner.set_transfer_timeouts(timeouts)
+    }
+}
+
+/// Block the thread for `delay` on the tokio clock, until a paused clock is advanced that much
+fn block_for(delay: Duration) {
+    let until = tokio::time::Instant::now() + delay;
+    loop {
+        let left = until.saturating_duration_since(tokio::time::Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        // a paused clock only moves when advanced
+        std::thread::sleep(left.min(Duration::from_millis(1)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use crate::vendor::VendorControlHandler;
+    use crate::{EndpointAttributes, UsbDevice};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::Instant;
+
+    fn delayed_device(delay: Delay) -> UsbDevice {
+        let responses = HashMap::from([((0x01, 0, 0), vec![1, 2, 3])]);
+        let handler = DelayHandler::new(VendorControlHandler::new(responses), delay);
+        UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            None,
+            vec![],
+            Arc::new(Mutex::new(Box::new(handler))),
+        )
+    }
+
+    fn vendor_request(device: &UsbDevice) -> (Result<Vec<u8>>, Duration) {
+        let setup = SetupPacket {
+            request_type: 0b11000001, // vendor, interface
+            request: 0x01,
+            value: 0,
+            index: 0,
+            length: 3,
+        };
+        let ep = UsbEndpoint {
+            address: 0x80,
+            attributes: EndpointAttributes::Control as u8,
+            max_packet_size: 64,
+            interval: 0,
+        };
+        let intf = &device.interfaces[0];
+        let start = Instant::now();
+        let res = intf
+            .handler
+            .lock()
+            .unwrap()
+            .handle_urb(intf, ep, 0, 3, setup, &[]);
+        (res, start.elapsed())
+    }
+
+    /// A request to `device` on a blocking thread, advancing the paused clock until it is answered
+    async fn delayed_request(device: UsbDevice) -> (Result<Vec<u8>>, Duration) {
+        let request = tokio::task::spawn_blocking(move || vendor_request(&device));
+        while !request.is_finished() {
+            tokio::time::advance(Duration::from_millis(1)).await;
+            tokio::task::yield_now().await;
+        }
+        request.await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_delay() {
+        setup_test_logger();
+        let device = delayed_device(Delay::Fixed(Duration::from_secs(30)));
+        let (res, elapsed) = delayed_request(device).await;
+        assert_eq!(res.unwrap(), vec![1, 2, 3]);
+        assert!(elapsed >= Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_delay_within_bounds() {
+        setup_test_logger();
+        let (min, max) = (Duration::from_millis(5), Duration::from_millis(15));
+        let mut handler =
+            DelayHandler::new(VendorControlHandler::default(), Delay::Random { min, max });
+        for _ in 0..100 {
+            let delay = handler.next_delay();
+            assert!(min <= delay && delay <= max, "{delay:?}");
+        }
+        let device = delayed_device(Delay::Random { min, max });
+        let (res, elapsed) = delayed_request(device).await;
+        assert_eq!(res.unwrap(), vec![1, 2, 3]);
+        assert!(elapsed >= min);
+    }
+
+    /// A handler recording the timeouts it is given
+    #[derive(Debug, Default)]
+    struct TimeoutsHandler {
+        timeouts: Option<TransferTimeouts>,
+    }
+
+    impl UsbInterfaceHandler for TimeoutsHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_flags: u32,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn set_transfer_timeouts(&mut self, timeouts: TransferTimeouts) {
+            self.timeouts = Some(timeouts);
+        }
+    }
+
+    #[test]
+    fn wrapped_handler_reached() {
+        setup_test_logger();
+        let handler = DelayHandler::new(TimeoutsHandler::default(), Delay::Fixed(Duration::ZERO));
+        let device = UsbDevice::new(0).with_interface(
+            0xFF,
+            0,
+            0,
+            None,
+            vec![],
+            Arc::new(Mutex::new(Box::new(handler))),
+        );
+        let timeouts = TransferTimeouts {
+            bulk: Duration::from_secs(5),
+            ..Default::default()
+        };
+        device.set_transfer_timeouts(timeouts);
+        let mut handler = device.interfaces[0].handler.lock().unwrap();
+        let inner = handler.as_any().downcast_mut::<TimeoutsHandler>().unwrap();
+        assert_eq!(inner.timeouts, Some(timeouts));
+    }
+}
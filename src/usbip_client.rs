@@ -0,0 +1,130 @@
+//! Client side of the USB/IP protocol, for test harnesses talking to a server
+use super::*;
+use crate::usbip_protocol::{OP_REP_IMPORT, USBIP_VERSION};
+
+/// A device imported with [import], as described by OP_REP_IMPORT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportedDevice {
+    pub path: String,
+    pub bus_id: String,
+    pub bus_num: u32,
+    pub dev_num: u32,
+    /// USB/IP speed code, see [UsbSpeed]
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice
+    pub device_bcd: u16,
+    pub device_class: u8,
+    pub device_subclass: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+}
+
+impl ImportedDevice {
+    /// The devid of the device in USBIP_CMD_SUBMIT headers
+    pub fn devid(&self) -> u32 {
+        (self.bus_num << 16) | self.dev_num
+    }
+
+    /// Parse the 312 bytes describing a device
+    fn parse(buf: &[u8; 0x138]) -> Self {
+        let string = |bytes: &[u8]| {
+            let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..len]).into_owned()
+        };
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let u16_at = |i: usize| u16::from_be_bytes(buf[i..i + 2].try_into().unwrap());
+        Self {
+            path: string(&buf[..256]),
+            bus_id: string(&buf[256..288]),
+            bus_num: u32_at(288),
+            dev_num: u32_at(292),
+            speed: u32_at(296),
+            vendor_id: u16_at(300),
+            product_id: u16_at(302),
+            device_bcd: u16_at(304),
+            device_class: buf[306],
+            device_subclass: buf[307],
+            device_protocol: buf[308],
+            configuration_value: buf[309],
+            num_configurations: buf[310],
+            num_interfaces: buf[311],
+        }
+    }
+}
+
+/// Import the device `bus_id` from the server at the other end of `stream`
+///
+/// Fails if the server refuses, e.g. because the device doesn't exist or is in use.
+pub async fn import<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut T,
+    bus_id: &str,
+) -> Result<ImportedDevice> {
+    let mut busid = bus_id.as_bytes().to_vec();
+    // the server reads the bus id up to the NUL terminator
+    if busid.len() >= 32 {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Bus id {bus_id} is too long"),
+        ));
+    }
+    busid.resize(32, 0);
+    let req = UsbIpCommand::OpReqImport {
+        status: 0,
+        busid: busid.try_into().unwrap(),
+    };
+    stream.write_all(&req.to_bytes()).await?;
+
+    let version = stream.read_u16().await?;
+    let code = stream.read_u16().await?;
+    if version != USBIP_VERSION || code != OP_REP_IMPORT {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected reply {code:#06x} of version {version:#06x}"),
+        ));
+    }
+    let status = stream.read_u32().await?;
+    if status != 0 {
+        return Err(std::io::Error::other(format!(
+            "Import of {bus_id} failed with status {status}"
+        )));
+    }
+    let mut buf = [0; 0x138];
+    stream.read_exact(&mut buf).await?;
+    Ok(ImportedDevice::parse(&buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[tokio::test]
+    async fn import_simulated_device() {
+        setup_test_logger();
+        let mut device = UsbDevice::new(3).with_speed(UsbSpeed::Full);
+        device.bus_num = 2;
+        device.vendor_id = 0x1234;
+        device.product_id = 0x5678;
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let (mut client, stream) = tokio::io::duplex(4096);
+        tokio::spawn(serve_connection(stream, server));
+
+        assert!(import(&mut client, "1-2-3").await.is_err());
+        let too_long = "1".repeat(32);
+        let err = import(&mut client, &too_long).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let imported = import(&mut client, "0-0-0").await.unwrap();
+        assert_eq!(imported.bus_id, "0-0-0");
+        assert_eq!(imported.path, "/sys/bus/0/0/0");
+        assert_eq!(imported.bus_num, 2);
+        assert_eq!(imported.dev_num, 3);
+        assert_eq!(imported.devid(), 0x0002_0003);
+        assert_eq!(imported.speed, UsbSpeed::Full as u32);
+        assert_eq!((imported.vendor_id, imported.product_id), (0x1234, 0x5678));
+        assert_eq!(imported.num_interfaces, 0);
+    }
+}
@@ -7,7 +7,7 @@
 //! They are based on the [Linux kernel documentation](https://docs.kernel.org/usb/usbip_protocol.html).
 
 use log::{info, trace};
-use std::io::Result;
+use std::io::{ErrorKind, Result};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[cfg(feature = "serde")]
@@ -21,6 +21,31 @@ use crate::{SetupPacket, UsbDevice};
 /// for this library.
 pub const USBIP_VERSION: u16 = 0x0111;
 
+/// Largest transfer_buffer_length of a USBIP_CMD_SUBMIT, the 16 MiB usbfs allows by default
+///
+/// Submits above it are rejected by [UsbIpCommand::read_from_socket] before
+/// their data is read.
+pub const MAX_TRANSFER_BUFFER_LENGTH: u32 = 16 * 1024 * 1024;
+
+/// Error for an OP_REQ_* request of a protocol version other than [USBIP_VERSION]
+///
+/// The request is consumed from the socket, so it can be rejected with a
+/// failed reply to `command`. Returned wrapped in an [std::io::Error] of kind
+/// [ErrorKind::Unsupported] by [UsbIpCommand::read_from_socket].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedVersion {
+    pub version: u16,
+    pub command: u16,
+}
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown version: {:#04X}", self.version)
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
 /// Command code: Retrieve the list of exported USB devices
 pub const OP_REQ_DEVLIST: u16 = 0x8005;
 /// Command code: import a remote USB device
@@ -29,6 +54,19 @@ pub const OP_REQ_IMPORT: u16 = 0x8003;
 pub const OP_REP_DEVLIST: u16 = 0x0005;
 /// Reply code: Reply to import
 pub const OP_REP_IMPORT: u16 = 0x0003;
+/// Command code: negotiate compression of RET_SUBMIT data, an extension of this crate
+///
+/// Clients send it before importing, and keep the usual wire format unless
+/// the reply selects an algorithm. Servers not knowing it close the connection.
+pub const OP_REQ_COMPRESSION: u16 = 0x80C0;
+/// Reply code: Reply to compression negotiation
+pub const OP_REP_COMPRESSION: u16 = 0x00C0;
+/// LZ4 block compression, as a bit of the algorithms offered by the client
+///
+/// Once selected, the data of each RET_SUBMIT is sent as its compressed
+/// length (u32, big endian) followed by the LZ4 block. `actual_length`
+/// remains the uncompressed length.
+pub const COMPRESSION_LZ4: u32 = 0x1;
 
 /// Command code: Submit an URB
 pub const USBIP_CMD_SUBMIT: u16 = 0x0001;
@@ -112,7 +150,12 @@ impl UsbIpHeaderBasic {
         let devid = socket.read_u32().await?;
         let direction = socket.read_u32().await?;
         // The direction should be 0 or 1
-        debug_assert!(direction & 1 == direction);
+        if direction & 1 != direction {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid direction: {direction:#X}"),
+            ));
+        }
         let ep = socket.read_u32().await?;
 
         Ok(UsbIpHeaderBasic {
@@ -136,6 +179,10 @@ pub enum UsbIpCommand {
         status: u32,
         busid: [u8; 32],
     },
+    /// Algorithms offered by the client, see [OP_REQ_COMPRESSION]
+    OpReqCompression {
+        algorithms: u32,
+    },
     UsbIpCmdSubmit {
         header: UsbIpHeaderBasic,
         transfer_flags: u32,
@@ -159,22 +206,38 @@ impl UsbIpCommand {
     /// This will consume a variable amount of bytes from the socket.
     /// It might fail if the bytes does not follow the USB/IP protocol properly.
     pub async fn read_from_socket<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<UsbIpCommand> {
+        // USBIP_CMD_* have a 32 bit command code instead, whose upper half is 0
         let version: u16 = socket.read_u16().await?;
+        let command: u16 = socket.read_u16().await?;
 
         if version != 0 && version != USBIP_VERSION {
-            return Err(std::io::Error::other(format!(
-                "Unknown version: {version:#04X}"
-            )));
+            // skip the request body, so the request can be rejected
+            match command {
+                OP_REQ_DEVLIST => {
+                    socket.read_u32().await?;
+                }
+                OP_REQ_IMPORT => {
+                    socket.read_u32().await?;
+                    socket.read_exact(&mut [0; 32]).await?;
+                }
+                OP_REQ_COMPRESSION => {
+                    socket.read_u32().await?;
+                }
+                _ => {}
+            }
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                UnsupportedVersion { version, command },
+            ));
         }
 
-        let command: u16 = socket.read_u16().await?;
-
         trace!(
             "Received command: {:#04X} ({}), parsing...",
             command,
             match command {
                 OP_REQ_DEVLIST => "OP_REQ_DEVLIST",
                 OP_REQ_IMPORT => "OP_REQ_IMPORT",
+                OP_REQ_COMPRESSION => "OP_REQ_COMPRESSION",
                 USBIP_CMD_SUBMIT => "USBIP_CMD_SUBMIT",
                 USBIP_CMD_UNLINK => "USBIP_CMD_UNLINK",
                 _ => "Unknown",
@@ -195,6 +258,10 @@ impl UsbIpCommand {
                 socket.read_exact(&mut busid).await?;
                 Ok(UsbIpCommand::OpReqImport { status, busid })
             }
+            OP_REQ_COMPRESSION => {
+                let algorithms = socket.read_u32().await?;
+                Ok(UsbIpCommand::OpReqCompression { algorithms })
+            }
             USBIP_CMD_SUBMIT => {
                 let header =
                     UsbIpHeaderBasic::read_from_socket_with_command(socket, USBIP_CMD_SUBMIT)
@@ -207,11 +274,20 @@ impl UsbIpCommand {
                 // let setup = socket.read_u64().await?;
                 let mut setup = [0; 8];
                 socket.read_exact(&mut setup).await?;
+                if transfer_buffer_length > MAX_TRANSFER_BUFFER_LENGTH {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "URB {} of {transfer_buffer_length} bytes is too large",
+                            header.seqnum
+                        ),
+                    ));
+                }
 
                 let data = if header.direction == Direction::In as u32 {
                     vec![]
                 } else {
-                    let mut data = vec![0; transfer_buffer_length as usize];
+                    let mut data = crate::buffer_pool().take(transfer_buffer_length as usize);
                     socket.read_exact(&mut data).await?;
                     data
                 };
@@ -255,9 +331,10 @@ impl UsbIpCommand {
                     unlink_seqnum,
                 })
             }
-            _ => Err(std::io::Error::other(format!(
-                "Unknown command: {command:#04X}"
-            ))),
+            _ => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown command: {command:#04X}"),
+            )),
         }
     }
 
@@ -279,6 +356,13 @@ impl UsbIpCommand {
                 result.extend_from_slice(&busid);
                 result
             }
+            UsbIpCommand::OpReqCompression { algorithms } => {
+                let mut result = Vec::with_capacity(8);
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REQ_COMPRESSION.to_be_bytes());
+                result.extend_from_slice(&algorithms.to_be_bytes());
+                result
+            }
             UsbIpCommand::UsbIpCmdSubmit {
                 ref header,
                 transfer_flags,
@@ -363,8 +447,10 @@ pub enum UsbIpResponse {
     },
     OpRepImport {
         status: u32,
-        device: Option<UsbDevice>,
+        device: Option<Box<UsbDevice>>,
     },
+    /// `algorithm` selected for the rest of the connection, if `status` is 0
+    OpRepCompression { status: u32, algorithm: u32 },
     UsbIpRetSubmit {
         // Reply headers from server should be all 0 from 0xc to 0x14
         header: UsbIpHeaderBasic,
@@ -418,6 +504,14 @@ impl UsbIpResponse {
                 }
                 result
             }
+            Self::OpRepCompression { status, algorithm } => {
+                let mut result = Vec::with_capacity(12);
+                result.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+                result.extend_from_slice(&OP_REP_COMPRESSION.to_be_bytes());
+                result.extend_from_slice(&status.to_be_bytes());
+                result.extend_from_slice(&algorithm.to_be_bytes());
+                result
+            }
             Self::UsbIpRetSubmit {
                 ref header,
                 status,
@@ -431,7 +525,12 @@ impl UsbIpResponse {
                 let mut result =
                     Vec::with_capacity(48 + transfer_buffer.len() + iso_packet_descriptor.len());
 
-                debug_assert!(header.command == USBIP_RET_SUBMIT.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_SUBMIT));
+                // ISO packet descriptors are 16 bytes each
+                debug_assert!(
+                    iso_packet_descriptor.is_empty()
+                        || iso_packet_descriptor.len() == 16 * number_of_packets as usize
+                );
                 // debug_assert!(if header.direction == Direction::In as u32 {
                 //     actual_length == transfer_buffer.len() as u32
                 // } else {
@@ -453,7 +552,7 @@ impl UsbIpResponse {
             Self::UsbIpRetUnlink { ref header, status } => {
                 let mut result = Vec::with_capacity(48);
 
-                debug_assert!(header.command == USBIP_RET_UNLINK.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_UNLINK));
 
                 result.extend_from_slice(&header.to_bytes());
                 result.extend_from_slice(&status.to_be_bytes());
@@ -463,8 +562,52 @@ impl UsbIpResponse {
         }
     }
 
+    /// Write [Self::to_bytes] to `socket`
+    ///
+    /// OP_REP_DEVLIST is encoded a device at a time, see [write_op_rep_devlist].
     pub async fn write_to_socket<T: AsyncWriteExt + Unpin>(&self, socket: &mut T) -> Result<()> {
-        socket.write_all(&self.to_bytes()).await
+        match self {
+            Self::OpRepDevlist {
+                status,
+                device_count,
+                devices,
+            } => {
+                let records = devices.iter().map(UsbDevice::to_bytes_with_interfaces);
+                write_devlist(socket, *status, *device_count, records).await
+            }
+            _ => socket.write_all(&self.to_bytes()).await,
+        }
+    }
+
+    /// Like [Self::to_bytes], with the data of a RET_SUBMIT compressed as for [COMPRESSION_LZ4]
+    #[cfg(feature = "compression")]
+    pub fn to_bytes_compressed(&self) -> Vec<u8> {
+        match self {
+            Self::UsbIpRetSubmit {
+                transfer_buffer,
+                iso_packet_descriptor,
+                ..
+            } if !transfer_buffer.is_empty() => {
+                // the header is kept as is
+                let mut result = self.to_bytes();
+                result.truncate(48);
+                let compressed = lz4_flex::block::compress(transfer_buffer);
+                result.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+                result.extend_from_slice(&compressed);
+                result.extend_from_slice(iso_packet_descriptor);
+                result
+            }
+            _ => self.to_bytes(),
+        }
+    }
+
+    /// Write it with [Self::to_bytes_compressed]
+    #[cfg(feature = "compression")]
+    pub async fn write_to_socket_compressed<T: AsyncWriteExt + Unpin>(
+        &self,
+        socket: &mut T,
+    ) -> Result<()> {
+        socket.write_all(&self.to_bytes_compressed()).await
     }
 
     /// Constructs a OP_REP_DEVLIST response
@@ -476,11 +619,20 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs a failed OP_REP_DEVLIST response
+    pub fn op_rep_devlist_fail() -> Self {
+        Self::OpRepDevlist {
+            status: 1,
+            device_count: 0,
+            devices: vec![],
+        }
+    }
+
     /// Constructs a successful OP_REP_IMPORT response
     pub fn op_rep_import_success(device: &UsbDevice) -> Self {
         Self::OpRepImport {
             status: 0,
-            device: Some(device.clone()),
+            device: Some(Box::new(device.clone())),
         }
     }
 
@@ -492,6 +644,20 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs an OP_REP_COMPRESSION response, `None` keeps data uncompressed
+    pub fn op_rep_compression(algorithm: Option<u32>) -> Self {
+        match algorithm {
+            Some(algorithm) => Self::OpRepCompression {
+                status: 0,
+                algorithm,
+            },
+            None => Self::OpRepCompression {
+                status: 1,
+                algorithm: 0,
+            },
+        }
+    }
+
     /// Constructs a successful OP_REP_IMPORT response
     pub fn usbip_ret_submit_success(
         header: &UsbIpHeaderBasic,
@@ -515,10 +681,14 @@ impl UsbIpResponse {
     }
 
     /// Constructs a failed OP_REP_IMPORT response
-    pub fn usbip_ret_submit_fail(header: &UsbIpHeaderBasic, actual_length: u32) -> Self {
+    pub fn usbip_ret_submit_fail(
+        header: &UsbIpHeaderBasic,
+        status: i32,
+        actual_length: u32,
+    ) -> Self {
         let s = Self::UsbIpRetSubmit {
             header: header.clone(),
-            status: 1,
+            status: status as u32,
             actual_length,
             start_frame: 0,
             number_of_packets: 0,
@@ -547,6 +717,35 @@ impl UsbIpResponse {
     }
 }
 
+/// Write an OP_REP_DEVLIST of `devices`, as [UsbIpResponse::op_rep_devlist] would encode it
+///
+/// Devices are written as they are encoded, without cloning them into a reply.
+pub async fn write_op_rep_devlist<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    devices: &[&UsbDevice],
+) -> Result<()> {
+    let records = devices.iter().map(|dev| dev.to_bytes_with_interfaces());
+    write_devlist(socket, 0, devices.len() as u32, records).await
+}
+
+/// Write the OP_REP_DEVLIST header then each encoded device, buffered into full segments
+pub(crate) async fn write_devlist<T: AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    status: u32,
+    device_count: u32,
+    records: impl Iterator<Item = impl AsRef<[u8]>>,
+) -> Result<()> {
+    let mut socket = tokio::io::BufWriter::new(socket);
+    socket.write_all(&USBIP_VERSION.to_be_bytes()).await?;
+    socket.write_all(&OP_REP_DEVLIST.to_be_bytes()).await?;
+    socket.write_all(&status.to_be_bytes()).await?;
+    socket.write_all(&device_count.to_be_bytes()).await?;
+    for record in records {
+        socket.write_all(record.as_ref()).await?;
+    }
+    socket.flush().await
+}
+
 #[cfg(test)]
 mod tests {
     use crate::util::tests::*;
@@ -669,11 +868,69 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn header_basic_big_endian_round_trip() {
+        setup_test_logger();
+        let header = UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum: 0x0102_0304,
+            devid: 0x0002_0003,
+            direction: 1,
+            ep: 0x0000_0005,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(
+            bytes,
+            [
+                0x00, 0x00, 0x00, 0x01, // command
+                0x01, 0x02, 0x03, 0x04, // seqnum
+                0x00, 0x02, 0x00, 0x03, // devid
+                0x00, 0x00, 0x00, 0x01, // direction
+                0x00, 0x00, 0x00, 0x05, // ep
+            ]
+        );
+        assert_eq!(UsbIpHeaderBasic::from_bytes(&bytes), header);
+
+        // as read from a client
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: header.clone(),
+            transfer_flags: 0,
+            transfer_buffer_length: 8,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        let mut socket = MockSocket::new(cmd.to_bytes());
+        match UsbIpCommand::read_from_socket(&mut socket).await.unwrap() {
+            UsbIpCommand::UsbIpCmdSubmit { header: read, .. } => assert_eq!(read, header),
+            cmd => panic!("Unexpected command {cmd:?}"),
+        }
+
+        // the reply keeps the seqnum only
+        let device = UsbDevice::new(0);
+        let reply = crate::UsbIpServer::new_simulated(vec![])
+            .handle_usbip_cmd_submit(header, 0, 8, [0; 8], vec![], &device)
+            .unwrap();
+        assert_eq!(
+            reply.to_bytes()[..20],
+            [
+                0x00, 0x00, 0x00, 0x03, // command
+                0x01, 0x02, 0x03, 0x04, // seqnum
+                0x00, 0x00, 0x00, 0x00, // devid
+                0x00, 0x00, 0x00, 0x00, // direction
+                0x00, 0x00, 0x00, 0x00, // ep
+            ]
+        );
+    }
+
     #[test]
     fn byte_serialize_op_rep_devlist() {
         setup_test_logger();
         let device = example_device();
-        let res = UsbIpResponse::op_rep_devlist(&[device.clone()]);
+        let res = UsbIpResponse::op_rep_devlist(std::slice::from_ref(&device));
         assert_eq!(
             res.to_bytes(),
             [
@@ -688,6 +945,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn byte_serialize_empty_op_rep_devlist() {
+        setup_test_logger();
+        // as sent by usbipd of Linux 6.x with no exportable device
+        let capture = [
+            0x01, 0x11, // version
+            0x00, 0x05, // command
+            0x00, 0x00, 0x00, 0x00, // status
+            0x00, 0x00, 0x00, 0x00, // device_count
+        ];
+        assert_eq!(UsbIpResponse::op_rep_devlist(&[]).to_bytes(), capture);
+    }
+
+    #[tokio::test]
+    async fn write_op_rep_devlist_per_device() {
+        setup_test_logger();
+        let devices: Vec<UsbDevice> = (0..300)
+            .map(|i| {
+                let mut device = UsbDevice::new(i);
+                device.bus_id = format!("1-{i}");
+                (0..i % 3)
+                    .fold(crate::UsbDeviceBuilder::new(device), |device, _| {
+                        device.with_interface(0xFF, 0, 0, |intf| intf)
+                    })
+                    .build()
+            })
+            .collect();
+        let res = UsbIpResponse::op_rep_devlist(&devices);
+        let mut written = WriteCounter::default();
+        res.write_to_socket(&mut written).await.unwrap();
+        assert_eq!(written.output[8..12], 300u32.to_be_bytes());
+        assert_eq!(written.output, res.to_bytes());
+        // in segments of several devices
+        assert!(written.writes < 30, "{} writes", written.writes);
+
+        let borrowed: Vec<&UsbDevice> = devices.iter().collect();
+        let mut written = WriteCounter::default();
+        write_op_rep_devlist(&mut written, &borrowed).await.unwrap();
+        assert_eq!(written.output, res.to_bytes());
+    }
+
+    /// Collects what is written to it, counting the writes
+    #[derive(Default)]
+    struct WriteCounter {
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl tokio::io::AsyncWrite for WriteCounter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<Result<usize>> {
+            self.writes += 1;
+            self.output.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
     #[test]
     fn byte_serialize_op_rep_import() {
         setup_test_logger();
@@ -963,6 +1294,33 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn unsupported_version_consumes_request() {
+        setup_test_logger();
+
+        let mut busid = [0; 32];
+        busid[..5].copy_from_slice(b"0-0-0");
+        let cmd = UsbIpCommand::OpReqImport { status: 0, busid };
+        let mut bytes = cmd.to_bytes();
+        bytes[0] = 0x01;
+        bytes[1] = 0x00; // set version to 0x0100
+
+        let mut socket = MockSocket::new(bytes);
+        let err = UsbIpCommand::read_from_socket(&mut socket)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<UnsupportedVersion>()),
+            Some(&UnsupportedVersion {
+                version: 0x0100,
+                command: OP_REQ_IMPORT
+            })
+        );
+        assert_eq!(socket.unread(), 0);
+    }
+
     #[tokio::test]
     async fn byte_serialization_fails_on_invalid_command() {
         setup_test_logger();
@@ -974,9 +1332,8 @@ mod tests {
         let mut socket = MockSocket::new(bytes);
         let result = UsbIpCommand::read_from_socket(&mut socket).await;
         assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err().to_string(),
-            "Unknown command: 0x1005".to_string()
-        );
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Unknown command: 0x1005".to_string());
     }
 }
@@ -1,3 +1,8 @@
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
 /// Check validity of a USB descriptor
 pub fn verify_descriptor(desc: &[u8]) {
     let mut offset = 0;
@@ -7,61 +12,103 @@ pub fn verify_descriptor(desc: &[u8]) {
     assert_eq!(offset, desc.len());
 }
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use std::{
-        io::*,
-        net::SocketAddr,
-        pin::Pin,
-        task::{Context, Poll},
-    };
-    use tokio::{
-        io::{AsyncRead, AsyncWrite, ReadBuf},
-        net::{TcpListener, TcpStream},
-    };
+/// Quote and escape `s` as a JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Wake a thread parked in [block_on]
+struct ThreadWaker(std::thread::Thread);
 
-    pub(crate) struct MockSocket {
-        pub input: Cursor<Vec<u8>>,
-        pub output: Vec<u8>,
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
     }
+}
 
-    impl MockSocket {
-        pub(crate) fn new(input: Vec<u8>) -> Self {
-            Self {
-                input: Cursor::new(input),
-                output: vec![],
-            }
+/// Drive `fut` to completion on the current thread
+///
+/// For futures that complete without a runtime, like those of nusb, or
+/// the blocking sockets of the synchronous server.
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => return out,
+            Poll::Pending => std::thread::park(),
         }
     }
+}
 
-    impl AsyncRead for MockSocket {
-        fn poll_read(
-            self: Pin<&mut Self>,
-            cx: &mut Context,
-            buf: &mut ReadBuf,
-        ) -> Poll<Result<()>> {
-            // safe, see https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
-            unsafe { self.map_unchecked_mut(|s| &mut s.input).poll_read(cx, buf) }
-        }
+/// Sleep for `duration` on the tokio timer, or block the thread without a runtime
+///
+/// Threads of the synchronous server run without one.
+pub(crate) async fn sleep(duration: std::time::Duration) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio::time::sleep(duration).await,
+        Err(_) => std::thread::sleep(duration),
     }
+}
 
-    #[cfg(test)]
-    impl AsyncWrite for MockSocket {
-        fn poll_write(
-            self: Pin<&mut Self>,
-            _cx: &mut Context<'_>,
-            buf: &[u8],
-        ) -> Poll<Result<usize>> {
-            self.get_mut().output.extend_from_slice(buf);
-            Poll::Ready(Ok(buf.len()))
-        }
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
 
-        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
-            Poll::Ready(Ok(()))
+    pub(crate) use crate::testing::*;
+    use crate::usbip_protocol::{USBIP_CMD_SUBMIT, UsbIpHeaderBasic, UsbIpResponse};
+    use crate::{SetupPacket, UsbDevice, UsbIpServer};
+
+    /// The header of a USBIP_CMD_SUBMIT with seqnum 1 to `ep` of the device `devid`
+    pub(crate) fn submit_header(devid: u32, direction: u32, ep: u32) -> UsbIpHeaderBasic {
+        UsbIpHeaderBasic {
+            command: USBIP_CMD_SUBMIT.into(),
+            seqnum: 1,
+            devid,
+            direction,
+            ep,
         }
+    }
 
-        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
-            Poll::Ready(Ok(()))
+    /// Submit a URB to `device` on `server`, returns the status and data of the reply
+    pub(crate) fn submit(
+        server: &UsbIpServer,
+        device: &UsbDevice,
+        header: UsbIpHeaderBasic,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        data: Vec<u8>,
+    ) -> (i32, Vec<u8>) {
+        let resp = server
+            .handle_usbip_cmd_submit(
+                header,
+                0,
+                transfer_buffer_length,
+                setup.to_bytes(),
+                data,
+                device,
+            )
+            .unwrap();
+        match resp {
+            UsbIpResponse::UsbIpRetSubmit {
+                status,
+                transfer_buffer,
+                ..
+            } => (status as i32, transfer_buffer),
+            _ => panic!("Unexpected response {resp:?}"),
         }
     }
 
@@ -0,0 +1,220 @@
+//! Checks of the descriptors of simulated devices, see [UsbDevice::validate]
+use super::*;
+
+/// A problem found by [UsbDevice::validate]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The control endpoint `address` is missing or is not a control endpoint
+    MissingEp0 { address: u8 },
+    /// Two endpoints of `configuration` have the same bEndpointAddress
+    DuplicateEndpoint { configuration: u8, address: u8 },
+    /// An interface lists endpoint zero
+    Ep0InInterface { configuration: u8, interface: u8 },
+    /// A descriptor refers to string `index`, which is not in the string pool
+    DanglingString { index: u8 },
+    /// Two configurations have the same bConfigurationValue, or it is 0
+    InvalidConfigurationValue { configuration: u8 },
+    /// bNumConfigurations does not match the configurations of the device
+    ConfigurationCount { declared: u8, actual: usize },
+    /// The max packet size of ep0 is not allowed at the speed of the device
+    InvalidEp0MaxPacketSize { size: u16, speed: u32 },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingEp0 { address } => write!(f, "Missing control endpoint {address:#04x}"),
+            Self::DuplicateEndpoint {
+                configuration,
+                address,
+            } => write!(
+                f,
+                "Duplicate endpoint {address:#04x} in configuration {configuration}"
+            ),
+            Self::Ep0InInterface {
+                configuration,
+                interface,
+            } => write!(
+                f,
+                "Endpoint zero in interface {interface} of configuration {configuration}"
+            ),
+            Self::DanglingString { index } => write!(f, "No string {index}"),
+            Self::InvalidConfigurationValue { configuration } => {
+                write!(
+                    f,
+                    "Invalid or duplicate configuration value {configuration}"
+                )
+            }
+            Self::ConfigurationCount { declared, actual } => write!(
+                f,
+                "bNumConfigurations is {declared}, but the device has {actual} configurations"
+            ),
+            Self::InvalidEp0MaxPacketSize { size, speed } => write!(
+                f,
+                "Max packet size {size} of ep0 is invalid at speed {speed}"
+            ),
+        }
+    }
+}
+
+impl UsbDevice {
+    /// Check that the descriptors of a simulated device are well-formed
+    ///
+    /// All problems found are reported. Interfaces are numbered by their
+    /// position, so interface numbers are always contiguous. String indices
+    /// are checked against the strings of the device, interfaces of host
+    /// devices refer to strings of the real device instead.
+    pub fn validate(&self) -> std::result::Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+
+        for (ep0, address) in [(self.ep0_in, 0x80), (self.ep0_out, 0x00)] {
+            if ep0.address != address || ep0.attributes != EndpointAttributes::Control as u8 {
+                errors.push(ValidationError::MissingEp0 { address });
+            }
+        }
+
+        let size = self.ep0_in.max_packet_size;
+        let valid_sizes: &[u16] = match self.speed {
+            s if s == UsbSpeed::Low as u32 => &[8],
+            s if s == UsbSpeed::Full as u32 => &[8, 16, 32, 64],
+            s if s == UsbSpeed::High as u32 => &[64],
+            s if s >= UsbSpeed::Super as u32 => &[512],
+            _ => &[8, 16, 32, 64, 512],
+        };
+        if !valid_sizes.contains(&size) || self.ep0_out.max_packet_size != size {
+            errors.push(ValidationError::InvalidEp0MaxPacketSize {
+                size,
+                speed: self.speed,
+            });
+        }
+
+        let mut strings = vec![
+            self.string_manufacturer,
+            self.string_product,
+            self.string_serial,
+            self.string_configuration,
+        ];
+        strings.extend(self.configurations.iter().map(|c| c.string_configuration));
+        strings.extend(
+            self.interface_associations
+                .iter()
+                .chain(
+                    self.configurations
+                        .iter()
+                        .flat_map(|c| &c.interface_associations),
+                )
+                .map(|a| a.string_function),
+        );
+        let mut values = vec![];
+        let configurations = std::iter::once((self.configuration_value, &self.interfaces)).chain(
+            self.configurations
+                .iter()
+                .map(|c| (c.configuration_value, &c.interfaces)),
+        );
+        for (configuration, interfaces) in configurations {
+            if configuration == 0 || values.contains(&configuration) {
+                errors.push(ValidationError::InvalidConfigurationValue { configuration });
+            }
+            values.push(configuration);
+
+            let mut addresses = vec![];
+            for (i, intf) in interfaces.iter().enumerate() {
+                strings.push(intf.string_interface);
+                for ep in &intf.endpoints {
+                    if ep.is_ep0() {
+                        errors.push(ValidationError::Ep0InInterface {
+                            configuration,
+                            interface: i as u8,
+                        });
+                    } else if addresses.contains(&ep.address) {
+                        errors.push(ValidationError::DuplicateEndpoint {
+                            configuration,
+                            address: ep.address,
+                        });
+                    }
+                    addresses.push(ep.address);
+                }
+            }
+        }
+
+        if self.num_configurations as usize != values.len() {
+            errors.push(ValidationError::ConfigurationCount {
+                declared: self.num_configurations,
+                actual: values.len(),
+            });
+        }
+
+        strings.sort_unstable();
+        strings.dedup();
+        for index in strings {
+            if index != 0 && !self.string_pool.contains_key(&index) {
+                errors.push(ValidationError::DanglingString { index });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    fn bulk(address: u8) -> UsbEndpoint {
+        UsbEndpoint {
+            address,
+            attributes: EndpointAttributes::Bulk as u8,
+            max_packet_size: 512,
+            interval: 0,
+        }
+    }
+
+    fn device_with_endpoints(endpoints: Vec<UsbEndpoint>) -> UsbDevice {
+        UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0,
+            0,
+            Some("Interface"),
+            endpoints,
+            Arc::new(Mutex::new(Box::new(
+                crate::vendor::VendorControlHandler::default(),
+            ))),
+        )
+    }
+
+    #[test]
+    fn valid_device() {
+        setup_test_logger();
+        let device = device_with_endpoints(vec![bulk(0x81), bulk(0x01)]);
+        assert_eq!(device.validate(), Ok(()));
+    }
+
+    #[test]
+    fn duplicate_endpoint_address() {
+        setup_test_logger();
+        let device = device_with_endpoints(vec![bulk(0x81), bulk(0x01), bulk(0x81)]);
+        assert_eq!(
+            device.validate(),
+            Err(vec![ValidationError::DuplicateEndpoint {
+                configuration: 1,
+                address: 0x81
+            }])
+        );
+    }
+
+    #[test]
+    fn dangling_string_index() {
+        setup_test_logger();
+        let mut device = device_with_endpoints(vec![]);
+        device.interfaces[0].string_interface = 42;
+        assert_eq!(
+            device.validate(),
+            Err(vec![ValidationError::DanglingString { index: 42 }])
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Implement vendor-specific control requests
+use super::*;
+
+/// Callback answering a control request, see [VendorControlHandler::with_callback]
+pub type VendorControlCallback = dyn Fn(SetupPacket, &[u8]) -> Result<Vec<u8>> + Send;
+
+/// A control-only handler for simulated vendor devices
+///
+/// Requests are answered from a table keyed by `(bRequest, wValue, wIndex)`,
+/// then by an optional callback; other requests are stalled.
+/// It can serve requests to an interface, or to the device with [UsbDevice::with_device_handler].
+#[derive(Default)]
+pub struct VendorControlHandler {
+    pub responses: HashMap<(u8, u16, u16), Vec<u8>>,
+    callback: Option<Box<VendorControlCallback>>,
+}
+
+impl std::fmt::Debug for VendorControlHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VendorControlHandler")
+            .field("responses", &self.responses)
+            .field("callback", &self.callback.as_ref().map(|_| "Opaque"))
+            .finish()
+    }
+}
+
+impl VendorControlHandler {
+    pub fn new(responses: HashMap<(u8, u16, u16), Vec<u8>>) -> Self {
+        Self {
+            responses,
+            callback: None,
+        }
+    }
+
+    /// Answer requests missing from the table with `callback`
+    pub fn with_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(SetupPacket, &[u8]) -> Result<Vec<u8>> + Send + 'static,
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    fn handle_control(&self, setup: SetupPacket, req: &[u8]) -> Result<Vec<u8>> {
+        let mut resp = match self
+            .responses
+            .get(&(setup.request, setup.value, setup.index))
+        {
+            // data of OUT requests is accepted as is
+            Some(data) if setup.request_type & 0x80 != 0 => data.clone(),
+            Some(_) => vec![],
+            None => match &self.callback {
+                Some(callback) => callback(setup, req)?,
+                None => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::Unsupported,
+                        format!("Unsupported control request {setup:x?}"),
+                    ));
+                }
+            },
+        };
+        // requested len too short: wLength < real length
+        if setup.length < resp.len() as u16 {
+            resp.truncate(setup.length as usize);
+        }
+        Ok(resp)
+    }
+}
+
+impl UsbInterfaceHandler for VendorControlHandler {
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        _transfer_flags: u32,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        if ep.is_ep0() {
+            self.handle_control(setup, req)
+        } else {
+            warn!("Transfer to non-control endpoint {ep:x?} of a vendor control handler");
+            Ok(vec![])
+        }
+    }
+
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl UsbDeviceHandler for VendorControlHandler {
+    fn handle_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.handle_control(setup, req)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[test]
+    fn vendor_control_in() {
+        setup_test_logger();
+        let handler = VendorControlHandler::new(HashMap::from([(
+            (0x05, 0x0001, 0x0000),
+            vec![0x12, 0x34, 0x56],
+        )]))
+        .with_callback(|setup, _req| Ok(vec![setup.request; 2]));
+        let device = UsbDevice::new(0).with_interface(
+            ClassCode::VendorSpecific as u8,
+            0xFF,
+            0xFF,
+            None,
+            vec![],
+            Arc::new(Mutex::new(Box::new(handler))),
+        );
+
+        let mut setup = SetupPacket {
+            request_type: 0b11000001, // vendor, interface 0
+            request: 0x05,
+            value: 0x0001,
+            index: 0x0000,
+            length: 64,
+        };
+        let resp = device
+            .handle_urb(device.ep0_in, None, 0, 64, setup, &[])
+            .unwrap();
+        assert_eq!(resp, vec![0x12, 0x34, 0x56]);
+
+        setup.length = 2;
+        let resp = device
+            .handle_urb(device.ep0_in, None, 0, 2, setup, &[])
+            .unwrap();
+        assert_eq!(resp, vec![0x12, 0x34]);
+
+        // not in the table
+        setup.request = 0x07;
+        let resp = device
+            .handle_urb(device.ep0_in, None, 0, 2, setup, &[])
+            .unwrap();
+        assert_eq!(resp, vec![0x07, 0x07]);
+    }
+}